@@ -25,6 +25,11 @@ enum Commands {
         #[command(subcommand)]
         cmd: UserCommands,
     },
+    /// Manage exit groups
+    Group {
+        #[command(subcommand)]
+        cmd: GroupCommands,
+    },
     /// Manage exit nodes
     Node {
         #[command(subcommand)]
@@ -43,6 +48,53 @@ enum UserCommands {
         /// Quota in bytes
         #[arg(long)]
         quota: Option<u64>,
+        /// Exit group ID
+        #[arg(long)]
+        group: Option<i32>,
+    },
+    /// List users
+    List,
+    /// Show a user
+    Show {
+        /// User ID
+        id: u64,
+    },
+    /// List a user's open sessions across the cluster
+    Sessions {
+        /// User ID
+        id: u64,
+    },
+    /// Update a user's group, quota, limits or status
+    ///
+    /// An option given without a value resets it: the default group, no
+    /// quota, or the configured default limit.
+    Update {
+        /// User ID
+        id: u64,
+        /// Exit group ID
+        #[arg(long)]
+        group: Option<Option<i32>>,
+        /// Quota in bytes
+        #[arg(long)]
+        quota: Option<Option<u64>>,
+        /// Upload limit in bytes per second (0 = unlimited)
+        #[arg(long)]
+        rate_up: Option<Option<u64>>,
+        /// Download limit in bytes per second (0 = unlimited)
+        #[arg(long)]
+        rate_down: Option<Option<u64>>,
+        /// Concurrent session limit (0 = unlimited)
+        #[arg(long)]
+        max_sessions: Option<Option<u32>>,
+        /// Device limit (0 = unlimited)
+        #[arg(long)]
+        max_devices: Option<Option<u32>>,
+        /// Disable the user
+        #[arg(long, conflicts_with = "enable")]
+        disable: bool,
+        /// Re-enable a disabled user
+        #[arg(long)]
+        enable: bool,
     },
     /// Delete a user
     Delete {
@@ -51,6 +103,25 @@ enum UserCommands {
     },
 }
 
+#[derive(Subcommand, Debug)]
+enum GroupCommands {
+    /// List exit groups
+    List,
+    /// Update the bandwidth shared by a group's users
+    ///
+    /// An option given without a value resets it to the configured default.
+    Update {
+        /// Group ID
+        id: i32,
+        /// Upload limit in bytes per second (0 = unlimited)
+        #[arg(long)]
+        rate_up: Option<Option<u64>>,
+        /// Download limit in bytes per second (0 = unlimited)
+        #[arg(long)]
+        rate_down: Option<Option<u64>>,
+    },
+}
+
 #[derive(Subcommand, Debug)]
 enum NodeCommands {
     /// Register a new exit node
@@ -63,12 +134,145 @@ enum NodeCommands {
         #[arg(long, default_value = "1.0")]
         weight: f64,
     },
+    /// Enroll a reverse-mode exit node and print its key
+    Enroll {
+        /// Name the exit registers under (its `location`)
+        name: String,
+        /// Exit group IDs it may join
+        #[arg(long, value_delimiter = ',', required = true)]
+        groups: Vec<i32>,
+    },
+    /// List enrolled exit nodes
+    Credentials,
+    /// Revoke an exit node's credential
+    Revoke {
+        /// Name
+        name: String,
+    },
 }
 
 #[derive(Debug, Serialize)]
 struct CreateUserRequest {
     username: String,
     quota_bytes: Option<u64>,
+    group_id: Option<i32>,
+}
+
+/// Omitted fields are left unchanged; `null` resets one
+#[derive(Debug, Serialize)]
+struct UpdateUserRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group_id: Option<Option<i32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota_bytes: Option<Option<u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disabled: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rate_up_bps: Option<Option<u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rate_down_bps: Option<Option<u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_sessions: Option<Option<u32>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_devices: Option<Option<u32>>,
+}
+
+#[derive(Debug, Deserialize, Tabled)]
+struct UserInfo {
+    id: i64,
+    username: String,
+    #[tabled(display_with = "display_option")]
+    group_id: Option<i32>,
+    balance: i64,
+    #[tabled(display_with = "display_option")]
+    quota_bytes: Option<i64>,
+    disabled: bool,
+    #[tabled(display_with = "display_option")]
+    rate_up_bps: Option<i64>,
+    #[tabled(display_with = "display_option")]
+    rate_down_bps: Option<i64>,
+    #[tabled(display_with = "display_option")]
+    max_sessions: Option<i32>,
+    #[tabled(display_with = "display_option")]
+    max_devices: Option<i32>,
+}
+
+/// Omitted fields are left unchanged; `null` resets one
+#[derive(Debug, Serialize)]
+struct UpdateGroupRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rate_up_bps: Option<Option<u64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rate_down_bps: Option<Option<u64>>,
+}
+
+#[derive(Debug, Deserialize, Tabled)]
+struct GroupInfo {
+    id: i32,
+    name: String,
+    #[tabled(display_with = "display_option")]
+    description: Option<String>,
+    #[tabled(display_with = "display_option")]
+    rate_up_bps: Option<i64>,
+    #[tabled(display_with = "display_option")]
+    rate_down_bps: Option<i64>,
+}
+
+#[derive(Debug, Deserialize, Tabled)]
+struct SessionInfo {
+    conn_id: u64,
+    node_id: u64,
+    device: String,
+}
+
+#[derive(Debug, Serialize)]
+struct EnrollExitRequest {
+    name: String,
+    group_ids: Vec<i32>,
+}
+
+#[derive(Debug, Deserialize, Tabled)]
+struct ExitCredentialInfo {
+    id: i64,
+    name: String,
+    #[tabled(display_with = "display_list")]
+    group_ids: Vec<i32>,
+    revoked: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct EnrollExitResponse {
+    credential: ExitCredentialInfo,
+    exit_key: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateUserResponse {
+    user: UserInfo,
+    hmac_secret: String,
+}
+
+fn display_option<T: std::fmt::Display>(value: &Option<T>) -> String {
+    value.as_ref().map_or_else(|| "-".to_string(), T::to_string)
+}
+
+fn display_list<T: std::fmt::Display>(values: &[T]) -> String {
+    let values: Vec<String> = values.iter().map(T::to_string).collect();
+    values.join(",")
+}
+
+/// Print the error message returned by the management API
+async fn print_error(resp: reqwest::Response) {
+    let status = resp.status();
+    let message = resp
+        .json::<serde_json::Value>()
+        .await
+        .ok()
+        .and_then(|v| v["message"].as_str().map(str::to_string));
+    match message {
+        Some(message) => eprintln!("Error: {} ({})", message, status),
+        None => eprintln!("Error: {}", status),
+    }
 }
 
 #[derive(Debug, Serialize)]
@@ -103,10 +307,15 @@ async fn main() -> Result<()> {
             println!("{}", table);
         }
         Commands::User { cmd } => match cmd {
-            UserCommands::Create { username, quota } => {
+            UserCommands::Create {
+                username,
+                quota,
+                group,
+            } => {
                 let req = CreateUserRequest {
                     username,
                     quota_bytes: quota,
+                    group_id: group,
                 };
                 let resp = client
                     .post(format!("{}/admin/users", args.api))
@@ -115,9 +324,84 @@ async fn main() -> Result<()> {
                     .await?;
 
                 if resp.status().is_success() {
-                    println!("User created successfully");
+                    let created: CreateUserResponse = resp.json().await?;
+                    println!("{}", tabled::Table::new(vec![created.user]));
+                    println!("HMAC secret: {}", created.hmac_secret);
+                    println!("Store it now; it cannot be retrieved again.");
                 } else {
-                    eprintln!("Error: {}", resp.status());
+                    print_error(resp).await;
+                }
+            }
+            UserCommands::List => {
+                let resp = client
+                    .get(format!("{}/admin/users", args.api))
+                    .send()
+                    .await?;
+
+                if resp.status().is_success() {
+                    let users: Vec<UserInfo> = resp.json().await?;
+                    println!("{}", tabled::Table::new(users));
+                } else {
+                    print_error(resp).await;
+                }
+            }
+            UserCommands::Show { id } => {
+                let resp = client
+                    .get(format!("{}/admin/users/{}", args.api, id))
+                    .send()
+                    .await?;
+
+                if resp.status().is_success() {
+                    let user: UserInfo = resp.json().await?;
+                    println!("{}", tabled::Table::new(vec![user]));
+                } else {
+                    print_error(resp).await;
+                }
+            }
+            UserCommands::Sessions { id } => {
+                let resp = client
+                    .get(format!("{}/admin/users/{}/sessions", args.api, id))
+                    .send()
+                    .await?;
+
+                if resp.status().is_success() {
+                    let sessions: Vec<SessionInfo> = resp.json().await?;
+                    println!("{}", tabled::Table::new(sessions));
+                } else {
+                    print_error(resp).await;
+                }
+            }
+            UserCommands::Update {
+                id,
+                group,
+                quota,
+                rate_up,
+                rate_down,
+                max_sessions,
+                max_devices,
+                disable,
+                enable,
+            } => {
+                let req = UpdateUserRequest {
+                    group_id: group,
+                    quota_bytes: quota,
+                    disabled: (disable || enable).then_some(disable),
+                    rate_up_bps: rate_up,
+                    rate_down_bps: rate_down,
+                    max_sessions,
+                    max_devices,
+                };
+                let resp = client
+                    .patch(format!("{}/admin/users/{}", args.api, id))
+                    .json(&req)
+                    .send()
+                    .await?;
+
+                if resp.status().is_success() {
+                    let user: UserInfo = resp.json().await?;
+                    println!("{}", tabled::Table::new(vec![user]));
+                } else {
+                    print_error(resp).await;
                 }
             }
             UserCommands::Delete { id } => {
@@ -129,7 +413,44 @@ async fn main() -> Result<()> {
                 if resp.status().is_success() {
                     println!("User deleted successfully");
                 } else {
-                    eprintln!("Error: {}", resp.status());
+                    print_error(resp).await;
+                }
+            }
+        },
+        Commands::Group { cmd } => match cmd {
+            GroupCommands::List => {
+                let resp = client
+                    .get(format!("{}/admin/groups", args.api))
+                    .send()
+                    .await?;
+
+                if resp.status().is_success() {
+                    let groups: Vec<GroupInfo> = resp.json().await?;
+                    println!("{}", tabled::Table::new(groups));
+                } else {
+                    print_error(resp).await;
+                }
+            }
+            GroupCommands::Update {
+                id,
+                rate_up,
+                rate_down,
+            } => {
+                let req = UpdateGroupRequest {
+                    rate_up_bps: rate_up,
+                    rate_down_bps: rate_down,
+                };
+                let resp = client
+                    .patch(format!("{}/admin/groups/{}", args.api, id))
+                    .json(&req)
+                    .send()
+                    .await?;
+
+                if resp.status().is_success() {
+                    let group: GroupInfo = resp.json().await?;
+                    println!("{}", tabled::Table::new(vec![group]));
+                } else {
+                    print_error(resp).await;
                 }
             }
         },
@@ -156,6 +477,53 @@ async fn main() -> Result<()> {
                     eprintln!("Error: {}", resp.status());
                 }
             }
+            NodeCommands::Enroll { name, groups } => {
+                let req = EnrollExitRequest {
+                    name,
+                    group_ids: groups,
+                };
+                let resp = client
+                    .post(format!("{}/admin/exits", args.api))
+                    .json(&req)
+                    .send()
+                    .await?;
+
+                if resp.status().is_success() {
+                    let enrolled: EnrollExitResponse = resp.json().await?;
+                    println!("{}", tabled::Table::new(vec![enrolled.credential]));
+                    println!("Exit key: {}", enrolled.exit_key);
+                    println!(
+                        "Set it as server.exit_key on the exit; it cannot be retrieved again."
+                    );
+                } else {
+                    print_error(resp).await;
+                }
+            }
+            NodeCommands::Credentials => {
+                let resp = client
+                    .get(format!("{}/admin/exits", args.api))
+                    .send()
+                    .await?;
+
+                if resp.status().is_success() {
+                    let credentials: Vec<ExitCredentialInfo> = resp.json().await?;
+                    println!("{}", tabled::Table::new(credentials));
+                } else {
+                    print_error(resp).await;
+                }
+            }
+            NodeCommands::Revoke { name } => {
+                let resp = client
+                    .delete(format!("{}/admin/exits/{}", args.api, name))
+                    .send()
+                    .await?;
+
+                if resp.status().is_success() {
+                    println!("Exit node revoked");
+                } else {
+                    print_error(resp).await;
+                }
+            }
         },
     }
 
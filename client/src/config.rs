@@ -3,7 +3,7 @@
 use anyhow::Result;
 use serde::Deserialize;
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 /// Client configuration
 #[derive(Debug, Clone, Deserialize)]
@@ -12,6 +12,10 @@ pub struct ClientConfig {
     #[serde(default)]
     pub socks5: Socks5Config,
 
+    /// HTTP proxy configuration
+    #[serde(default)]
+    pub http_proxy: HttpProxyConfig,
+
     /// TUN configuration
     #[serde(default)]
     pub tun: TunConfig,
@@ -35,6 +39,10 @@ pub struct ClientConfig {
     /// DNS configuration (Local DNS)
     #[serde(default)]
     pub dns: DnsConfig,
+
+    /// Rule-based routing
+    #[serde(default)]
+    pub routing: RoutingConfig,
 }
 
 impl ClientConfig {
@@ -50,12 +58,14 @@ impl Default for ClientConfig {
     fn default() -> Self {
         Self {
             socks5: Socks5Config::default(),
+            http_proxy: HttpProxyConfig::default(),
             tun: TunConfig::default(),
             connection: ConnectionConfig::default(),
             security: SecurityConfig::default(),
             emergency: EmergencyConfig::default(),
             obfuscation: ObfuscationConfig::default(),
             dns: DnsConfig::default(),
+            routing: RoutingConfig::default(),
         }
     }
 }
@@ -67,9 +77,20 @@ pub struct Socks5Config {
     #[serde(default = "default_socks5_bind")]
     pub bind: SocketAddr,
 
-    /// Enable authentication
+    /// Require username/password authentication (RFC 1929)
     #[serde(default)]
     pub auth: bool,
+
+    /// Accepted credentials when `auth` is enabled
+    #[serde(default)]
+    pub users: Vec<Socks5User>,
+}
+
+/// SOCKS5 username/password credential
+#[derive(Debug, Clone, Deserialize)]
+pub struct Socks5User {
+    pub username: String,
+    pub password: String,
 }
 
 fn default_socks5_bind() -> SocketAddr {
@@ -81,6 +102,32 @@ impl Default for Socks5Config {
         Self {
             bind: default_socks5_bind(),
             auth: false,
+            users: Vec::new(),
+        }
+    }
+}
+
+/// HTTP proxy server configuration
+#[derive(Debug, Clone, Deserialize)]
+pub struct HttpProxyConfig {
+    /// Enable the HTTP proxy listener
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Bind address
+    #[serde(default = "default_http_proxy_bind")]
+    pub bind: SocketAddr,
+}
+
+fn default_http_proxy_bind() -> SocketAddr {
+    "127.0.0.1:8080".parse().unwrap()
+}
+
+impl Default for HttpProxyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            bind: default_http_proxy_bind(),
         }
     }
 }
@@ -277,9 +324,13 @@ pub struct DnsConfig {
     #[serde(default = "default_true")]
     pub enabled: bool,
 
-    /// Bind address (udp)
+    /// Bind address (udp and tcp)
     #[serde(default = "default_dns_bind")]
     pub bind: SocketAddr,
+
+    /// Fake-IP mode (TUN only)
+    #[serde(default)]
+    pub fake_ip: FakeIpConfig,
 }
 
 fn default_dns_bind() -> SocketAddr {
@@ -291,6 +342,108 @@ impl Default for DnsConfig {
         Self {
             enabled: default_true(),
             bind: default_dns_bind(),
+            fake_ip: FakeIpConfig::default(),
+        }
+    }
+}
+
+/// Fake-IP DNS configuration
+///
+/// A queries are answered with addresses from `range`, and TUN connections to
+/// those addresses are sent to the exit node by hostname.
+#[derive(Debug, Clone, Deserialize)]
+pub struct FakeIpConfig {
+    /// Enable fake-IP answers
+    #[serde(default)]
+    pub enabled: bool,
+
+    /// Address range handed out (CIDR)
+    #[serde(default = "default_fake_ip_range")]
+    pub range: String,
+
+    /// Seconds a mapping is kept after its last use
+    #[serde(default = "default_fake_ip_ttl")]
+    pub ttl: u64,
+
+    /// File the mapping table is saved to across restarts
+    #[serde(default)]
+    pub persist_path: Option<PathBuf>,
+}
+
+fn default_fake_ip_range() -> String {
+    "198.18.0.0/15".to_string()
+}
+
+fn default_fake_ip_ttl() -> u64 {
+    3600 // 1 hour
+}
+
+impl Default for FakeIpConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            range: default_fake_ip_range(),
+            ttl: default_fake_ip_ttl(),
+            persist_path: None,
+        }
+    }
+}
+
+/// Rule-based routing configuration
+///
+/// Rules are `type,value,action` strings, e.g. `domain-suffix,example.com,direct`
+/// or `geoip,CN,proxy(asia)`. The first matching rule wins.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RoutingConfig {
+    /// Inline rules, evaluated before those of `rules_file`
+    #[serde(default)]
+    pub rules: Vec<String>,
+
+    /// Rule file (one rule per line, `#` comments), reloaded when it changes
+    #[serde(default)]
+    pub rules_file: Option<PathBuf>,
+
+    /// Action when no rule matches
+    #[serde(default = "default_final_action")]
+    pub final_action: String,
+
+    /// MaxMind database used by `geoip` rules
+    #[serde(default)]
+    pub geoip_db: Option<PathBuf>,
+
+    /// Named proxy groups, selected with `proxy(name)`
+    #[serde(default)]
+    pub groups: Vec<ProxyGroupConfig>,
+
+    /// Interface direct connections are bound to
+    /// Required in TUN mode so direct traffic does not loop back into the TUN.
+    #[serde(default)]
+    pub direct_interface: Option<String>,
+}
+
+fn default_final_action() -> String {
+    "proxy".to_string()
+}
+
+impl Default for RoutingConfig {
+    fn default() -> Self {
+        Self {
+            rules: Vec::new(),
+            rules_file: None,
+            final_action: default_final_action(),
+            geoip_db: None,
+            groups: Vec::new(),
+            direct_interface: None,
         }
     }
 }
+
+/// A proxy group: its own set of server endpoints
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProxyGroupConfig {
+    /// Group name used in `proxy(name)` actions
+    pub name: String,
+
+    /// Server endpoints of this group
+    pub endpoints: Vec<String>,
+}
@@ -0,0 +1,393 @@
+//! Fake-IP DNS mapping
+//!
+//! In fake-IP mode the local DNS server answers A queries with synthetic
+//! addresses from a reserved range (198.18.0.0/15 by default). The TUN stack
+//! maps connections to those addresses back to the hostname, so the exit node
+//! resolves the name itself and the client never leaks a real lookup.
+//!
+//! Mappings are dropped after `ttl` seconds without use and can be persisted to
+//! a file so that addresses cached by applications survive a client restart.
+
+use crate::config::FakeIpConfig;
+use anyhow::{Result, anyhow};
+use apfsds_protocol::dns;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+/// TTL of synthesized answers; kept short so applications re-query and
+/// refresh the mapping instead of caching an address that may be evicted
+const ANSWER_TTL: u32 = 1;
+
+/// Interval between eviction and persistence runs
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(60);
+
+struct Entry {
+    domain: String,
+    /// Unix time of the last lookup in either direction
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Table {
+    /// Host offset within the range -> entry
+    entries: HashMap<u32, Entry>,
+    by_domain: HashMap<String, u32>,
+    /// Next offset to try when allocating
+    cursor: u32,
+    /// Changed since the last save
+    dirty: bool,
+}
+
+/// Bidirectional domain <-> fake IP table
+pub struct FakeIpPool {
+    network: u32,
+    /// Number of addresses in the range
+    size: u32,
+    ttl: u64,
+    persist_path: Option<PathBuf>,
+    table: Mutex<Table>,
+}
+
+impl FakeIpPool {
+    /// Create the pool, restoring saved mappings if a persist file exists
+    pub fn new(config: &FakeIpConfig) -> Result<Arc<Self>> {
+        let (addr, netmask) = crate::parse_cidr(&config.range)
+            .ok_or_else(|| anyhow!("Invalid fake-IP range: {}", config.range))?;
+        let mask = u32::from(netmask);
+        let size = (!mask).wrapping_add(1);
+        if !(4..=1 << 24).contains(&size) {
+            return Err(anyhow!(
+                "Fake-IP range {} must be between /8 and /30",
+                config.range
+            ));
+        }
+
+        let pool = Arc::new(Self {
+            network: u32::from(addr) & mask,
+            size,
+            ttl: config.ttl,
+            persist_path: config.persist_path.clone(),
+            table: Mutex::new(Table {
+                cursor: 1,
+                ..Default::default()
+            }),
+        });
+
+        if let Some(path) = &pool.persist_path {
+            match std::fs::read_to_string(path) {
+                Ok(content) => {
+                    let restored = pool.restore(&content);
+                    info!(
+                        "Restored {} fake-IP mappings from {}",
+                        restored,
+                        path.display()
+                    );
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => warn!("Failed to read {}: {}", path.display(), e),
+            }
+        }
+
+        Ok(pool)
+    }
+
+    /// Whether `ip` lies in the fake range
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        u32::from(ip).wrapping_sub(self.network) < self.size
+    }
+
+    /// Fake address for `domain`, allocating one if needed
+    pub fn lookup(&self, domain: &str) -> Ipv4Addr {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let now = now_secs();
+        let mut table = self.table.lock().unwrap();
+
+        if let Some(&offset) = table.by_domain.get(&domain) {
+            if let Some(entry) = table.entries.get_mut(&offset) {
+                entry.last_used = now;
+            }
+            return self.address(offset);
+        }
+
+        let offset = self.allocate(&mut table);
+        debug!("Fake IP {} -> {}", self.address(offset), domain);
+        table.by_domain.insert(domain.clone(), offset);
+        table.entries.insert(
+            offset,
+            Entry {
+                domain,
+                last_used: now,
+            },
+        );
+        table.dirty = true;
+        self.address(offset)
+    }
+
+    /// Domain mapped to a fake address, if any
+    pub fn domain(&self, ip: Ipv4Addr) -> Option<String> {
+        if !self.contains(ip) {
+            return None;
+        }
+        let offset = u32::from(ip) - self.network;
+        let mut table = self.table.lock().unwrap();
+        let entry = table.entries.get_mut(&offset)?;
+        entry.last_used = now_secs();
+        Some(entry.domain.clone())
+    }
+
+    /// Answer a DNS query locally
+    ///
+    /// A queries get a fake address; AAAA queries get an empty answer so that
+    /// clients fall back to IPv4. Returns None for anything that should be
+    /// forwarded upstream.
+    pub fn answer(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let (question, _) = dns::parse_query(query).ok()?;
+        if question.qclass != dns::CLASS_IN || question.name.is_empty() {
+            return None;
+        }
+
+        let addrs = match question.qtype {
+            dns::TYPE_A => vec![IpAddr::V4(self.lookup(&question.name))],
+            dns::TYPE_AAAA => Vec::new(),
+            _ => return None,
+        };
+        dns::build_response(query, &addrs, ANSWER_TTL).ok()
+    }
+
+    /// Drop mappings unused for longer than the TTL
+    /// Returns the number of mappings removed
+    pub fn evict_expired(&self) -> usize {
+        let cutoff = now_secs().saturating_sub(self.ttl);
+        let mut table = self.table.lock().unwrap();
+
+        let expired: Vec<u32> = table
+            .entries
+            .iter()
+            .filter(|(_, e)| e.last_used < cutoff)
+            .map(|(&offset, _)| offset)
+            .collect();
+        for offset in &expired {
+            if let Some(entry) = table.entries.remove(offset) {
+                table.by_domain.remove(&entry.domain);
+            }
+        }
+        if !expired.is_empty() {
+            table.dirty = true;
+        }
+        expired.len()
+    }
+
+    /// Periodically evict expired mappings and save the table
+    pub fn spawn_maintenance(self: &Arc<Self>) -> tokio::task::JoinHandle<()> {
+        let pool = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+            loop {
+                interval.tick().await;
+                let evicted = pool.evict_expired();
+                if evicted > 0 {
+                    debug!("Evicted {} fake-IP mappings", evicted);
+                }
+                if let Err(e) = pool.save().await {
+                    warn!("Failed to save fake-IP table: {}", e);
+                }
+            }
+        })
+    }
+
+    /// Write the table to the persist file if it changed
+    pub async fn save(&self) -> Result<()> {
+        let Some(path) = &self.persist_path else {
+            return Ok(());
+        };
+        let Some(content) = self.snapshot() else {
+            return Ok(());
+        };
+
+        // Write-then-rename so a crash never leaves a partial file
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, content).await?;
+        tokio::fs::rename(&tmp, path).await?;
+        Ok(())
+    }
+
+    /// Serialize the table as `ip domain last_used` lines, if it changed
+    fn snapshot(&self) -> Option<String> {
+        let mut table = self.table.lock().unwrap();
+        if !table.dirty {
+            return None;
+        }
+        table.dirty = false;
+
+        let mut content = String::new();
+        for (&offset, entry) in &table.entries {
+            content.push_str(&format!(
+                "{} {} {}\n",
+                self.address(offset),
+                entry.domain,
+                entry.last_used
+            ));
+        }
+        Some(content)
+    }
+
+    /// Load lines written by `snapshot`, skipping expired or foreign entries
+    fn restore(&self, content: &str) -> usize {
+        let cutoff = now_secs().saturating_sub(self.ttl);
+        let mut table = self.table.lock().unwrap();
+        let mut restored = 0;
+
+        for line in content.lines() {
+            let mut parts = line.split_whitespace();
+            let (Some(ip), Some(domain), Some(last_used)) =
+                (parts.next(), parts.next(), parts.next())
+            else {
+                continue;
+            };
+            let (Ok(ip), Ok(last_used)) = (ip.parse::<Ipv4Addr>(), last_used.parse::<u64>()) else {
+                continue;
+            };
+            if !self.contains(ip) || last_used < cutoff {
+                continue;
+            }
+
+            let offset = u32::from(ip) - self.network;
+            if !self.is_host(offset) || table.by_domain.contains_key(domain) {
+                continue;
+            }
+            table.by_domain.insert(domain.to_string(), offset);
+            table.entries.insert(
+                offset,
+                Entry {
+                    domain: domain.to_string(),
+                    last_used,
+                },
+            );
+            table.cursor = table.cursor.max(offset + 1);
+            restored += 1;
+        }
+        restored
+    }
+
+    /// Pick a free offset, evicting the least recently used mapping when the
+    /// range is exhausted
+    fn allocate(&self, table: &mut Table) -> u32 {
+        let hosts = self.size - 2;
+        if table.entries.len() as u32 >= hosts {
+            let lru = table
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(&offset, _)| offset)
+                .expect("range is not empty");
+            if let Some(entry) = table.entries.remove(&lru) {
+                table.by_domain.remove(&entry.domain);
+            }
+            return lru;
+        }
+
+        loop {
+            let offset = table.cursor;
+            table.cursor = if offset + 1 >= self.size - 1 {
+                1
+            } else {
+                offset + 1
+            };
+            if self.is_host(offset) && !table.entries.contains_key(&offset) {
+                return offset;
+            }
+        }
+    }
+
+    /// Offsets other than the network and broadcast addresses
+    fn is_host(&self, offset: u32) -> bool {
+        offset > 0 && offset < self.size - 1
+    }
+
+    fn address(&self, offset: u32) -> Ipv4Addr {
+        Ipv4Addr::from(self.network + offset)
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(range: &str) -> Arc<FakeIpPool> {
+        FakeIpPool::new(&FakeIpConfig {
+            range: range.to_string(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_lookup_roundtrip() {
+        let pool = pool("198.18.0.0/15");
+        let a = pool.lookup("Example.com.");
+        let b = pool.lookup("example.org");
+
+        assert_eq!(a, Ipv4Addr::new(198, 18, 0, 1));
+        assert_ne!(a, b);
+        assert_eq!(pool.lookup("example.com"), a);
+        assert_eq!(pool.domain(a).as_deref(), Some("example.com"));
+        assert!(pool.contains(Ipv4Addr::new(198, 19, 255, 255)));
+        assert!(!pool.contains(Ipv4Addr::new(198, 20, 0, 0)));
+        assert_eq!(pool.domain(Ipv4Addr::new(8, 8, 8, 8)), None);
+    }
+
+    #[test]
+    fn test_exhausted_range_evicts_lru() {
+        // /30 has two usable hosts
+        let pool = pool("10.1.0.0/30");
+        let a = pool.lookup("a.test");
+        let b = pool.lookup("b.test");
+        pool.table
+            .lock()
+            .unwrap()
+            .entries
+            .get_mut(&1)
+            .unwrap()
+            .last_used = 0;
+
+        let c = pool.lookup("c.test");
+        assert_eq!(c, a);
+        assert_eq!(pool.domain(a).as_deref(), Some("c.test"));
+        assert_eq!(pool.domain(b).as_deref(), Some("b.test"));
+    }
+
+    #[test]
+    fn test_evict_and_restore() {
+        let pool = pool("198.18.0.0/15");
+        let ip = pool.lookup("keep.test");
+        pool.lookup("stale.test");
+        pool.table
+            .lock()
+            .unwrap()
+            .entries
+            .get_mut(&2)
+            .unwrap()
+            .last_used = 0;
+
+        assert_eq!(pool.evict_expired(), 1);
+        let content = pool.snapshot().unwrap();
+        assert!(pool.snapshot().is_none());
+
+        let restored = self::pool("198.18.0.0/15");
+        assert_eq!(restored.restore(&content), 1);
+        assert_eq!(restored.domain(ip).as_deref(), Some("keep.test"));
+        // New allocations do not collide with restored ones
+        assert_ne!(restored.lookup("new.test"), ip);
+    }
+}
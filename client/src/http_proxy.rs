@@ -0,0 +1,655 @@
+//! HTTP/1.1 proxy server
+//!
+//! Handles `CONNECT host:port` tunnels and absolute-URI requests
+//! (`GET http://host/path`) for tools that cannot speak SOCKS5. Requests are
+//! routed like SOCKS5 CONNECT: through the tunnel, direct, or refused with 403.
+//! A forwarded connection carries a single request, so keep-alive requests for
+//! another origin never reach the first one.
+
+use crate::config::ClientConfig;
+use crate::mux::TargetAddr;
+use crate::routing::{Outbound, Router};
+use anyhow::Result;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info, warn};
+
+/// Maximum size of a request head (request line + headers)
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Maximum length of a chunk-size or trailer line in a chunked body
+const MAX_CHUNK_LINE: usize = 4096;
+
+/// Buffer between a forwarded request's client and its outbound relay
+const FORWARD_PIPE_SIZE: usize = 64 * 1024;
+
+/// Connection headers dropped from responses to forwarded requests
+const RESPONSE_CONNECTION_HEADERS: &[&str] = &["connection", "keep-alive", "proxy-connection"];
+
+/// Hop-by-hop headers that must not be forwarded to the origin
+const HOP_BY_HOP_HEADERS: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Parsed HTTP request head
+#[derive(Debug, PartialEq)]
+struct RequestHead {
+    method: String,
+    target: String,
+    version: String,
+    headers: Vec<(String, String)>,
+}
+
+/// Run the HTTP proxy server
+pub async fn run(config: &ClientConfig, router: Arc<Router>) -> Result<()> {
+    let listener = TcpListener::bind(config.http_proxy.bind).await?;
+    info!("HTTP proxy listening on {}", config.http_proxy.bind);
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        debug!("New HTTP proxy connection from {}", addr);
+
+        let router = router.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, addr, router).await {
+                error!("HTTP proxy error from {}: {}", addr, e);
+            }
+        });
+    }
+}
+
+/// Handle a single HTTP proxy connection
+async fn handle_connection(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    router: Arc<Router>,
+) -> Result<()> {
+    // Check emergency mode
+    if crate::emergency::is_emergency_mode() {
+        warn!("Rejecting connection due to emergency mode");
+        send_status(&mut stream, 503, "Service Unavailable").await?;
+        return Ok(());
+    }
+
+    let (head, rest) = match read_head(&mut stream).await? {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+
+    let head = match parse_head(&head) {
+        Ok(h) => h,
+        Err(e) => {
+            send_status(&mut stream, 400, "Bad Request").await?;
+            return Err(e);
+        }
+    };
+    debug!("{} {} from {}", head.method, head.target, addr);
+
+    if head.method.eq_ignore_ascii_case("CONNECT") {
+        handle_connect(stream, addr, head, rest, router).await
+    } else {
+        handle_forward(stream, addr, head, rest, router).await
+    }
+}
+
+/// Handle `CONNECT host:port` by tunneling raw bytes to the target
+async fn handle_connect(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    head: RequestHead,
+    rest: Vec<u8>,
+    router: Arc<Router>,
+) -> Result<()> {
+    let Some((host, port)) = split_host_port(&head.target, 443) else {
+        send_status(&mut stream, 400, "Bad Request").await?;
+        return Err(anyhow::anyhow!("Invalid CONNECT target: {}", head.target));
+    };
+    let target = TargetAddr::from_host(host, port);
+
+    let Some(outbound) = open(&mut stream, addr, &target, &router).await? else {
+        return Ok(());
+    };
+
+    stream
+        .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+        .await?;
+
+    // Clients may pipeline the first bytes (e.g. a TLS ClientHello)
+    outbound.relay(stream, &rest).await
+}
+
+/// Handle an absolute-URI request by rewriting it to origin form
+///
+/// Each forwarded request gets its own stream and `Connection: close`, so the
+/// origin ends the exchange and the relay finishes with it. Only the request's
+/// own body is passed on, and the client is told the connection closes after
+/// the response: whatever it sends next was meant for a new connection.
+async fn handle_forward(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    head: RequestHead,
+    rest: Vec<u8>,
+    router: Arc<Router>,
+) -> Result<()> {
+    let (Some((target, request)), Some(body)) = (rewrite_request(&head), BodyTracker::new(&head))
+    else {
+        send_status(&mut stream, 400, "Bad Request").await?;
+        return Err(anyhow::anyhow!(
+            "Unsupported request: {} {}",
+            head.method,
+            head.target
+        ));
+    };
+
+    let Some(outbound) = open(&mut stream, addr, &target, &router).await? else {
+        return Ok(());
+    };
+    debug!("Forwarding {} {}", head.method, head.target);
+
+    let (local, remote) = tokio::io::duplex(FORWARD_PIPE_SIZE);
+    let exchange = tokio::spawn(single_exchange(stream, local, rest, body));
+    outbound.relay(remote, &request).await?;
+    exchange.await?
+}
+
+/// Pass one request body from `client` to `origin` and its response back
+async fn single_exchange<O>(
+    client: TcpStream,
+    origin: O,
+    rest: Vec<u8>,
+    mut body: BodyTracker,
+) -> Result<()>
+where
+    O: AsyncRead + AsyncWrite,
+{
+    let (mut client_read, mut client_write) = client.into_split();
+    let (mut origin_read, mut origin_write) = tokio::io::split(origin);
+
+    let upload = async {
+        let used = body.feed(&rest)?;
+        origin_write.write_all(&rest[..used]).await?;
+
+        let mut buf = [0u8; 8192];
+        while !body.is_done() {
+            let n = client_read.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            let used = body.feed(&buf[..n])?;
+            origin_write.write_all(&buf[..used]).await?;
+        }
+        // Keep the origin's side open until the response is through
+        std::future::pending::<Result<()>>().await
+    };
+    let download = forward_response(&mut origin_read, &mut client_write);
+
+    tokio::select! {
+        res = upload => res,
+        res = download => res,
+    }
+}
+
+/// Copy a response to the client, marking its final head `Connection: close`
+async fn forward_response<R, W>(origin: &mut R, client: &mut W) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 8192];
+
+    // Interim (1xx) responses pass through until the final head
+    loop {
+        let n = origin.read(&mut chunk).await?;
+        if n == 0 {
+            client.write_all(&buf).await?;
+            client.shutdown().await?;
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        while let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head: Vec<u8> = buf.drain(..end + 4).collect();
+            match close_response(&head) {
+                Some(head) => {
+                    client.write_all(&head).await?;
+                    client.write_all(&buf).await?;
+                    tokio::io::copy(origin, client).await?;
+                    client.shutdown().await?;
+                    return Ok(());
+                }
+                None => client.write_all(&head).await?,
+            }
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            return Err(anyhow::anyhow!("Response head too large"));
+        }
+    }
+}
+
+/// Final response head rewritten to close the connection; None for 1xx heads
+fn close_response(head: &[u8]) -> Option<Vec<u8>> {
+    let Ok(text) = std::str::from_utf8(head) else {
+        return Some(head.to_vec());
+    };
+    let mut lines = text.split("\r\n").filter(|l| !l.is_empty());
+    let status_line = lines.next().unwrap_or_default();
+    let interim = status_line
+        .split_whitespace()
+        .nth(1)
+        .is_some_and(|status| status.starts_with('1'));
+    if interim {
+        return None;
+    }
+
+    let mut response = format!("{}\r\n", status_line);
+    for line in lines {
+        let name = line.split(':').next().unwrap_or_default().trim();
+        if !RESPONSE_CONNECTION_HEADERS.contains(&name.to_ascii_lowercase().as_str()) {
+            response.push_str(line);
+            response.push_str("\r\n");
+        }
+    }
+    response.push_str("Connection: close\r\n\r\n");
+    Some(response.into_bytes())
+}
+
+/// Finds where a request body ends in the bytes that follow its head
+#[derive(Debug)]
+enum BodyTracker {
+    Length(u64),
+    ChunkSize(Vec<u8>),
+    ChunkData(u64),
+    /// CRLF left after a chunk's data
+    ChunkEnd(u8),
+    Trailer(Vec<u8>),
+    Done,
+}
+
+impl BodyTracker {
+    /// Framing of the body of `head`; None if it cannot be told
+    fn new(head: &RequestHead) -> Option<Self> {
+        let header = |name: &str| {
+            head.headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        };
+        match (header("transfer-encoding"), header("content-length")) {
+            (Some(coding), _) => {
+                let chunked = coding
+                    .rsplit(',')
+                    .next()
+                    .is_some_and(|last| last.trim().eq_ignore_ascii_case("chunked"));
+                chunked.then(|| BodyTracker::ChunkSize(Vec::new()))
+            }
+            (None, Some(length)) => match length.parse() {
+                Ok(0) => Some(BodyTracker::Done),
+                Ok(length) => Some(BodyTracker::Length(length)),
+                Err(_) => None,
+            },
+            (None, None) => Some(BodyTracker::Done),
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        matches!(self, BodyTracker::Done)
+    }
+
+    /// Consume `data`, returning how many of its bytes belong to the body
+    fn feed(&mut self, data: &[u8]) -> Result<usize> {
+        let mut used = 0;
+        while used < data.len() {
+            let left = (data.len() - used) as u64;
+            match self {
+                BodyTracker::Done => break,
+                BodyTracker::Length(remaining) | BodyTracker::ChunkData(remaining) => {
+                    let take = left.min(*remaining);
+                    *remaining -= take;
+                    used += take as usize;
+                    if *remaining == 0 {
+                        *self = match self {
+                            BodyTracker::Length(_) => BodyTracker::Done,
+                            _ => BodyTracker::ChunkEnd(2),
+                        };
+                    }
+                }
+                BodyTracker::ChunkEnd(remaining) => {
+                    used += 1;
+                    *remaining -= 1;
+                    if *remaining == 0 {
+                        *self = BodyTracker::ChunkSize(Vec::new());
+                    }
+                }
+                BodyTracker::ChunkSize(line) | BodyTracker::Trailer(line) => {
+                    let byte = data[used];
+                    used += 1;
+                    if byte != b'\n' {
+                        line.push(byte);
+                        if line.len() > MAX_CHUNK_LINE {
+                            return Err(anyhow::anyhow!("Chunk line too long"));
+                        }
+                        continue;
+                    }
+                    let text = String::from_utf8_lossy(line)
+                        .trim_end_matches('\r')
+                        .to_string();
+                    *self = if matches!(self, BodyTracker::Trailer(_)) {
+                        if text.is_empty() {
+                            BodyTracker::Done
+                        } else {
+                            BodyTracker::Trailer(Vec::new())
+                        }
+                    } else {
+                        let size = text.split(';').next().unwrap_or_default().trim();
+                        match u64::from_str_radix(size, 16) {
+                            Ok(0) => BodyTracker::Trailer(Vec::new()),
+                            Ok(size) => BodyTracker::ChunkData(size),
+                            Err(_) => return Err(anyhow::anyhow!("Invalid chunk size: {}", size)),
+                        }
+                    };
+                }
+            }
+        }
+        Ok(used)
+    }
+}
+
+/// Route and connect to `target`, answering the client on reject or failure
+async fn open(
+    stream: &mut TcpStream,
+    addr: SocketAddr,
+    target: &TargetAddr,
+    router: &Router,
+) -> Result<Option<Outbound>> {
+    let action = router.route_connection(target, addr).await;
+    info!("Connection to {} routed {:?}", target, action);
+    match router.connect(&action, target).await {
+        Ok(Outbound::Reject) => {
+            send_status(stream, 403, "Forbidden").await?;
+            Ok(None)
+        }
+        Ok(outbound) => Ok(Some(outbound)),
+        Err(e) => {
+            error!("Failed to connect to {}: {}", target, e);
+            send_status(stream, 502, "Bad Gateway").await?;
+            Ok(None)
+        }
+    }
+}
+
+/// Read until the end of the request head
+/// Returns the head and any bytes already read past it
+async fn read_head(stream: &mut TcpStream) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let mut buf = Vec::with_capacity(4096);
+    let mut chunk = [0u8; 4096];
+
+    loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(end + 4);
+            return Ok(Some((buf, rest)));
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            send_status(stream, 431, "Request Header Fields Too Large").await?;
+            return Err(anyhow::anyhow!("Request head too large"));
+        }
+    }
+}
+
+/// Parse a request head into its request line and headers
+fn parse_head(head: &[u8]) -> Result<RequestHead> {
+    let text = std::str::from_utf8(head)?;
+    let mut lines = text.split("\r\n");
+
+    let request_line = lines
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Empty request"))?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(anyhow::anyhow!("Malformed request line: {}", request_line));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(anyhow::anyhow!("Unsupported HTTP version: {}", version));
+    }
+
+    let mut headers = Vec::new();
+    for line in lines.filter(|l| !l.is_empty()) {
+        let (name, value) = line
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("Malformed header: {}", line))?;
+        headers.push((name.trim().to_string(), value.trim().to_string()));
+    }
+
+    Ok(RequestHead {
+        method: method.to_string(),
+        target: target.to_string(),
+        version: version.to_string(),
+        headers,
+    })
+}
+
+/// Rewrite an absolute-URI request for the origin server
+/// Returns the origin to connect to and the request head to send
+fn rewrite_request(head: &RequestHead) -> Option<(TargetAddr, Vec<u8>)> {
+    // Only plain HTTP can be forwarded; HTTPS clients use CONNECT
+    if !head.target.get(..7)?.eq_ignore_ascii_case("http://") {
+        return None;
+    }
+    let rest = &head.target[7..];
+
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = split_host_port(authority, 80)?;
+
+    let mut request = format!("{} {} {}\r\n", head.method, path, head.version);
+    let mut has_host = false;
+    for (name, value) in &head.headers {
+        let lower = name.to_ascii_lowercase();
+        if HOP_BY_HOP_HEADERS.contains(&lower.as_str()) {
+            continue;
+        }
+        has_host |= lower == "host";
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if !has_host {
+        request.push_str(&format!("Host: {}\r\n", authority));
+    }
+    request.push_str("Connection: close\r\n\r\n");
+
+    Some((TargetAddr::from_host(host, port), request.into_bytes()))
+}
+
+/// Split "host:port" (or "[v6]:port"), falling back to `default_port`
+fn split_host_port(authority: &str, default_port: u16) -> Option<(String, u16)> {
+    // Strip any userinfo
+    let authority = authority.rsplit('@').next()?;
+    if authority.is_empty() {
+        return None;
+    }
+
+    if let Some(v6) = authority.strip_prefix('[') {
+        let (host, rest) = v6.split_once(']')?;
+        let port = match rest.strip_prefix(':') {
+            Some(p) => p.parse().ok()?,
+            None if rest.is_empty() => default_port,
+            None => return None,
+        };
+        return Some((host.to_string(), port));
+    }
+
+    match authority.rsplit_once(':') {
+        Some((host, port)) => Some((host.to_string(), port.parse().ok()?)),
+        None => Some((authority.to_string(), default_port)),
+    }
+}
+
+/// Send a bodyless status response
+async fn send_status(stream: &mut TcpStream, code: u16, reason: &str) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        code, reason
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_head() {
+        let head = parse_head(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n")
+            .unwrap();
+        assert_eq!(head.method, "CONNECT");
+        assert_eq!(head.target, "example.com:443");
+        assert_eq!(
+            head.headers,
+            vec![("Host".to_string(), "example.com:443".to_string())]
+        );
+
+        assert!(parse_head(b"GET / HTTP/2\r\n\r\n").is_err());
+        assert!(parse_head(b"GET\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn test_split_host_port() {
+        assert_eq!(
+            split_host_port("example.com:8443", 443),
+            Some(("example.com".to_string(), 8443))
+        );
+        assert_eq!(
+            split_host_port("example.com", 80),
+            Some(("example.com".to_string(), 80))
+        );
+        assert_eq!(
+            split_host_port("[2001:db8::1]:8080", 80),
+            Some(("2001:db8::1".to_string(), 8080))
+        );
+        assert_eq!(split_host_port("example.com:http", 80), None);
+    }
+
+    #[test]
+    fn test_rewrite_request() {
+        let head = parse_head(
+            b"GET http://example.com/index.html?q=1 HTTP/1.1\r\n\
+              Host: example.com\r\n\
+              Proxy-Connection: keep-alive\r\n\
+              Accept: */*\r\n\r\n",
+        )
+        .unwrap();
+
+        let (target, request) = rewrite_request(&head).unwrap();
+        assert_eq!(target, TargetAddr::Domain("example.com".to_string(), 80));
+        assert_eq!(
+            String::from_utf8(request).unwrap(),
+            "GET /index.html?q=1 HTTP/1.1\r\n\
+             Host: example.com\r\n\
+             Accept: */*\r\n\
+             Connection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_body_ends_before_next_request() {
+        let head =
+            parse_head(b"POST http://a.example/ HTTP/1.1\r\nContent-Length: 4\r\n\r\n").unwrap();
+        let mut body = BodyTracker::new(&head).unwrap();
+        assert_eq!(body.feed(b"ab").unwrap(), 2);
+        assert_eq!(body.feed(b"cdGET http://b.example/").unwrap(), 2);
+        assert!(body.is_done());
+
+        let head =
+            parse_head(b"POST http://a.example/ HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n")
+                .unwrap();
+        let mut body = BodyTracker::new(&head).unwrap();
+        let chunked = b"4;ext=1\r\nwiki\r\n5\r\npedia\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let mut data = chunked.to_vec();
+        data.extend_from_slice(b"GET http://b.example/ HTTP/1.1\r\n");
+        // Fed in pieces, as reads would split it
+        let used = body.feed(&data[..7]).unwrap() + body.feed(&data[7..]).unwrap();
+        assert_eq!(used, chunked.len());
+        assert!(body.is_done());
+
+        let head = parse_head(b"GET http://a.example/ HTTP/1.1\r\n\r\n").unwrap();
+        assert!(BodyTracker::new(&head).unwrap().is_done());
+        let head =
+            parse_head(b"POST http://a.example/ HTTP/1.1\r\nContent-Length: x\r\n\r\n").unwrap();
+        assert!(BodyTracker::new(&head).is_none());
+    }
+
+    #[test]
+    fn test_close_response() {
+        assert_eq!(close_response(b"HTTP/1.1 100 Continue\r\n\r\n"), None);
+
+        let head = close_response(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: keep-alive\r\nKeep-Alive: timeout=5\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(head).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_single_exchange_drops_later_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (proxied, _) = listener.accept().await.unwrap();
+        let (local, mut origin) = tokio::io::duplex(FORWARD_PIPE_SIZE);
+
+        let head =
+            parse_head(b"POST http://a.example/ HTTP/1.1\r\nContent-Length: 2\r\n\r\n").unwrap();
+        let body = BodyTracker::new(&head).unwrap();
+        let exchange = tokio::spawn(single_exchange(proxied, local, b"o".to_vec(), body));
+
+        client
+            .write_all(b"kGET http://b.example/secret HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut received = [0u8; 2];
+        origin.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"ok");
+
+        origin
+            .write_all(b"HTTP/1.1 204 No Content\r\nConnection: keep-alive\r\n\r\n")
+            .await
+            .unwrap();
+        drop(origin);
+        exchange.await.unwrap().unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        assert_eq!(
+            response,
+            "HTTP/1.1 204 No Content\r\nConnection: close\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_rewrite_rejects_https() {
+        let head = parse_head(b"GET https://example.com/ HTTP/1.1\r\n\r\n").unwrap();
+        assert!(rewrite_request(&head).is_none());
+    }
+}
@@ -3,19 +3,31 @@
 pub mod config;
 pub mod doh;
 pub mod emergency;
+pub mod fake_ip;
+pub mod http_proxy;
 pub mod local_dns;
 pub mod mobile;
+pub mod mux;
+pub mod netstack;
+pub mod routing;
 pub mod socks5;
 pub mod tun_device;
 pub mod wss;
 
 use anyhow::Result;
-
-pub async fn run_tun(config: &config::ClientConfig) -> Result<()> {
+use std::sync::Arc;
+
+/// Run TUN mode: capture traffic on a TUN device and route it with `router`
+///
+/// With `fake_ip`, connections to fake addresses are opened by hostname.
+pub async fn run_tun(
+    config: &config::ClientConfig,
+    router: Arc<routing::Router>,
+    fake_ip: Option<Arc<fake_ip::FakeIpPool>>,
+) -> Result<()> {
     tracing::info!("Initializing TUN device...");
 
     // Parse CIDR "10.0.0.2/24" -> (Ip, Netmask)
-    // Simple helper or dumb logic for now
     let (addr, netmask) = parse_cidr(&config.tun.address).unwrap_or((
         "10.0.0.2".parse().unwrap(),
         "255.255.255.0".parse().unwrap(),
@@ -28,28 +40,13 @@ pub async fn run_tun(config: &config::ClientConfig) -> Result<()> {
         mtu: config.tun.mtu,
     };
 
-    let mut device = tun_device::TunDevice::create(&tun_config)?;
-
-    tracing::info!("TUN device started. Reading packets...");
-
-    // Simple echo loop stub
-    let mut buf = [0u8; 1504];
-    loop {
-        match device.read(&mut buf) {
-            Ok(n) => {
-                tracing::debug!("Read {} bytes from TUN", n);
-            }
-            Err(e) => {
-                tracing::error!("TUN read error: {}", e);
-                break;
-            }
-        }
-    }
+    let device = tun_device::TunDevice::create(&tun_config)?;
 
-    Ok(())
+    tracing::info!("TUN device started");
+    netstack::run(device, &tun_config, router, fake_ip).await
 }
 
-fn parse_cidr(cidr: &str) -> Option<(std::net::Ipv4Addr, std::net::Ipv4Addr)> {
+pub(crate) fn parse_cidr(cidr: &str) -> Option<(std::net::Ipv4Addr, std::net::Ipv4Addr)> {
     let parts: Vec<&str> = cidr.split('/').collect();
     if parts.len() != 2 {
         return None;
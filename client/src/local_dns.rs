@@ -1,94 +1,408 @@
 //! Local DNS server implementation
 //!
-//! Provides a local UDP DNS server that forwards queries over the secure WSS tunnel.
+//! Provides a local DNS server (UDP and TCP on the same port) that forwards
+//! queries over the secure WSS tunnel as `DohQuery` control messages.
+//!
+//! Every forwarded query gets a fresh upstream transaction ID so that queries
+//! from different clients never collide; responses are matched back by that
+//! ID and question, restored to the client's ID and cached for their TTL.
 
 use crate::config::ClientConfig;
+use crate::fake_ip::FakeIpPool;
 use anyhow::Result;
-use apfsds_obfuscation::{PaddingStrategy, XorMask};
-use apfsds_protocol::{ControlMessage, FrameFlags, ProxyFrame};
-use futures::SinkExt;
+use apfsds_protocol::{ControlMessage, ProxyFrame, dns};
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::net::UdpSocket;
-use tokio::sync::Mutex;
-use tokio_tungstenite::{connect_async, tungstenite::Message};
-use tracing::{debug, error, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, info, trace, warn};
+
+/// Time to wait for an upstream answer before replying SERVFAIL
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Delay between upstream reconnection attempts
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Idle time after which a TCP client connection is closed
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum number of cached answers
+const MAX_CACHE_ENTRIES: usize = 4096;
+
+/// Upper bound on how long an answer is cached
+const MAX_CACHE_TTL: u32 = 3600;
+
+/// Maximum DNS message size over UDP
+const MAX_UDP_SIZE: usize = 4096;
 
 /// Run the local DNS server
-pub async fn run(config: &ClientConfig) -> Result<()> {
+///
+/// With `fake_ip`, A and AAAA queries are answered locally from the fake-IP
+/// table instead of being forwarded.
+pub async fn run(config: &ClientConfig, fake_ip: Option<Arc<FakeIpPool>>) -> Result<()> {
     if !config.dns.enabled {
         return Ok(());
     }
 
     let udp_socket = Arc::new(UdpSocket::bind(config.dns.bind).await?);
-    info!("Local DNS server listening on {}", config.dns.bind);
+    let tcp_listener = TcpListener::bind(config.dns.bind).await?;
+    info!(
+        "Local DNS server listening on {} (udp/tcp)",
+        config.dns.bind
+    );
+
+    let (outbound_tx, outbound_rx) = mpsc::unbounded_channel();
+    let resolver = Arc::new(Resolver::new(fake_ip, outbound_tx));
+
+    // Upstream session over WSS
+    let upstream_config = config.clone();
+    let upstream_resolver = resolver.clone();
+    tokio::spawn(async move {
+        run_upstream(&upstream_config, upstream_resolver, outbound_rx).await;
+    });
+
+    // TCP listener
+    let tcp_resolver = resolver.clone();
+    tokio::spawn(async move {
+        loop {
+            match tcp_listener.accept().await {
+                Ok((stream, addr)) => {
+                    let resolver = tcp_resolver.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = handle_tcp(stream, resolver).await {
+                            debug!("DNS TCP connection from {} ended: {}", addr, e);
+                        }
+                    });
+                }
+                Err(e) => error!("DNS TCP accept error: {}", e),
+            }
+        }
+    });
+
+    // UDP: one task per query so slow answers do not hold up others
+    let mut buf = vec![0u8; MAX_UDP_SIZE];
+    loop {
+        let (len, src) = match udp_socket.recv_from(&mut buf).await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("UDP recv error: {}", e);
+                continue;
+            }
+        };
+        let query = buf[..len].to_vec();
+        let resolver = resolver.clone();
+        let socket = udp_socket.clone();
+        tokio::spawn(async move {
+            if let Some(response) = resolver.resolve(&query).await
+                && let Err(e) = socket.send_to(&response, src).await
+            {
+                warn!("Failed to answer {}: {}", src, e);
+            }
+        });
+    }
+}
+
+/// Serve length-prefixed queries on a TCP connection (RFC 7766)
+/// Queries are resolved concurrently and answered as they complete.
+async fn handle_tcp(stream: TcpStream, resolver: Arc<Resolver>) -> Result<()> {
+    let (mut reader, mut writer) = stream.into_split();
+    let (response_tx, mut response_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+
+    let write_task = tokio::spawn(async move {
+        while let Some(response) = response_rx.recv().await {
+            let len = (response.len() as u16).to_be_bytes();
+            if writer.write_all(&len).await.is_err() || writer.write_all(&response).await.is_err() {
+                break;
+            }
+        }
+    });
 
-    // Connect to Daemon WSS
+    loop {
+        let mut len = [0u8; 2];
+        match tokio::time::timeout(TCP_IDLE_TIMEOUT, reader.read_exact(&mut len)).await {
+            Ok(Ok(_)) => {}
+            // Idle timeout or the client closed the connection
+            Ok(Err(_)) | Err(_) => break,
+        }
+
+        let mut query = vec![0u8; u16::from_be_bytes(len) as usize];
+        reader.read_exact(&mut query).await?;
+
+        let resolver = resolver.clone();
+        let response_tx = response_tx.clone();
+        tokio::spawn(async move {
+            if let Some(response) = resolver.resolve(&query).await {
+                let _ = response_tx.send(response);
+            }
+        });
+    }
+
+    // Let in-flight answers drain before closing
+    drop(response_tx);
+    let _ = write_task.await;
+    Ok(())
+}
+
+/// Keep a WSS session to the daemon and move queries and answers across it
+async fn run_upstream(
+    config: &ClientConfig,
+    resolver: Arc<Resolver>,
+    mut outbound: mpsc::UnboundedReceiver<Vec<u8>>,
+) {
     info!("Connecting to upstream for DNS...");
 
-    // Connect with retry logic
     loop {
-        match crate::wss::WssSession::connect(config).await {
-            Ok(session) => {
-                info!("Connected to Daemon WSS for DNS");
-                let conn_id = session.conn_id;
-                let (wss_tx, mut wss_rx) = session.split();
-
-                let udp_socket_rx = udp_socket.clone();
-                let _udp_socket_tx = udp_socket.clone(); // Needed if we implement reply mapping
-
-                // UDP -> WSS
-                let _udp_task = tokio::spawn(async move {
-                    let mut buf = [0u8; 4096];
-
-                    loop {
-                        match udp_socket_rx.recv_from(&mut buf).await {
-                            Ok((len, _)) => {
-                                let query = buf[..len].to_vec();
-                                let msg = ControlMessage::DohQuery { query };
-
-                                let payload = match rkyv::to_bytes::<rkyv::rancor::Error>(&msg) {
-                                    Ok(b) => b.to_vec(),
-                                    Err(_) => continue,
-                                };
-
-                                let mut frame = ProxyFrame::new_control(payload);
-                                frame.conn_id = conn_id;
-
-                                if let Err(e) = wss_tx.send_frame(&frame).await {
-                                    error!("WS send error: {}", e);
-                                    break;
-                                }
-                            }
-                            Err(e) => error!("UDP recv error: {}", e),
-                        }
+        let session = match crate::wss::WssSession::connect(config).await {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to connect to WSS: {}", e);
+                tokio::time::sleep(RECONNECT_DELAY).await;
+                continue;
+            }
+        };
+        info!("Connected to Daemon WSS for DNS");
+
+        let conn_id = session.conn_id;
+        let (wss_tx, mut wss_rx) = session.split();
+
+        loop {
+            tokio::select! {
+                query = outbound.recv() => {
+                    // All senders live in the resolver, which outlives this task
+                    let Some(query) = query else { return };
+                    let msg = ControlMessage::DohQuery { query };
+                    let payload = match rkyv::to_bytes::<rkyv::rancor::Error>(&msg) {
+                        Ok(b) => b.to_vec(),
+                        Err(_) => continue,
+                    };
+
+                    let mut frame = ProxyFrame::new_control(payload);
+                    frame.conn_id = conn_id;
+
+                    if let Err(e) = wss_tx.send_frame(&frame).await {
+                        error!("WS send error: {}", e);
+                        break;
                     }
-                });
-
-                // WSS -> UDP
-                while let Ok(Some(frame)) = wss_rx.recv_frame().await {
-                    if frame.flags.is_control {
-                        if let Ok(ctrl) =
-                            rkyv::from_bytes::<ControlMessage, rkyv::rancor::Error>(&frame.payload)
-                        {
-                            if let ControlMessage::DohResponse { response } = ctrl {
-                                // Send back to UDP
-                                debug!(
-                                    "Received DNS Response ({} bytes), dropping (no src addr map)",
-                                    response.len()
-                                );
-                            }
-                        }
+                }
+                frame = wss_rx.recv_frame() => {
+                    let Ok(Some(frame)) = frame else { break };
+                    if !frame.flags.is_control {
+                        continue;
+                    }
+                    if let Ok(ControlMessage::DohResponse { response }) =
+                        rkyv::from_bytes::<ControlMessage, rkyv::rancor::Error>(&frame.payload)
+                    {
+                        resolver.complete(response);
                     }
                 }
-
-                info!("WSS connection lost, reconnecting...");
             }
+        }
+
+        info!("WSS connection lost, reconnecting...");
+    }
+}
+
+/// A query waiting for its upstream answer
+struct Pending {
+    client_id: u16,
+    question: dns::Question,
+    reply: oneshot::Sender<Vec<u8>>,
+}
+
+/// Query forwarding with transaction tracking and an answer cache
+struct Resolver {
+    fake_ip: Option<Arc<FakeIpPool>>,
+    /// Upstream transaction ID -> waiting query
+    pending: Mutex<HashMap<u16, Pending>>,
+    cache: Mutex<dns::AnswerCache>,
+    outbound: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl Resolver {
+    fn new(fake_ip: Option<Arc<FakeIpPool>>, outbound: mpsc::UnboundedSender<Vec<u8>>) -> Self {
+        Self {
+            fake_ip,
+            pending: Mutex::new(HashMap::new()),
+            cache: Mutex::new(dns::AnswerCache::new(MAX_CACHE_ENTRIES, MAX_CACHE_TTL)),
+            outbound,
+        }
+    }
+
+    /// Answer a query from a local client
+    /// Returns None for messages that are not answerable queries
+    async fn resolve(&self, query: &[u8]) -> Option<Vec<u8>> {
+        let (question, _) = match dns::parse_query(query) {
+            Ok(q) => q,
             Err(e) => {
-                error!("Failed to connect to WSS: {}", e);
-                tokio::time::sleep(Duration::from_secs(5)).await;
+                trace!("Ignoring DNS message: {}", e);
+                return None;
             }
+        };
+        let client_id = dns::message_id(query)?;
+
+        if let Some(answer) = self.fake_ip.as_ref().and_then(|pool| pool.answer(query)) {
+            return Some(answer);
+        }
+
+        if let Some(answer) = self.cache.lock().unwrap().get(&question, client_id) {
+            trace!("DNS cache hit for {}", question.name);
+            return Some(answer);
         }
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let upstream_id = {
+            let mut pending = self.pending.lock().unwrap();
+            let Some(id) = free_id(&pending) else {
+                warn!("Too many DNS queries in flight");
+                return dns::build_error(query, dns::RCODE_SERVFAIL).ok();
+            };
+            pending.insert(
+                id,
+                Pending {
+                    client_id,
+                    question: question.clone(),
+                    reply: reply_tx,
+                },
+            );
+            id
+        };
+
+        let mut upstream_query = query.to_vec();
+        dns::set_message_id(&mut upstream_query, upstream_id);
+        let _ = self.outbound.send(upstream_query);
+
+        match tokio::time::timeout(QUERY_TIMEOUT, reply_rx).await {
+            Ok(Ok(response)) => Some(response),
+            _ => {
+                self.pending.lock().unwrap().remove(&upstream_id);
+                debug!("DNS query for {} timed out", question.name);
+                dns::build_error(query, dns::RCODE_SERVFAIL).ok()
+            }
+        }
+    }
+
+    /// Hand an upstream answer to the query waiting for it
+    fn complete(&self, mut response: Vec<u8>) {
+        let Some(upstream_id) = dns::message_id(&response) else {
+            return;
+        };
+        let Ok((question, _)) = dns::parse_question(&response) else {
+            return;
+        };
+
+        let pending = {
+            let mut pending = self.pending.lock().unwrap();
+            // Only accept answers to the question that was asked
+            match pending.get(&upstream_id) {
+                Some(p) if p.question == question => pending.remove(&upstream_id),
+                _ => None,
+            }
+        };
+        let Some(pending) = pending else {
+            trace!("Dropping unmatched DNS response {}", upstream_id);
+            return;
+        };
+
+        self.cache.lock().unwrap().insert(question, &response);
+        dns::set_message_id(&mut response, pending.client_id);
+        let _ = pending.reply.send(response);
+    }
+}
+
+/// Pick an unused upstream transaction ID
+fn free_id(pending: &HashMap<u16, Pending>) -> Option<u16> {
+    if pending.len() > u16::MAX as usize / 2 {
+        return None;
+    }
+    loop {
+        let id = fastrand::u16(..);
+        if !pending.contains_key(&id) {
+            return Some(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn answer(query: &[u8], ttl: u32) -> Vec<u8> {
+        let addr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        dns::build_response(query, &[addr], ttl).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_queries_are_mapped_back() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let resolver = Arc::new(Resolver::new(None, tx));
+
+        // Two clients using the same transaction ID
+        let a = tokio::spawn({
+            let resolver = resolver.clone();
+            async move {
+                resolver
+                    .resolve(&dns::build_query(42, "a.test", dns::TYPE_A))
+                    .await
+            }
+        });
+        let b = tokio::spawn({
+            let resolver = resolver.clone();
+            async move {
+                resolver
+                    .resolve(&dns::build_query(42, "b.test", dns::TYPE_A))
+                    .await
+            }
+        });
+
+        let first = rx.recv().await.unwrap();
+        let second = rx.recv().await.unwrap();
+        assert_ne!(dns::message_id(&first), dns::message_id(&second));
+
+        // Answer in reverse order
+        resolver.complete(answer(&second, 60));
+        resolver.complete(answer(&first, 60));
+
+        for (task, name) in [(a, "a.test"), (b, "b.test")] {
+            let response = task.await.unwrap().unwrap();
+            assert_eq!(dns::message_id(&response), Some(42));
+            assert_eq!(dns::parse_question(&response).unwrap().0.name, name);
+        }
+
+        // Answered from cache without going upstream
+        let cached = resolver
+            .resolve(&dns::build_query(7, "a.test", dns::TYPE_A))
+            .await
+            .unwrap();
+        assert_eq!(dns::message_id(&cached), Some(7));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mismatched_response_is_dropped() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let resolver = Arc::new(Resolver::new(None, tx));
+
+        let task = tokio::spawn({
+            let resolver = resolver.clone();
+            async move {
+                resolver
+                    .resolve(&dns::build_query(1, "a.test", dns::TYPE_A))
+                    .await
+            }
+        });
+        let sent = rx.recv().await.unwrap();
+
+        // Right ID, wrong question
+        let mut forged = answer(&dns::build_query(0, "evil.test", dns::TYPE_A), 60);
+        dns::set_message_id(&mut forged, dns::message_id(&sent).unwrap());
+        resolver.complete(forged);
+        assert_eq!(resolver.pending.lock().unwrap().len(), 1);
+
+        resolver.complete(answer(&sent, 60));
+        let response = task.await.unwrap().unwrap();
+        assert_eq!(dns::parse_question(&response).unwrap().0.name, "a.test");
     }
 }
@@ -8,7 +8,10 @@ use tracing::{Level, info};
 use tracing_subscriber::FmtSubscriber;
 
 use apfsds_client::config::ClientConfig;
-use apfsds_client::{emergency, socks5};
+use apfsds_client::fake_ip::FakeIpPool;
+use apfsds_client::mux::MuxPool;
+use apfsds_client::routing::Router;
+use apfsds_client::{emergency, http_proxy, socks5};
 
 /// APFSDS Client - Privacy-preserving network proxy
 #[derive(Parser, Debug)]
@@ -59,21 +62,47 @@ async fn main() -> Result<()> {
     // Start emergency mode checker
     let emergency_handle = emergency::start_checker(config.emergency.clone());
 
+    // All modes share one pool of multiplexed sessions
+    let pool = MuxPool::new(&config);
+    let router = Router::new(&config, pool)?;
+    router.spawn_reloader();
+
+    // Fake-IP DNS only makes sense when connections arrive on the TUN device
+    let fake_ip = if args.tun && config.dns.fake_ip.enabled {
+        let fake_ip = FakeIpPool::new(&config.dns.fake_ip)?;
+        fake_ip.spawn_maintenance();
+        info!("Fake-IP DNS enabled for {}", config.dns.fake_ip.range);
+        Some(fake_ip)
+    } else {
+        None
+    };
+
+    // Start Local DNS service in background
+    let config_dns = config.clone();
+    let fake_ip_dns = fake_ip.clone();
+    tokio::spawn(async move {
+        if let Err(e) = apfsds_client::local_dns::run(&config_dns, fake_ip_dns).await {
+            tracing::error!("Local DNS service failed: {}", e);
+        }
+    });
+
     // Run appropriate mode
     if args.tun {
         info!("Starting in TUN mode");
-        apfsds_client::run_tun(&config).await?;
+        apfsds_client::run_tun(&config, router, fake_ip).await?;
     } else {
-        // Start Local DNS service in background
-        let config_dns = config.clone();
-        tokio::spawn(async move {
-            if let Err(e) = apfsds_client::local_dns::run(&config_dns).await {
-                tracing::error!("Local DNS service failed: {}", e);
-            }
-        });
+        if config.http_proxy.enabled {
+            let config_http = config.clone();
+            let router = router.clone();
+            tokio::spawn(async move {
+                if let Err(e) = http_proxy::run(&config_http, router).await {
+                    tracing::error!("HTTP proxy failed: {}", e);
+                }
+            });
+        }
 
         info!("Starting in SOCKS5 mode on {}", config.socks5.bind);
-        socks5::run(&config).await?;
+        socks5::run(&config, router).await?;
     }
 
     // Cleanup
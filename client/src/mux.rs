@@ -0,0 +1,755 @@
+//! Stream multiplexing over WSS sessions
+//!
+//! Carries many logical streams over a small pool of long-lived `WssSession`s
+//! instead of opening a WebSocket (and a handshake) per proxied connection.
+//! A stream is addressed by its session's `conn_id` plus a `stream_id`, and
+//! uses credit-based flow control via `ControlMessage::WindowUpdate`.
+
+use crate::config::ClientConfig;
+use crate::wss::WssSession;
+use anyhow::{Result, anyhow};
+use apfsds_protocol::{
+    ControlMessage, INITIAL_STREAM_WINDOW, ProxyFrame, ReceiveWindow, StreamState,
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{Notify, Semaphore, mpsc};
+use tracing::{debug, error, info, trace, warn};
+
+/// Maximum payload carried by a single data frame
+pub const MAX_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Destination of a stream or datagram
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TargetAddr {
+    /// IP address (IPv4 mapped into IPv6, as in ProxyFrame)
+    Ip([u8; 16], u16),
+    /// Hostname, resolved by the exit node so no lookup leaks locally
+    Domain(String, u16),
+}
+
+impl TargetAddr {
+    /// Build a target from a hostname, keeping IP literals as IPs
+    pub fn from_host(host: String, port: u16) -> Self {
+        let literal = host.trim_start_matches('[').trim_end_matches(']');
+        match literal.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, port).into(),
+            Err(_) => TargetAddr::Domain(host, port),
+        }
+    }
+
+    /// Target port
+    pub fn port(&self) -> u16 {
+        match self {
+            TargetAddr::Ip(_, port) | TargetAddr::Domain(_, port) => *port,
+        }
+    }
+
+    /// Socket address of an IP target (IPv4-mapped addresses unmapped)
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            TargetAddr::Ip(rip, rport) => {
+                let ip = match ProxyFrame::mapped_to_ipv4(rip) {
+                    Some(ipv4) => IpAddr::from(ipv4),
+                    None => IpAddr::from(*rip),
+                };
+                Some(SocketAddr::new(ip, *rport))
+            }
+            TargetAddr::Domain(..) => None,
+        }
+    }
+
+    /// Address a frame to this target
+    fn address(&self, mut frame: ProxyFrame) -> ProxyFrame {
+        match self {
+            TargetAddr::Ip(rip, rport) => {
+                frame.rip = *rip;
+                frame.rport = *rport;
+                frame
+            }
+            TargetAddr::Domain(host, rport) => {
+                frame.rip = [0; 16];
+                frame.rport = *rport;
+                frame.with_host(host.clone())
+            }
+        }
+    }
+}
+
+impl From<SocketAddr> for TargetAddr {
+    fn from(addr: SocketAddr) -> Self {
+        let rip = match addr.ip() {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+            IpAddr::V6(ip) => ip.octets(),
+        };
+        TargetAddr::Ip(rip, addr.port())
+    }
+}
+
+impl fmt::Display for TargetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TargetAddr::Ip(rip, rport) => match ProxyFrame::mapped_to_ipv4(rip) {
+                Some(ipv4) => write!(f, "{}:{}", std::net::Ipv4Addr::from(ipv4), rport),
+                None => write!(f, "[{}]:{}", std::net::Ipv6Addr::from(*rip), rport),
+            },
+            TargetAddr::Domain(host, rport) => write!(f, "{}:{}", host, rport),
+        }
+    }
+}
+
+/// Pool of multiplexed WSS sessions
+///
+/// Sessions are connected lazily up to `connection.pool_size` and streams are
+/// spread across them round-robin. Dead sessions are pruned on the next open.
+/// Connects run outside the pool lock, so only the opens that have no live
+/// session to use wait for one.
+pub struct MuxPool {
+    config: ClientConfig,
+    slots: Mutex<PoolSlots>,
+    /// Woken whenever a connect finishes, successfully or not
+    connect_done: Notify,
+    next_index: AtomicUsize,
+}
+
+#[derive(Default)]
+struct PoolSlots {
+    sessions: Vec<Arc<MuxSession>>,
+    /// Sessions being connected
+    connecting: usize,
+    /// Failed connects so far, and the last error
+    failures: u64,
+    last_error: String,
+}
+
+/// A pool slot held while its session connects; released on drop, so a
+/// cancelled connect frees it too
+struct Reservation<'a> {
+    pool: &'a MuxPool,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        self.pool.slots.lock().unwrap().connecting -= 1;
+        self.pool.connect_done.notify_waiters();
+    }
+}
+
+impl MuxPool {
+    pub fn new(config: &ClientConfig) -> Arc<Self> {
+        Arc::new(Self {
+            config: config.clone(),
+            slots: Mutex::new(PoolSlots::default()),
+            connect_done: Notify::new(),
+            next_index: AtomicUsize::new(0),
+        })
+    }
+
+    /// Open a new stream to `target` on one of the pooled sessions
+    pub async fn open_stream(&self, target: &TargetAddr) -> Result<MuxStream> {
+        let session = self.session().await?;
+        session.open_stream(target)
+    }
+
+    /// Open a datagram stream (UDP association) on one of the pooled sessions
+    pub async fn open_datagram_stream(&self) -> Result<MuxStream> {
+        let session = self.session().await?;
+        session.open_datagram_stream()
+    }
+
+    /// Open a BIND stream; the exit listens for one inbound connection
+    pub async fn open_bind_stream(&self, peer: &TargetAddr) -> Result<MuxStream> {
+        let session = self.session().await?;
+        session.open_bind_stream(peer)
+    }
+
+    /// Number of live sessions in the pool
+    pub async fn session_count(&self) -> usize {
+        let slots = self.slots.lock().unwrap();
+        slots.sessions.iter().filter(|s| !s.is_closed()).count()
+    }
+
+    /// Pick a live session, connecting a new one while the pool is not full
+    ///
+    /// With every slot still connecting, wait for those connects and fail
+    /// with them rather than queue up behind one another.
+    async fn session(&self) -> Result<Arc<MuxSession>> {
+        let reservation = loop {
+            let connect_done = self.connect_done.notified();
+            let failures = {
+                let mut slots = self.slots.lock().unwrap();
+                slots.sessions.retain(|s| !s.is_closed());
+
+                let pool_size = self.config.connection.pool_size.max(1);
+                if slots.sessions.len() + slots.connecting < pool_size {
+                    slots.connecting += 1;
+                    break Reservation { pool: self };
+                }
+                if !slots.sessions.is_empty() {
+                    let index = self.next_index.fetch_add(1, Ordering::Relaxed);
+                    return Ok(slots.sessions[index % slots.sessions.len()].clone());
+                }
+                slots.failures
+            };
+
+            connect_done.await;
+            let slots = self.slots.lock().unwrap();
+            if slots.failures != failures {
+                return Err(anyhow!("Mux session connect failed: {}", slots.last_error));
+            }
+        };
+
+        let result = MuxSession::connect(&self.config).await;
+        let mut slots = self.slots.lock().unwrap();
+        match &result {
+            Ok(session) => slots.sessions.push(session.clone()),
+            Err(e) => {
+                slots.failures += 1;
+                slots.last_error = e.to_string();
+            }
+        }
+        drop(slots);
+        drop(reservation);
+        result
+    }
+}
+
+/// Per-stream bookkeeping held by the session
+struct StreamSlot {
+    /// Frames received for this stream
+    inbound: mpsc::UnboundedSender<ProxyFrame>,
+    /// Send credit granted by the peer (bytes)
+    send_window: Arc<Semaphore>,
+    /// Traffic counters
+    state: Arc<Mutex<StreamState>>,
+}
+
+/// A single WSS session carrying multiplexed streams
+pub struct MuxSession {
+    conn_id: u64,
+    outbound: mpsc::UnboundedSender<ProxyFrame>,
+    streams: Mutex<HashMap<u32, StreamSlot>>,
+    next_stream_id: AtomicU32,
+    closed: AtomicBool,
+}
+
+impl MuxSession {
+    /// Connect a new session and start its reader/writer tasks
+    pub async fn connect(config: &ClientConfig) -> Result<Arc<Self>> {
+        let session = WssSession::connect(config).await?;
+        let conn_id = session.conn_id;
+        let (wss_sender, mut wss_receiver) = session.split();
+        let (outbound, mut outbound_rx) = mpsc::unbounded_channel::<ProxyFrame>();
+
+        let mux = Arc::new(Self {
+            conn_id,
+            outbound,
+            streams: Mutex::new(HashMap::new()),
+            next_stream_id: AtomicU32::new(1),
+            closed: AtomicBool::new(false),
+        });
+
+        // Task: outbound queue -> WSS (serializes writes from all streams)
+        let writer_mux = Arc::downgrade(&mux);
+        tokio::spawn(async move {
+            while let Some(frame) = outbound_rx.recv().await {
+                if let Err(e) = wss_sender.send_frame(&frame).await {
+                    error!("Mux session {} send failed: {}", conn_id, e);
+                    break;
+                }
+            }
+            if let Some(mux) = writer_mux.upgrade() {
+                mux.shutdown();
+            }
+        });
+
+        // Task: WSS -> streams
+        let reader_mux = mux.clone();
+        tokio::spawn(async move {
+            loop {
+                match wss_receiver.recv_frame().await {
+                    Ok(Some(frame)) => reader_mux.dispatch(frame),
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("Mux session {} receive failed: {}", conn_id, e);
+                        break;
+                    }
+                }
+            }
+            reader_mux.shutdown();
+            info!("Mux session {} closed", conn_id);
+        });
+
+        info!("Mux session {} established", conn_id);
+        Ok(mux)
+    }
+
+    /// Connection ID assigned by the handler
+    pub fn conn_id(&self) -> u64 {
+        self.conn_id
+    }
+
+    /// Whether the underlying WSS connection is gone
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// Snapshot of traffic counters for all open streams
+    pub fn stream_states(&self) -> Vec<StreamState> {
+        let streams = self.streams.lock().unwrap();
+        streams
+            .values()
+            .map(|slot| slot.state.lock().unwrap().clone())
+            .collect()
+    }
+
+    /// Open a new stream to `target`
+    pub fn open_stream(self: &Arc<Self>, target: &TargetAddr) -> Result<MuxStream> {
+        let open = ProxyFrame::new_open(self.conn_id, 0, [0; 16], 0);
+        self.open(target.address(open))
+    }
+
+    /// Open a datagram stream; each datagram carries its own destination
+    pub fn open_datagram_stream(self: &Arc<Self>) -> Result<MuxStream> {
+        let mut open = ProxyFrame::new_open(self.conn_id, 0, [0; 16], 0);
+        open.flags.is_datagram = true;
+        self.open(open)
+    }
+
+    /// Open a BIND stream accepting a connection from `peer` (zero for any)
+    pub fn open_bind_stream(self: &Arc<Self>, peer: &TargetAddr) -> Result<MuxStream> {
+        let open = ProxyFrame::new_bind(self.conn_id, 0, [0; 16], 0);
+        self.open(peer.address(open))
+    }
+
+    /// Register a stream and send its open frame
+    fn open(self: &Arc<Self>, open: ProxyFrame) -> Result<MuxStream> {
+        if self.is_closed() {
+            return Err(anyhow!("Mux session {} is closed", self.conn_id));
+        }
+
+        let stream_id = self.next_stream_id.fetch_add(1, Ordering::Relaxed);
+        let (inbound, inbound_rx) = mpsc::unbounded_channel();
+        let send_window = Arc::new(Semaphore::new(INITIAL_STREAM_WINDOW as usize));
+        let state = Arc::new(Mutex::new(StreamState::new(stream_id)));
+
+        self.streams.lock().unwrap().insert(
+            stream_id,
+            StreamSlot {
+                inbound,
+                send_window: send_window.clone(),
+                state: state.clone(),
+            },
+        );
+
+        let (rip, rport) = (open.rip, open.rport);
+        self.send(open.with_stream(stream_id))?;
+        debug!("Opened stream {} on session {}", stream_id, self.conn_id);
+
+        Ok(MuxStream {
+            handle: Arc::new(StreamHandle {
+                session: self.clone(),
+                stream_id,
+                rip,
+                rport,
+                state,
+            }),
+            send_window,
+            inbound: inbound_rx,
+        })
+    }
+
+    /// Queue a frame for sending
+    fn send(&self, frame: ProxyFrame) -> Result<()> {
+        self.outbound
+            .send(frame)
+            .map_err(|_| anyhow!("Mux session {} is closed", self.conn_id))
+    }
+
+    /// Queue a control message for sending
+    fn send_control(&self, msg: &ControlMessage) -> Result<()> {
+        let payload = rkyv::to_bytes::<rkyv::rancor::Error>(msg)?.to_vec();
+        let mut frame = ProxyFrame::new_control(payload);
+        frame.conn_id = self.conn_id;
+        self.send(frame)
+    }
+
+    /// Route a received frame to its stream
+    fn dispatch(&self, frame: ProxyFrame) {
+        if frame.flags.is_control {
+            match rkyv::from_bytes::<ControlMessage, rkyv::rancor::Error>(&frame.payload) {
+                Ok(ControlMessage::WindowUpdate {
+                    stream_id,
+                    increment,
+                }) => {
+                    if let Some(slot) = self.streams.lock().unwrap().get(&stream_id) {
+                        slot.send_window.add_permits(increment as usize);
+                    }
+                }
+                Ok(ControlMessage::QuotaExceeded { kind, throttled }) => {
+                    if throttled {
+                        warn!("{:?} quota exceeded, traffic is being throttled", kind);
+                    } else {
+                        warn!(
+                            "{:?} quota exceeded, the server is closing the session",
+                            kind
+                        );
+                    }
+                }
+                Ok(other) => trace!("Ignoring control message on mux session: {:?}", other),
+                Err(e) => debug!("Invalid control message: {}", e),
+            }
+            return;
+        }
+
+        let stream_id = frame.stream_id;
+        let is_final = frame.flags.is_final;
+        let mut streams = self.streams.lock().unwrap();
+
+        match streams.get(&stream_id) {
+            Some(slot) => {
+                slot.state.lock().unwrap().bytes_received += frame.payload.len() as u64;
+                let _ = slot.inbound.send(frame);
+            }
+            None => trace!("Frame for unknown stream {}", stream_id),
+        }
+
+        if is_final && let Some(slot) = streams.remove(&stream_id) {
+            slot.send_window.close();
+        }
+    }
+
+    /// Tear down the session and wake every stream
+    fn shutdown(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        let mut streams = self.streams.lock().unwrap();
+        for (_, slot) in streams.drain() {
+            slot.send_window.close();
+        }
+    }
+
+    /// Forget a stream that was closed locally
+    fn remove_stream(&self, stream_id: u32) {
+        if let Some(slot) = self.streams.lock().unwrap().remove(&stream_id) {
+            slot.send_window.close();
+        }
+    }
+}
+
+/// Shared half of a stream; closes the stream when both halves are dropped
+struct StreamHandle {
+    session: Arc<MuxSession>,
+    stream_id: u32,
+    rip: [u8; 16],
+    rport: u16,
+    state: Arc<Mutex<StreamState>>,
+}
+
+impl Drop for StreamHandle {
+    fn drop(&mut self) {
+        let closed_by_peer = !self
+            .session
+            .streams
+            .lock()
+            .unwrap()
+            .contains_key(&self.stream_id);
+
+        if !closed_by_peer {
+            let _ = self
+                .session
+                .send(ProxyFrame::new_close(self.session.conn_id).with_stream(self.stream_id));
+            self.session.remove_stream(self.stream_id);
+        }
+
+        let state = self.state.lock().unwrap();
+        debug!(
+            "Stream {} closed (sent {} bytes, received {} bytes)",
+            state.stream_id, state.bytes_sent, state.bytes_received
+        );
+    }
+}
+
+/// A multiplexed stream
+pub struct MuxStream {
+    handle: Arc<StreamHandle>,
+    send_window: Arc<Semaphore>,
+    inbound: mpsc::UnboundedReceiver<ProxyFrame>,
+}
+
+impl MuxStream {
+    /// Stream ID within the session
+    pub fn stream_id(&self) -> u32 {
+        self.handle.stream_id
+    }
+
+    /// Split into independently usable write and read halves
+    pub fn split(self) -> (MuxWriter, MuxReader) {
+        let writer = MuxWriter {
+            handle: self.handle.clone(),
+            send_window: self.send_window,
+        };
+        let reader = MuxReader {
+            handle: self.handle,
+            inbound: self.inbound,
+            window: ReceiveWindow::default(),
+            finished: false,
+            half_closed: false,
+        };
+        (writer, reader)
+    }
+}
+
+/// Write half of a multiplexed stream
+pub struct MuxWriter {
+    handle: Arc<StreamHandle>,
+    send_window: Arc<Semaphore>,
+}
+
+impl MuxWriter {
+    /// Stream ID within the session
+    pub fn stream_id(&self) -> u32 {
+        self.handle.stream_id
+    }
+
+    /// Send data, waiting for send window from the peer as needed
+    pub async fn send(&self, data: &[u8]) -> Result<()> {
+        let handle = &self.handle;
+
+        for chunk in data.chunks(MAX_CHUNK_SIZE) {
+            self.send_window
+                .acquire_many(chunk.len() as u32)
+                .await
+                .map_err(|_| anyhow!("Stream {} is closed", handle.stream_id))?
+                .forget();
+
+            let frame = ProxyFrame::new_data(
+                handle.session.conn_id,
+                handle.rip,
+                handle.rport,
+                chunk.to_vec(),
+            )
+            .with_stream(handle.stream_id);
+            handle.session.send(frame)?;
+
+            handle.state.lock().unwrap().bytes_sent += chunk.len() as u64;
+        }
+
+        Ok(())
+    }
+
+    /// Send a single datagram to `target` (datagram streams only)
+    pub async fn send_datagram(&self, target: &TargetAddr, data: &[u8]) -> Result<()> {
+        let handle = &self.handle;
+
+        self.send_window
+            .acquire_many(data.len() as u32)
+            .await
+            .map_err(|_| anyhow!("Stream {} is closed", handle.stream_id))?
+            .forget();
+
+        let frame = ProxyFrame::new_datagram(
+            handle.session.conn_id,
+            handle.stream_id,
+            [0; 16],
+            0,
+            data.to_vec(),
+        );
+        handle.session.send(target.address(frame))?;
+
+        handle.state.lock().unwrap().bytes_sent += data.len() as u64;
+        Ok(())
+    }
+
+    /// Half-close: signal that no more data will be sent on this stream
+    pub fn shutdown(&self) -> Result<()> {
+        let handle = &self.handle;
+        handle.session.send(ProxyFrame::new_half_close(
+            handle.session.conn_id,
+            handle.stream_id,
+        ))
+    }
+}
+
+/// Read half of a multiplexed stream
+pub struct MuxReader {
+    handle: Arc<StreamHandle>,
+    inbound: mpsc::UnboundedReceiver<ProxyFrame>,
+    window: ReceiveWindow,
+    finished: bool,
+    half_closed: bool,
+}
+
+impl MuxReader {
+    /// Receive the next chunk of data
+    /// Returns None once the peer has half-closed or closed the stream
+    pub async fn recv(&mut self) -> Option<Vec<u8>> {
+        self.recv_frame().await.map(|frame| frame.payload)
+    }
+
+    /// Receive the next data frame, keeping its addressing (e.g. the source
+    /// of a datagram in rip/rport)
+    pub async fn recv_frame(&mut self) -> Option<ProxyFrame> {
+        while !self.finished {
+            let frame = self.inbound.recv().await?;
+            self.half_closed = frame.flags.is_half_close;
+            self.finished = frame.flags.is_half_close || frame.flags.is_final;
+
+            if !frame.payload.is_empty() {
+                self.grant(frame.payload.len() as u32);
+                return Some(frame);
+            }
+        }
+        None
+    }
+
+    /// Wait for the next open frame from the peer and return its address
+    ///
+    /// BIND streams report the listening address and then the accepted peer
+    /// this way. Returns None if the stream closes first.
+    pub async fn recv_open(&mut self) -> Option<([u8; 16], u16)> {
+        while !self.finished {
+            let frame = self.inbound.recv().await?;
+            if frame.flags.is_open {
+                return Some((frame.rip, frame.rport));
+            }
+            self.half_closed = frame.flags.is_half_close;
+            self.finished = frame.flags.is_half_close || frame.flags.is_final;
+        }
+        None
+    }
+
+    /// Whether the peer half-closed (and may still accept data) rather than
+    /// closing the stream outright
+    pub fn is_half_closed(&self) -> bool {
+        self.half_closed
+    }
+
+    /// Hand consumed bytes back to the peer as send window
+    fn grant(&mut self, len: u32) {
+        if let Some(increment) = self.window.consume(len) {
+            let update = ControlMessage::WindowUpdate {
+                stream_id: self.handle.stream_id,
+                increment,
+            };
+            if let Err(e) = self.handle.session.send_control(&update) {
+                debug!("Failed to send window update: {}", e);
+            }
+        }
+    }
+}
+
+/// Relay data between a local client and a tunnel stream until both sides finish
+pub async fn relay<S>(stream: S, mux_writer: MuxWriter, mut mux_reader: MuxReader)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let stream_id = mux_writer.stream_id();
+    let (mut client_read, mut client_write) = tokio::io::split(stream);
+
+    // Task: TCP -> Stream
+    let sender_task = tokio::spawn(async move {
+        let mut buf = [0u8; 8192];
+        loop {
+            match client_read.read(&mut buf).await {
+                Ok(0) => {
+                    // EOF: half-close our side, keep receiving
+                    let _ = mux_writer.shutdown();
+                    break;
+                }
+                Ok(n) => {
+                    if let Err(e) = mux_writer.send(&buf[..n]).await {
+                        error!("Stream {} send failed: {}", stream_id, e);
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!("TCP read failed: {}", e);
+                    break;
+                }
+            }
+        }
+        mux_writer
+    });
+
+    // Task: Stream -> TCP
+    while let Some(data) = mux_reader.recv().await {
+        if let Err(e) = client_write.write_all(&data).await {
+            error!("TCP write failed: {}", e);
+            break;
+        }
+    }
+    let _ = client_write.shutdown().await;
+
+    // A half-closed peer may still accept data; otherwise stop sending
+    if !mux_reader.is_half_closed() {
+        sender_task.abort();
+    }
+
+    // Dropping both halves closes the stream
+    let _ = sender_task.await;
+    trace!("Stream {} finished", stream_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_from_host() {
+        assert_eq!(
+            TargetAddr::from_host("example.com".to_string(), 443),
+            TargetAddr::Domain("example.com".to_string(), 443)
+        );
+        assert_eq!(
+            TargetAddr::from_host("10.0.0.1".to_string(), 80),
+            TargetAddr::from(SocketAddr::from(([10, 0, 0, 1], 80)))
+        );
+        assert_eq!(
+            TargetAddr::from_host("[::1]".to_string(), 80).to_string(),
+            "[::1]:80"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pool_opens_share_a_failing_connect() {
+        // An upstream that accepts and hangs up after a while
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(async move {
+                    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+                    drop(socket);
+                });
+            }
+        });
+
+        let mut config = ClientConfig::default();
+        config.connection.pool_size = 1;
+        config.connection.endpoints = vec![addr.to_string()];
+        let pool = MuxPool::new(&config);
+
+        let start = std::time::Instant::now();
+        let opens: Vec<_> = (0..6)
+            .map(|_| {
+                let pool = pool.clone();
+                tokio::spawn(async move { pool.session().await.map(|_| ()) })
+            })
+            .collect();
+        for open in opens {
+            assert!(open.await.unwrap().is_err());
+        }
+
+        // One connect was tried, and nobody waited behind a second one
+        assert_eq!(accepted.load(Ordering::Relaxed), 1);
+        assert!(start.elapsed() < std::time::Duration::from_millis(550));
+        assert_eq!(pool.session_count().await, 0);
+    }
+}
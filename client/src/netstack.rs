@@ -0,0 +1,831 @@
+//! Userspace TCP/IP stack for TUN mode
+//!
+//! Terminates flows read from the TUN device and maps them onto tunnel
+//! streams:
+//! - TCP connections are accepted by a smoltcp interface running in AnyIP mode
+//!   (one socket per flow, listening on the original destination) and bridged
+//!   to a `MuxStream` each.
+//! - UDP is handled without sockets: every local source port gets its own
+//!   datagram stream, and replies are written back as raw IPv4/UDP packets.
+//! - ICMP echo requests are answered by the stack itself, since the tunnel
+//!   only carries TCP and UDP.
+//!
+//! In fake-IP mode, flows to fake addresses are opened by hostname and DNS
+//! queries sent to any resolver through the TUN are answered from the
+//! fake-IP table.
+//!
+//! TCP flows follow the routing rules: rejected flows are reset and direct
+//! flows are bridged to a local socket. UDP honors `reject` only.
+//!
+//! Only IPv4 is handled; other packets are dropped.
+
+use crate::fake_ip::FakeIpPool;
+use crate::mux::{MAX_CHUNK_SIZE, MuxPool, MuxStream, TargetAddr};
+use crate::routing::{Action, Outbound, Router};
+use crate::tun_device::{TunConfig, TunDevice};
+use anyhow::{Result, anyhow};
+use apfsds_protocol::ProxyFrame;
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{ChecksumCapabilities, Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use smoltcp::socket::tcp;
+use smoltcp::time::Instant;
+use smoltcp::wire::{
+    HardwareAddress, Icmpv4Packet, Icmpv4Repr, IpAddress, IpCidr, IpListenEndpoint, IpProtocol,
+    Ipv4Packet, Ipv4Repr, TcpPacket, UdpPacket, UdpRepr,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::{Notify, mpsc};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, trace};
+
+/// Per-socket TCP buffer size
+const TCP_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Chunks queued between a TCP socket and its stream task
+const TCP_CHANNEL_DEPTH: usize = 16;
+
+/// Idle time after which a UDP flow's datagram stream is closed
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Upper bound on how long the stack sleeps between polls
+const MAX_POLL_DELAY: Duration = Duration::from_millis(100);
+
+/// Source and destination of a TCP flow
+type FlowTuple = (SocketAddrV4, SocketAddrV4);
+
+/// A UDP datagram: address as seen on the TUN, tunnel target, payload
+type Datagram = (SocketAddrV4, TargetAddr, Vec<u8>);
+
+/// A UDP reply to be written back to the TUN device
+struct UdpReply {
+    from: SocketAddrV4,
+    to: SocketAddrV4,
+    payload: Vec<u8>,
+}
+
+/// Run the stack on `device` until the device fails
+pub async fn run(
+    device: TunDevice,
+    config: &TunConfig,
+    router: Arc<Router>,
+    fake_ip: Option<Arc<FakeIpPool>>,
+) -> Result<()> {
+    let (mut reader, mut writer) = device.split();
+    let mtu = config.mtu as usize;
+
+    // TUN I/O is blocking; keep it on dedicated threads
+    let (tun_in, mut tun_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    std::thread::spawn(move || {
+        let mut buf = vec![0u8; mtu.max(1500) + 4];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(n) => {
+                    if tun_in.send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error!("TUN read error: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    let (tun_tx, mut tun_out) = mpsc::unbounded_channel::<Vec<u8>>();
+    std::thread::spawn(move || {
+        while let Some(packet) = tun_out.blocking_recv() {
+            if let Err(e) = writer.write(&packet) {
+                debug!("TUN write error: {}", e);
+            }
+        }
+    });
+
+    let (udp_replies, mut udp_rx) = mpsc::unbounded_channel();
+    let mut stack = NetStack::new(config, router, fake_ip, udp_replies)?;
+    info!(
+        "Userspace stack up on {} (mtu {})",
+        stack.address, config.mtu
+    );
+
+    loop {
+        let delay = stack.poll_delay();
+        tokio::select! {
+            packet = tun_rx.recv() => {
+                let packet = packet.ok_or_else(|| anyhow!("TUN device closed"))?;
+                stack.ingress(packet);
+                while let Ok(packet) = tun_rx.try_recv() {
+                    stack.ingress(packet);
+                }
+            }
+            Some(reply) = udp_rx.recv() => {
+                stack.emit_udp(reply);
+            }
+            _ = stack.notify.notified() => {}
+            _ = tokio::time::sleep(delay) => {}
+        }
+
+        stack.poll();
+        for packet in stack.device.tx.drain(..) {
+            let _ = tun_tx.send(packet);
+        }
+    }
+}
+
+/// smoltcp device backed by in-memory packet queues
+struct QueueDevice {
+    rx: VecDeque<Vec<u8>>,
+    tx: VecDeque<Vec<u8>>,
+    mtu: usize,
+}
+
+impl Device for QueueDevice {
+    type RxToken<'a> = RxPacket;
+    type TxToken<'a> = TxQueue<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let packet = self.rx.pop_front()?;
+        Some((RxPacket(packet), TxQueue(&mut self.tx)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(TxQueue(&mut self.tx))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = self.mtu;
+        caps
+    }
+}
+
+struct RxPacket(Vec<u8>);
+
+impl RxToken for RxPacket {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.0)
+    }
+}
+
+struct TxQueue<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl TxToken for TxQueue<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut buf = vec![0u8; len];
+        let result = f(&mut buf);
+        self.0.push_back(buf);
+        result
+    }
+}
+
+/// TCP flow bridged to a tunnel stream
+struct TcpFlow {
+    tuple: FlowTuple,
+    /// Socket -> stream; None once the local side has finished sending
+    to_mux: Option<mpsc::Sender<Vec<u8>>>,
+    /// Stream -> socket
+    from_mux: mpsc::Receiver<Vec<u8>>,
+    /// Data from the stream not yet accepted by the socket
+    pending: Vec<u8>,
+    established: bool,
+    mux_closed: bool,
+    task: JoinHandle<()>,
+}
+
+struct NetStack {
+    address: Ipv4Addr,
+    iface: Interface,
+    device: QueueDevice,
+    sockets: SocketSet<'static>,
+    tcp_flows: HashMap<SocketHandle, TcpFlow>,
+    tcp_tuples: HashMap<FlowTuple, SocketHandle>,
+    udp_flows: HashMap<SocketAddrV4, mpsc::UnboundedSender<Datagram>>,
+    udp_replies: mpsc::UnboundedSender<UdpReply>,
+    router: Arc<Router>,
+    fake_ip: Option<Arc<FakeIpPool>>,
+    notify: Arc<Notify>,
+}
+
+impl NetStack {
+    fn new(
+        config: &TunConfig,
+        router: Arc<Router>,
+        fake_ip: Option<Arc<FakeIpPool>>,
+        udp_replies: mpsc::UnboundedSender<UdpReply>,
+    ) -> Result<Self> {
+        let mut device = QueueDevice {
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+            mtu: config.mtu as usize,
+        };
+
+        let mut iface_config = Config::new(HardwareAddress::Ip);
+        iface_config.random_seed = fastrand::u64(..);
+        let mut iface = Interface::new(iface_config, &mut device, Instant::now());
+
+        // The stack sits next to the device address and accepts everything
+        // routed through it (AnyIP with a default route via itself)
+        let address = stack_address(config.address, config.netmask);
+        let prefix = u32::from(config.netmask).count_ones() as u8;
+        iface.update_ip_addrs(|addrs| {
+            let _ = addrs.push(IpCidr::new(IpAddress::Ipv4(address), prefix));
+        });
+        iface.set_any_ip(true);
+        iface
+            .routes_mut()
+            .add_default_ipv4_route(address)
+            .map_err(|_| anyhow!("Route table full"))?;
+
+        Ok(Self {
+            address,
+            iface,
+            device,
+            sockets: SocketSet::new(Vec::new()),
+            tcp_flows: HashMap::new(),
+            tcp_tuples: HashMap::new(),
+            udp_flows: HashMap::new(),
+            udp_replies,
+            router,
+            fake_ip,
+            notify: Arc::new(Notify::new()),
+        })
+    }
+
+    /// Time until the stack needs polling again
+    fn poll_delay(&mut self) -> Duration {
+        self.iface
+            .poll_delay(Instant::now(), &self.sockets)
+            .map(Duration::from)
+            .map_or(MAX_POLL_DELAY, |d| d.min(MAX_POLL_DELAY))
+    }
+
+    /// Tunnel target for a destination seen on the TUN
+    /// Returns None for fake addresses with no (or an evicted) mapping
+    fn target(&self, dst: SocketAddrV4) -> Option<TargetAddr> {
+        match &self.fake_ip {
+            Some(fake_ip) if fake_ip.contains(*dst.ip()) => fake_ip
+                .domain(*dst.ip())
+                .map(|domain| TargetAddr::Domain(domain, dst.port())),
+            _ => Some(SocketAddr::V4(dst).into()),
+        }
+    }
+
+    /// Handle a packet read from the TUN device
+    fn ingress(&mut self, packet: Vec<u8>) {
+        let Ok(ip) = Ipv4Packet::new_checked(&packet[..]) else {
+            trace!("Dropping non-IPv4 packet ({} bytes)", packet.len());
+            return;
+        };
+
+        match ip.next_header() {
+            IpProtocol::Tcp => {
+                self.accept_tcp(&ip);
+                self.device.rx.push_back(packet);
+            }
+            IpProtocol::Udp => self.handle_udp(&ip),
+            IpProtocol::Icmp => self.handle_icmp(&ip),
+            other => trace!("Dropping {} packet", other),
+        }
+    }
+
+    /// Create a listening socket for a new TCP flow before its SYN is processed
+    fn accept_tcp(&mut self, ip: &Ipv4Packet<&[u8]>) {
+        let Ok(tcp) = TcpPacket::new_checked(ip.payload()) else {
+            return;
+        };
+        if !tcp.syn() || tcp.ack() {
+            return;
+        }
+
+        let src = SocketAddrV4::new(ip.src_addr(), tcp.src_port());
+        let dst = SocketAddrV4::new(ip.dst_addr(), tcp.dst_port());
+        if self.tcp_tuples.contains_key(&(src, dst)) {
+            // Retransmitted SYN; the existing socket handles it
+            return;
+        }
+        let Some(target) = self.target(dst) else {
+            // No listener: the stack answers the SYN with a reset
+            debug!("No fake-IP mapping for {}", dst);
+            return;
+        };
+
+        let mut socket = tcp::Socket::new(
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+            tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        );
+        let endpoint = IpListenEndpoint {
+            addr: Some(IpAddress::Ipv4(*dst.ip())),
+            port: dst.port(),
+        };
+        if let Err(e) = socket.listen(endpoint) {
+            debug!("Cannot accept TCP flow {} -> {}: {}", src, dst, e);
+            return;
+        }
+        let handle = self.sockets.add(socket);
+
+        let (to_mux, to_mux_rx) = mpsc::channel(TCP_CHANNEL_DEPTH);
+        let (from_mux_tx, from_mux) = mpsc::channel(TCP_CHANNEL_DEPTH);
+        let task = tokio::spawn(run_tcp_flow(
+            self.router.clone(),
+            src,
+            target,
+            to_mux_rx,
+            from_mux_tx,
+            self.notify.clone(),
+        ));
+
+        debug!("TCP flow {} -> {}", src, dst);
+        self.tcp_tuples.insert((src, dst), handle);
+        self.tcp_flows.insert(
+            handle,
+            TcpFlow {
+                tuple: (src, dst),
+                to_mux: Some(to_mux),
+                from_mux,
+                pending: Vec::new(),
+                established: false,
+                mux_closed: false,
+                task,
+            },
+        );
+    }
+
+    /// Send a UDP datagram through its source port's datagram stream
+    fn handle_udp(&mut self, ip: &Ipv4Packet<&[u8]>) {
+        let Ok(udp) = UdpPacket::new_checked(ip.payload()) else {
+            return;
+        };
+        let dst_ip = ip.dst_addr();
+        if dst_ip.is_broadcast() || dst_ip.is_multicast() {
+            return;
+        }
+
+        let src = SocketAddrV4::new(ip.src_addr(), udp.src_port());
+        let dst = SocketAddrV4::new(dst_ip, udp.dst_port());
+
+        // Hijack DNS so that lookups through the TUN also get fake addresses
+        if dst.port() == 53
+            && let Some(answer) = self.fake_ip.as_ref().and_then(|f| f.answer(udp.payload()))
+        {
+            self.emit_udp(UdpReply {
+                from: dst,
+                to: src,
+                payload: answer,
+            });
+            return;
+        }
+
+        let Some(target) = self.target(dst) else {
+            debug!("No fake-IP mapping for {}", dst);
+            return;
+        };
+        if self.router.route(&target) == Action::Reject {
+            trace!("Dropping UDP datagram to rejected target {}", target);
+            return;
+        }
+        let mut datagram = (dst, target, udp.payload().to_vec());
+
+        // Reuse the flow unless its task has ended (idle timeout)
+        if let Some(flow) = self.udp_flows.get(&src) {
+            match flow.send(datagram) {
+                Ok(()) => return,
+                Err(mpsc::error::SendError(d)) => datagram = d,
+            }
+        }
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let _ = tx.send(datagram);
+        tokio::spawn(run_udp_flow(
+            self.router.pool().clone(),
+            src,
+            rx,
+            self.udp_replies.clone(),
+        ));
+        self.udp_flows.insert(src, tx);
+        debug!("UDP flow from {}", src);
+    }
+
+    /// Answer ICMP echo requests locally
+    fn handle_icmp(&mut self, ip: &Ipv4Packet<&[u8]>) {
+        let caps = ChecksumCapabilities::default();
+        let Ok(icmp) = Icmpv4Packet::new_checked(ip.payload()) else {
+            return;
+        };
+        let Ok(Icmpv4Repr::EchoRequest {
+            ident,
+            seq_no,
+            data,
+        }) = Icmpv4Repr::parse(&icmp, &caps)
+        else {
+            return;
+        };
+
+        let reply = Icmpv4Repr::EchoReply {
+            ident,
+            seq_no,
+            data,
+        };
+        let packet = build_ipv4(
+            ip.dst_addr(),
+            ip.src_addr(),
+            IpProtocol::Icmp,
+            reply.buffer_len(),
+            |payload| reply.emit(&mut Icmpv4Packet::new_unchecked(payload), &caps),
+        );
+        self.device.tx.push_back(packet);
+    }
+
+    /// Queue a UDP reply for the TUN device
+    fn emit_udp(&mut self, reply: UdpReply) {
+        self.device
+            .tx
+            .push_back(build_udp(reply.from, reply.to, &reply.payload));
+    }
+
+    /// Run the interface and move data between sockets and streams
+    fn poll(&mut self) {
+        self.iface
+            .poll(Instant::now(), &mut self.device, &mut self.sockets);
+        self.pump_tcp();
+        // Flush whatever the pump wrote into socket buffers
+        self.iface
+            .poll(Instant::now(), &mut self.device, &mut self.sockets);
+    }
+
+    fn pump_tcp(&mut self) {
+        let mut finished = Vec::new();
+
+        for (&handle, flow) in self.tcp_flows.iter_mut() {
+            let socket = self.sockets.get_mut::<tcp::Socket>(handle);
+            if socket.state() == tcp::State::Established {
+                flow.established = true;
+            }
+
+            // Socket -> stream
+            if let Some(tx) = &flow.to_mux {
+                while socket.can_recv() {
+                    let Ok(permit) = tx.try_reserve() else { break };
+                    match socket.recv(|buf| (buf.len(), buf.to_vec())) {
+                        Ok(data) => permit.send(data),
+                        Err(_) => break,
+                    }
+                }
+                // Local side sent FIN: half-close the stream
+                if flow.established && !socket.may_recv() && !socket.can_recv() {
+                    flow.to_mux = None;
+                }
+            }
+
+            // Stream -> socket
+            loop {
+                if flow.pending.is_empty() {
+                    match flow.from_mux.try_recv() {
+                        Ok(data) => flow.pending = data,
+                        Err(mpsc::error::TryRecvError::Empty) => break,
+                        Err(mpsc::error::TryRecvError::Disconnected) => {
+                            flow.mux_closed = true;
+                            break;
+                        }
+                    }
+                }
+                if !socket.can_send() {
+                    break;
+                }
+                match socket.send_slice(&flow.pending) {
+                    Ok(n) if n > 0 => {
+                        flow.pending.drain(..n);
+                    }
+                    _ => break,
+                }
+            }
+
+            if flow.mux_closed && flow.pending.is_empty() {
+                if !flow.established {
+                    // Stream never opened: refuse the connection
+                    socket.abort();
+                } else if socket.may_send() {
+                    socket.close();
+                }
+            }
+
+            if matches!(socket.state(), tcp::State::Closed | tcp::State::TimeWait) {
+                finished.push(handle);
+            }
+        }
+
+        for handle in finished {
+            if let Some(flow) = self.tcp_flows.remove(&handle) {
+                flow.task.abort();
+                self.tcp_tuples.remove(&flow.tuple);
+                trace!("TCP flow {} -> {} finished", flow.tuple.0, flow.tuple.1);
+            }
+            self.sockets.remove(handle);
+        }
+    }
+}
+
+/// Route one TCP flow and bridge it to its outbound
+async fn run_tcp_flow(
+    router: Arc<Router>,
+    src: SocketAddrV4,
+    target: TargetAddr,
+    to_mux: mpsc::Receiver<Vec<u8>>,
+    from_mux: mpsc::Sender<Vec<u8>>,
+    notify: Arc<Notify>,
+) {
+    match router.open(&target, SocketAddr::V4(src)).await {
+        Ok(Outbound::Tunnel(stream)) => {
+            bridge_tunnel(stream, to_mux, from_mux, &notify).await;
+        }
+        Ok(Outbound::Direct(stream)) => {
+            bridge_direct(stream, to_mux, from_mux, &notify).await;
+        }
+        Ok(Outbound::Reject) => {
+            debug!("Rejected TCP flow {} -> {}", src, target);
+            // Dropping `from_mux` tells the stack to reset the connection
+            notify.notify_one();
+        }
+        Err(e) => {
+            debug!("Failed to connect to {}: {}", target, e);
+            notify.notify_one();
+        }
+    }
+}
+
+/// Bridge a TCP flow to a tunnel stream
+async fn bridge_tunnel(
+    stream: MuxStream,
+    mut to_mux: mpsc::Receiver<Vec<u8>>,
+    from_mux: mpsc::Sender<Vec<u8>>,
+    notify: &Notify,
+) {
+    let (writer, mut reader) = stream.split();
+
+    let upstream = async {
+        while let Some(data) = to_mux.recv().await {
+            // Channel space freed: the stack can drain the socket again
+            notify.notify_one();
+            if writer.send(&data).await.is_err() {
+                return;
+            }
+        }
+        let _ = writer.shutdown();
+    };
+
+    let downstream = async {
+        while let Some(data) = reader.recv().await {
+            if from_mux.send(data).await.is_err() {
+                break;
+            }
+            notify.notify_one();
+        }
+        drop(from_mux);
+        notify.notify_one();
+    };
+
+    tokio::join!(upstream, downstream);
+}
+
+/// Bridge a TCP flow to a direct connection
+async fn bridge_direct(
+    stream: TcpStream,
+    mut to_remote: mpsc::Receiver<Vec<u8>>,
+    from_remote: mpsc::Sender<Vec<u8>>,
+    notify: &Notify,
+) {
+    let (mut reader, mut writer) = stream.into_split();
+
+    let upstream = async {
+        while let Some(data) = to_remote.recv().await {
+            notify.notify_one();
+            if writer.write_all(&data).await.is_err() {
+                return;
+            }
+        }
+        let _ = writer.shutdown().await;
+    };
+
+    let downstream = async {
+        let mut buf = vec![0u8; MAX_CHUNK_SIZE];
+        loop {
+            match reader.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if from_remote.send(buf[..n].to_vec()).await.is_err() {
+                        break;
+                    }
+                }
+            }
+            notify.notify_one();
+        }
+        drop(from_remote);
+        notify.notify_one();
+    };
+
+    tokio::join!(upstream, downstream);
+}
+
+/// Carry one local UDP source port over a datagram stream
+///
+/// Replies carry the real address of the peer. For peers reached by hostname
+/// the source is rewritten to the fake address the application sent to,
+/// matched by port.
+async fn run_udp_flow(
+    pool: Arc<MuxPool>,
+    local: SocketAddrV4,
+    mut outbound: mpsc::UnboundedReceiver<Datagram>,
+    replies: mpsc::UnboundedSender<UdpReply>,
+) {
+    let stream = match pool.open_datagram_stream().await {
+        Ok(s) => s,
+        Err(e) => {
+            debug!("Failed to open datagram stream for {}: {}", local, e);
+            return;
+        }
+    };
+    let (writer, mut reader) = stream.split();
+
+    let mut direct_peers = HashSet::new();
+    let mut fake_peers = HashMap::new();
+
+    let idle = tokio::time::sleep(UDP_IDLE_TIMEOUT);
+    tokio::pin!(idle);
+
+    loop {
+        tokio::select! {
+            datagram = outbound.recv() => {
+                let Some((dst, target, data)) = datagram else { break };
+                if matches!(target, TargetAddr::Domain(..)) {
+                    fake_peers.insert(dst.port(), *dst.ip());
+                } else {
+                    direct_peers.insert(dst);
+                }
+                if let Err(e) = writer.send_datagram(&target, &data).await {
+                    debug!("Datagram send failed: {}", e);
+                    break;
+                }
+            }
+            frame = reader.recv_frame() => {
+                let Some(frame) = frame else { break };
+                let Some(ip) = ProxyFrame::mapped_to_ipv4(&frame.rip) else { continue };
+                let mut from = SocketAddrV4::new(ip.into(), frame.rport);
+                if !direct_peers.contains(&from)
+                    && let Some(&fake) = fake_peers.get(&frame.rport)
+                {
+                    from = SocketAddrV4::new(fake, frame.rport);
+                }
+                let reply = UdpReply {
+                    from,
+                    to: local,
+                    payload: frame.payload,
+                };
+                if replies.send(reply).is_err() {
+                    break;
+                }
+            }
+            _ = &mut idle => break,
+        }
+        idle.as_mut()
+            .reset(tokio::time::Instant::now() + UDP_IDLE_TIMEOUT);
+    }
+
+    trace!("UDP flow from {} closed", local);
+}
+
+/// Pick the stack's own address: a neighbor of the device address
+fn stack_address(device: Ipv4Addr, netmask: Ipv4Addr) -> Ipv4Addr {
+    let network = u32::from(device) & u32::from(netmask);
+    let first = network + 1;
+    if first == u32::from(device) {
+        Ipv4Addr::from(first + 1)
+    } else {
+        Ipv4Addr::from(first)
+    }
+}
+
+/// Build an IPv4 packet, letting `emit` fill in the payload
+fn build_ipv4(
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    protocol: IpProtocol,
+    payload_len: usize,
+    emit: impl FnOnce(&mut [u8]),
+) -> Vec<u8> {
+    let repr = Ipv4Repr {
+        src_addr: src,
+        dst_addr: dst,
+        next_header: protocol,
+        payload_len,
+        hop_limit: 64,
+    };
+    let mut buf = vec![0u8; repr.buffer_len() + payload_len];
+    let mut packet = Ipv4Packet::new_unchecked(&mut buf[..]);
+    repr.emit(&mut packet, &ChecksumCapabilities::default());
+    emit(packet.payload_mut());
+    buf
+}
+
+/// Build an IPv4/UDP packet
+fn build_udp(from: SocketAddrV4, to: SocketAddrV4, payload: &[u8]) -> Vec<u8> {
+    let repr = UdpRepr {
+        src_port: from.port(),
+        dst_port: to.port(),
+    };
+    build_ipv4(
+        *from.ip(),
+        *to.ip(),
+        IpProtocol::Udp,
+        repr.header_len() + payload.len(),
+        |buf| {
+            repr.emit(
+                &mut UdpPacket::new_unchecked(buf),
+                &IpAddress::Ipv4(*from.ip()),
+                &IpAddress::Ipv4(*to.ip()),
+                payload.len(),
+                |data| data.copy_from_slice(payload),
+                &ChecksumCapabilities::default(),
+            )
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stack_address() {
+        let mask = Ipv4Addr::new(255, 255, 255, 0);
+        assert_eq!(
+            stack_address(Ipv4Addr::new(10, 0, 0, 2), mask),
+            Ipv4Addr::new(10, 0, 0, 1)
+        );
+        assert_eq!(
+            stack_address(Ipv4Addr::new(10, 0, 0, 1), mask),
+            Ipv4Addr::new(10, 0, 0, 2)
+        );
+    }
+
+    #[test]
+    fn test_build_udp() {
+        let from = SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 53);
+        let to = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 40000);
+        let packet = build_udp(from, to, b"answer");
+
+        let ip = Ipv4Packet::new_checked(&packet[..]).unwrap();
+        assert!(ip.verify_checksum());
+        assert_eq!(ip.src_addr(), *from.ip());
+        assert_eq!(ip.dst_addr(), *to.ip());
+
+        let udp = UdpPacket::new_checked(ip.payload()).unwrap();
+        assert!(udp.verify_checksum(&IpAddress::Ipv4(*from.ip()), &IpAddress::Ipv4(*to.ip())));
+        assert_eq!(udp.dst_port(), 40000);
+        assert_eq!(udp.payload(), b"answer");
+    }
+
+    #[tokio::test]
+    async fn test_icmp_echo_reply() {
+        let (udp_replies, _rx) = mpsc::unbounded_channel();
+        let config = TunConfig::default();
+        let pool = MuxPool::new(&crate::config::ClientConfig::default());
+        let router = Router::tunnel_only(pool);
+        let mut stack = NetStack::new(&config, router, None, udp_replies).unwrap();
+
+        let caps = ChecksumCapabilities::default();
+        let request = Icmpv4Repr::EchoRequest {
+            ident: 7,
+            seq_no: 1,
+            data: b"ping",
+        };
+        let packet = build_ipv4(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(1, 1, 1, 1),
+            IpProtocol::Icmp,
+            request.buffer_len(),
+            |payload| request.emit(&mut Icmpv4Packet::new_unchecked(payload), &caps),
+        );
+
+        stack.ingress(packet);
+        let reply = stack.device.tx.pop_front().expect("echo reply");
+
+        let ip = Ipv4Packet::new_checked(&reply[..]).unwrap();
+        assert_eq!(ip.src_addr(), Ipv4Addr::new(1, 1, 1, 1));
+        let icmp = Icmpv4Packet::new_checked(ip.payload()).unwrap();
+        assert!(matches!(
+            Icmpv4Repr::parse(&icmp, &caps),
+            Ok(Icmpv4Repr::EchoReply { ident: 7, seq_no: 1, data }) if data == b"ping"
+        ));
+    }
+}
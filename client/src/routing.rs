@@ -0,0 +1,729 @@
+//! Rule-based routing
+//!
+//! Every connection entering the client (SOCKS5, HTTP proxy or TUN) is matched
+//! against an ordered rule list and sent `direct`, through the tunnel
+//! (`proxy` or `proxy(group)`), or rejected. Rules are `type,value,action`:
+//!
+//! - `domain`, `domain-suffix`, `domain-keyword`, `domain-regex`: hostname
+//!   targets (SOCKS5/HTTP domains, fake-IP flows in TUN mode)
+//! - `ip-cidr`, `geoip`: IP targets; domains are never resolved locally just
+//!   to evaluate these
+//! - `port`: destination port or range (`6881-6889`)
+//! - `process`: name of the local process owning the connection (Linux)
+//! - `match`: always matches
+//!
+//! SOCKS5 UDP datagrams honor `reject` and `direct`, TUN-mode UDP honors
+//! `reject` only; tunneled datagrams always use the default tunnel.
+
+use crate::config::ClientConfig;
+use crate::mux::{self, MuxPool, MuxStream, TargetAddr};
+use anyhow::{Result, anyhow};
+use ipnet::IpNet;
+use maxminddb::{Reader, geoip2};
+use regex_automata::meta::Regex;
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
+use tracing::{debug, info, warn};
+
+/// Interval between checks of the rule file for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Timeout for direct connections
+const DIRECT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What to do with a connection
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Direct,
+    /// Tunnel through the default pool (None) or a named group
+    Proxy(Option<String>),
+    Reject,
+}
+
+impl Action {
+    fn parse(s: &str) -> Result<Self> {
+        let s = s.trim();
+        match s.to_ascii_lowercase().as_str() {
+            "direct" => Ok(Action::Direct),
+            "reject" => Ok(Action::Reject),
+            "proxy" => Ok(Action::Proxy(None)),
+            _ => {
+                let group = s
+                    .strip_prefix("proxy(")
+                    .and_then(|g| g.strip_suffix(')'))
+                    .map(str::trim)
+                    .filter(|g| !g.is_empty())
+                    .ok_or_else(|| anyhow!("Unknown action: {}", s))?;
+                Ok(Action::Proxy(Some(group.to_string())))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Domain(String),
+    DomainSuffix(String),
+    DomainKeyword(String),
+    DomainRegex(Regex),
+    IpCidr(IpNet),
+    GeoIp(String),
+    Port(RangeInclusive<u16>),
+    Process(String),
+    Any,
+}
+
+#[derive(Debug)]
+struct Rule {
+    matcher: Matcher,
+    action: Action,
+}
+
+/// Parse one `type,value,action` rule
+fn parse_rule(line: &str) -> Result<Rule> {
+    let (head, action) = line
+        .rsplit_once(',')
+        .ok_or_else(|| anyhow!("Malformed rule: {}", line))?;
+    let action = Action::parse(action)?;
+
+    let (kind, value) = head.split_once(',').unwrap_or((head, ""));
+    let value = value.trim();
+    let matcher = match kind.trim().to_ascii_lowercase().as_str() {
+        "match" => Matcher::Any,
+        "domain" => Matcher::Domain(value.to_ascii_lowercase()),
+        "domain-suffix" => {
+            Matcher::DomainSuffix(value.trim_start_matches('.').to_ascii_lowercase())
+        }
+        "domain-keyword" => Matcher::DomainKeyword(value.to_ascii_lowercase()),
+        "domain-regex" => Matcher::DomainRegex(Regex::new(value)?),
+        "ip-cidr" => Matcher::IpCidr(value.parse()?),
+        "geoip" => Matcher::GeoIp(value.to_ascii_uppercase()),
+        "port" => {
+            let range = match value.split_once('-') {
+                Some((lo, hi)) => lo.trim().parse()?..=hi.trim().parse()?,
+                None => {
+                    let port = value.parse()?;
+                    port..=port
+                }
+            };
+            Matcher::Port(range)
+        }
+        "process" => Matcher::Process(value.to_string()),
+        other => return Err(anyhow!("Unknown rule type: {}", other)),
+    };
+    if value.is_empty() && !matches!(matcher, Matcher::Any) {
+        return Err(anyhow!("Rule has no value: {}", line));
+    }
+
+    Ok(Rule { matcher, action })
+}
+
+/// Parse rules, one per line, skipping blanks and `#` comments
+fn parse_rules(text: &str) -> Result<Vec<Rule>> {
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(parse_rule)
+        .collect()
+}
+
+/// A connection to be routed
+struct Request<'a> {
+    domain: Option<&'a str>,
+    ip: Option<IpAddr>,
+    port: u16,
+    /// Name of the local process owning the connection, if looked up
+    process: Option<&'a str>,
+}
+
+#[derive(Debug)]
+struct RuleSet {
+    rules: Vec<Rule>,
+    final_action: Action,
+    /// Whether any rule needs the owning process (looked up only then)
+    has_process_rules: bool,
+}
+
+impl RuleSet {
+    fn new(rules: Vec<Rule>, final_action: Action) -> Self {
+        let has_process_rules = rules
+            .iter()
+            .any(|r| matches!(r.matcher, Matcher::Process(_)));
+        Self {
+            rules,
+            final_action,
+            has_process_rules,
+        }
+    }
+
+    fn evaluate(&self, req: &Request, geoip: Option<&Reader<Vec<u8>>>) -> &Action {
+        let country = req.ip.and_then(|ip| geoip.and_then(|g| country(g, ip)));
+
+        for rule in &self.rules {
+            let matched = match &rule.matcher {
+                Matcher::Any => true,
+                Matcher::Domain(d) => req.domain == Some(d.as_str()),
+                Matcher::DomainSuffix(suffix) => req.domain.is_some_and(|d| {
+                    d == suffix
+                        || d.strip_suffix(suffix.as_str())
+                            .is_some_and(|rest| rest.ends_with('.'))
+                }),
+                Matcher::DomainKeyword(keyword) => {
+                    req.domain.is_some_and(|d| d.contains(keyword.as_str()))
+                }
+                Matcher::DomainRegex(re) => req.domain.is_some_and(|d| re.is_match(d)),
+                Matcher::IpCidr(net) => req.ip.is_some_and(|ip| net.contains(&ip)),
+                Matcher::GeoIp(code) => country.as_deref() == Some(code.as_str()),
+                Matcher::Port(range) => range.contains(&req.port),
+                Matcher::Process(name) => req.process == Some(name.as_str()),
+            };
+            if matched {
+                return &rule.action;
+            }
+        }
+        &self.final_action
+    }
+}
+
+/// Where a routed connection goes
+pub enum Outbound {
+    Tunnel(MuxStream),
+    Direct(TcpStream),
+    Reject,
+}
+
+impl Outbound {
+    /// Relay `stream` to the outbound, sending `initial` first
+    pub async fn relay<S>(self, mut stream: S, initial: &[u8]) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        match self {
+            Outbound::Tunnel(mux_stream) => {
+                let (mux_writer, mux_reader) = mux_stream.split();
+                if !initial.is_empty() {
+                    mux_writer.send(initial).await?;
+                }
+                mux::relay(stream, mux_writer, mux_reader).await;
+            }
+            Outbound::Direct(mut remote) => {
+                if !initial.is_empty() {
+                    remote.write_all(initial).await?;
+                }
+                let (up, down) = tokio::io::copy_bidirectional(&mut stream, &mut remote).await?;
+                debug!("Direct relay finished ({} up, {} down)", up, down);
+            }
+            Outbound::Reject => {}
+        }
+        Ok(())
+    }
+}
+
+/// Rule engine and the pools it routes to
+pub struct Router {
+    rules: RwLock<Arc<RuleSet>>,
+    /// Inline rules from the config, kept for reloads
+    inline_rules: Vec<String>,
+    rules_file: Option<PathBuf>,
+    final_action: Action,
+    geoip: Option<Reader<Vec<u8>>>,
+    pool: Arc<MuxPool>,
+    groups: HashMap<String, Arc<MuxPool>>,
+    direct_interface: Option<String>,
+    processes: Arc<ProcessCache>,
+}
+
+impl Router {
+    /// Build the router; `pool` carries `proxy` traffic
+    pub fn new(config: &ClientConfig, pool: Arc<MuxPool>) -> Result<Arc<Self>> {
+        let routing = &config.routing;
+
+        let groups = routing
+            .groups
+            .iter()
+            .map(|group| {
+                let mut group_config = config.clone();
+                group_config.connection.endpoints = group.endpoints.clone();
+                (group.name.clone(), MuxPool::new(&group_config))
+            })
+            .collect();
+
+        let geoip =
+            match &routing.geoip_db {
+                Some(path) => Some(Reader::open_readfile(path).map_err(|e| {
+                    anyhow!("Failed to open GeoIP database {}: {}", path.display(), e)
+                })?),
+                None => None,
+            };
+
+        let mut router = Self {
+            rules: RwLock::new(Arc::new(RuleSet::new(Vec::new(), Action::Proxy(None)))),
+            inline_rules: routing.rules.clone(),
+            rules_file: routing.rules_file.clone(),
+            final_action: Action::parse(&routing.final_action)?,
+            geoip,
+            pool,
+            groups,
+            direct_interface: routing.direct_interface.clone(),
+            processes: Arc::new(ProcessCache::default()),
+        };
+        router.check_group(&router.final_action)?;
+        let rules = router.load_rules()?;
+        info!("Routing with {} rule(s)", rules.rules.len());
+        router.rules = RwLock::new(Arc::new(rules));
+
+        Ok(Arc::new(router))
+    }
+
+    /// A router that tunnels everything through `pool`
+    pub fn tunnel_only(pool: Arc<MuxPool>) -> Arc<Self> {
+        Self::new(&ClientConfig::default(), pool).expect("default routing config is valid")
+    }
+
+    /// Default tunnel pool
+    pub fn pool(&self) -> &Arc<MuxPool> {
+        &self.pool
+    }
+
+    /// Decide what to do with traffic to `target` that has no owning local
+    /// socket (datagrams); `process` rules never match it
+    pub fn route(&self, target: &TargetAddr) -> Action {
+        let rules = self.rules.read().unwrap().clone();
+        self.evaluate(&rules, target, None)
+    }
+
+    /// Decide what to do with a connection to `target` from `source`
+    ///
+    /// The owning process is looked up on the blocking pool, and only when
+    /// the rule set has `process` rules.
+    pub async fn route_connection(&self, target: &TargetAddr, source: SocketAddr) -> Action {
+        let rules = self.rules.read().unwrap().clone();
+        let process = if rules.has_process_rules {
+            let processes = self.processes.clone();
+            tokio::task::spawn_blocking(move || processes.lookup(source))
+                .await
+                .ok()
+                .flatten()
+        } else {
+            None
+        };
+        self.evaluate(&rules, target, process.as_deref())
+    }
+
+    fn evaluate(&self, rules: &RuleSet, target: &TargetAddr, process: Option<&str>) -> Action {
+        let (domain, ip) = match target {
+            TargetAddr::Domain(host, _) => (Some(host.to_ascii_lowercase()), None),
+            TargetAddr::Ip(..) => (None, target.socket_addr().map(|a| a.ip())),
+        };
+        let req = Request {
+            domain: domain.as_deref(),
+            ip,
+            port: target.port(),
+            process,
+        };
+
+        let action = rules.evaluate(&req, self.geoip.as_ref()).clone();
+        debug!("Route {} -> {:?}", target, action);
+        action
+    }
+
+    /// Route and connect in one step
+    pub async fn open(&self, target: &TargetAddr, source: SocketAddr) -> Result<Outbound> {
+        let action = self.route_connection(target, source).await;
+        self.connect(&action, target).await
+    }
+
+    /// Carry out a routing decision
+    pub async fn connect(&self, action: &Action, target: &TargetAddr) -> Result<Outbound> {
+        match action {
+            Action::Reject => Ok(Outbound::Reject),
+            Action::Direct => Ok(Outbound::Direct(self.connect_direct(target).await?)),
+            Action::Proxy(group) => {
+                let pool = match group {
+                    Some(name) => self
+                        .groups
+                        .get(name)
+                        .ok_or_else(|| anyhow!("Unknown proxy group: {}", name))?,
+                    None => &self.pool,
+                };
+                Ok(Outbound::Tunnel(pool.open_stream(target).await?))
+            }
+        }
+    }
+
+    /// Resolve `target` for a direct connection or datagram
+    pub async fn resolve_direct(&self, target: &TargetAddr) -> Result<Vec<SocketAddr>> {
+        Ok(match target {
+            TargetAddr::Domain(host, port) => tokio::net::lookup_host((host.as_str(), *port))
+                .await?
+                .collect(),
+            TargetAddr::Ip(..) => target.socket_addr().into_iter().collect(),
+        })
+    }
+
+    /// Bind a UDP socket for direct datagrams to `addr`'s address family
+    pub async fn bind_direct_udp(&self, addr: SocketAddr) -> Result<UdpSocket> {
+        let local: SocketAddr = if addr.is_ipv4() {
+            (Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = UdpSocket::bind(local).await?;
+        #[cfg(target_os = "linux")]
+        if let Some(interface) = &self.direct_interface {
+            socket.bind_device(Some(interface.as_bytes()))?;
+        }
+        Ok(socket)
+    }
+
+    /// Connect to `target` without the tunnel
+    async fn connect_direct(&self, target: &TargetAddr) -> Result<TcpStream> {
+        let addrs = self.resolve_direct(target).await?;
+
+        let mut last_error = anyhow!("No addresses for {}", target);
+        for addr in addrs {
+            let socket = if addr.is_ipv4() {
+                TcpSocket::new_v4()?
+            } else {
+                TcpSocket::new_v6()?
+            };
+            #[cfg(target_os = "linux")]
+            if let Some(interface) = &self.direct_interface {
+                socket.bind_device(Some(interface.as_bytes()))?;
+            }
+
+            match tokio::time::timeout(DIRECT_CONNECT_TIMEOUT, socket.connect(addr)).await {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => last_error = e.into(),
+                Err(_) => {
+                    last_error = std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("Connection to {} timed out", addr),
+                    )
+                    .into()
+                }
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Watch the rule file and swap in new rules when it changes
+    pub fn spawn_reloader(self: &Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        let path = self.rules_file.clone()?;
+        let router = self.clone();
+        Some(tokio::spawn(async move {
+            let mut last_modified = modified(&path);
+            let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+            loop {
+                interval.tick().await;
+                let current = modified(&path);
+                if current == last_modified {
+                    continue;
+                }
+                last_modified = current;
+
+                match router.load_rules() {
+                    Ok(rules) => {
+                        info!(
+                            "Reloaded {} routing rule(s) from {}",
+                            rules.rules.len(),
+                            path.display()
+                        );
+                        *router.rules.write().unwrap() = Arc::new(rules);
+                    }
+                    Err(e) => warn!("Keeping previous rules, reload failed: {}", e),
+                }
+            }
+        }))
+    }
+
+    /// Parse the inline rules followed by the rule file
+    fn load_rules(&self) -> Result<RuleSet> {
+        let mut rules = parse_rules(&self.inline_rules.join("\n"))?;
+        if let Some(path) = &self.rules_file {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?;
+            rules.extend(parse_rules(&text)?);
+        }
+
+        for rule in &rules {
+            self.check_group(&rule.action)?;
+            if matches!(rule.matcher, Matcher::GeoIp(_)) && self.geoip.is_none() {
+                return Err(anyhow!("geoip rules need routing.geoip_db"));
+            }
+        }
+        Ok(RuleSet::new(rules, self.final_action.clone()))
+    }
+
+    fn check_group(&self, action: &Action) -> Result<()> {
+        match action {
+            Action::Proxy(Some(name)) if !self.groups.contains_key(name) => {
+                Err(anyhow!("Unknown proxy group: {}", name))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+fn modified(path: &PathBuf) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// ISO country code of an address
+fn country(reader: &Reader<Vec<u8>>, ip: IpAddr) -> Option<String> {
+    let result = reader.lookup(ip).ok()?;
+    let country: geoip2::Country = result.decode().ok()??;
+    country.country.iso_code.map(|s| s.to_string())
+}
+
+/// Number of recent socket owners tried before a full scan of /proc
+const RECENT_OWNERS: usize = 16;
+
+/// Finds the local process owning a socket
+///
+/// Remembers the processes that recently owned connections: most connections
+/// come from a few programs, so their descriptors are checked before falling
+/// back to scanning every process.
+#[derive(Default)]
+struct ProcessCache {
+    recent: Mutex<VecDeque<u32>>,
+}
+
+impl ProcessCache {
+    /// Name of the process owning the TCP socket bound to `local`; blocks
+    #[cfg(target_os = "linux")]
+    fn lookup(&self, local: SocketAddr) -> Option<String> {
+        let inode = ["/proc/net/tcp", "/proc/net/tcp6"]
+            .iter()
+            .filter_map(|path| std::fs::read_to_string(path).ok())
+            .find_map(|table| find_socket_inode(&table, local))?;
+        let target = format!("socket:[{}]", inode);
+
+        let recent: Vec<u32> = self.recent.lock().unwrap().iter().copied().collect();
+        let pid = recent
+            .into_iter()
+            .find(|&pid| owns_socket(pid, &target))
+            .or_else(|| {
+                std::fs::read_dir("/proc")
+                    .ok()?
+                    .flatten()
+                    .filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+                    .find(|&pid| owns_socket(pid, &target))
+            })?;
+
+        let mut recent = self.recent.lock().unwrap();
+        recent.retain(|&p| p != pid);
+        recent.push_front(pid);
+        recent.truncate(RECENT_OWNERS);
+        drop(recent);
+
+        let comm = std::fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+        Some(comm.trim().to_string())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn lookup(&self, _local: SocketAddr) -> Option<String> {
+        None
+    }
+}
+
+/// Whether process `pid` holds a descriptor for `socket` (`socket:[inode]`)
+#[cfg(target_os = "linux")]
+fn owns_socket(pid: u32, socket: &str) -> bool {
+    let Ok(fds) = std::fs::read_dir(format!("/proc/{}/fd", pid)) else {
+        return false;
+    };
+    fds.flatten()
+        .any(|fd| std::fs::read_link(fd.path()).is_ok_and(|link| link.as_os_str() == socket))
+}
+
+/// Find the inode of the socket bound to `local` in a /proc/net/tcp{,6} table
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn find_socket_inode(table: &str, local: SocketAddr) -> Option<u64> {
+    // IPv4 sockets appear in tcp6 as v4-mapped addresses
+    let mapped = match local.ip() {
+        IpAddr::V4(v4) => IpAddr::V6(v4.to_ipv6_mapped()),
+        ip => ip,
+    };
+
+    for line in table.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (Some(addr), Some(inode)) = (fields.get(1), fields.get(9)) else {
+            continue;
+        };
+        let Some((ip, port)) = addr.split_once(':') else {
+            continue;
+        };
+        if u16::from_str_radix(port, 16).ok() != Some(local.port()) {
+            continue;
+        }
+        let Some(ip) = parse_proc_ip(ip) else {
+            continue;
+        };
+        if ip == local.ip() || ip == mapped {
+            return inode.parse().ok();
+        }
+    }
+    None
+}
+
+/// Parse an address as printed in /proc/net: 32-bit words in host byte order
+fn parse_proc_ip(hex: &str) -> Option<IpAddr> {
+    let words = (0..hex.len() / 8)
+        .map(|i| u32::from_str_radix(hex.get(i * 8..i * 8 + 8)?, 16).ok())
+        .collect::<Option<Vec<u32>>>()?;
+
+    match words.as_slice() {
+        [a] => Some(IpAddr::from(a.to_ne_bytes())),
+        [a, b, c, d] => {
+            let mut octets = [0u8; 16];
+            for (i, word) in [a, b, c, d].iter().enumerate() {
+                octets[i * 4..i * 4 + 4].copy_from_slice(&word.to_ne_bytes());
+            }
+            Some(IpAddr::from(octets))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ruleset(rules: &str) -> RuleSet {
+        RuleSet::new(parse_rules(rules).unwrap(), Action::Proxy(None))
+    }
+
+    fn domain(rules: &RuleSet, host: &str, port: u16) -> Action {
+        let req = Request {
+            domain: Some(host),
+            ip: None,
+            port,
+            process: None,
+        };
+        rules.evaluate(&req, None).clone()
+    }
+
+    fn ip(rules: &RuleSet, ip: &str, port: u16) -> Action {
+        let req = Request {
+            domain: None,
+            ip: Some(ip.parse().unwrap()),
+            port,
+            process: None,
+        };
+        rules.evaluate(&req, None).clone()
+    }
+
+    #[test]
+    fn test_parse_action() {
+        assert_eq!(Action::parse("DIRECT").unwrap(), Action::Direct);
+        assert_eq!(
+            Action::parse("proxy(jp)").unwrap(),
+            Action::Proxy(Some("jp".to_string()))
+        );
+        assert!(Action::parse("proxy()").is_err());
+        assert!(Action::parse("drop").is_err());
+    }
+
+    #[test]
+    fn test_domain_rules() {
+        let rules = ruleset(
+            "# comment\n\
+             domain,exact.test,reject\n\
+             domain-suffix,example.com,direct\n\
+             domain-keyword,ads,reject\n\
+             domain-regex,^cdn[0-9]+\\.,proxy(cdn)\n",
+        );
+
+        assert_eq!(domain(&rules, "exact.test", 80), Action::Reject);
+        assert_eq!(domain(&rules, "example.com", 80), Action::Direct);
+        assert_eq!(domain(&rules, "www.example.com", 80), Action::Direct);
+        // Suffix matches whole labels only
+        assert_eq!(domain(&rules, "badexample.com", 80), Action::Proxy(None));
+        assert_eq!(domain(&rules, "myads.net", 80), Action::Reject);
+        assert_eq!(
+            domain(&rules, "cdn12.net", 80),
+            Action::Proxy(Some("cdn".to_string()))
+        );
+        // Domain rules never match IP targets
+        assert_eq!(ip(&rules, "93.184.216.34", 80), Action::Proxy(None));
+    }
+
+    #[test]
+    fn test_ip_and_port_rules() {
+        let rules = ruleset(
+            "ip-cidr,10.0.0.0/8,direct\n\
+             ip-cidr,fd00::/8,direct\n\
+             port,6881-6889,reject\n\
+             match,,proxy(rest)\n",
+        );
+
+        assert_eq!(ip(&rules, "10.1.2.3", 443), Action::Direct);
+        assert_eq!(ip(&rules, "fd12::1", 443), Action::Direct);
+        assert_eq!(ip(&rules, "1.1.1.1", 6885), Action::Reject);
+        assert_eq!(domain(&rules, "a.test", 6881), Action::Reject);
+        assert_eq!(
+            ip(&rules, "1.1.1.1", 443),
+            Action::Proxy(Some("rest".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!(parse_rule("domain-suffix,example.com").is_err());
+        assert!(parse_rule("ip-cidr,not-a-net,direct").is_err());
+        assert!(parse_rule("port,http,direct").is_err());
+        assert!(parse_rule("domain,,direct").is_err());
+        assert!(parse_rule("source,1.2.3.4,direct").is_err());
+        // Regexes may contain commas
+        assert!(parse_rule("domain-regex,^a{1,3}\\.test$,reject").is_ok());
+    }
+
+    #[test]
+    fn test_find_socket_inode() {
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n\
+           0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1000        0 12345 1 0000000000000000 100 0 0 10 0\n";
+        let local: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        assert_eq!(find_socket_inode(table, local), Some(12345));
+        assert_eq!(
+            find_socket_inode(table, "127.0.0.1:8081".parse().unwrap()),
+            None
+        );
+
+        let table6 = "header\n\
+           0: 0000000000000000FFFF00000100007F:1F90 00000000000000000000000000000000:0000 01 00000000:00000000 00:00000000 00000000  1000        0 777 1\n";
+        assert_eq!(find_socket_inode(table6, local), Some(777));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_process_cache_finds_own_socket() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let local = stream.local_addr().unwrap();
+        let comm = std::fs::read_to_string("/proc/self/comm").unwrap();
+
+        let cache = ProcessCache::default();
+        assert_eq!(cache.lookup(local).as_deref(), Some(comm.trim()));
+        assert_eq!(
+            cache.recent.lock().unwrap().front(),
+            Some(&std::process::id())
+        );
+        // Served from the recent owners the second time
+        assert_eq!(cache.lookup(local).as_deref(), Some(comm.trim()));
+    }
+
+    #[tokio::test]
+    async fn test_router_rejects_unknown_group() {
+        let mut config = ClientConfig::default();
+        config.routing.rules = vec!["domain,a.test,proxy(missing)".to_string()];
+        let pool = MuxPool::new(&config);
+        assert!(Router::new(&config, pool).is_err());
+    }
+}
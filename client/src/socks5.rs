@@ -1,10 +1,17 @@
 //! SOCKS5 proxy server
 
-use crate::config::ClientConfig;
+use crate::config::{ClientConfig, Socks5Config};
+use crate::mux::{self, TargetAddr};
+use crate::routing::{Action, Outbound, Router};
 use anyhow::Result;
-use std::net::SocketAddr;
+use apfsds_protocol::ProxyFrame;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use subtle::{Choice, ConstantTimeEq};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
 use tracing::{debug, error, info, trace, warn};
 
 /// SOCKS5 version
@@ -12,15 +19,27 @@ const SOCKS5_VERSION: u8 = 0x05;
 
 /// SOCKS5 authentication methods
 const AUTH_NO_AUTH: u8 = 0x00;
+const AUTH_USERNAME_PASSWORD: u8 = 0x02;
+const AUTH_NO_ACCEPTABLE: u8 = 0xFF;
+
+/// Username/password sub-negotiation (RFC 1929)
+const USERPASS_VERSION: u8 = 0x01;
+const USERPASS_SUCCESS: u8 = 0x00;
+const USERPASS_FAILURE: u8 = 0x01;
 
 /// SOCKS5 commands
 const CMD_CONNECT: u8 = 0x01;
+const CMD_BIND: u8 = 0x02;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
 
 /// SOCKS5 address types
 const ATYP_IPV4: u8 = 0x01;
 const ATYP_DOMAIN: u8 = 0x03;
 const ATYP_IPV6: u8 = 0x04;
 
+/// Replies from direct UDP sockets waiting for the association loop
+const DIRECT_REPLY_QUEUE: usize = 64;
+
 /// SOCKS5 reply codes
 const REP_SUCCESS: u8 = 0x00;
 const REP_GENERAL_FAILURE: u8 = 0x01;
@@ -30,17 +49,23 @@ const REP_HOST_UNREACHABLE: u8 = 0x04;
 const REP_CONNECTION_REFUSED: u8 = 0x05;
 
 /// Run the SOCKS5 server
-pub async fn run(config: &ClientConfig) -> Result<()> {
+pub async fn run(config: &ClientConfig, router: Arc<Router>) -> Result<()> {
     let listener = TcpListener::bind(config.socks5.bind).await?;
     info!("SOCKS5 server listening on {}", config.socks5.bind);
 
+    if config.socks5.auth && config.socks5.users.is_empty() {
+        warn!("SOCKS5 auth is enabled but no users are configured; all clients will be rejected");
+    }
+    let socks5 = Arc::new(config.socks5.clone());
+
     loop {
         let (stream, addr) = listener.accept().await?;
         debug!("New connection from {}", addr);
 
-        let config = config.clone();
+        let router = router.clone();
+        let socks5 = socks5.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_connection(stream, addr, &config).await {
+            if let Err(e) = handle_connection(stream, addr, router, socks5).await {
                 error!("Connection error from {}: {}", addr, e);
             }
         });
@@ -51,7 +76,8 @@ pub async fn run(config: &ClientConfig) -> Result<()> {
 async fn handle_connection(
     mut stream: TcpStream,
     addr: SocketAddr,
-    config: &ClientConfig,
+    router: Arc<Router>,
+    socks5: Arc<Socks5Config>,
 ) -> Result<()> {
     // Check emergency mode
     if crate::emergency::is_emergency_mode() {
@@ -69,14 +95,23 @@ async fn handle_connection(
     let mut methods = vec![0u8; nmethods as usize];
     stream.read_exact(&mut methods).await?;
 
-    // We only support no-auth for now
-    if !methods.contains(&AUTH_NO_AUTH) {
-        stream.write_all(&[SOCKS5_VERSION, 0xFF]).await?;
+    let method = if socks5.auth {
+        AUTH_USERNAME_PASSWORD
+    } else {
+        AUTH_NO_AUTH
+    };
+    if !methods.contains(&method) {
+        stream
+            .write_all(&[SOCKS5_VERSION, AUTH_NO_ACCEPTABLE])
+            .await?;
         return Err(anyhow::anyhow!("No acceptable auth method"));
     }
+    stream.write_all(&[SOCKS5_VERSION, method]).await?;
 
-    // Accept no-auth
-    stream.write_all(&[SOCKS5_VERSION, AUTH_NO_AUTH]).await?;
+    if method == AUTH_USERNAME_PASSWORD {
+        let username = authenticate(&mut stream, &socks5).await?;
+        debug!("Authenticated {} as {}", addr, username);
+    }
 
     // 2. Request
     let version = stream.read_u8().await?;
@@ -88,102 +123,412 @@ async fn handle_connection(
         return Err(anyhow::anyhow!("Invalid version in request"));
     }
 
-    if cmd != CMD_CONNECT {
-        send_reply(&mut stream, REP_GENERAL_FAILURE).await?;
-        return Err(anyhow::anyhow!("Unsupported command: {}", cmd));
-    }
-
     // Parse target address
     let target = parse_target(&mut stream, atyp).await?;
-    debug!("Connection from {} to {}", addr, target);
+    debug!("Request {} from {} to {}", cmd, addr, target);
 
-    // Resolve target to IP/Port (ProxyFrame requires IP)
-    let target_sock_addr = match tokio::net::lookup_host(&target).await {
-        Ok(mut iter) => iter
-            .next()
-            .ok_or(anyhow::anyhow!("No IP found for target"))?,
+    match cmd {
+        CMD_CONNECT => handle_connect(stream, addr, target, router).await,
+        CMD_BIND => handle_bind(stream, addr, target, router).await,
+        CMD_UDP_ASSOCIATE => handle_udp_associate(stream, addr, router).await,
+        _ => {
+            send_reply(&mut stream, REP_GENERAL_FAILURE).await?;
+            Err(anyhow::anyhow!("Unsupported command: {}", cmd))
+        }
+    }
+}
+
+/// Handle a CONNECT request by routing it to the tunnel or a direct socket
+///
+/// Tunneled domain targets are passed through as hostnames and resolved by
+/// the exit node, so no DNS query leaves this machine.
+async fn handle_connect(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    target: TargetAddr,
+    router: Arc<Router>,
+) -> Result<()> {
+    let action = router.route_connection(&target, addr).await;
+    info!("Connection to {} routed {:?}", target, action);
+    match router.connect(&action, &target).await {
+        Ok(Outbound::Reject) => {
+            send_reply(&mut stream, REP_CONNECTION_NOT_ALLOWED).await?;
+        }
+        Ok(outbound) => {
+            send_reply(&mut stream, REP_SUCCESS).await?;
+            outbound.relay(stream, &[]).await?;
+        }
         Err(e) => {
-            error!("DNS resolution failed for {}: {}", target, e);
-            send_reply(&mut stream, REP_HOST_UNREACHABLE).await?;
+            error!("Failed to connect to {}: {}", target, e);
+            send_reply(&mut stream, failure_reply(&e)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Reply code telling why a connection could not be made
+fn failure_reply(error: &anyhow::Error) -> u8 {
+    use std::io::ErrorKind;
+
+    match error.downcast_ref::<std::io::Error>().map(|e| e.kind()) {
+        Some(ErrorKind::NetworkUnreachable) => REP_NETWORK_UNREACHABLE,
+        Some(ErrorKind::HostUnreachable | ErrorKind::TimedOut) => REP_HOST_UNREACHABLE,
+        Some(ErrorKind::ConnectionRefused) => REP_CONNECTION_REFUSED,
+        _ => REP_GENERAL_FAILURE,
+    }
+}
+
+/// Handle a BIND request
+///
+/// The exit listens for one inbound connection. As in RFC 1928, the first
+/// reply carries the listening address and the second the connected peer.
+/// Routing rules may reject the request; otherwise it uses the default tunnel.
+async fn handle_bind(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    target: TargetAddr,
+    router: Arc<Router>,
+) -> Result<()> {
+    if router.route_connection(&target, addr).await == Action::Reject {
+        send_reply(&mut stream, REP_CONNECTION_NOT_ALLOWED).await?;
+        return Ok(());
+    }
+
+    // DST.ADDR names the peer we expect to connect back
+    let mux_stream = match router.pool().open_bind_stream(&target).await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to open bind stream: {}", e);
+            send_reply(&mut stream, REP_GENERAL_FAILURE).await?;
             return Ok(());
         }
     };
 
-    // Connect to Daemon via WSS Tunnel
-    info!("Tunneling connection to {} via WSS", target);
-    match crate::wss::WssSession::connect(config).await {
-        Ok(session) => {
-            send_reply(&mut stream, REP_SUCCESS).await?;
+    let (mux_writer, mut mux_reader) = mux_stream.split();
 
-            let conn_id = session.conn_id; // Capture ID before split
-            let (wss_sender, mut wss_receiver) = session.split();
-            let (mut client_read, mut client_write) = stream.into_split();
+    // First reply: address the exit is listening on
+    let Some((bound_ip, bound_port)) = mux_reader.recv_open().await else {
+        send_reply(&mut stream, REP_GENERAL_FAILURE).await?;
+        return Ok(());
+    };
+    let bound = socket_addr(&bound_ip, bound_port);
+    send_reply_addr(&mut stream, REP_SUCCESS, bound).await?;
+    info!("BIND for {} listening on {}", target, bound);
 
-            // Prepare Target Info for ProxyFrame
-            let rip = match target_sock_addr.ip() {
-                std::net::IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
-                std::net::IpAddr::V6(ip) => ip.octets(),
-            };
-            let rport = target_sock_addr.port();
-
-            // Task: TCP -> WSS
-            let sender_task = tokio::spawn(async move {
-                let mut buf = [0u8; 8192];
-                loop {
-                    match client_read.read(&mut buf).await {
-                        Ok(0) => break, // EOF
-                        Ok(n) => {
-                            let frame = apfsds_protocol::ProxyFrame::new_data(
-                                conn_id,
-                                rip,
-                                rport,
-                                buf[..n].to_vec(),
-                            );
-                            if let Err(e) = wss_sender.send_frame(&frame).await {
-                                error!("WSS send failed: {}", e);
-                                break;
-                            }
-                        }
-                        Err(e) => {
-                            error!("TCP read failed: {}", e);
-                            break;
-                        }
+    // Second reply: the peer that connected
+    let Some((peer_ip, peer_port)) = mux_reader.recv_open().await else {
+        send_reply(&mut stream, REP_CONNECTION_REFUSED).await?;
+        return Ok(());
+    };
+    let peer = socket_addr(&peer_ip, peer_port);
+    send_reply_addr(&mut stream, REP_SUCCESS, peer).await?;
+    debug!("BIND for {} accepted {}", target, peer);
+
+    mux::relay(stream, mux_writer, mux_reader).await;
+    Ok(())
+}
+
+/// Handle a UDP ASSOCIATE request
+///
+/// Binds a relay socket next to the SOCKS5 listener and carries each datagram
+/// over a datagram stream. The association lives as long as the TCP control
+/// connection stays open. Datagrams to rejected targets are dropped and
+/// datagrams routed `direct` bypass the tunnel.
+async fn handle_udp_associate(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    router: Arc<Router>,
+) -> Result<()> {
+    // Relay socket on the interface the client reached us on
+    let relay = UdpSocket::bind(SocketAddr::new(stream.local_addr()?.ip(), 0)).await?;
+    let relay_addr = relay.local_addr()?;
+
+    let mux_stream = match router.pool().open_datagram_stream().await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to open datagram stream: {}", e);
+            send_reply(&mut stream, REP_GENERAL_FAILURE).await?;
+            return Ok(());
+        }
+    };
+
+    send_reply_addr(&mut stream, REP_SUCCESS, relay_addr).await?;
+    info!("UDP association for {} relaying on {}", addr, relay_addr);
+
+    let (mux_writer, mut mux_reader) = mux_stream.split();
+    let (direct_tx, mut direct_rx) = mpsc::channel(DIRECT_REPLY_QUEUE);
+    let mut direct = DirectUdp::new(direct_tx);
+
+    // Replies go to the last address the client sent from
+    let mut client_udp: Option<SocketAddr> = None;
+    let mut buf = vec![0u8; 65535];
+    let mut control = [0u8; 1];
+
+    loop {
+        tokio::select! {
+            // Control connection closed: the association ends
+            res = stream.read(&mut control) => {
+                if matches!(res, Ok(0) | Err(_)) {
+                    break;
+                }
+            }
+            res = relay.recv_from(&mut buf) => {
+                // Errors such as ICMP port unreachable only concern one datagram
+                let (len, src) = match res {
+                    Ok(v) => v,
+                    Err(e) => {
+                        debug!("UDP relay receive on {} failed: {}", relay_addr, e);
+                        continue;
                     }
+                };
+
+                // Only accept datagrams from the host that opened the association
+                if src.ip() != addr.ip() {
+                    trace!("Dropping datagram from foreign host {}", src);
+                    continue;
                 }
-            });
-
-            // Task: WSS -> TCP
-            while let Ok(Some(frame)) = wss_receiver.recv_frame().await {
-                if !frame.flags.is_control {
-                    if let Err(e) = client_write.write_all(&frame.payload).await {
-                        error!("TCP write failed: {}", e);
-                        break;
+                client_udp = Some(src);
+
+                let (target, offset) = match parse_udp_header(&buf[..len]) {
+                    Ok(v) => v,
+                    Err(e) => {
+                        debug!("Invalid SOCKS5 UDP datagram from {}: {}", src, e);
+                        continue;
+                    }
+                };
+                match router.route(&target) {
+                    Action::Reject => {
+                        trace!("Dropping datagram to rejected target {}", target);
+                        continue;
+                    }
+                    Action::Direct => {
+                        let payload = &buf[offset..len];
+                        if let Err(e) = direct.send(&router, &target, payload).await {
+                            debug!("Direct datagram to {} failed: {}", target, e);
+                        }
+                        continue;
                     }
+                    Action::Proxy(_) => {}
+                }
+
+                if let Err(e) = mux_writer.send_datagram(&target, &buf[offset..len]).await {
+                    error!("Datagram send failed: {}", e);
+                    break;
                 }
             }
+            frame = mux_reader.recv_frame() => {
+                let Some(frame) = frame else { break };
 
-            let _ = sender_task.await;
-        }
-        Err(e) => {
-            error!("Failed to connect to WSS Upstream: {}", e);
-            send_reply(&mut stream, REP_CONNECTION_REFUSED).await?;
+                if let Some(client) = client_udp {
+                    let from = socket_addr(&frame.rip, frame.rport);
+                    send_to_client(&relay, client, from, &frame.payload).await;
+                }
+            }
+            Some((from, payload)) = direct_rx.recv() => {
+                if let Some(client) = client_udp {
+                    send_to_client(&relay, client, from, &payload).await;
+                }
+            }
         }
     }
 
+    info!("UDP association for {} closed", addr);
     Ok(())
 }
 
+/// Wrap a reply from `from` in a SOCKS5 UDP header and send it to the client
+async fn send_to_client(relay: &UdpSocket, client: SocketAddr, from: SocketAddr, payload: &[u8]) {
+    let mut datagram = build_udp_header(from);
+    datagram.extend_from_slice(payload);
+    if let Err(e) = relay.send_to(&datagram, client).await {
+        debug!("UDP relay send to {} failed: {}", client, e);
+    }
+}
+
+/// Sockets for the datagrams of one association that are routed `direct`
+///
+/// One socket per address family, bound on first use. Background readers
+/// hand replies to the association loop and stop when it drops this.
+struct DirectUdp {
+    v4: Option<Arc<UdpSocket>>,
+    v6: Option<Arc<UdpSocket>>,
+    replies: mpsc::Sender<(SocketAddr, Vec<u8>)>,
+    readers: JoinSet<()>,
+}
+
+impl DirectUdp {
+    fn new(replies: mpsc::Sender<(SocketAddr, Vec<u8>)>) -> Self {
+        Self {
+            v4: None,
+            v6: None,
+            replies,
+            readers: JoinSet::new(),
+        }
+    }
+
+    /// Send `payload` to `target` without the tunnel
+    async fn send(&mut self, router: &Router, target: &TargetAddr, payload: &[u8]) -> Result<()> {
+        let addr = router
+            .resolve_direct(target)
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("No addresses for {}", target))?;
+
+        let slot = if addr.is_ipv4() {
+            &mut self.v4
+        } else {
+            &mut self.v6
+        };
+        let socket = match slot {
+            Some(socket) => socket.clone(),
+            None => {
+                let socket = Arc::new(router.bind_direct_udp(addr).await?);
+                self.readers
+                    .spawn(read_direct(socket.clone(), self.replies.clone()));
+                slot.insert(socket).clone()
+            }
+        };
+
+        socket.send_to(payload, addr).await?;
+        Ok(())
+    }
+}
+
+/// Forward replies arriving on a direct socket to the association
+async fn read_direct(socket: Arc<UdpSocket>, replies: mpsc::Sender<(SocketAddr, Vec<u8>)>) {
+    let mut buf = vec![0u8; 65535];
+    loop {
+        match socket.recv_from(&mut buf).await {
+            Ok((len, from)) => {
+                if replies.send((from, buf[..len].to_vec())).await.is_err() {
+                    break;
+                }
+            }
+            // Errors such as ICMP port unreachable only concern one datagram
+            Err(e) => debug!("Direct UDP receive failed: {}", e),
+        }
+    }
+}
+
+/// Convert ProxyFrame addressing back to a socket address
+fn socket_addr(rip: &[u8; 16], rport: u16) -> SocketAddr {
+    match ProxyFrame::mapped_to_ipv4(rip) {
+        Some(ipv4) => SocketAddr::from((ipv4, rport)),
+        None => SocketAddr::from((*rip, rport)),
+    }
+}
+
+/// Run the username/password sub-negotiation (RFC 1929)
+/// Returns the authenticated username
+async fn authenticate(stream: &mut TcpStream, socks5: &Socks5Config) -> Result<String> {
+    // VER ULEN UNAME PLEN PASSWD
+    let version = stream.read_u8().await?;
+    if version != USERPASS_VERSION {
+        return Err(anyhow::anyhow!("Invalid auth version: {}", version));
+    }
+
+    let ulen = stream.read_u8().await? as usize;
+    let mut username = vec![0u8; ulen];
+    stream.read_exact(&mut username).await?;
+
+    let plen = stream.read_u8().await? as usize;
+    let mut password = vec![0u8; plen];
+    stream.read_exact(&mut password).await?;
+
+    if !check_credentials(socks5, &username, &password) {
+        stream
+            .write_all(&[USERPASS_VERSION, USERPASS_FAILURE])
+            .await?;
+        return Err(anyhow::anyhow!("Authentication failed"));
+    }
+
+    stream
+        .write_all(&[USERPASS_VERSION, USERPASS_SUCCESS])
+        .await?;
+    Ok(String::from_utf8_lossy(&username).into_owned())
+}
+
+/// Check a username/password pair against the configured users
+fn check_credentials(socks5: &Socks5Config, username: &[u8], password: &[u8]) -> bool {
+    // Compare in constant time and check every user, so timing reveals
+    // neither how much of a credential matched nor which user did
+    let mut matched = Choice::from(0);
+    for user in &socks5.users {
+        matched |=
+            user.username.as_bytes().ct_eq(username) & user.password.as_bytes().ct_eq(password);
+    }
+    matched.into()
+}
+
+/// Parse the SOCKS5 UDP request header
+/// Returns the target and the offset of the payload
+fn parse_udp_header(buf: &[u8]) -> Result<(TargetAddr, usize)> {
+    // RSV(2) FRAG(1) ATYP(1) DST.ADDR DST.PORT DATA
+    if buf.len() < 4 {
+        return Err(anyhow::anyhow!("Datagram too short"));
+    }
+    if buf[2] != 0 {
+        return Err(anyhow::anyhow!("Fragmented datagrams are not supported"));
+    }
+
+    let (host, offset) = match buf[3] {
+        ATYP_IPV4 if buf.len() >= 10 => {
+            let ip = Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]);
+            (Ok(IpAddr::V4(ip)), 8)
+        }
+        ATYP_DOMAIN if buf.len() >= 5 => {
+            let len = buf[4] as usize;
+            if buf.len() < 5 + len + 2 {
+                return Err(anyhow::anyhow!("Datagram too short"));
+            }
+            (Err(String::from_utf8(buf[5..5 + len].to_vec())?), 5 + len)
+        }
+        ATYP_IPV6 if buf.len() >= 22 => {
+            let mut addr = [0u8; 16];
+            addr.copy_from_slice(&buf[4..20]);
+            (Ok(IpAddr::V6(Ipv6Addr::from(addr))), 20)
+        }
+        ATYP_IPV4 | ATYP_DOMAIN | ATYP_IPV6 => {
+            return Err(anyhow::anyhow!("Datagram too short"));
+        }
+        atyp => return Err(anyhow::anyhow!("Unknown address type: {}", atyp)),
+    };
+
+    let port = u16::from_be_bytes([buf[offset], buf[offset + 1]]);
+    let target = match host {
+        Ok(ip) => SocketAddr::new(ip, port).into(),
+        Err(domain) => TargetAddr::from_host(domain, port),
+    };
+    Ok((target, offset + 2))
+}
+
+/// Build the SOCKS5 UDP header for a reply from `from`
+fn build_udp_header(from: SocketAddr) -> Vec<u8> {
+    let mut header = vec![0x00, 0x00, 0x00];
+    match from.ip() {
+        IpAddr::V4(ipv4) => {
+            header.push(ATYP_IPV4);
+            header.extend_from_slice(&ipv4.octets());
+        }
+        IpAddr::V6(ipv6) => {
+            header.push(ATYP_IPV6);
+            header.extend_from_slice(&ipv6.octets());
+        }
+    }
+    header.extend_from_slice(&from.port().to_be_bytes());
+    header
+}
+
 /// Parse target address from SOCKS5 request
-async fn parse_target(stream: &mut TcpStream, atyp: u8) -> Result<String> {
+async fn parse_target(stream: &mut TcpStream, atyp: u8) -> Result<TargetAddr> {
     match atyp {
         ATYP_IPV4 => {
             let mut addr = [0u8; 4];
             stream.read_exact(&mut addr).await?;
             let port = stream.read_u16().await?;
-            Ok(format!(
-                "{}.{}.{}.{}:{}",
-                addr[0], addr[1], addr[2], addr[3], port
-            ))
+            Ok(SocketAddr::from((addr, port)).into())
         }
         ATYP_DOMAIN => {
             let len = stream.read_u8().await? as usize;
@@ -191,14 +536,13 @@ async fn parse_target(stream: &mut TcpStream, atyp: u8) -> Result<String> {
             stream.read_exact(&mut domain).await?;
             let port = stream.read_u16().await?;
             let domain_str = String::from_utf8(domain)?;
-            Ok(format!("{}:{}", domain_str, port))
+            Ok(TargetAddr::from_host(domain_str, port))
         }
         ATYP_IPV6 => {
             let mut addr = [0u8; 16];
             stream.read_exact(&mut addr).await?;
             let port = stream.read_u16().await?;
-            let ipv6 = std::net::Ipv6Addr::from(addr);
-            Ok(format!("[{}]:{}", ipv6, port))
+            Ok(TargetAddr::Ip(addr, port))
         }
         _ => Err(anyhow::anyhow!("Unknown address type: {}", atyp)),
     }
@@ -206,20 +550,147 @@ async fn parse_target(stream: &mut TcpStream, atyp: u8) -> Result<String> {
 
 /// Send SOCKS5 reply
 async fn send_reply(stream: &mut TcpStream, rep: u8) -> Result<()> {
-    // Reply: VER REP RSV ATYP BND.ADDR BND.PORT
     // We send 0.0.0.0:0 as bound address
-    let reply = [
-        SOCKS5_VERSION,
-        rep,
-        0x00, // RSV
-        ATYP_IPV4,
-        0,
-        0,
-        0,
-        0, // BND.ADDR
-        0,
-        0, // BND.PORT
-    ];
+    send_reply_addr(stream, rep, SocketAddr::from(([0, 0, 0, 0], 0))).await
+}
+
+/// Send SOCKS5 reply with an explicit bound address
+async fn send_reply_addr(stream: &mut TcpStream, rep: u8, bound: SocketAddr) -> Result<()> {
+    // Reply: VER REP RSV ATYP BND.ADDR BND.PORT
+    let mut reply = vec![SOCKS5_VERSION, rep, 0x00];
+    match bound.ip() {
+        IpAddr::V4(ip) => {
+            reply.push(ATYP_IPV4);
+            reply.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            reply.push(ATYP_IPV6);
+            reply.extend_from_slice(&ip.octets());
+        }
+    }
+    reply.extend_from_slice(&bound.port().to_be_bytes());
     stream.write_all(&reply).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_credentials() {
+        let socks5 = Socks5Config {
+            auth: true,
+            users: vec![
+                crate::config::Socks5User {
+                    username: "alice".to_string(),
+                    password: "secret".to_string(),
+                },
+                crate::config::Socks5User {
+                    username: "carol".to_string(),
+                    password: "hunter2".to_string(),
+                },
+            ],
+            ..Default::default()
+        };
+
+        assert!(check_credentials(&socks5, b"alice", b"secret"));
+        assert!(check_credentials(&socks5, b"carol", b"hunter2"));
+        assert!(!check_credentials(&socks5, b"alice", b"wrong"));
+        assert!(!check_credentials(&socks5, b"alice", b"secre"));
+        assert!(!check_credentials(&socks5, b"bob", b"secret"));
+        // Username and password must belong to the same user
+        assert!(!check_credentials(&socks5, b"alice", b"hunter2"));
+    }
+
+    #[test]
+    fn test_failure_reply() {
+        use std::io::{Error, ErrorKind};
+
+        let reply = |kind| failure_reply(&Error::from(kind).into());
+        assert_eq!(
+            reply(ErrorKind::NetworkUnreachable),
+            REP_NETWORK_UNREACHABLE
+        );
+        assert_eq!(reply(ErrorKind::HostUnreachable), REP_HOST_UNREACHABLE);
+        assert_eq!(reply(ErrorKind::TimedOut), REP_HOST_UNREACHABLE);
+        assert_eq!(reply(ErrorKind::ConnectionRefused), REP_CONNECTION_REFUSED);
+        assert_eq!(
+            failure_reply(&anyhow::anyhow!("No session")),
+            REP_GENERAL_FAILURE
+        );
+    }
+
+    #[test]
+    fn test_socket_addr_roundtrip() {
+        for addr in ["192.0.2.1:21", "[2001:db8::1]:21"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let TargetAddr::Ip(rip, rport) = TargetAddr::from(addr) else {
+                panic!("expected IP target");
+            };
+            assert_eq!(socket_addr(&rip, rport), addr);
+        }
+    }
+
+    #[test]
+    fn test_parse_udp_header_ipv4() {
+        let mut datagram = vec![0, 0, 0, ATYP_IPV4, 8, 8, 8, 8, 0, 53];
+        datagram.extend_from_slice(b"query");
+
+        let (target, offset) = parse_udp_header(&datagram).unwrap();
+        assert_eq!(target.to_string(), "8.8.8.8:53");
+        assert_eq!(&datagram[offset..], b"query");
+    }
+
+    #[test]
+    fn test_parse_udp_header_domain() {
+        let mut datagram = vec![0, 0, 0, ATYP_DOMAIN, 11];
+        datagram.extend_from_slice(b"example.com");
+        datagram.extend_from_slice(&443u16.to_be_bytes());
+        datagram.extend_from_slice(b"hello");
+
+        let (target, offset) = parse_udp_header(&datagram).unwrap();
+        assert_eq!(target, TargetAddr::Domain("example.com".to_string(), 443));
+        assert_eq!(&datagram[offset..], b"hello");
+    }
+
+    #[test]
+    fn test_parse_udp_header_rejects_fragments() {
+        let datagram = [0, 0, 1, ATYP_IPV4, 1, 1, 1, 1, 0, 53];
+        assert!(parse_udp_header(&datagram).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_direct_datagram_roundtrip() {
+        let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (len, from) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..len], from).await.unwrap();
+        });
+
+        let pool = crate::mux::MuxPool::new(&ClientConfig::default());
+        let router = Router::tunnel_only(pool);
+        let (tx, mut rx) = mpsc::channel(DIRECT_REPLY_QUEUE);
+        let mut direct = DirectUdp::new(tx);
+
+        direct
+            .send(&router, &echo_addr.into(), b"ping")
+            .await
+            .unwrap();
+        let (from, payload) = rx.recv().await.unwrap();
+        assert_eq!(from, echo_addr);
+        assert_eq!(payload, b"ping");
+    }
+
+    #[test]
+    fn test_udp_header_roundtrip() {
+        let rip = ProxyFrame::ipv4_to_mapped([1, 2, 3, 4]);
+        let header = build_udp_header(socket_addr(&rip, 5353));
+
+        let (target, offset) = parse_udp_header(&header).unwrap();
+        assert_eq!(target.to_string(), "1.2.3.4:5353");
+        assert_eq!(offset, header.len());
+    }
+}
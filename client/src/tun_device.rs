@@ -66,6 +66,34 @@ mod platform {
             use std::io::Write;
             Ok(self.device.write(buf)?)
         }
+
+        /// Split into halves that can be used from separate threads
+        pub fn split(self) -> (TunReader, TunWriter) {
+            let (reader, writer) = self.device.split();
+            (TunReader { reader }, TunWriter { writer })
+        }
+    }
+
+    pub struct TunReader {
+        reader: tun::Reader,
+    }
+
+    impl TunReader {
+        pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            use std::io::Read;
+            Ok(self.reader.read(buf)?)
+        }
+    }
+
+    pub struct TunWriter {
+        writer: tun::Writer,
+    }
+
+    impl TunWriter {
+        pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            use std::io::Write;
+            Ok(self.writer.write(buf)?)
+        }
     }
 }
 
@@ -108,26 +136,68 @@ mod platform {
         }
 
         pub fn read(&self, buf: &mut [u8]) -> Result<usize> {
-            // Use blocking receive (requires Arc<Session>)
-            match self.session.receive_blocking() {
-                Ok(packet) => {
-                    let bytes = packet.bytes();
-                    let len = bytes.len().min(buf.len());
-                    buf[..len].copy_from_slice(&bytes[..len]);
-                    Ok(len)
-                }
-                Err(e) => Err(anyhow::anyhow!("Wintun read error: {}", e)),
-            }
+            read_packet(&self.session, buf)
         }
 
         pub fn write(&self, buf: &[u8]) -> Result<usize> {
-            // allocate_send_packet requires Arc<Session>
-            let mut packet = self.session.allocate_send_packet(buf.len() as u16)?;
-            packet.bytes_mut().copy_from_slice(buf);
-            self.session.send_packet(packet);
-            Ok(buf.len())
+            write_packet(&self.session, buf)
+        }
+
+        /// Split into halves that can be used from separate threads
+        pub fn split(self) -> (TunReader, TunWriter) {
+            (
+                TunReader {
+                    session: self.session.clone(),
+                },
+                TunWriter {
+                    _adapter: self._adapter,
+                    session: self.session,
+                },
+            )
+        }
+    }
+
+    pub struct TunReader {
+        session: Arc<Session>,
+    }
+
+    impl TunReader {
+        pub fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            read_packet(&self.session, buf)
         }
     }
+
+    pub struct TunWriter {
+        _adapter: Arc<Adapter>,
+        session: Arc<Session>,
+    }
+
+    impl TunWriter {
+        pub fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            write_packet(&self.session, buf)
+        }
+    }
+
+    fn read_packet(session: &Arc<Session>, buf: &mut [u8]) -> Result<usize> {
+        // Use blocking receive (requires Arc<Session>)
+        match session.receive_blocking() {
+            Ok(packet) => {
+                let bytes = packet.bytes();
+                let len = bytes.len().min(buf.len());
+                buf[..len].copy_from_slice(&bytes[..len]);
+                Ok(len)
+            }
+            Err(e) => Err(anyhow::anyhow!("Wintun read error: {}", e)),
+        }
+    }
+
+    fn write_packet(session: &Arc<Session>, buf: &[u8]) -> Result<usize> {
+        // allocate_send_packet requires Arc<Session>
+        let mut packet = session.allocate_send_packet(buf.len() as u16)?;
+        packet.bytes_mut().copy_from_slice(buf);
+        session.send_packet(packet);
+        Ok(buf.len())
+    }
 }
 
 // ==================== Stub for other platforms ====================
@@ -139,20 +209,42 @@ mod platform {
 
     impl TunDevice {
         pub fn create(_config: &TunConfig) -> Result<Self> {
-            Err(anyhow::anyhow!(
-                "TUN devices not supported on this platform"
-            ))
+            Err(unsupported())
         }
 
         pub fn read(&self, _buf: &mut [u8]) -> Result<usize> {
-            unimplemented!()
+            Err(unsupported())
         }
 
         pub fn write(&self, _buf: &[u8]) -> Result<usize> {
-            unimplemented!()
+            Err(unsupported())
+        }
+
+        pub fn split(self) -> (TunReader, TunWriter) {
+            (TunReader, TunWriter)
+        }
+    }
+
+    pub struct TunReader;
+
+    impl TunReader {
+        pub fn read(&mut self, _buf: &mut [u8]) -> Result<usize> {
+            Err(unsupported())
         }
     }
+
+    pub struct TunWriter;
+
+    impl TunWriter {
+        pub fn write(&mut self, _buf: &[u8]) -> Result<usize> {
+            Err(unsupported())
+        }
+    }
+
+    fn unsupported() -> anyhow::Error {
+        anyhow::anyhow!("TUN devices not supported on this platform")
+    }
 }
 
 // Re-export platform-specific implementation
-pub use platform::TunDevice;
+pub use platform::{TunDevice, TunReader, TunWriter};
@@ -12,13 +12,34 @@ use futures::{SinkExt, StreamExt};
 use std::sync::Arc;
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
-use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async, tungstenite::Message};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
 use tracing::{debug, error, info};
 
 type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
 type WsTx = SplitSink<WsStream, Message>;
 type WsRx = SplitStream<WsStream>;
 
+/// Turn a refused upgrade into a readable error
+fn refusal(e: tungstenite::Error) -> anyhow::Error {
+    let tungstenite::Error::Http(response) = &e else {
+        return e.into();
+    };
+    match response.status().as_u16() {
+        429 => {
+            let limit = response
+                .headers()
+                .get("X-Session-Limit")
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or("session");
+            anyhow!("Upstream refused the session: {} limit reached", limit)
+        }
+        402 => anyhow!("Upstream refused the session: quota exceeded"),
+        403 => anyhow!("Upstream refused the session: account disabled"),
+        _ => e.into(),
+    }
+}
+
 /// Encapsulated WSS Session
 pub struct WssSession {
     tx: Arc<Mutex<WsTx>>,
@@ -44,7 +65,7 @@ impl WssSession {
         };
 
         info!("Connecting to WSS upstream: {}", url);
-        let (ws_stream, _) = connect_async(&url).await?;
+        let (ws_stream, _) = connect_async(&url).await.map_err(refusal)?;
 
         let (tx, mut rx) = ws_stream.split();
 
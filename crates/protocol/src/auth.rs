@@ -60,6 +60,9 @@ pub struct TokenPayload {
     /// Nonce from auth request
     pub nonce: [u8; 32],
 
+    /// Client public key from auth request, identifying the device
+    pub client_pk: [u8; 32],
+
     /// Issue timestamp
     pub issued_at: u64,
 
@@ -123,3 +126,15 @@ pub struct StreamState {
     /// Is stream closed
     pub is_closed: bool,
 }
+
+impl StreamState {
+    /// Create state for a freshly opened stream
+    pub fn new(stream_id: u32) -> Self {
+        Self {
+            stream_id,
+            bytes_sent: 0,
+            bytes_received: 0,
+            is_closed: false,
+        }
+    }
+}
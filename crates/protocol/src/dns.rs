@@ -0,0 +1,442 @@
+//! Minimal DNS wire format helpers
+//!
+//! Just enough of RFC 1035 to read the question of a message, synthesize
+//! simple answers and cache responses by TTL. Full resolution is left to
+//! upstream resolvers.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+use thiserror::Error;
+
+/// Size of the fixed DNS header
+pub const HEADER_LEN: usize = 12;
+
+/// Record type A
+pub const TYPE_A: u16 = 1;
+/// Record type AAAA
+pub const TYPE_AAAA: u16 = 28;
+/// Record type OPT (EDNS pseudo-record, has no TTL)
+pub const TYPE_OPT: u16 = 41;
+/// Class IN
+pub const CLASS_IN: u16 = 1;
+
+/// Response code: server failure
+pub const RCODE_SERVFAIL: u8 = 2;
+/// Response code: name does not exist
+pub const RCODE_NXDOMAIN: u8 = 3;
+/// Response code: query refused
+pub const RCODE_REFUSED: u8 = 5;
+
+/// Maximum number of compression pointers followed while reading a name
+const MAX_POINTERS: usize = 16;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum DnsError {
+    #[error("Message truncated")]
+    Truncated,
+
+    #[error("Message is not a query")]
+    NotQuery,
+
+    #[error("Expected exactly one question, got {0}")]
+    QuestionCount(u16),
+
+    #[error("Malformed name")]
+    BadName,
+}
+
+/// The question section of a single-question message
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Question {
+    /// Lower-cased name without the trailing dot
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+/// Transaction ID of a message
+pub fn message_id(msg: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes([*msg.first()?, *msg.get(1)?]))
+}
+
+/// Overwrite the transaction ID of a message
+pub fn set_message_id(msg: &mut [u8], id: u16) {
+    if msg.len() >= 2 {
+        msg[..2].copy_from_slice(&id.to_be_bytes());
+    }
+}
+
+/// Response code of a message
+pub fn rcode(msg: &[u8]) -> Option<u8> {
+    msg.get(3).map(|b| b & 0x0f)
+}
+
+/// Parse the question of a standard query
+/// Returns the question and the offset just past it
+pub fn parse_query(msg: &[u8]) -> Result<(Question, usize), DnsError> {
+    if msg.len() < HEADER_LEN {
+        return Err(DnsError::Truncated);
+    }
+    // QR must be clear and OPCODE must be QUERY
+    if msg[2] & 0xf8 != 0 {
+        return Err(DnsError::NotQuery);
+    }
+    parse_question(msg)
+}
+
+/// Parse the single question of a query or response
+/// Returns the question and the offset just past it
+pub fn parse_question(msg: &[u8]) -> Result<(Question, usize), DnsError> {
+    if msg.len() < HEADER_LEN {
+        return Err(DnsError::Truncated);
+    }
+    let qdcount = u16::from_be_bytes([msg[4], msg[5]]);
+    if qdcount != 1 {
+        return Err(DnsError::QuestionCount(qdcount));
+    }
+
+    let (name, offset) = read_name(msg, HEADER_LEN)?;
+    let fixed = msg.get(offset..offset + 4).ok_or(DnsError::Truncated)?;
+    let question = Question {
+        name,
+        qtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+        qclass: u16::from_be_bytes([fixed[2], fixed[3]]),
+    };
+    Ok((question, offset + 4))
+}
+
+/// Build a recursive query for `name` (labels of up to 63 bytes) of type `qtype`
+pub fn build_query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(HEADER_LEN + name.len() + 6);
+    msg.extend_from_slice(&id.to_be_bytes());
+    msg.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        msg.push(label.len() as u8);
+        msg.extend_from_slice(label.as_bytes());
+    }
+    msg.push(0);
+    msg.extend_from_slice(&qtype.to_be_bytes());
+    msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+    msg
+}
+
+/// Build a response to `query` carrying `addrs` as answers
+///
+/// Addresses are written as A or AAAA records depending on their family; an
+/// empty list yields a NOERROR response without answers.
+pub fn build_response(query: &[u8], addrs: &[IpAddr], ttl: u32) -> Result<Vec<u8>, DnsError> {
+    let (_, question_end) = parse_query(query)?;
+
+    let mut msg = Vec::with_capacity(question_end + addrs.len() * 28);
+    msg.extend_from_slice(&query[..question_end]);
+    // QR, keep OPCODE and RD; RA, RCODE 0
+    msg[2] = 0x80 | (query[2] & 0x79);
+    msg[3] = 0x80;
+    msg[6..8].copy_from_slice(&(addrs.len() as u16).to_be_bytes());
+    // No authority or additional records (drops any EDNS OPT)
+    msg[8..12].fill(0);
+
+    for addr in addrs {
+        // Name: pointer to the question name
+        msg.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        let (rtype, rdata) = match addr {
+            IpAddr::V4(v4) => (TYPE_A, v4.octets().to_vec()),
+            IpAddr::V6(v6) => (TYPE_AAAA, v6.octets().to_vec()),
+        };
+        msg.extend_from_slice(&rtype.to_be_bytes());
+        msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+        msg.extend_from_slice(&ttl.to_be_bytes());
+        msg.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        msg.extend_from_slice(&rdata);
+    }
+
+    Ok(msg)
+}
+
+/// Build a response to `query` with no records and the given response code
+pub fn build_error(query: &[u8], rcode: u8) -> Result<Vec<u8>, DnsError> {
+    let mut msg = build_response(query, &[], 0)?;
+    msg[3] |= rcode & 0x0f;
+    Ok(msg)
+}
+
+/// Offsets of the TTL fields of all resource records in a message
+/// The EDNS OPT pseudo-record is skipped since its TTL field holds flags.
+pub fn ttl_offsets(msg: &[u8]) -> Result<Vec<usize>, DnsError> {
+    let (_, mut offset) = parse_question(msg)?;
+    let count = |i: usize| u16::from_be_bytes([msg[i], msg[i + 1]]) as usize;
+    let records = count(6) + count(8) + count(10);
+
+    let mut offsets = Vec::with_capacity(records);
+    for _ in 0..records {
+        let (_, end) = read_name(msg, offset)?;
+        let fixed = msg.get(end..end + 10).ok_or(DnsError::Truncated)?;
+        let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let rdlength = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        if rtype != TYPE_OPT {
+            offsets.push(end + 4);
+        }
+        offset = end + 10 + rdlength;
+        if offset > msg.len() {
+            return Err(DnsError::Truncated);
+        }
+    }
+    Ok(offsets)
+}
+
+/// Smallest TTL among the records of a message, None if it has no records
+pub fn min_ttl(msg: &[u8]) -> Option<u32> {
+    ttl_offsets(msg)
+        .ok()?
+        .into_iter()
+        .map(|i| read_ttl(msg, i))
+        .min()
+}
+
+/// Decrease every record TTL by `elapsed` seconds, saturating at zero
+pub fn age_ttls(msg: &mut [u8], elapsed: u32) -> Result<(), DnsError> {
+    for i in ttl_offsets(msg)? {
+        let ttl = read_ttl(msg, i).saturating_sub(elapsed);
+        msg[i..i + 4].copy_from_slice(&ttl.to_be_bytes());
+    }
+    Ok(())
+}
+
+fn read_ttl(msg: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        msg[offset],
+        msg[offset + 1],
+        msg[offset + 2],
+        msg[offset + 3],
+    ])
+}
+
+struct CachedAnswer {
+    response: Vec<u8>,
+    stored: Instant,
+    ttl: u32,
+}
+
+/// Responses keyed by question, kept for the smallest TTL they carry
+pub struct AnswerCache {
+    entries: HashMap<Question, CachedAnswer>,
+    capacity: usize,
+    max_ttl: u32,
+}
+
+impl AnswerCache {
+    /// Create a cache holding at most `capacity` answers for up to `max_ttl`
+    /// seconds each
+    pub fn new(capacity: usize, max_ttl: u32) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+            max_ttl,
+        }
+    }
+
+    /// Cached answer with `id` and TTLs reduced by the time spent in cache
+    pub fn get(&mut self, question: &Question, id: u16) -> Option<Vec<u8>> {
+        let entry = self.entries.get(question)?;
+        let elapsed = entry.stored.elapsed().as_secs() as u32;
+        if elapsed >= entry.ttl {
+            self.entries.remove(question);
+            return None;
+        }
+
+        let mut response = entry.response.clone();
+        age_ttls(&mut response, elapsed).ok()?;
+        set_message_id(&mut response, id);
+        Some(response)
+    }
+
+    /// Cache successful and NXDOMAIN answers that carry a TTL
+    pub fn insert(&mut self, question: Question, response: &[u8]) {
+        if !matches!(rcode(response), Some(0 | RCODE_NXDOMAIN)) {
+            return;
+        }
+        // Negative answers take their TTL from the authority SOA record
+        let Some(ttl) = min_ttl(response).map(|t| t.min(self.max_ttl)) else {
+            return;
+        };
+        if ttl == 0 {
+            return;
+        }
+
+        if self.entries.len() >= self.capacity {
+            self.entries
+                .retain(|_, e| e.stored.elapsed().as_secs() < e.ttl as u64);
+            if self.entries.len() >= self.capacity {
+                return;
+            }
+        }
+
+        self.entries.insert(
+            question,
+            CachedAnswer {
+                response: response.to_vec(),
+                stored: Instant::now(),
+                ttl,
+            },
+        );
+    }
+
+    /// Number of cached answers, including expired ones not yet dropped
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Read a possibly compressed name starting at `offset`
+/// Returns the name and the offset just past it in the original position
+fn read_name(msg: &[u8], mut offset: usize) -> Result<(String, usize), DnsError> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    let mut pointers = 0;
+
+    loop {
+        let len = *msg.get(offset).ok_or(DnsError::Truncated)? as usize;
+        match len & 0xc0 {
+            0x00 if len == 0 => {
+                end.get_or_insert(offset + 1);
+                break;
+            }
+            0x00 => {
+                let label = msg
+                    .get(offset + 1..offset + 1 + len)
+                    .ok_or(DnsError::Truncated)?;
+                labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+                offset += 1 + len;
+            }
+            0xc0 => {
+                let low = *msg.get(offset + 1).ok_or(DnsError::Truncated)? as usize;
+                end.get_or_insert(offset + 2);
+                pointers += 1;
+                if pointers > MAX_POINTERS {
+                    return Err(DnsError::BadName);
+                }
+                offset = ((len & 0x3f) << 8) | low;
+            }
+            _ => return Err(DnsError::BadName),
+        }
+    }
+
+    let name = labels.join(".");
+    if name.len() > 253 {
+        return Err(DnsError::BadName);
+    }
+    Ok((name, end.unwrap_or(offset)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_parse_query() {
+        let msg = build_query(0x1234, "Example.COM", TYPE_A);
+        let (question, end) = parse_query(&msg).unwrap();
+        assert_eq!(question.name, "example.com");
+        assert_eq!(question.qtype, TYPE_A);
+        assert_eq!(question.qclass, CLASS_IN);
+        assert_eq!(end, msg.len());
+        assert_eq!(message_id(&msg), Some(0x1234));
+
+        assert_eq!(parse_query(&msg[..10]), Err(DnsError::Truncated));
+        assert_eq!(parse_query(&msg[..msg.len() - 2]), Err(DnsError::Truncated));
+    }
+
+    #[test]
+    fn test_pointer_loop_rejected() {
+        let mut msg = build_query(1, "a", TYPE_A);
+        // Replace the name with a pointer to itself
+        msg.truncate(HEADER_LEN);
+        msg.extend_from_slice(&[0xc0, HEADER_LEN as u8, 0, 1, 0, 1]);
+        assert_eq!(parse_query(&msg), Err(DnsError::BadName));
+    }
+
+    #[test]
+    fn test_build_response() {
+        let msg = build_query(7, "example.com", TYPE_A);
+        let addr = IpAddr::V4(Ipv4Addr::new(198, 18, 0, 1));
+        let response = build_response(&msg, &[addr], 60).unwrap();
+
+        assert_eq!(message_id(&response), Some(7));
+        assert_eq!(response[2] & 0x80, 0x80);
+        assert_eq!(u16::from_be_bytes([response[6], response[7]]), 1);
+        assert_eq!(&response[response.len() - 4..], &[198, 18, 0, 1]);
+        assert_eq!(
+            &response[response.len() - 10..response.len() - 6],
+            &60u32.to_be_bytes()
+        );
+
+        // A response is not a query
+        assert_eq!(parse_query(&response), Err(DnsError::NotQuery));
+        assert_eq!(parse_question(&response).unwrap().0.name, "example.com");
+    }
+
+    #[test]
+    fn test_ttls() {
+        let msg = build_query(7, "example.com", TYPE_A);
+        let addrs = [
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)),
+        ];
+        let mut response = build_response(&msg, &addrs, 300).unwrap();
+        assert_eq!(ttl_offsets(&response).unwrap().len(), 2);
+        assert_eq!(min_ttl(&response), Some(300));
+
+        age_ttls(&mut response, 120).unwrap();
+        assert_eq!(min_ttl(&response), Some(180));
+        age_ttls(&mut response, 1000).unwrap();
+        assert_eq!(min_ttl(&response), Some(0));
+
+        let error = build_error(&msg, RCODE_SERVFAIL).unwrap();
+        assert_eq!(rcode(&error), Some(RCODE_SERVFAIL));
+        assert_eq!(min_ttl(&error), None);
+
+        // Record count larger than the message
+        response[7] = 3;
+        assert_eq!(ttl_offsets(&response), Err(DnsError::Truncated));
+    }
+
+    #[test]
+    fn test_answer_cache() {
+        let mut cache = AnswerCache::new(1, 3600);
+        let msg = build_query(1, "a.test", TYPE_A);
+        let question = parse_query(&msg).unwrap().0;
+        let addr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+        // Zero TTLs and server failures are not cached
+        cache.insert(question.clone(), &build_response(&msg, &[addr], 0).unwrap());
+        cache.insert(
+            question.clone(),
+            &build_error(&msg, RCODE_SERVFAIL).unwrap(),
+        );
+        assert!(cache.get(&question, 1).is_none());
+
+        cache.insert(
+            question.clone(),
+            &build_response(&msg, &[addr], 30).unwrap(),
+        );
+        let cached = cache.get(&question, 9).unwrap();
+        assert_eq!(message_id(&cached), Some(9));
+        assert_eq!(min_ttl(&cached), Some(30));
+
+        // Full: unexpired entries are kept and new ones dropped
+        let other = build_query(2, "b.test", TYPE_A);
+        let other_question = parse_query(&other).unwrap().0;
+        cache.insert(
+            other_question.clone(),
+            &build_response(&other, &[addr], 30).unwrap(),
+        );
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get(&other_question, 2).is_none());
+    }
+}
@@ -13,12 +13,18 @@ pub struct ProxyFrame {
     /// Connection ID - unique per logical connection
     pub conn_id: u64,
 
+    /// Stream ID - multiplexed stream within the connection (0 = connection-level)
+    pub stream_id: u32,
+
     /// Remote IP address (16 bytes for IPv6, IPv4 mapped to ::ffff:x.x.x.x)
     pub rip: [u8; 16],
 
     /// Remote port
     pub rport: u16,
 
+    /// Remote hostname, resolved by the exit node instead of `rip`
+    pub rhost: Option<String>,
+
     /// Payload data
     pub payload: Vec<u8>,
 
@@ -53,6 +59,20 @@ pub struct FrameFlags {
 
     /// This frame is an acknowledgment
     pub is_ack: bool,
+
+    /// This frame opens a new stream (rip/rport carry the target)
+    pub is_open: bool,
+
+    /// Sender will send no more data on this stream (half-close)
+    pub is_half_close: bool,
+
+    /// Payload is a single datagram (UDP) addressed to rip/rport
+    pub is_datagram: bool,
+
+    /// With `is_open`: listen for an inbound connection instead of connecting
+    /// (SOCKS5 BIND). The exit answers with `is_open` frames carrying the
+    /// bound address and then the accepted peer.
+    pub is_bind: bool,
 }
 
 impl ProxyFrame {
@@ -67,8 +87,10 @@ impl ProxyFrame {
 
         Self {
             conn_id,
+            stream_id: 0,
             rip,
             rport,
+            rhost: None,
             payload,
             uuid,
             timestamp,
@@ -91,6 +113,53 @@ impl ProxyFrame {
         frame
     }
 
+    /// Create a stream open frame
+    pub fn new_open(conn_id: u64, stream_id: u32, rip: [u8; 16], rport: u16) -> Self {
+        let mut frame = Self::new_data(conn_id, rip, rport, vec![]).with_stream(stream_id);
+        frame.flags.is_open = true;
+        frame
+    }
+
+    /// Create a stream open frame that binds a listener on the exit
+    /// (`rip`/`rport` name the expected peer, or zero for any)
+    pub fn new_bind(conn_id: u64, stream_id: u32, rip: [u8; 16], rport: u16) -> Self {
+        let mut frame = Self::new_open(conn_id, stream_id, rip, rport);
+        frame.flags.is_bind = true;
+        frame
+    }
+
+    /// Create a stream half-close frame (no more data from the sender)
+    pub fn new_half_close(conn_id: u64, stream_id: u32) -> Self {
+        let mut frame = Self::new_data(conn_id, [0; 16], 0, vec![]).with_stream(stream_id);
+        frame.flags.is_half_close = true;
+        frame
+    }
+
+    /// Create a datagram frame for a UDP association stream
+    pub fn new_datagram(
+        conn_id: u64,
+        stream_id: u32,
+        rip: [u8; 16],
+        rport: u16,
+        payload: Vec<u8>,
+    ) -> Self {
+        let mut frame = Self::new_data(conn_id, rip, rport, payload).with_stream(stream_id);
+        frame.flags.is_datagram = true;
+        frame
+    }
+
+    /// Set the stream ID of this frame
+    pub fn with_stream(mut self, stream_id: u32) -> Self {
+        self.stream_id = stream_id;
+        self
+    }
+
+    /// Address this frame to a hostname for the exit node to resolve
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.rhost = Some(host.into());
+        self
+    }
+
     /// Verify the checksum
     pub fn verify_checksum(&self) -> bool {
         crc32fast::hash(&self.payload) == self.checksum
@@ -165,6 +234,40 @@ pub enum ControlMessage {
 
     /// Group selection (exit-node -> handler)
     GroupSelect { group_id: i32 },
+
+    /// Flow control credit for a multiplexed stream
+    WindowUpdate { stream_id: u32, increment: u32 },
+
+    /// The user ran out of quota or balance (handler -> client)
+    /// With `throttled`, the session stays open at a reduced rate.
+    QuotaExceeded { kind: QuotaKind, throttled: bool },
+
+    /// An exit refused traffic of this connection as abuse (exit-node -> handler)
+    AbuseReport { kind: AbuseKind },
+}
+
+/// Why an exit refused a connection's traffic
+#[derive(Archive, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[rkyv(compare(PartialEq), derive(Debug))]
+pub enum AbuseKind {
+    /// Outbound SMTP (port 25)
+    Smtp,
+    /// New connections above the per-connection cap
+    ConnectionRate,
+    /// BitTorrent handshake, DHT or tracker traffic
+    BitTorrent,
+}
+
+/// Which limit a user ran into
+#[derive(Archive, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[rkyv(compare(PartialEq), derive(Debug))]
+pub enum QuotaKind {
+    /// Daily traffic quota of the user's plan
+    Daily,
+    /// Monthly traffic quota (plan or per-user)
+    Monthly,
+    /// Account balance used up
+    Balance,
 }
 
 /// Emergency level
@@ -191,15 +294,24 @@ pub struct PlainPacket {
     /// Connection ID
     pub conn_id: u64,
 
+    /// Stream ID within the connection
+    pub stream_id: u32,
+
     /// Handler node ID (for response routing)
     pub handler_id: u64,
 
+    /// User the connection belongs to (0 in responses)
+    pub user_id: u64,
+
     /// Remote IP address (16 bytes)
     pub rip: [u8; 16],
 
     /// Remote port
     pub rport: u16,
 
+    /// Remote hostname to resolve on the exit node (overrides `rip`)
+    pub rhost: Option<String>,
+
     /// Payload data
     pub payload: Vec<u8>,
 
@@ -208,6 +320,9 @@ pub struct PlainPacket {
 
     /// Is this a response (from exit to handler)?
     pub is_response: bool,
+
+    /// Stream control flags carried over from the originating frame
+    pub flags: FrameFlags,
 }
 
 impl PlainPacket {
@@ -219,12 +334,16 @@ impl PlainPacket {
         Self {
             magic: Self::MAGIC,
             conn_id: frame.conn_id,
+            stream_id: frame.stream_id,
             handler_id,
+            user_id: 0,
             rip: frame.rip,
             rport: frame.rport,
+            rhost: frame.rhost.clone(),
             payload: frame.payload.clone(),
             checksum: frame.checksum,
             is_response: false,
+            flags: frame.flags,
         }
     }
 
@@ -234,15 +353,31 @@ impl PlainPacket {
         Self {
             magic: Self::MAGIC,
             conn_id,
+            stream_id: 0,
             handler_id,
+            user_id: 0,
             rip: [0; 16],
             rport: 0,
+            rhost: None,
             payload,
             checksum,
             is_response: true,
+            flags: FrameFlags::default(),
         }
     }
 
+    /// Set the stream ID of this packet
+    pub fn with_stream(mut self, stream_id: u32) -> Self {
+        self.stream_id = stream_id;
+        self
+    }
+
+    /// Set the user this packet's connection belongs to
+    pub fn with_user(mut self, user_id: u64) -> Self {
+        self.user_id = user_id;
+        self
+    }
+
     /// Verify magic number
     pub fn is_valid(&self) -> bool {
         self.magic == Self::MAGIC && crc32fast::hash(&self.payload) == self.checksum
@@ -311,4 +446,52 @@ mod tests {
         assert_eq!(archived.conn_id, 1);
         assert_eq!(archived.rport, 443);
     }
+
+    #[test]
+    fn test_stream_frames() {
+        let open = ProxyFrame::new_open(7, 3, ProxyFrame::ipv4_to_mapped([10, 0, 0, 1]), 80);
+        assert_eq!(open.stream_id, 3);
+        assert!(open.flags.is_open);
+        assert!(open.payload.is_empty());
+
+        let half = ProxyFrame::new_half_close(7, 3);
+        assert!(half.flags.is_half_close);
+        assert!(!half.flags.is_final);
+
+        let close = ProxyFrame::new_close(7).with_stream(3);
+        assert_eq!(close.stream_id, 3);
+        assert!(close.flags.is_final);
+
+        let datagram = ProxyFrame::new_datagram(7, 4, [0; 16], 53, vec![1, 2]);
+        assert_eq!(datagram.stream_id, 4);
+        assert!(datagram.flags.is_datagram);
+        assert!(datagram.verify_checksum());
+
+        let bind = ProxyFrame::new_bind(7, 5, [0; 16], 0);
+        assert!(bind.flags.is_open);
+        assert!(bind.flags.is_bind);
+    }
+
+    #[test]
+    fn test_plain_packet_keeps_stream() {
+        let frame = ProxyFrame::new_open(9, 5, [0; 16], 443);
+        let packet = PlainPacket::from_frame(&frame, 1);
+
+        assert_eq!(packet.stream_id, 5);
+        assert!(packet.flags.is_open);
+        assert!(packet.is_valid());
+    }
+
+    #[test]
+    fn test_frame_with_host() {
+        let frame = ProxyFrame::new_open(9, 6, [0; 16], 443).with_host("example.com");
+        assert_eq!(frame.rhost.as_deref(), Some("example.com"));
+
+        let bytes = rkyv::to_bytes::<rkyv::rancor::Error>(&frame).unwrap();
+        let decoded = rkyv::from_bytes::<ProxyFrame, rkyv::rancor::Error>(&bytes).unwrap();
+        assert_eq!(decoded, frame);
+
+        let packet = PlainPacket::from_frame(&frame, 1);
+        assert_eq!(packet.rhost.as_deref(), Some("example.com"));
+    }
 }
@@ -5,13 +5,18 @@
 //! - `AuthRequest`/`AuthResponse`: Authentication handshake
 //! - `TokenPayload`: One-time connection tokens
 //! - `ControlMessage`: Out-of-band control messages
+//! - `ReceiveWindow`/`SendWindow`: Flow control for multiplexed streams
+//! - `dns`: Minimal DNS wire format helpers
 //!
 //! All structures use rkyv for zero-copy deserialization.
 
 mod auth;
+pub mod dns;
 mod frame;
+mod stream;
 mod validation;
 
 pub use auth::*;
 pub use frame::*;
+pub use stream::*;
 pub use validation::*;
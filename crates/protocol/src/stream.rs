@@ -0,0 +1,147 @@
+//! Flow control for multiplexed streams
+
+use std::collections::VecDeque;
+
+/// Initial send window for a new stream (bytes)
+pub const INITIAL_STREAM_WINDOW: u32 = 256 * 1024;
+
+/// Receive-side window accounting
+///
+/// Accumulates consumed bytes and yields a credit to hand back to the peer
+/// (as `ControlMessage::WindowUpdate`) once half of the window is used up,
+/// so that updates are batched instead of sent per frame.
+#[derive(Debug, Clone)]
+pub struct ReceiveWindow {
+    pending: u32,
+    threshold: u32,
+}
+
+impl ReceiveWindow {
+    /// Create accounting for a window of `window` bytes
+    pub fn new(window: u32) -> Self {
+        Self {
+            pending: 0,
+            threshold: (window / 2).max(1),
+        }
+    }
+
+    /// Record `len` consumed bytes, returning the credit to grant if due
+    pub fn consume(&mut self, len: u32) -> Option<u32> {
+        self.pending = self.pending.saturating_add(len);
+        if self.pending >= self.threshold {
+            Some(std::mem::take(&mut self.pending))
+        } else {
+            None
+        }
+    }
+}
+
+impl Default for ReceiveWindow {
+    fn default() -> Self {
+        Self::new(INITIAL_STREAM_WINDOW)
+    }
+}
+
+/// Send-side window accounting
+///
+/// Tracks the credit the peer granted and holds back items sent beyond it, in
+/// order, until `WindowUpdate`s arrive. An item goes out while any credit is
+/// left, so one larger than the whole window cannot stall the stream.
+#[derive(Debug)]
+pub struct SendWindow<T> {
+    credit: i64,
+    backlog: VecDeque<(u32, T)>,
+    queued: usize,
+}
+
+impl<T> SendWindow<T> {
+    /// Create accounting for a window of `window` bytes
+    pub fn new(window: u32) -> Self {
+        Self {
+            credit: i64::from(window),
+            backlog: VecDeque::new(),
+            queued: 0,
+        }
+    }
+
+    /// Queue `item` of `len` bytes, returning the items now allowed to go out
+    pub fn push(&mut self, len: u32, item: T) -> Vec<T> {
+        self.backlog.push_back((len, item));
+        self.queued += len as usize;
+        self.release()
+    }
+
+    /// Add credit granted by the peer, returning the items it lets out
+    pub fn grant(&mut self, increment: u32) -> Vec<T> {
+        self.credit += i64::from(increment);
+        self.release()
+    }
+
+    /// Bytes held back waiting for credit
+    pub fn queued(&self) -> usize {
+        self.queued
+    }
+
+    fn release(&mut self) -> Vec<T> {
+        let mut ready = Vec::new();
+        while let Some((len, _)) = self.backlog.front() {
+            // Empty items (e.g. a close) only wait for those ahead of them
+            if *len > 0 && self.credit <= 0 {
+                break;
+            }
+            let (len, item) = self.backlog.pop_front().unwrap();
+            self.credit -= i64::from(len);
+            self.queued -= len as usize;
+            ready.push(item);
+        }
+        ready
+    }
+}
+
+impl<T> Default for SendWindow<T> {
+    fn default() -> Self {
+        Self::new(INITIAL_STREAM_WINDOW)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_credit_batched_at_half_window() {
+        let mut window = ReceiveWindow::new(1000);
+
+        assert_eq!(window.consume(200), None);
+        assert_eq!(window.consume(200), None);
+        assert_eq!(window.consume(200), Some(600));
+
+        // Pending credit resets after a grant
+        assert_eq!(window.consume(100), None);
+    }
+
+    #[test]
+    fn test_large_read_grants_immediately() {
+        let mut window = ReceiveWindow::default();
+        assert_eq!(
+            window.consume(INITIAL_STREAM_WINDOW),
+            Some(INITIAL_STREAM_WINDOW)
+        );
+    }
+
+    #[test]
+    fn test_send_window_holds_back_until_granted() {
+        let mut window = SendWindow::new(1000);
+
+        assert_eq!(window.push(600, 'a'), vec!['a']);
+        assert_eq!(window.push(600, 'b'), vec!['b']);
+        // Out of credit: later items wait, in order
+        assert!(window.push(100, 'c').is_empty());
+        assert!(window.push(0, 'd').is_empty());
+        assert_eq!(window.queued(), 100);
+
+        assert!(window.grant(100).is_empty());
+        assert_eq!(window.grant(200), vec!['c', 'd']);
+        assert_eq!(window.queued(), 0);
+    }
+}
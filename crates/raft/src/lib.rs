@@ -4,6 +4,8 @@
 
 mod network;
 mod node;
+pub mod peer_auth;
+mod sessions;
 mod storage;
 mod types;
 
@@ -11,6 +13,7 @@ mod types;
 pub use async_raft::Config;
 pub use network::Network;
 pub use node::{ApfsdsRaft, RaftNode};
+pub use sessions::{NODE_LEASE, SessionRecord};
 pub use storage::PersistentStorage;
 pub use types::*;
 
@@ -25,7 +28,7 @@ mod tests {
     #[tokio::test]
     async fn test_raft_node_creation() {
         let config = Arc::new(Config::build("test-cluster".into()).validate().unwrap());
-        let _node = RaftNode::new(1, config);
+        let _node = RaftNode::new(1, config, None);
         // Async-raft node starts automatically in background usually?
         // Actually async-raft 0.6 Raft::new just creates it.
         // We need to check if it implements what we expect.
@@ -1,3 +1,4 @@
+use crate::peer_auth::{self, PeerAuth, SIGNATURE_HEADER, TIMESTAMP_HEADER};
 use crate::{ClientRequest, ClientResponse, NodeId};
 use anyhow::{Result, anyhow};
 use async_raft::RaftNetwork;
@@ -15,16 +16,19 @@ use tokio::sync::RwLock;
 pub struct Network {
     client: Client,
     peers: Arc<RwLock<HashMap<NodeId, String>>>,
+    /// Signs requests for peers; unsigned requests are refused by them
+    auth: Option<Arc<PeerAuth>>,
 }
 
 impl Network {
-    pub fn new(peers: Arc<RwLock<HashMap<NodeId, String>>>) -> Self {
+    pub fn new(peers: Arc<RwLock<HashMap<NodeId, String>>>, auth: Option<Arc<PeerAuth>>) -> Self {
         Self {
             client: Client::builder()
                 .timeout(std::time::Duration::from_secs(5))
                 .build()
                 .unwrap(),
             peers,
+            auth,
         }
     }
 
@@ -43,11 +47,20 @@ impl Network {
         Resp: serde::de::DeserializeOwned,
     {
         let url = format!("http://{}{}", self.get_peer_url(target).await?, path);
+        let body = serde_json::to_vec(&req)?;
 
-        let resp = self
+        let mut request = self
             .client
             .post(&url)
-            .json(&req)
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        if let Some(auth) = &self.auth {
+            let timestamp = peer_auth::unix_now();
+            request = request
+                .header(TIMESTAMP_HEADER, timestamp)
+                .header(SIGNATURE_HEADER, auth.sign(&body, timestamp));
+        }
+        let resp = request
+            .body(body)
             .send()
             .await
             .map_err(|e| anyhow!("Network error: {}", e))?;
@@ -60,6 +73,15 @@ impl Network {
             .await
             .map_err(|e| anyhow!("Serialization error: {}", e))
     }
+
+    /// Hand a client write to the leader `target`
+    pub async fn forward_write(
+        &self,
+        target: NodeId,
+        request: ClientRequest,
+    ) -> Result<ClientResponse> {
+        self.post(target, "/raft/write", request).await
+    }
 }
 
 #[async_trait]
@@ -1,7 +1,10 @@
 use crate::network::Network;
+use crate::peer_auth::PeerAuth;
 use crate::storage::PersistentStorage;
 use crate::{ClientRequest, ClientResponse, NodeId};
 use async_raft::Config;
+use async_raft::error::ClientWriteError;
+use async_raft::raft::ClientWriteRequest;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
@@ -18,13 +21,16 @@ pub struct RaftNode {
     pub storage: Arc<PersistentStorage>,
     pub network: Arc<Network>,
     pub peers: Arc<RwLock<std::collections::HashMap<NodeId, String>>>,
+    /// Shared with peers; None refuses requests from them
+    pub peer_auth: Option<Arc<PeerAuth>>,
 }
 
 impl RaftNode {
     /// Create a new Raft node
-    pub fn new(node_id: NodeId, config: Arc<Config>) -> Self {
+    pub fn new(node_id: NodeId, config: Arc<Config>, peer_auth: Option<PeerAuth>) -> Self {
         let peers = Arc::new(RwLock::new(std::collections::HashMap::new()));
-        let network = Arc::new(Network::new(peers.clone()));
+        let peer_auth = peer_auth.map(Arc::new);
+        let network = Arc::new(Network::new(peers.clone(), peer_auth.clone()));
         // For Phase 3, we default to a data directory in current working dir
         let data_dir = std::env::current_dir().unwrap().join("data");
         // ClickHouse config from environment or default (daemon passes actual config via init)
@@ -46,6 +52,7 @@ impl RaftNode {
             storage,
             network,
             peers,
+            peer_auth,
         }
     }
 
@@ -64,6 +71,36 @@ impl RaftNode {
             .map_err(|e| anyhow::anyhow!("Raft membership error: {:?}", e))
     }
 
+    /// Bootstrap a new cluster of `members`
+    ///
+    /// Fails if this node already has state, e.g. after a restart.
+    pub async fn initialize(
+        &self,
+        members: std::collections::HashSet<NodeId>,
+    ) -> anyhow::Result<()> {
+        self.raft
+            .initialize(members)
+            .await
+            .map_err(|e| anyhow::anyhow!("Raft initialize error: {:?}", e))
+    }
+
+    /// Commit `request` through the leader and return its result
+    pub async fn propose(&self, request: ClientRequest) -> anyhow::Result<ClientResponse> {
+        match self
+            .raft
+            .client_write(ClientWriteRequest::new(request))
+            .await
+        {
+            Ok(response) => Ok(response.data),
+            Err(ClientWriteError::ForwardToLeader(request, Some(leader)))
+                if leader != self.node_id =>
+            {
+                self.network.forward_write(leader, request).await
+            }
+            Err(e) => Err(anyhow::anyhow!("Raft write error: {:?}", e)),
+        }
+    }
+
     /// Get Raft metrics
     pub async fn get_metrics(&self) -> async_raft::RaftMetrics {
         self.raft.metrics().borrow().clone()
@@ -0,0 +1,251 @@
+//! Proxy sessions of all handlers
+//!
+//! Sessions are opened and closed through the Raft log, so every node applies
+//! the same limit checks in the same order and agrees on who got in.
+//!
+//! Handlers renew a lease on their sessions with heartbeats. Time is taken
+//! from the heartbeats themselves, as stamped by their proposers, so every
+//! node expires the same sessions at the same point of the log.
+
+use crate::{NodeId, SessionLimit};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+/// How long the sessions of a node outlive its last heartbeat
+pub const NODE_LEASE: Duration = Duration::from_secs(60);
+
+/// Closed conn_ids remembered, so opens committed after their close are refused
+const MAX_TOMBSTONES: u64 = 65536;
+
+/// One open proxy session
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub user_id: u64,
+    /// Device the session was authenticated from
+    pub client_pk: [u8; 32],
+    /// Handler serving the session
+    pub node_id: NodeId,
+}
+
+/// Open sessions by conn_id
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct SessionTable {
+    sessions: HashMap<u64, SessionRecord>,
+    /// Recently closed conn_ids, with the order they closed in
+    #[serde(default)]
+    closed: HashMap<u64, u64>,
+    #[serde(default)]
+    closed_count: u64,
+    /// Last heartbeat of each node, in milliseconds since the epoch
+    #[serde(default)]
+    leases: HashMap<NodeId, u64>,
+}
+
+impl SessionTable {
+    /// Add a session unless its user is at one of the limits (0 = unlimited)
+    ///
+    /// Returns false for a session that was closed before its open committed.
+    pub(crate) fn open(
+        &mut self,
+        conn_id: u64,
+        record: SessionRecord,
+        max_sessions: u32,
+        max_devices: u32,
+    ) -> Result<bool, SessionLimit> {
+        // Re-applying the same entry is a no-op
+        if self.sessions.contains_key(&conn_id) {
+            return Ok(true);
+        }
+        if self.closed.contains_key(&conn_id) {
+            return Ok(false);
+        }
+
+        let mut sessions = 0;
+        let mut devices = HashSet::new();
+        for other in self
+            .sessions
+            .values()
+            .filter(|s| s.user_id == record.user_id)
+        {
+            sessions += 1;
+            devices.insert(other.client_pk);
+        }
+
+        if max_sessions > 0 && sessions >= max_sessions {
+            return Err(SessionLimit::Sessions);
+        }
+        if max_devices > 0
+            && !devices.contains(&record.client_pk)
+            && devices.len() >= max_devices as usize
+        {
+            return Err(SessionLimit::Devices);
+        }
+
+        self.sessions.insert(conn_id, record);
+        Ok(true)
+    }
+
+    pub(crate) fn close(&mut self, conn_id: u64) -> bool {
+        self.tombstone(conn_id);
+        self.sessions.remove(&conn_id).is_some()
+    }
+
+    /// Remove all sessions served by `node_id`, returning how many there were
+    pub(crate) fn reset(&mut self, node_id: NodeId) -> usize {
+        let mut gone: Vec<u64> = self
+            .sessions
+            .iter()
+            .filter(|(_, s)| s.node_id == node_id)
+            .map(|(conn_id, _)| *conn_id)
+            .collect();
+        // Sorted, so every node numbers the tombstones alike
+        gone.sort_unstable();
+        for conn_id in &gone {
+            self.close(*conn_id);
+        }
+        self.leases.remove(&node_id);
+        gone.len()
+    }
+
+    /// Renew the lease of `node_id` at `now_ms` and remove the sessions of
+    /// nodes whose lease ran out, returning how many there were
+    ///
+    /// Nodes serving sessions without ever sending a heartbeat start their
+    /// lease at the first heartbeat of another.
+    pub(crate) fn heartbeat(&mut self, node_id: NodeId, now_ms: u64) -> usize {
+        self.leases.insert(node_id, now_ms);
+        for session in self.sessions.values() {
+            self.leases.entry(session.node_id).or_insert(now_ms);
+        }
+
+        let lease = NODE_LEASE.as_millis() as u64;
+        let mut expired: Vec<NodeId> = self
+            .leases
+            .iter()
+            .filter(|(_, seen)| now_ms.saturating_sub(**seen) > lease)
+            .map(|(node_id, _)| *node_id)
+            .collect();
+        expired.sort_unstable();
+        expired.into_iter().map(|node_id| self.reset(node_id)).sum()
+    }
+
+    /// Remember a closed conn_id, forgetting the oldest ones beyond the limit
+    fn tombstone(&mut self, conn_id: u64) {
+        self.closed.insert(conn_id, self.closed_count);
+        self.closed_count += 1;
+        if self.closed.len() as u64 > 2 * MAX_TOMBSTONES {
+            let oldest = self.closed_count - MAX_TOMBSTONES;
+            self.closed.retain(|_, order| *order >= oldest);
+        }
+    }
+
+    pub(crate) fn user_sessions(&self, user_id: u64) -> Vec<(u64, SessionRecord)> {
+        let mut sessions: Vec<_> = self
+            .sessions
+            .iter()
+            .filter(|(_, s)| s.user_id == user_id)
+            .map(|(conn_id, s)| (*conn_id, s.clone()))
+            .collect();
+        sessions.sort_by_key(|(conn_id, _)| *conn_id);
+        sessions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(user_id: u64, device: u8, node_id: NodeId) -> SessionRecord {
+        SessionRecord {
+            user_id,
+            client_pk: [device; 32],
+            node_id,
+        }
+    }
+
+    #[test]
+    fn test_session_limit() {
+        let mut table = SessionTable::default();
+        assert_eq!(table.open(1, record(7, 1, 1), 2, 0), Ok(true));
+        assert_eq!(table.open(2, record(7, 1, 1), 2, 0), Ok(true));
+        assert_eq!(
+            table.open(3, record(7, 1, 1), 2, 0),
+            Err(SessionLimit::Sessions)
+        );
+
+        // Other users are not affected, replays are idempotent
+        assert_eq!(table.open(3, record(8, 1, 1), 2, 0), Ok(true));
+        assert_eq!(table.open(1, record(7, 1, 1), 2, 0), Ok(true));
+
+        assert!(table.close(1));
+        assert_eq!(table.open(4, record(7, 1, 1), 2, 0), Ok(true));
+    }
+
+    #[test]
+    fn test_device_limit() {
+        let mut table = SessionTable::default();
+        assert_eq!(table.open(1, record(7, 1, 1), 0, 2), Ok(true));
+        assert_eq!(table.open(2, record(7, 2, 2), 0, 2), Ok(true));
+        assert_eq!(
+            table.open(3, record(7, 3, 1), 0, 2),
+            Err(SessionLimit::Devices)
+        );
+        // Known devices may open more sessions
+        assert_eq!(table.open(3, record(7, 2, 1), 0, 2), Ok(true));
+
+        // A restarted handler frees its sessions
+        assert_eq!(table.reset(1), 2);
+        assert_eq!(table.open(4, record(7, 3, 1), 0, 2), Ok(true));
+        assert_eq!(table.user_sessions(7).len(), 2);
+    }
+
+    #[test]
+    fn test_open_after_close() {
+        let mut table = SessionTable::default();
+
+        // The close of a session whose open was slow to commit came first
+        assert!(!table.close(1));
+        assert_eq!(table.open(1, record(7, 1, 1), 0, 0), Ok(false));
+        assert!(table.user_sessions(7).is_empty());
+
+        // Sessions a reset removed stay closed too
+        assert_eq!(table.open(2, record(7, 1, 1), 0, 0), Ok(true));
+        assert_eq!(table.reset(1), 1);
+        assert_eq!(table.open(2, record(7, 1, 1), 0, 0), Ok(false));
+    }
+
+    #[test]
+    fn test_tombstones_bounded() {
+        let mut table = SessionTable::default();
+        for conn_id in 0..3 * MAX_TOMBSTONES {
+            table.close(conn_id);
+        }
+        assert!(table.closed.len() as u64 <= 2 * MAX_TOMBSTONES);
+        assert!(table.closed.contains_key(&(3 * MAX_TOMBSTONES - 1)));
+        assert_eq!(table.open(0, record(7, 1, 1), 0, 0), Ok(true));
+    }
+
+    #[test]
+    fn test_lease_expiry() {
+        let lease = NODE_LEASE.as_millis() as u64;
+        let mut table = SessionTable::default();
+        assert_eq!(table.open(1, record(7, 1, 1), 0, 0), Ok(true));
+        assert_eq!(table.open(2, record(7, 1, 2), 0, 0), Ok(true));
+        assert_eq!(table.open(3, record(7, 1, 3), 0, 0), Ok(true));
+
+        // Node 3 never heartbeats: its lease starts with the first one seen
+        assert_eq!(table.heartbeat(1, 1_000), 0);
+        assert_eq!(table.heartbeat(2, 1_000 + lease / 2), 0);
+        assert_eq!(table.heartbeat(1, 1_000 + lease), 0);
+
+        // Node 2 keeps renewing, node 3 is gone
+        assert_eq!(table.heartbeat(2, 1_000 + lease + 1), 1);
+        let left: Vec<u64> = table.user_sessions(7).iter().map(|(id, _)| *id).collect();
+        assert_eq!(left, vec![1, 2]);
+
+        // and so is node 1 once it stops
+        assert_eq!(table.heartbeat(2, 2_000 + 2 * lease), 1);
+        assert_eq!(table.user_sessions(7).len(), 1);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::sessions::{SessionRecord, SessionTable};
 use crate::{ClientRequest, ClientResponse, NodeId};
 use anyhow::Result;
 use apfsds_storage::{ClickHouseBackup, ClickHouseConfig, Wal};
@@ -5,11 +6,19 @@ use async_raft::RaftStorage;
 use async_raft::raft::{Entry, MembershipConfig};
 use async_raft::storage::{CurrentSnapshotData, HardState, InitialState};
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use std::io::Cursor;
 use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 
+/// State machine contents, as carried by snapshots
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateMachine {
+    last_applied: u64,
+    sessions: SessionTable,
+}
+
 /// Persistent storage implementation for async-raft
 pub struct PersistentStorage {
     node_id: NodeId,
@@ -19,6 +28,7 @@ pub struct PersistentStorage {
     snapshot: RwLock<Option<CurrentSnapshotData<Cursor<Vec<u8>>>>>,
     wal: Arc<Wal>,
     clickhouse: Arc<ClickHouseBackup>,
+    state: RwLock<StateMachine>,
 }
 
 impl PersistentStorage {
@@ -66,8 +76,14 @@ impl PersistentStorage {
             snapshot: RwLock::new(None),
             wal,
             clickhouse,
+            state: RwLock::new(StateMachine::default()),
         })
     }
+
+    /// Open sessions of `user_id` across the cluster, by conn_id
+    pub async fn user_sessions(&self, user_id: u64) -> Vec<(u64, SessionRecord)> {
+        self.state.read().await.sessions.user_sessions(user_id)
+    }
 }
 
 #[async_trait]
@@ -171,6 +187,10 @@ impl RaftStorage<ClientRequest, ClientResponse> for PersistentStorage {
                 ClientRequest::Upsert { .. } => "Upsert",
                 ClientRequest::Delete { .. } => "Delete",
                 ClientRequest::Cleanup { .. } => "Cleanup",
+                ClientRequest::SessionOpen { .. } => "SessionOpen",
+                ClientRequest::SessionClose { .. } => "SessionClose",
+                ClientRequest::SessionReset { .. } => "SessionReset",
+                ClientRequest::SessionHeartbeat { .. } => "SessionHeartbeat",
                 ClientRequest::Noop => "Noop",
             };
 
@@ -186,9 +206,54 @@ impl RaftStorage<ClientRequest, ClientResponse> for PersistentStorage {
                 .await;
         }
 
+        let mut state = self.state.write().await;
+        state.last_applied = *index;
         match data {
             ClientRequest::Upsert { .. } => Ok(ClientResponse::Ok { affected: 1 }),
             ClientRequest::Delete { .. } => Ok(ClientResponse::Ok { affected: 1 }),
+            ClientRequest::SessionOpen {
+                conn_id,
+                user_id,
+                client_pk,
+                node_id,
+                max_sessions,
+                max_devices,
+            } => {
+                let record = SessionRecord {
+                    user_id: *user_id,
+                    client_pk: *client_pk,
+                    node_id: *node_id,
+                };
+                let opened = state
+                    .sessions
+                    .open(*conn_id, record, *max_sessions, *max_devices);
+                match opened {
+                    Ok(true) => Ok(ClientResponse::Ok { affected: 1 }),
+                    Ok(false) => Ok(ClientResponse::SessionClosed),
+                    Err(limit) => Ok(ClientResponse::SessionRejected { limit }),
+                }
+            }
+            ClientRequest::SessionClose { conn_id } => {
+                let closed = state.sessions.close(*conn_id);
+                Ok(ClientResponse::Ok {
+                    affected: closed as u64,
+                })
+            }
+            ClientRequest::SessionReset { node_id } => {
+                let removed = state.sessions.reset(*node_id);
+                Ok(ClientResponse::Ok {
+                    affected: removed as u64,
+                })
+            }
+            ClientRequest::SessionHeartbeat { node_id, now_ms } => {
+                let expired = state.sessions.heartbeat(*node_id, *now_ms);
+                if expired > 0 {
+                    tracing::info!("Expired {} sessions of handlers that went silent", expired);
+                }
+                Ok(ClientResponse::Ok {
+                    affected: expired as u64,
+                })
+            }
             _ => Ok(ClientResponse::Ok { affected: 0 }),
         }
     }
@@ -201,12 +266,35 @@ impl RaftStorage<ClientRequest, ClientResponse> for PersistentStorage {
     }
 
     async fn do_log_compaction(&self) -> Result<CurrentSnapshotData<Self::Snapshot>> {
+        let (data, index) = {
+            let state = self.state.read().await;
+            (serde_json::to_vec(&*state)?, state.last_applied)
+        };
+        let membership = self.membership.read().await.clone();
+
+        let term = {
+            let mut log = self.log.write().await;
+            let term = log.iter().find(|e| e.index == index).map_or(0, |e| e.term);
+            log.retain(|e| e.index > index);
+            log.insert(
+                0,
+                Entry::new_snapshot_pointer(index, term, String::new(), membership.clone()),
+            );
+            term
+        };
+
         let snapshot = CurrentSnapshotData {
-            term: 0,
-            index: 0,
-            membership: self.membership.read().await.clone(),
-            snapshot: Box::new(Cursor::new(Vec::new())),
+            term,
+            index,
+            membership: membership.clone(),
+            snapshot: Box::new(Cursor::new(data.clone())),
         };
+        *self.snapshot.write().await = Some(CurrentSnapshotData {
+            term,
+            index,
+            membership,
+            snapshot: Box::new(Cursor::new(data)),
+        });
         Ok(snapshot)
     }
 
@@ -216,12 +304,34 @@ impl RaftStorage<ClientRequest, ClientResponse> for PersistentStorage {
 
     async fn finalize_snapshot_installation(
         &self,
-        _index: u64,
-        _term: u64,
-        _delete_through: Option<u64>,
-        _id: String,
-        _snapshot: Box<Self::Snapshot>,
+        index: u64,
+        term: u64,
+        delete_through: Option<u64>,
+        id: String,
+        snapshot: Box<Self::Snapshot>,
     ) -> Result<()> {
+        let data = snapshot.into_inner();
+        let state: StateMachine = serde_json::from_slice(&data)?;
+        let membership = self.membership.read().await.clone();
+
+        {
+            let mut log = self.log.write().await;
+            match delete_through {
+                Some(through) => log.retain(|e| e.index > through),
+                None => log.clear(),
+            }
+            log.insert(
+                0,
+                Entry::new_snapshot_pointer(index, term, id, membership.clone()),
+            );
+        }
+        *self.state.write().await = state;
+        *self.snapshot.write().await = Some(CurrentSnapshotData {
+            term,
+            index,
+            membership,
+            snapshot: Box::new(Cursor::new(data)),
+        });
         Ok(())
     }
 
@@ -240,3 +350,74 @@ impl RaftStorage<ClientRequest, ClientResponse> for PersistentStorage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SessionLimit;
+    use async_raft::raft::EntryPayload;
+
+    fn storage(node_id: NodeId) -> PersistentStorage {
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = std::env::temp_dir().join(format!("apfsds-raft-{}-{}", node_id, nanos));
+        PersistentStorage::new(node_id, dir, ClickHouseConfig::default()).unwrap()
+    }
+
+    fn open(conn_id: u64, device: u8) -> ClientRequest {
+        ClientRequest::SessionOpen {
+            conn_id,
+            user_id: 7,
+            client_pk: [device; 32],
+            node_id: 1,
+            max_sessions: 0,
+            max_devices: 2,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_carries_sessions() {
+        let leader = storage(1);
+        for (index, request) in [(1, open(1, 1)), (2, open(2, 2))] {
+            leader
+                .append_entry_to_log(&Entry {
+                    term: 3,
+                    index,
+                    payload: EntryPayload::Normal(async_raft::raft::EntryNormal {
+                        data: request.clone(),
+                    }),
+                })
+                .await
+                .unwrap();
+            leader
+                .apply_entry_to_state_machine(&index, &request)
+                .await
+                .unwrap();
+        }
+
+        let snapshot = leader.do_log_compaction().await.unwrap();
+        assert_eq!((snapshot.term, snapshot.index), (3, 2));
+        assert_eq!(leader.get_log_entries(0, 10).await.unwrap().len(), 1);
+
+        // A follower installing the snapshot knows both sessions
+        let follower = storage(2);
+        let (id, _) = follower.create_snapshot().await.unwrap();
+        follower
+            .finalize_snapshot_installation(2, 3, None, id, snapshot.snapshot)
+            .await
+            .unwrap();
+        assert_eq!(follower.user_sessions(7).await.len(), 2);
+        let rejected = follower
+            .apply_entry_to_state_machine(&3, &open(3, 3))
+            .await
+            .unwrap();
+        assert!(matches!(
+            rejected,
+            ClientResponse::SessionRejected {
+                limit: SessionLimit::Devices
+            }
+        ));
+    }
+}
@@ -1,3 +1,4 @@
+use crate::NodeId;
 use async_raft::{AppData, AppDataResponse};
 use serde::{Deserialize, Serialize};
 
@@ -18,6 +19,26 @@ pub enum ClientRequest {
     /// Cleanup expired connections
     Cleanup { before_timestamp: u64 },
 
+    /// Register a proxy session unless its user is at a limit (0 = unlimited)
+    SessionOpen {
+        conn_id: u64,
+        user_id: u64,
+        client_pk: [u8; 32],
+        node_id: NodeId,
+        max_sessions: u32,
+        max_devices: u32,
+    },
+
+    /// Remove a proxy session
+    SessionClose { conn_id: u64 },
+
+    /// Remove all sessions of a handler (e.g. after it restarted)
+    SessionReset { node_id: NodeId },
+
+    /// Renew the lease of a handler on its sessions, expiring those of
+    /// handlers that stopped renewing theirs
+    SessionHeartbeat { node_id: NodeId, now_ms: u64 },
+
     /// No-op
     Noop,
 }
@@ -32,6 +53,21 @@ pub enum ClientResponse {
 
     /// Error with message
     Error { message: String },
+
+    /// Session refused because its user is at `limit`
+    SessionRejected { limit: SessionLimit },
+
+    /// Session refused because it was closed before its open committed
+    SessionClosed,
+}
+
+/// Per-user limit on proxy sessions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SessionLimit {
+    /// Concurrent sessions
+    Sessions,
+    /// Distinct client devices with open sessions
+    Devices,
 }
 
 impl AppDataResponse for ClientResponse {}
@@ -1,12 +1,21 @@
-use serde::{Deserialize, Serialize};
-use sqlx::{Pool, Postgres, Row, postgres::PgPoolOptions};
+use serde::{Deserialize, Deserializer, Serialize};
+use sqlx::{
+    Pool, Postgres, Row,
+    postgres::{PgListener, PgPoolOptions},
+};
 use std::time::Duration;
 use thiserror::Error;
 
+/// Channel notified with the user id whenever a user's group, status or limits change
+const GROUP_CHANGED_CHANNEL: &str = "user_group_changed";
+
 #[derive(Error, Debug)]
 pub enum PgError {
     #[error("Database error: {0}")]
     DbError(#[from] sqlx::Error),
+
+    #[error("Already exists: {0}")]
+    Conflict(String),
 }
 
 /// User Group definition (e.g., "Premium Asia", "Free US")
@@ -15,6 +24,10 @@ pub struct ExitGroup {
     pub id: i32,
     pub name: String,
     pub description: Option<String>,
+    /// Bandwidth shared by all users of the group, in bytes per second
+    /// (None = configured default, 0 = unlimited)
+    pub rate_up_bps: Option<i64>,
+    pub rate_down_bps: Option<i64>,
 }
 
 /// User definition
@@ -22,9 +35,93 @@ pub struct ExitGroup {
 pub struct User {
     pub id: i64,
     pub username: String,
+    #[serde(skip_serializing, default)]
     pub token_hash: String,
-    pub group_id: i32,
+    /// HMAC secret sealed under the handlers' credential key
+    #[serde(skip_serializing, default)]
+    pub sealed_secret: Option<String>,
+    pub group_id: Option<i32>,
     pub balance: i64, // simplified billing
+    /// Traffic quota in bytes (None = unlimited)
+    pub quota_bytes: Option<i64>,
+    pub disabled: bool,
+    /// Bandwidth limits in bytes per second (None = configured default, 0 = unlimited)
+    pub rate_up_bps: Option<i64>,
+    pub rate_down_bps: Option<i64>,
+    /// Session limits (None = configured default, 0 = unlimited)
+    pub max_sessions: Option<i32>,
+    pub max_devices: Option<i32>,
+}
+
+/// A user's limits and traffic in the current day and month
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct UserUsage {
+    pub id: i64,
+    pub group_id: Option<i32>,
+    pub quota_bytes: Option<i64>,
+    pub balance: i64,
+    pub daily_bytes: i64,
+    pub monthly_bytes: i64,
+}
+
+/// Traffic and its cost to bill to one user
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageCharge {
+    pub user_id: i64,
+    pub bytes: u64,
+    pub cost: i64,
+}
+
+/// Partial update of a user; None leaves a field unchanged and Some(None)
+/// resets it to NULL (`null` in JSON)
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UserUpdate {
+    #[serde(default, deserialize_with = "nullable")]
+    pub group_id: Option<Option<i32>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub quota_bytes: Option<Option<i64>>,
+    pub disabled: Option<bool>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub rate_up_bps: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub rate_down_bps: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub max_sessions: Option<Option<i32>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub max_devices: Option<Option<i32>>,
+}
+
+/// Partial update of an exit group's limits, with the same meaning as `UserUpdate`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct GroupUpdate {
+    #[serde(default, deserialize_with = "nullable")]
+    pub rate_up_bps: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "nullable")]
+    pub rate_down_bps: Option<Option<i64>>,
+}
+
+/// A field that is present, even as `null`, is Some; with `#[serde(default)]`
+/// an absent one stays None
+fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+/// Credential an exit node registers with (reverse mode)
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ExitCredential {
+    pub id: i64,
+    /// Name the exit registers under
+    pub name: String,
+    /// The exit's key, the HMAC key of its registrations, sealed by the handlers
+    #[serde(skip_serializing, default)]
+    pub sealed_key: String,
+    /// Groups the exit may join
+    pub group_ids: Vec<i32>,
+    pub revoked: bool,
 }
 
 /// Postgres Client helper
@@ -69,23 +166,105 @@ impl PgClient {
                 bytes_used BIGINT NOT NULL,
                 timestamp TIMESTAMP WITH TIME ZONE DEFAULT NOW()
             );
+
+            CREATE TABLE IF NOT EXISTS billing_batches (
+                id VARCHAR(64) PRIMARY KEY,
+                applied_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+            );
+            CREATE INDEX IF NOT EXISTS billing_batches_applied_at
+                ON billing_batches (applied_at);
+
+            CREATE TABLE IF NOT EXISTS exit_credentials (
+                id BIGSERIAL PRIMARY KEY,
+                name VARCHAR(100) NOT NULL UNIQUE,
+                sealed_key TEXT NOT NULL,
+                group_ids INT[] NOT NULL,
+                revoked BOOLEAN NOT NULL DEFAULT FALSE,
+                created_at TIMESTAMP WITH TIME ZONE DEFAULT NOW()
+            );
+
+            ALTER TABLE users ADD COLUMN IF NOT EXISTS quota_bytes BIGINT;
+            ALTER TABLE users ADD COLUMN IF NOT EXISTS disabled BOOLEAN NOT NULL DEFAULT FALSE;
+            ALTER TABLE users ADD COLUMN IF NOT EXISTS rate_up_bps BIGINT;
+            ALTER TABLE users ADD COLUMN IF NOT EXISTS rate_down_bps BIGINT;
+            ALTER TABLE users ADD COLUMN IF NOT EXISTS max_sessions INT;
+            ALTER TABLE users ADD COLUMN IF NOT EXISTS max_devices INT;
+            ALTER TABLE users ADD COLUMN IF NOT EXISTS sealed_secret TEXT;
+            ALTER TABLE exit_groups ADD COLUMN IF NOT EXISTS rate_up_bps BIGINT;
+            ALTER TABLE exit_groups ADD COLUMN IF NOT EXISTS rate_down_bps BIGINT;
+
+            CREATE OR REPLACE FUNCTION notify_user_group_changed() RETURNS trigger AS $$
+            BEGIN
+                IF TG_OP = 'DELETE' THEN
+                    PERFORM pg_notify('user_group_changed', OLD.id::text);
+                ELSE
+                    PERFORM pg_notify('user_group_changed', NEW.id::text);
+                END IF;
+                RETURN NULL;
+            END;
+            $$ LANGUAGE plpgsql;
+
+            DROP TRIGGER IF EXISTS users_group_changed ON users;
+            CREATE TRIGGER users_group_changed
+                AFTER UPDATE OF group_id, disabled, rate_up_bps, rate_down_bps,
+                    max_sessions, max_devices OR DELETE ON users
+                FOR EACH ROW EXECUTE FUNCTION notify_user_group_changed();
+
+            -- Group limits are part of every member's profile; users without
+            -- a group belong to the default group (id 0)
+            CREATE OR REPLACE FUNCTION notify_group_limits_changed() RETURNS trigger AS $$
+            BEGIN
+                PERFORM pg_notify('user_group_changed', id::text)
+                    FROM users WHERE COALESCE(group_id, 0) = NEW.id;
+                RETURN NULL;
+            END;
+            $$ LANGUAGE plpgsql;
+
+            DROP TRIGGER IF EXISTS exit_groups_limits_changed ON exit_groups;
+            CREATE TRIGGER exit_groups_limits_changed
+                AFTER UPDATE OF rate_up_bps, rate_down_bps ON exit_groups
+                FOR EACH ROW EXECUTE FUNCTION notify_group_limits_changed();
             "#,
         )
         .execute(&self.pool)
         .await?;
 
-        // Seed default group if empty
-        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM exit_groups")
-            .fetch_one(&self.pool)
-            .await?;
+        // The default group has id 0, which users without a group fall back
+        // to, and the SERIAL sequence carries on from 1. Databases seeded
+        // before that have it at another id; it moves to 0 with its members
+        // and the exits allowed into it.
+        sqlx::query(
+            r#"
+            DO $$
+            DECLARE
+                legacy INT;
+            BEGIN
+                LOCK TABLE exit_groups IN EXCLUSIVE MODE;
+                IF EXISTS (SELECT 1 FROM exit_groups WHERE id = 0) THEN
+                    RETURN;
+                END IF;
 
-        if count == 0 {
-            sqlx::query(
-                "INSERT INTO exit_groups (name, description) VALUES ('default', 'Default Group')",
-            )
-            .execute(&self.pool)
-            .await?;
-        }
+                SELECT id INTO legacy FROM exit_groups WHERE name = 'default';
+                IF legacy IS NULL THEN
+                    INSERT INTO exit_groups (id, name, description)
+                        VALUES (0, 'default', 'Default Group');
+                    RETURN;
+                END IF;
+
+                UPDATE exit_groups SET name = 'default (moving)' WHERE id = legacy;
+                INSERT INTO exit_groups (id, name, description, rate_up_bps, rate_down_bps)
+                    SELECT 0, 'default', description, rate_up_bps, rate_down_bps
+                    FROM exit_groups WHERE id = legacy;
+                UPDATE users SET group_id = 0 WHERE group_id = legacy;
+                UPDATE exit_credentials SET group_ids = array_replace(group_ids, legacy, 0)
+                    WHERE legacy = ANY(group_ids);
+                DELETE FROM exit_groups WHERE id = legacy;
+            END
+            $$;
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
 
         Ok(())
     }
@@ -100,6 +279,276 @@ impl PgClient {
             .map_err(Into::into)
     }
 
+    /// Create a user; `token_hash` is the digest of its generated secret and
+    /// `sealed_secret` the secret encrypted for verification
+    pub async fn create_user(
+        &self,
+        username: &str,
+        token_hash: &str,
+        sealed_secret: &str,
+        group_id: Option<i32>,
+        quota_bytes: Option<i64>,
+    ) -> Result<User, PgError> {
+        sqlx::query_as::<_, User>(
+            "INSERT INTO users (username, token_hash, sealed_secret, group_id, quota_bytes) \
+             VALUES ($1, $2, $3, $4, $5) RETURNING *",
+        )
+        .bind(username)
+        .bind(token_hash)
+        .bind(sealed_secret)
+        .bind(group_id)
+        .bind(quota_bytes)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(db) if db.is_unique_violation() => {
+                PgError::Conflict(username.to_string())
+            }
+            e => e.into(),
+        })
+    }
+
+    pub async fn get_user(&self, user_id: i64) -> Result<Option<User>, PgError> {
+        sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(Into::into)
+    }
+
+    pub async fn list_users(&self) -> Result<Vec<User>, PgError> {
+        sqlx::query_as::<_, User>("SELECT * FROM users ORDER BY id")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(Into::into)
+    }
+
+    pub async fn list_exit_groups(&self) -> Result<Vec<ExitGroup>, PgError> {
+        sqlx::query_as::<_, ExitGroup>("SELECT * FROM exit_groups ORDER BY id")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(Into::into)
+    }
+
+    pub async fn get_exit_group(&self, group_id: i32) -> Result<Option<ExitGroup>, PgError> {
+        sqlx::query_as::<_, ExitGroup>("SELECT * FROM exit_groups WHERE id = $1")
+            .bind(group_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(Into::into)
+    }
+
+    /// Apply `update`; returns None if the group does not exist
+    pub async fn update_exit_group(
+        &self,
+        group_id: i32,
+        update: &GroupUpdate,
+    ) -> Result<Option<ExitGroup>, PgError> {
+        sqlx::query_as::<_, ExitGroup>(
+            "UPDATE exit_groups SET \
+                rate_up_bps = CASE WHEN $2 THEN $3 ELSE rate_up_bps END, \
+                rate_down_bps = CASE WHEN $4 THEN $5 ELSE rate_down_bps END \
+             WHERE id = $1 RETURNING *",
+        )
+        .bind(group_id)
+        .bind(update.rate_up_bps.is_some())
+        .bind(update.rate_up_bps.flatten())
+        .bind(update.rate_down_bps.is_some())
+        .bind(update.rate_down_bps.flatten())
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(Into::into)
+    }
+
+    /// Apply `update`; returns None if the user does not exist
+    pub async fn update_user(
+        &self,
+        user_id: i64,
+        update: &UserUpdate,
+    ) -> Result<Option<User>, PgError> {
+        sqlx::query_as::<_, User>(
+            "UPDATE users SET \
+                group_id = CASE WHEN $2 THEN $3 ELSE group_id END, \
+                quota_bytes = CASE WHEN $4 THEN $5 ELSE quota_bytes END, \
+                disabled = COALESCE($6, disabled), \
+                rate_up_bps = CASE WHEN $7 THEN $8 ELSE rate_up_bps END, \
+                rate_down_bps = CASE WHEN $9 THEN $10 ELSE rate_down_bps END, \
+                max_sessions = CASE WHEN $11 THEN $12 ELSE max_sessions END, \
+                max_devices = CASE WHEN $13 THEN $14 ELSE max_devices END \
+             WHERE id = $1 RETURNING *",
+        )
+        .bind(user_id)
+        .bind(update.group_id.is_some())
+        .bind(update.group_id.flatten())
+        .bind(update.quota_bytes.is_some())
+        .bind(update.quota_bytes.flatten())
+        .bind(update.disabled)
+        .bind(update.rate_up_bps.is_some())
+        .bind(update.rate_up_bps.flatten())
+        .bind(update.rate_down_bps.is_some())
+        .bind(update.rate_down_bps.flatten())
+        .bind(update.max_sessions.is_some())
+        .bind(update.max_sessions.flatten())
+        .bind(update.max_devices.is_some())
+        .bind(update.max_devices.flatten())
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(Into::into)
+    }
+
+    /// Delete a user and its billing history
+    /// Returns false if the user does not exist
+    pub async fn delete_user(&self, user_id: i64) -> Result<bool, PgError> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM billing_logs WHERE user_id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        let deleted = sqlx::query("DELETE FROM users WHERE id = $1")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        tx.commit().await?;
+        Ok(deleted > 0)
+    }
+
+    /// Subscribe to user group and status changes
+    pub async fn listen_group_changes(&self) -> Result<GroupChangeListener, PgError> {
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(GROUP_CHANGED_CHANNEL).await?;
+        Ok(GroupChangeListener { listener })
+    }
+
+    /// Limits and current day/month traffic of every user
+    pub async fn load_usage(&self) -> Result<Vec<UserUsage>, PgError> {
+        sqlx::query_as::<_, UserUsage>(
+            r#"
+            SELECT u.id, u.group_id, u.quota_bytes, u.balance,
+                COALESCE(SUM(b.bytes_used)
+                    FILTER (WHERE b.timestamp >= date_trunc('day', NOW())), 0)::BIGINT
+                    AS daily_bytes,
+                COALESCE(SUM(b.bytes_used), 0)::BIGINT AS monthly_bytes
+            FROM users u
+            LEFT JOIN billing_logs b
+                ON b.user_id = u.id AND b.timestamp >= date_trunc('month', NOW())
+            GROUP BY u.id
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Into::into)
+    }
+
+    /// Log usage and deduct costs of many users in one transaction
+    ///
+    /// Each user must appear at most once; charges of users that no longer
+    /// exist are dropped. `batch_id` is recorded with the charges, and a
+    /// batch whose id was already applied is skipped, so retrying a batch
+    /// never bills twice. Returns whether the batch was applied now.
+    pub async fn charge_usage_batch(
+        &self,
+        batch_id: &str,
+        charges: &[UsageCharge],
+    ) -> Result<bool, PgError> {
+        if charges.is_empty() {
+            return Ok(false);
+        }
+        let user_ids: Vec<i64> = charges.iter().map(|c| c.user_id).collect();
+        let bytes: Vec<i64> = charges.iter().map(|c| c.bytes as i64).collect();
+        let costs: Vec<i64> = charges.iter().map(|c| c.cost).collect();
+
+        let mut tx = self.pool.begin().await?;
+        let inserted =
+            sqlx::query("INSERT INTO billing_batches (id) VALUES ($1) ON CONFLICT DO NOTHING")
+                .bind(batch_id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+        if inserted == 0 {
+            return Ok(false);
+        }
+        // Ids only need to outlive retries of the batch
+        sqlx::query("DELETE FROM billing_batches WHERE applied_at < NOW() - INTERVAL '30 days'")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "INSERT INTO billing_logs (user_id, bytes_used) \
+             SELECT c.user_id, c.bytes \
+             FROM UNNEST($1::BIGINT[], $2::BIGINT[]) AS c(user_id, bytes) \
+             JOIN users u ON u.id = c.user_id",
+        )
+        .bind(&user_ids)
+        .bind(&bytes)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            "UPDATE users u SET balance = u.balance - c.cost \
+             FROM UNNEST($1::BIGINT[], $2::BIGINT[]) AS c(user_id, cost) \
+             WHERE u.id = c.user_id AND c.cost <> 0",
+        )
+        .bind(&user_ids)
+        .bind(&costs)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    /// Enroll an exit node; `sealed_key` is its generated key, encrypted
+    pub async fn create_exit_credential(
+        &self,
+        name: &str,
+        sealed_key: &str,
+        group_ids: &[i32],
+    ) -> Result<ExitCredential, PgError> {
+        sqlx::query_as::<_, ExitCredential>(
+            "INSERT INTO exit_credentials (name, sealed_key, group_ids) \
+             VALUES ($1, $2, $3) \
+             RETURNING id, name, sealed_key, group_ids, revoked",
+        )
+        .bind(name)
+        .bind(sealed_key)
+        .bind(group_ids)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(db) if db.is_unique_violation() => {
+                PgError::Conflict(name.to_string())
+            }
+            e => e.into(),
+        })
+    }
+
+    pub async fn get_exit_credential(&self, name: &str) -> Result<Option<ExitCredential>, PgError> {
+        sqlx::query_as::<_, ExitCredential>(
+            "SELECT id, name, sealed_key, group_ids, revoked FROM exit_credentials WHERE name = $1",
+        )
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(Into::into)
+    }
+
+    pub async fn list_exit_credentials(&self) -> Result<Vec<ExitCredential>, PgError> {
+        sqlx::query_as::<_, ExitCredential>(
+            "SELECT id, name, sealed_key, group_ids, revoked FROM exit_credentials ORDER BY id",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(Into::into)
+    }
+
+    /// Revoke an exit's credential; returns false if there is none
+    pub async fn revoke_exit_credential(&self, name: &str) -> Result<bool, PgError> {
+        let revoked = sqlx::query("UPDATE exit_credentials SET revoked = TRUE WHERE name = $1")
+            .bind(name)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        Ok(revoked > 0)
+    }
+
     pub async fn record_usage(&self, user_id: i64, bytes: u64) -> Result<(), PgError> {
         sqlx::query("INSERT INTO billing_logs (user_id, bytes_used) VALUES ($1, $2)")
             .bind(user_id)
@@ -109,3 +558,25 @@ impl PgClient {
         Ok(())
     }
 }
+
+/// Stream of user ids whose group changed
+pub struct GroupChangeListener {
+    listener: PgListener,
+}
+
+impl GroupChangeListener {
+    /// Wait for the next change
+    ///
+    /// Returns `Ok(None)` when the connection was lost; it is re-established on
+    /// the next call, but notifications sent in between are missed.
+    pub async fn recv(&mut self) -> Result<Option<i64>, PgError> {
+        loop {
+            let Some(notification) = self.listener.try_recv().await? else {
+                return Ok(None);
+            };
+            if let Ok(user_id) = notification.payload().parse() {
+                return Ok(Some(user_id));
+            }
+        }
+    }
+}
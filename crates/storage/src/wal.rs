@@ -6,7 +6,6 @@ use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 
 /// Write-Ahead Log (WAL) entry header size (CRC32 + Length)
-#[allow(dead_code)]
 const HEADER_SIZE: usize = 4 + 8;
 
 /// Write-Ahead Log for persistent storage
@@ -24,11 +23,13 @@ impl Wal {
             std::fs::create_dir_all(parent)?;
         }
 
-        let file = OpenOptions::new()
+        let mut file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
             .open(&path)?;
+        // Append after existing entries
+        file.seek(SeekFrom::End(0))?;
 
         Ok(Self {
             path,
@@ -62,60 +63,103 @@ impl Wal {
     }
 
     /// Truncate the WAL to a specific size
+    ///
+    /// Later appends continue at the new end.
     pub fn truncate(&self, size: u64) -> io::Result<()> {
-        let file = self.file.lock().unwrap();
+        let mut file = self.file.lock().unwrap();
         file.set_len(size)?;
+        file.seek(SeekFrom::Start(size))?;
         file.sync_all()
     }
 
     /// Read all entries from the WAL
     pub fn read_all(&self) -> io::Result<Vec<Vec<u8>>> {
         let mut file = self.file.lock().unwrap();
-        file.seek(SeekFrom::Start(0))?;
-
-        let mut entries = Vec::new();
-        let mut buffer = Vec::new();
-
-        loop {
-            // Read header
-            let checksum = match file.read_u32::<BigEndian>() {
-                Ok(c) => c,
-                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
-                Err(e) => return Err(e),
-            };
-
-            let len = file.read_u64::<BigEndian>()?;
-
-            // Validate length sanity check (max 128MB per entry)
-            if len > 128 * 1024 * 1024 {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Entry too large",
-                ));
-            }
+        let (entries, _, damage) = read_entries(&mut file)?;
+        match damage {
+            Some(e) => Err(e),
+            None => Ok(entries),
+        }
+    }
 
-            // Read data
-            buffer.resize(len as usize, 0);
-            file.read_exact(&mut buffer)?;
-
-            // Verify checksum
-            let mut hasher = Hasher::new();
-            hasher.update(&buffer);
-            if hasher.finalize() != checksum {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Checksum mismatch",
-                ));
-            }
+    /// Read entries up to the first damaged one and cut the WAL there
+    ///
+    /// Returns the intact entries and the number of bytes dropped.
+    pub fn recover(&self) -> io::Result<(Vec<Vec<u8>>, u64)> {
+        let mut file = self.file.lock().unwrap();
+        let (entries, good_len, damage) = read_entries(&mut file)?;
+        if damage.is_none() {
+            return Ok((entries, 0));
+        }
 
-            entries.push(buffer.clone());
+        let len = file.metadata()?.len();
+        file.set_len(good_len)?;
+        file.seek(SeekFrom::Start(good_len))?;
+        file.sync_all()?;
+        Ok((entries, len - good_len))
+    }
+}
+
+/// Read entries from the start of `file`, stopping at the first damaged one
+///
+/// Returns the entries, the length of the file they occupy and what stopped
+/// the read early, if anything. The file is left positioned at its end.
+fn read_entries(file: &mut File) -> io::Result<(Vec<Vec<u8>>, u64, Option<io::Error>)> {
+    file.seek(SeekFrom::Start(0))?;
+
+    let mut entries = Vec::new();
+    let mut good_len = 0;
+    let damage = loop {
+        match read_entry(file) {
+            Ok(Some(entry)) => {
+                good_len += (HEADER_SIZE + entry.len()) as u64;
+                entries.push(entry);
+            }
+            Ok(None) => break None,
+            Err(e) => break Some(e),
         }
+    };
 
-        // Restore file position to end
-        file.seek(SeekFrom::End(0))?;
+    // Restore file position to end
+    file.seek(SeekFrom::End(0))?;
+
+    Ok((entries, good_len, damage))
+}
+
+/// Read one entry; None at a clean end of the file
+fn read_entry(file: &mut File) -> io::Result<Option<Vec<u8>>> {
+    // Read header
+    let checksum = match file.read_u32::<BigEndian>() {
+        Ok(c) => c,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let len = file.read_u64::<BigEndian>()?;
+
+    // Validate length sanity check (max 128MB per entry)
+    if len > 128 * 1024 * 1024 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Entry too large",
+        ));
+    }
 
-        Ok(entries)
+    // Read data
+    let mut buffer = vec![0; len as usize];
+    file.read_exact(&mut buffer)?;
+
+    // Verify checksum
+    let mut hasher = Hasher::new();
+    hasher.update(&buffer);
+    if hasher.finalize() != checksum {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Checksum mismatch",
+        ));
     }
+
+    Ok(Some(buffer))
 }
 
 #[cfg(test)]
@@ -147,4 +191,57 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_wal_append_after_reopen_and_truncate() -> io::Result<()> {
+        let temp_file = NamedTempFile::new()?;
+        let path = temp_file.path().to_path_buf();
+
+        Wal::open(&path)?.append(b"one")?;
+        let wal = Wal::open(&path)?;
+        wal.append(b"two")?;
+        assert_eq!(wal.read_all()?, vec![b"one".to_vec(), b"two".to_vec()]);
+
+        wal.truncate(0)?;
+        wal.append(b"three")?;
+        assert_eq!(wal.read_all()?, vec![b"three".to_vec()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wal_recover_truncates_damaged_tail() -> io::Result<()> {
+        let temp_file = NamedTempFile::new()?;
+        let path = temp_file.path().to_path_buf();
+
+        let wal = Wal::open(&path)?;
+        wal.append(b"one")?;
+        let good_len = wal.append(b"two")?;
+        wal.append(b"three")?;
+        wal.sync()?;
+        drop(wal);
+
+        // Corrupt the last entry
+        let mut data = std::fs::read(&path)?;
+        let last = data.len() - 1;
+        data[last] ^= 0xff;
+        std::fs::write(&path, &data)?;
+
+        let wal = Wal::open(&path)?;
+        assert!(wal.read_all().is_err());
+        let (entries, dropped) = wal.recover()?;
+        assert_eq!(entries, vec![b"one".to_vec(), b"two".to_vec()]);
+        assert_eq!(dropped, data.len() as u64 - good_len);
+        assert_eq!(std::fs::metadata(&path)?.len(), good_len);
+
+        // Appends continue after the last good entry
+        wal.append(b"four")?;
+        assert_eq!(wal.recover()?.1, 0);
+        assert_eq!(
+            wal.read_all()?,
+            vec![b"one".to_vec(), b"two".to_vec(), b"four".to_vec()]
+        );
+
+        Ok(())
+    }
 }
@@ -1,17 +1,25 @@
 //! Exit node client for Handler → Exit communication
 //!
-//! Uses HTTP/2 + rkyv serialization for high performance.
+//! Packets travel over one persistent WebSocket tunnel per exit node, see
+//! [`run_tunnel`].
 
-use crate::SharedPacketDispatcher;
+use crate::exit_tunnel::{TUNNEL_PATH, TUNNEL_QUEUE, run_tunnel};
+use crate::{PacketDispatcher, SharedPacketDispatcher, SharedTunnelSigner};
 use apfsds_protocol::PlainPacket;
-use bytes::{Buf, Bytes, BytesMut};
-use futures::StreamExt;
-use reqwest::Client;
-use rkyv::rancor::Error as RkyvError;
+use async_trait::async_trait;
+use dashmap::DashSet;
 use std::sync::Arc;
-use std::time::Duration;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use thiserror::Error;
-use tracing::{debug, error, info, trace, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
+use tracing::{error, info, trace, warn};
 
 /// Exit client errors
 #[derive(Error, Debug)]
@@ -38,11 +46,11 @@ pub struct ExitClientConfig {
     /// Exit node base URL (e.g., "http://exit-1.internal:8081")
     pub base_url: String,
 
-    /// Request timeout
+    /// Connect timeout, also the longest a tunnel may stay silent
     pub timeout: Duration,
 
-    /// Enable HTTP/2
-    pub http2: bool,
+    /// Packets queued for the tunnel before `forward` waits
+    pub queue_size: usize,
 }
 
 impl Default for ExitClientConfig {
@@ -50,150 +58,157 @@ impl Default for ExitClientConfig {
         Self {
             base_url: "http://127.0.0.1:8081".to_string(),
             timeout: Duration::from_secs(10),
-            http2: true,
+            queue_size: TUNNEL_QUEUE,
         }
     }
 }
 
+/// Traffic of one exit node over the last sampling period
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExitLoad {
+    /// Connections that sent or received packets
+    pub connections: usize,
+    /// Payload bytes per second in both directions
+    pub throughput_bps: u64,
+}
+
+/// Traffic counted since the last [`ExitClient::sample_load`]
+struct LoadCounter {
+    bytes: AtomicU64,
+    conns: DashSet<u64>,
+    sampled: Mutex<(Instant, ExitLoad)>,
+}
+
+impl LoadCounter {
+    fn new() -> Self {
+        Self {
+            bytes: AtomicU64::new(0),
+            conns: DashSet::new(),
+            sampled: Mutex::new((Instant::now(), ExitLoad::default())),
+        }
+    }
+
+    fn record(&self, packet: &PlainPacket) {
+        self.bytes
+            .fetch_add(packet.payload.len() as u64, Ordering::Relaxed);
+        if !self.conns.contains(&packet.conn_id) {
+            self.conns.insert(packet.conn_id);
+        }
+    }
+
+    fn sample_at(&self, now: Instant) -> ExitLoad {
+        let mut sampled = self.sampled.lock().unwrap();
+        let elapsed = now.saturating_duration_since(sampled.0).as_secs_f64();
+        let bytes = self.bytes.swap(0, Ordering::Relaxed);
+        let connections = self.conns.len();
+        self.conns.clear();
+
+        sampled.0 = now;
+        sampled.1 = ExitLoad {
+            connections,
+            throughput_bps: if elapsed > 0.0 {
+                (bytes as f64 / elapsed) as u64
+            } else {
+                0
+            },
+        };
+        sampled.1
+    }
+}
+
+/// Counts return traffic before passing it on
+struct CountingDispatcher<'a> {
+    load: &'a LoadCounter,
+    inner: &'a dyn PacketDispatcher,
+}
+
+#[async_trait]
+impl PacketDispatcher for CountingDispatcher<'_> {
+    async fn dispatch(&self, packet: PlainPacket) {
+        self.load.record(&packet);
+        self.inner.dispatch(packet).await;
+    }
+}
+
 /// Client for communicating with exit nodes
 pub struct ExitClient {
-    client: Client,
     config: ExitClientConfig,
     healthy: std::sync::atomic::AtomicBool,
+    queue: mpsc::Sender<PlainPacket>,
+    /// Taken by the tunnel task once it starts
+    outgoing: Mutex<Option<mpsc::Receiver<PlainPacket>>>,
+    load: LoadCounter,
 }
 
 impl ExitClient {
     /// Create a new exit client
+    ///
+    /// Nothing is sent until [`ExitClient::start_tunnel`] connects or the
+    /// exit node's own tunnel is handed to [`ExitClient::serve_tunnel`].
     pub fn new(config: ExitClientConfig) -> Result<Self, ExitClientError> {
-        let mut builder = Client::builder()
-            .timeout(config.timeout)
-            .pool_max_idle_per_host(10);
-
-        if config.http2 {
-            builder = builder.http2_prior_knowledge();
-        }
-
-        let client = builder
-            .build()
-            .map_err(|e| ExitClientError::ConnectionFailed(e.to_string()))?;
-
+        let (queue, outgoing) = mpsc::channel(config.queue_size.max(1));
         Ok(Self {
-            client,
             config,
-            healthy: std::sync::atomic::AtomicBool::new(true),
+            healthy: std::sync::atomic::AtomicBool::new(false),
+            queue,
+            outgoing: Mutex::new(Some(outgoing)),
+            load: LoadCounter::new(),
         })
     }
 
-    /// Forward a packet to the exit node
+    /// Queue a packet for the exit node
+    ///
+    /// Waits while the tunnel queue is full.
     pub async fn forward(&self, packet: &PlainPacket) -> Result<(), ExitClientError> {
         if !self.is_healthy() {
             return Err(ExitClientError::Unhealthy);
         }
 
-        // Serialize with rkyv
-        let bytes = rkyv::to_bytes::<RkyvError>(packet)
-            .map_err(|e| ExitClientError::SerializationError(e.to_string()))?;
-
-        let url = format!("{}/forward", self.config.base_url);
-        trace!("Forwarding packet to {}", url);
-
-        let response = self
-            .client
-            .post(&url)
-            .header("Content-Type", "application/octet-stream")
-            .body(bytes.to_vec())
-            .send()
+        let permit = self
+            .queue
+            .reserve()
             .await
-            .map_err(|e| {
-                self.mark_unhealthy();
-                ExitClientError::RequestFailed(e.to_string())
-            })?;
-
-        if !response.status().is_success() {
-            error!("Exit node returned error: {}", response.status());
-            return Err(ExitClientError::RequestFailed(format!(
-                "HTTP {}",
-                response.status()
-            )));
-        }
+            .map_err(|_| ExitClientError::ConnectionFailed("Tunnel stopped".into()))?;
+        permit.send(packet.clone());
+        self.load.record(packet);
 
-        debug!("Packet forwarded successfully");
+        trace!("Queued packet for {}", self.config.base_url);
         Ok(())
     }
 
-    /// Subscribe to return traffic stream
-    pub fn subscribe(self: Arc<Self>, handler_id: u64, dispatcher: SharedPacketDispatcher) {
+    /// Keep the tunnel to the exit node connected
+    ///
+    /// Every attempt carries a fresh proof from `signer`. Return traffic is
+    /// handed to `dispatcher`.
+    pub fn start_tunnel(
+        self: Arc<Self>,
+        handler_id: u64,
+        dispatcher: SharedPacketDispatcher,
+        signer: SharedTunnelSigner,
+    ) {
+        let Some(mut outgoing) = self.outgoing.lock().unwrap().take() else {
+            warn!("Tunnel to {} already started", self.config.base_url);
+            return;
+        };
+
         tokio::spawn(async move {
-            let url = format!("{}/stream?handler_id={}", self.config.base_url, handler_id);
+            let url = tunnel_url(&self.config.base_url, handler_id);
             let mut backoff = Duration::from_secs(1);
 
             loop {
-                info!("Connecting to exit node stream at {}", url);
-                match self.client.get(&url).send().await {
-                    Ok(mut resp) => {
-                        if !resp.status().is_success() {
-                            warn!("Stream failed HTTP {}", resp.status());
-                            tokio::time::sleep(backoff).await;
-                            continue;
-                        }
-
-                        self.healthy
-                            .store(true, std::sync::atomic::Ordering::Relaxed);
+                info!("Connecting exit tunnel {}", url);
+                let proof = signer.sign(&self.config.base_url, handler_id);
+                match tokio::time::timeout(self.config.timeout, connect_tunnel(&url, proof)).await {
+                    Ok(Ok(ws)) => {
                         backoff = Duration::from_secs(1);
-
-                        // let mut stream = resp.bytes_stream();
-                        let mut buffer = BytesMut::new();
-
-                        loop {
-                            match resp.chunk().await {
-                                Ok(Some(chunk)) => {
-                                    buffer.extend_from_slice(&chunk);
-
-                                    // Process frames (Length + Payload)
-                                    loop {
-                                        if buffer.len() < 4 {
-                                            break;
-                                        }
-
-                                        let mut len_bytes = [0u8; 4];
-                                        len_bytes.copy_from_slice(&buffer[..4]);
-                                        let len = u32::from_le_bytes(len_bytes) as usize;
-
-                                        if buffer.len() < 4 + len {
-                                            break; // Wait for more data
-                                        }
-
-                                        // Consume header
-                                        buffer.advance(4);
-                                        // Extract payload
-                                        let payload = buffer.split_to(len);
-
-                                        // Deserialize PlainPacket
-                                        match rkyv::from_bytes::<PlainPacket, rkyv::rancor::Error>(
-                                            &payload,
-                                        ) {
-                                            Ok(packet) => {
-                                                dispatcher.dispatch(packet).await;
-                                            }
-                                            Err(e) => {
-                                                error!("Stream deserialization error: {}", e);
-                                            }
-                                        }
-                                    }
-                                }
-                                Ok(None) => {
-                                    break; // EOF
-                                }
-                                Err(e) => {
-                                    error!("Stream read error: {}", e);
-                                    break;
-                                }
-                            }
-                        }
-                        warn!("Stream disconnected");
+                        self.carry(ws, &mut outgoing, dispatcher.as_ref()).await;
+                    }
+                    Ok(Err(e)) => {
+                        error!("Failed to connect exit tunnel: {}", e);
+                        self.mark_unhealthy();
                     }
-                    Err(e) => {
-                        error!("Failed to connect stream: {}", e);
+                    Err(_) => {
+                        error!("Timed out connecting exit tunnel {}", url);
                         self.mark_unhealthy();
                     }
                 }
@@ -204,25 +219,60 @@ impl ExitClient {
         });
     }
 
-    /// Check health of exit node
-    pub async fn health_check(&self) -> bool {
-        let url = format!("{}/health", self.config.base_url);
+    /// Carry traffic over a tunnel the exit node opened to us
+    ///
+    /// Returns once the tunnel closes; the client stays unhealthy afterwards
+    /// until another tunnel is served.
+    pub async fn serve_tunnel<S>(&self, ws: WebSocketStream<S>, dispatcher: &dyn PacketDispatcher)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let Some(mut outgoing) = self.outgoing.lock().unwrap().take() else {
+            warn!("Tunnel to {} already served", self.config.base_url);
+            return;
+        };
+
+        self.carry(ws, &mut outgoing, dispatcher).await;
+        *self.outgoing.lock().unwrap() = Some(outgoing);
+    }
 
-        match self.client.get(&url).send().await {
-            Ok(resp) if resp.status().is_success() => {
-                self.healthy
-                    .store(true, std::sync::atomic::Ordering::Relaxed);
-                true
-            }
-            _ => {
-                self.healthy
-                    .store(false, std::sync::atomic::Ordering::Relaxed);
-                false
-            }
+    async fn carry<S>(
+        &self,
+        ws: WebSocketStream<S>,
+        outgoing: &mut mpsc::Receiver<PlainPacket>,
+        dispatcher: &dyn PacketDispatcher,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        info!("Exit tunnel to {} up", self.config.base_url);
+        self.healthy
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+
+        let dispatcher = CountingDispatcher {
+            load: &self.load,
+            inner: dispatcher,
+        };
+        let result = run_tunnel(ws, outgoing, &dispatcher, self.config.timeout).await;
+        self.mark_unhealthy();
+
+        // Whatever is still queued was meant for the old tunnel
+        let mut dropped = 0;
+        while outgoing.try_recv().is_ok() {
+            dropped += 1;
+        }
+        match result {
+            Ok(()) => warn!(
+                "Exit tunnel to {} closed, {} packets dropped",
+                self.config.base_url, dropped
+            ),
+            Err(e) => warn!(
+                "Exit tunnel to {} failed: {}, {} packets dropped",
+                self.config.base_url, e, dropped
+            ),
         }
     }
 
-    /// Check if client is marked healthy
+    /// Check if the tunnel is up
     pub fn is_healthy(&self) -> bool {
         self.healthy.load(std::sync::atomic::Ordering::Relaxed)
     }
@@ -233,12 +283,54 @@ impl ExitClient {
             .store(false, std::sync::atomic::Ordering::Relaxed);
     }
 
+    /// Close the current sampling period and return its traffic
+    pub fn sample_load(&self) -> ExitLoad {
+        self.load.sample_at(Instant::now())
+    }
+
+    /// Traffic of the last closed sampling period
+    pub fn load(&self) -> ExitLoad {
+        self.load.sampled.lock().unwrap().1
+    }
+
     /// Get base URL
     pub fn base_url(&self) -> &str {
         &self.config.base_url
     }
 }
 
+/// Open a tunnel to `url`, sending `proof` as a request header
+async fn connect_tunnel(
+    url: &str,
+    proof: Option<(&'static str, String)>,
+) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, ExitClientError> {
+    let failed = |e: &dyn std::fmt::Display| ExitClientError::ConnectionFailed(e.to_string());
+    let mut request = url.into_client_request().map_err(|e| failed(&e))?;
+    if let Some((name, value)) = proof {
+        let value = HeaderValue::from_str(&value).map_err(|e| failed(&e))?;
+        request.headers_mut().insert(name, value);
+    }
+    let (ws, _) = connect_async(request).await.map_err(|e| failed(&e))?;
+    Ok(ws)
+}
+
+/// WebSocket URL of the tunnel endpoint of an exit node
+///
+/// Accepts HTTP(S) and WS(S) URLs as well as a bare `host:port`.
+fn tunnel_url(base_url: &str, handler_id: u64) -> String {
+    let base = base_url.trim_end_matches('/');
+    let base = if let Some(rest) = base.strip_prefix("https://") {
+        format!("wss://{}", rest)
+    } else if let Some(rest) = base.strip_prefix("http://") {
+        format!("ws://{}", rest)
+    } else if base.starts_with("ws://") || base.starts_with("wss://") {
+        base.to_string()
+    } else {
+        format!("ws://{}", base)
+    };
+    format!("{}{}?handler_id={}", base, TUNNEL_PATH, handler_id)
+}
+
 /// Shared exit client
 pub type SharedExitClient = Arc<ExitClient>;
 
@@ -249,7 +341,46 @@ mod tests {
     #[test]
     fn test_exit_client_config_default() {
         let config = ExitClientConfig::default();
-        assert!(config.http2);
+        assert_eq!(config.queue_size, TUNNEL_QUEUE);
         assert_eq!(config.timeout, Duration::from_secs(10));
     }
+
+    #[test]
+    fn test_load_sampling() {
+        let load = LoadCounter::new();
+        let start = load.sampled.lock().unwrap().0;
+
+        load.record(&PlainPacket::response(1, 1, vec![0; 1000]));
+        load.record(&PlainPacket::response(1, 1, vec![0; 1000]));
+        load.record(&PlainPacket::response(2, 1, vec![0; 2000]));
+
+        let sample = load.sample_at(start + Duration::from_secs(2));
+        assert_eq!(
+            sample,
+            ExitLoad {
+                connections: 2,
+                throughput_bps: 2000,
+            }
+        );
+
+        // Each period starts from scratch
+        let sample = load.sample_at(start + Duration::from_secs(4));
+        assert_eq!(sample, ExitLoad::default());
+    }
+
+    #[test]
+    fn test_tunnel_url() {
+        assert_eq!(
+            tunnel_url("http://exit-1.internal:8081/", 3),
+            "ws://exit-1.internal:8081/tunnel?handler_id=3"
+        );
+        assert_eq!(
+            tunnel_url("https://exit-1.example.com", 3),
+            "wss://exit-1.example.com/tunnel?handler_id=3"
+        );
+        assert_eq!(
+            tunnel_url("203.0.113.1:25347", 3),
+            "ws://203.0.113.1:25347/tunnel?handler_id=3"
+        );
+    }
 }
@@ -1,16 +1,24 @@
 //! Exit node pool with health checking and load balancing
 //!
-//! Manages multiple exit nodes and distributes traffic.
+//! Manages multiple exit nodes and distributes traffic. Connections are
+//! spread over the nodes of their group, but every packet of one connection
+//! goes to the same node: only that node holds its streams.
 
-use crate::exit_client::{ExitClient, ExitClientConfig, ExitClientError, SharedExitClient};
+use crate::exit_client::{
+    ExitClient, ExitClientConfig, ExitClientError, ExitLoad, SharedExitClient,
+};
 use apfsds_protocol::PlainPacket;
+use dashmap::DashMap;
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::RwLock;
+use tokio_tungstenite::WebSocketStream;
 use tracing::{debug, info, warn};
 
-use crate::SharedPacketDispatcher;
+use crate::{SharedPacketDispatcher, SharedTunnelSigner};
 use std::collections::HashMap;
 
 /// Definition of an exit node
@@ -31,9 +39,6 @@ pub struct ExitPoolConfig {
 
     /// Per-client timeout
     pub client_timeout: Duration,
-
-    /// Use HTTP/2
-    pub http2: bool,
 }
 
 impl Default for ExitPoolConfig {
@@ -45,31 +50,52 @@ impl Default for ExitPoolConfig {
             }],
             health_check_interval: Duration::from_secs(10),
             client_timeout: Duration::from_secs(10),
-            http2: true,
         }
     }
 }
 
+/// Nodes and traffic of one group
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GroupLoad {
+    /// Configured and connected nodes
+    pub nodes: usize,
+    /// Nodes whose tunnel is up
+    pub healthy: usize,
+    /// Traffic of the healthy nodes
+    pub load: ExitLoad,
+}
+
 /// Pool of exit node clients for a specific group
 pub struct GroupPool {
     clients: Vec<SharedExitClient>,
     next_index: AtomicUsize,
 }
 
+/// Exit node a connection is pinned to, and the streams it opened there
+struct Route {
+    client: SharedExitClient,
+    streams: HashSet<u32>,
+}
+
 /// Pool of exit node clients with load balancing
 pub struct ExitPool {
     groups: RwLock<HashMap<i32, GroupPool>>,
+    routes: DashMap<u64, Route>,
     config: ExitPoolConfig,
     dispatcher: SharedPacketDispatcher,
+    signer: SharedTunnelSigner,
     handler_id: u64,
 }
 
 impl ExitPool {
     /// Create a new exit pool
+    ///
+    /// Tunnels to the configured nodes are opened with proofs from `signer`.
     pub fn new(
         config: ExitPoolConfig,
         handler_id: u64,
         dispatcher: SharedPacketDispatcher,
+        signer: SharedTunnelSigner,
     ) -> Result<Self, ExitClientError> {
         let mut groups_map: HashMap<i32, Vec<SharedExitClient>> = HashMap::new();
 
@@ -77,12 +103,13 @@ impl ExitPool {
             let client_config = ExitClientConfig {
                 base_url: node_def.url.clone(),
                 timeout: config.client_timeout,
-                http2: config.http2,
+                ..Default::default()
             };
 
             let client = Arc::new(ExitClient::new(client_config)?);
-            // Start return traffic subscription
-            client.clone().subscribe(handler_id, dispatcher.clone());
+            client
+                .clone()
+                .start_tunnel(handler_id, dispatcher.clone(), signer.clone());
 
             groups_map
                 .entry(node_def.group_id)
@@ -105,18 +132,138 @@ impl ExitPool {
 
         Ok(Self {
             groups: RwLock::new(groups),
+            routes: DashMap::new(),
             config,
             dispatcher,
+            signer,
             handler_id,
         })
     }
 
-    /// Forward a packet using round-robin selection within a group
+    /// Forward a packet to the exit node of its connection
+    ///
+    /// A new connection is pinned to a node picked round-robin within the
+    /// group. It moves to another node only when the tunnel to its node goes
+    /// down; the streams it had there are reset.
     pub async fn forward(
         &self,
         packet: &PlainPacket,
         group_id: i32,
     ) -> Result<(), ExitClientError> {
+        let conn_id = packet.conn_id;
+
+        let pinned = self.routes.get(&conn_id).map(|r| r.client.clone());
+        if let Some(client) = pinned {
+            match client.forward(packet).await {
+                Ok(()) => {
+                    self.track(packet);
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("Exit node {} failed: {}", client.base_url(), e);
+                    let reset = self.unpin(conn_id, &client).await;
+                    if reset.contains(&packet.stream_id) && !packet.flags.is_open {
+                        return Err(ExitClientError::ConnectionFailed(format!(
+                            "Exit node of stream {} went down",
+                            packet.stream_id
+                        )));
+                    }
+                }
+            }
+        }
+
+        // Forwarding waits for room in a tunnel queue; don't hold the group
+        // lock over it, or one stalled tunnel blocks adding and removing nodes
+        for client in self.candidates(group_id).await? {
+            if !client.is_healthy() {
+                continue;
+            }
+            // Another packet of the connection may have pinned it meanwhile
+            let client = self
+                .routes
+                .entry(conn_id)
+                .or_insert_with(|| Route {
+                    client,
+                    streams: HashSet::new(),
+                })
+                .client
+                .clone();
+
+            match client.forward(packet).await {
+                Ok(()) => {
+                    debug!(
+                        "Forwarded via exit node {} (Group {})",
+                        client.base_url(),
+                        group_id
+                    );
+                    self.track(packet);
+                    return Ok(());
+                }
+                Err(e) => {
+                    warn!("Exit node {} failed: {}", client.base_url(), e);
+                    self.unpin(conn_id, &client).await;
+                }
+            }
+        }
+
+        Err(ExitClientError::ConnectionFailed(
+            "All exit nodes failed".to_string(),
+        ))
+    }
+
+    /// Follow the streams a forwarded packet opens or closes
+    fn track(&self, packet: &PlainPacket) {
+        if packet.flags.is_control {
+            return;
+        }
+        // The connection is done; a new one may go anywhere
+        if packet.stream_id == 0 {
+            if packet.flags.is_final {
+                self.routes.remove(&packet.conn_id);
+            }
+            return;
+        }
+        if let Some(mut route) = self.routes.get_mut(&packet.conn_id) {
+            if packet.flags.is_open {
+                route.streams.insert(packet.stream_id);
+            } else if packet.flags.is_final {
+                route.streams.remove(&packet.stream_id);
+            }
+        }
+    }
+
+    /// Release a connection from a node that failed, resetting the streams
+    /// it had there
+    ///
+    /// Returns the streams reset; none if the connection already moved on.
+    async fn unpin(&self, conn_id: u64, client: &SharedExitClient) -> HashSet<u32> {
+        let Some((_, route)) = self
+            .routes
+            .remove_if(&conn_id, |_, route| Arc::ptr_eq(&route.client, client))
+        else {
+            return HashSet::new();
+        };
+
+        for &stream_id in &route.streams {
+            let mut close =
+                PlainPacket::response(conn_id, self.handler_id, Vec::new()).with_stream(stream_id);
+            close.flags.is_final = true;
+            self.dispatcher.dispatch(close).await;
+        }
+        if !route.streams.is_empty() {
+            info!(
+                "Reset {} streams of conn {} after losing exit node {}",
+                route.streams.len(),
+                conn_id,
+                client.base_url()
+            );
+        }
+        route.streams
+    }
+
+    /// Nodes of a group in the order to try them, starting at the next one
+    /// in round-robin order
+    async fn candidates(&self, group_id: i32) -> Result<Vec<SharedExitClient>, ExitClientError> {
         let groups = self.groups.read().await;
 
         // Fallback to default group 0 if requested group doesn't exist
@@ -138,40 +285,12 @@ impl ExitPool {
             ));
         }
 
-        // Round-robin with health awareness
         let start_index = group.next_index.fetch_add(1, Ordering::Relaxed) % group.clients.len();
-        let mut attempts = 0;
-
-        while attempts < group.clients.len() {
-            let index = (start_index + attempts) % group.clients.len();
-            let client = &group.clients[index];
-
-            if client.is_healthy() {
-                match client.forward(packet).await {
-                    Ok(()) => {
-                        debug!(
-                            "Forwarded via exit node {} (Group {})",
-                            client.base_url(),
-                            group_id
-                        );
-                        return Ok(());
-                    }
-                    Err(e) => {
-                        warn!("Exit node {} failed: {}", client.base_url(), e);
-                        attempts += 1;
-                    }
-                }
-            } else {
-                attempts += 1;
-            }
-        }
-
-        Err(ExitClientError::ConnectionFailed(
-            "All exit nodes failed".to_string(),
-        ))
+        let (tail, head) = group.clients.split_at(start_index);
+        Ok(head.iter().chain(tail).cloned().collect())
     }
 
-    /// Run health check on all nodes
+    /// Report nodes whose tunnel is down and sample every node's load
     pub async fn health_check_all(&self) {
         let groups = self.groups.read().await;
         let mut healthy_count = 0;
@@ -179,7 +298,8 @@ impl ExitPool {
 
         for group in groups.values() {
             for client in &group.clients {
-                if client.health_check().await {
+                client.sample_load();
+                if client.is_healthy() {
                     healthy_count += 1;
                 } else {
                     warn!("Exit node {} is unhealthy", client.base_url());
@@ -221,20 +341,81 @@ impl ExitPool {
         groups.values().map(|g| g.clients.len()).sum()
     }
 
+    /// Node counts and load of every group with nodes
+    ///
+    /// Load is as of the last health check.
+    pub async fn group_loads(&self) -> HashMap<i32, GroupLoad> {
+        let groups = self.groups.read().await;
+        groups
+            .iter()
+            .map(|(id, group)| {
+                let mut stats = GroupLoad {
+                    nodes: group.clients.len(),
+                    ..Default::default()
+                };
+                for client in group.clients.iter().filter(|c| c.is_healthy()) {
+                    let load = client.load();
+                    stats.healthy += 1;
+                    stats.load.connections += load.connections;
+                    stats.load.throughput_bps += load.throughput_bps;
+                }
+                (*id, stats)
+            })
+            .collect()
+    }
+
     /// Add a new exit node dynamically
     pub async fn add_node(&self, url: String, group_id: i32) -> Result<(), ExitClientError> {
         let client_config = ExitClientConfig {
             base_url: url.clone(),
             timeout: self.config.client_timeout,
-            http2: self.config.http2,
+            ..Default::default()
         };
 
         let client = Arc::new(ExitClient::new(client_config)?);
-        // Start subscription
-        client
-            .clone()
-            .subscribe(self.handler_id, self.dispatcher.clone());
+        client.clone().start_tunnel(
+            self.handler_id,
+            self.dispatcher.clone(),
+            self.signer.clone(),
+        );
+
+        self.insert_client(group_id, client).await;
 
+        info!("Added exit node: {} to Group {}", url, group_id);
+        Ok(())
+    }
+
+    /// Carry traffic of an exit node that connected to us (reverse mode)
+    ///
+    /// The node takes part in `group_id` like a configured one until its
+    /// tunnel closes or `until` completes.
+    pub async fn serve_reverse_node<S>(
+        &self,
+        name: &str,
+        group_id: i32,
+        ws: WebSocketStream<S>,
+        until: impl Future<Output = ()>,
+    ) -> Result<(), ExitClientError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let client_config = ExitClientConfig {
+            base_url: format!("reverse://{}", name),
+            timeout: self.config.client_timeout,
+            ..Default::default()
+        };
+        let client = Arc::new(ExitClient::new(client_config)?);
+
+        self.insert_client(group_id, client.clone()).await;
+        tokio::select! {
+            _ = client.serve_tunnel(ws, self.dispatcher.as_ref()) => {}
+            _ = until => info!("Dropping exit node {}", name),
+        }
+        self.remove_client(group_id, &client).await;
+        Ok(())
+    }
+
+    async fn insert_client(&self, group_id: i32, client: SharedExitClient) {
         let mut groups = self.groups.write().await;
 
         let group = groups.entry(group_id).or_insert_with(|| GroupPool {
@@ -243,8 +424,139 @@ impl ExitPool {
         });
 
         group.clients.push(client);
+    }
 
-        info!("Added exit node: {} to Group {}", url, group_id);
-        Ok(())
+    async fn remove_client(&self, group_id: i32, client: &SharedExitClient) {
+        let mut groups = self.groups.write().await;
+        if let Some(group) = groups.get_mut(&group_id) {
+            group.clients.retain(|c| !Arc::ptr_eq(c, client));
+            // Let traffic fall back to the default group again
+            if group.clients.is_empty() {
+                groups.remove(&group_id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PacketDispatcher, TUNNEL_QUEUE, TunnelSigner, run_tunnel};
+    use async_trait::async_trait;
+    use tokio::sync::{mpsc, oneshot};
+    use tokio_tungstenite::tungstenite::protocol::Role;
+
+    struct Collect(mpsc::UnboundedSender<PlainPacket>);
+
+    #[async_trait]
+    impl PacketDispatcher for Collect {
+        async fn dispatch(&self, packet: PlainPacket) {
+            let _ = self.0.send(packet);
+        }
+    }
+
+    struct Unsigned;
+
+    impl TunnelSigner for Unsigned {
+        fn sign(&self, _: &str, _: u64) -> Option<(&'static str, String)> {
+            None
+        }
+    }
+
+    /// Connect a reverse exit node; returns what it receives and a switch
+    /// that takes it down
+    async fn reverse_node(
+        pool: &Arc<ExitPool>,
+        name: &'static str,
+    ) -> (mpsc::UnboundedReceiver<PlainPacket>, oneshot::Sender<()>) {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let handler = WebSocketStream::from_raw_socket(a, Role::Server, None).await;
+        let exit = WebSocketStream::from_raw_socket(b, Role::Client, None).await;
+
+        let (received, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (_to_handler, mut outgoing) = mpsc::channel(TUNNEL_QUEUE);
+            let _ = run_tunnel(
+                exit,
+                &mut outgoing,
+                &Collect(received),
+                Duration::from_secs(5),
+            )
+            .await;
+        });
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        let serving = pool.clone();
+        tokio::spawn(async move {
+            let until = async {
+                let _ = stopped.await;
+            };
+            serving.serve_reverse_node(name, 0, handler, until).await
+        });
+
+        // Wait until the tunnel counts as up
+        let healthy = pool.healthy_count().await;
+        while pool.healthy_count().await == healthy {
+            tokio::task::yield_now().await;
+        }
+        (rx, stop)
+    }
+
+    fn packet(conn_id: u64, stream_id: u32, open: bool) -> PlainPacket {
+        let mut packet = PlainPacket::response(conn_id, 1, vec![1; 10]).with_stream(stream_id);
+        packet.is_response = false;
+        packet.flags.is_open = open;
+        packet
+    }
+
+    #[tokio::test]
+    async fn test_connection_sticks_to_exit() {
+        let (replies, mut handler_rx) = mpsc::unbounded_channel();
+        let config = ExitPoolConfig {
+            exit_nodes: Vec::new(),
+            ..Default::default()
+        };
+        let pool = ExitPool::new(config, 1, Arc::new(Collect(replies)), Arc::new(Unsigned));
+        let pool = Arc::new(pool.unwrap());
+
+        let (mut first, stop_first) = reverse_node(&pool, "first").await;
+        let (mut second, _stop_second) = reverse_node(&pool, "second").await;
+
+        // Every packet of a connection lands on the node it started on
+        for conn_id in 1..=4 {
+            pool.forward(&packet(conn_id, 1, true), 0).await.unwrap();
+            for _ in 0..3 {
+                pool.forward(&packet(conn_id, 1, false), 0).await.unwrap();
+            }
+        }
+        let mut on_first = Vec::new();
+        for _ in 0..8 {
+            let p = first.recv().await.unwrap();
+            on_first.push(p.conn_id);
+        }
+        let mut on_second = Vec::new();
+        for _ in 0..8 {
+            let p = second.recv().await.unwrap();
+            on_second.push(p.conn_id);
+        }
+        for conn_id in &on_first {
+            assert!(!on_second.contains(conn_id));
+        }
+        let moved = on_first[0];
+
+        // Losing the node resets the connection's streams there
+        stop_first.send(()).unwrap();
+        while pool.healthy_count().await > 1 {
+            tokio::task::yield_now().await;
+        }
+        assert!(pool.forward(&packet(moved, 1, false), 0).await.is_err());
+        let close = handler_rx.recv().await.unwrap();
+        assert_eq!((close.conn_id, close.stream_id), (moved, 1));
+        assert!(close.flags.is_final);
+
+        // New streams of the connection go to the remaining node
+        pool.forward(&packet(moved, 2, true), 0).await.unwrap();
+        let p = second.recv().await.unwrap();
+        assert_eq!((p.conn_id, p.stream_id), (moved, 2));
     }
 }
@@ -0,0 +1,240 @@
+//! Persistent Handler ↔ Exit tunnel
+//!
+//! One WebSocket per handler and exit node carries `PlainPacket`s in both
+//! directions. Each binary message is a batch of packets, every one prefixed
+//! with its u32 LE length and serialized with rkyv. Senders queue packets on a
+//! bounded channel, so a slow peer pushes back on whoever produces traffic.
+
+use crate::PacketDispatcher;
+use crate::exit_client::ExitClientError;
+use apfsds_protocol::PlainPacket;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use rkyv::rancor::Error as RkyvError;
+use rkyv::util::AlignedVec;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tracing::warn;
+
+/// Path of the tunnel endpoint on exit nodes
+pub const TUNNEL_PATH: &str = "/tunnel";
+
+/// Packets queued per tunnel before senders have to wait
+pub const TUNNEL_QUEUE: usize = 1024;
+
+/// Largest batch put into one WebSocket message
+pub const MAX_BATCH_BYTES: usize = 256 * 1024;
+
+/// Append one length-prefixed packet to a batch
+pub fn encode_packet(packet: &PlainPacket, batch: &mut BytesMut) -> Result<(), ExitClientError> {
+    let bytes = rkyv::to_bytes::<RkyvError>(packet)
+        .map_err(|e| ExitClientError::SerializationError(e.to_string()))?;
+    batch.put_u32_le(bytes.len() as u32);
+    batch.extend_from_slice(&bytes);
+    Ok(())
+}
+
+/// Split a batch back into packets
+pub fn decode_batch(mut data: &[u8]) -> Result<Vec<PlainPacket>, ExitClientError> {
+    let mut packets = Vec::new();
+    // Packets sit at arbitrary offsets, rkyv wants them aligned
+    let mut aligned = AlignedVec::<16>::new();
+
+    while !data.is_empty() {
+        let truncated = || ExitClientError::SerializationError("Truncated batch".into());
+        let len_bytes = data.get(..4).ok_or_else(truncated)?;
+        let len = u32::from_le_bytes(len_bytes.try_into().unwrap()) as usize;
+        let payload = data.get(4..4 + len).ok_or_else(truncated)?;
+
+        aligned.clear();
+        aligned.extend_from_slice(payload);
+        let packet = rkyv::from_bytes::<PlainPacket, RkyvError>(&aligned)
+            .map_err(|e| ExitClientError::SerializationError(e.to_string()))?;
+        packets.push(packet);
+
+        data = &data[4 + len..];
+    }
+
+    Ok(packets)
+}
+
+/// Wait for a queued packet and batch it with whatever else is queued
+///
+/// Returns None once all senders are gone.
+pub async fn next_batch(queue: &mut mpsc::Receiver<PlainPacket>) -> Option<Bytes> {
+    let mut batch = BytesMut::new();
+    let mut next = Some(queue.recv().await?);
+
+    while let Some(packet) = next {
+        if let Err(e) = encode_packet(&packet, &mut batch) {
+            warn!(
+                "Dropping unencodable packet for conn {}: {}",
+                packet.conn_id, e
+            );
+        }
+        if batch.len() >= MAX_BATCH_BYTES {
+            break;
+        }
+        next = queue.try_recv().ok();
+    }
+
+    Some(batch.freeze())
+}
+
+/// Run a tunnel until it closes or goes quiet for `idle_timeout`
+///
+/// Packets from `outgoing` are sent in batches; packets from the peer are
+/// handed to `dispatcher` one by one, so a slow dispatcher stops reading and
+/// pushes back on the peer. Both sides ping often enough to stay inside the
+/// other's timeout.
+pub async fn run_tunnel<S>(
+    ws: WebSocketStream<S>,
+    outgoing: &mut mpsc::Receiver<PlainPacket>,
+    dispatcher: &dyn PacketDispatcher,
+    idle_timeout: Duration,
+) -> Result<(), ExitClientError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    let closed =
+        |e: tokio_tungstenite::tungstenite::Error| ExitClientError::ConnectionFailed(e.to_string());
+
+    let writer = async {
+        let mut keepalive = tokio::time::interval(idle_timeout / 3);
+        keepalive.tick().await;
+
+        loop {
+            let msg = tokio::select! {
+                batch = next_batch(outgoing) => match batch {
+                    Some(batch) => Message::Binary(batch),
+                    None => return Ok(()),
+                },
+                _ = keepalive.tick() => Message::Ping(Bytes::new()),
+            };
+            ws_tx.send(msg).await.map_err(closed)?;
+            keepalive.reset();
+        }
+    };
+
+    let reader = async {
+        loop {
+            let msg = match tokio::time::timeout(idle_timeout, ws_rx.next()).await {
+                Err(_) => return Err(ExitClientError::Timeout),
+                Ok(None) => return Ok(()),
+                Ok(Some(msg)) => msg.map_err(closed)?,
+            };
+
+            match msg {
+                Message::Binary(data) => {
+                    for packet in decode_batch(&data)? {
+                        dispatcher.dispatch(packet).await;
+                    }
+                }
+                Message::Close(_) => return Ok(()),
+                // Pongs are answered by tungstenite itself
+                _ => {}
+            }
+        }
+    };
+
+    tokio::select! {
+        result = writer => result,
+        result = reader => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use tokio_tungstenite::tungstenite::protocol::Role;
+
+    struct Collect(mpsc::UnboundedSender<PlainPacket>);
+
+    #[async_trait]
+    impl PacketDispatcher for Collect {
+        async fn dispatch(&self, packet: PlainPacket) {
+            let _ = self.0.send(packet);
+        }
+    }
+
+    fn packet(conn_id: u64, len: usize) -> PlainPacket {
+        PlainPacket::response(conn_id, 1, vec![conn_id as u8; len])
+    }
+
+    #[test]
+    fn test_batch_roundtrip() {
+        let packets = vec![packet(1, 0), packet(2, 3), packet(3, 1500)];
+
+        let mut batch = BytesMut::new();
+        for p in &packets {
+            encode_packet(p, &mut batch).unwrap();
+        }
+        assert_eq!(decode_batch(&batch).unwrap(), packets);
+
+        // A cut-off batch is rejected instead of misread
+        assert!(decode_batch(&batch[..batch.len() - 1]).is_err());
+        assert!(decode_batch(&batch[..2]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_next_batch_drains_queue() {
+        let (tx, mut rx) = mpsc::channel(TUNNEL_QUEUE);
+        for conn_id in 0..3 {
+            tx.send(packet(conn_id, 100)).await.unwrap();
+        }
+
+        let batch = next_batch(&mut rx).await.unwrap();
+        assert_eq!(decode_batch(&batch).unwrap().len(), 3);
+
+        // Large packets are split over several batches
+        for conn_id in 0..3 {
+            tx.send(packet(conn_id, MAX_BATCH_BYTES / 2)).await.unwrap();
+        }
+        let batch = next_batch(&mut rx).await.unwrap();
+        assert_eq!(decode_batch(&batch).unwrap().len(), 2);
+
+        drop(tx);
+        assert_eq!(
+            decode_batch(&next_batch(&mut rx).await.unwrap())
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(next_batch(&mut rx).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_both_directions() {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let handler = WebSocketStream::from_raw_socket(a, Role::Client, None).await;
+        let exit = WebSocketStream::from_raw_socket(b, Role::Server, None).await;
+
+        let (to_exit, mut handler_out) = mpsc::channel(TUNNEL_QUEUE);
+        let (to_handler, mut exit_out) = mpsc::channel(TUNNEL_QUEUE);
+        let (exit_tx, mut exit_rx) = mpsc::unbounded_channel();
+        let (handler_tx, mut handler_rx) = mpsc::unbounded_channel();
+
+        let timeout = Duration::from_secs(5);
+        tokio::spawn(async move {
+            run_tunnel(handler, &mut handler_out, &Collect(handler_tx), timeout).await
+        });
+        tokio::spawn(
+            async move { run_tunnel(exit, &mut exit_out, &Collect(exit_tx), timeout).await },
+        );
+
+        for conn_id in 0..100 {
+            to_exit.send(packet(conn_id, 200)).await.unwrap();
+        }
+        to_handler.send(packet(7, 10)).await.unwrap();
+
+        for conn_id in 0..100 {
+            assert_eq!(exit_rx.recv().await.unwrap().conn_id, conn_id);
+        }
+        assert_eq!(handler_rx.recv().await.unwrap(), packet(7, 10));
+    }
+}
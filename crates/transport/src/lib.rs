@@ -9,6 +9,7 @@
 
 mod exit_client;
 mod exit_pool;
+mod exit_tunnel;
 mod frame_codec;
 mod mtls;
 mod noise;
@@ -20,6 +21,7 @@ mod wss_server;
 
 pub use exit_client::*;
 pub use exit_pool::*;
+pub use exit_tunnel::*;
 pub use frame_codec::*;
 pub use mtls::*;
 pub use noise::*;
@@ -39,3 +41,13 @@ pub trait PacketDispatcher: Send + Sync {
 }
 
 pub type SharedPacketDispatcher = Arc<dyn PacketDispatcher>;
+
+/// Proves a handler to the exit nodes it opens tunnels to
+pub trait TunnelSigner: Send + Sync {
+    /// Header (name, value) for a new tunnel of `handler_id` to `base_url`
+    ///
+    /// Asked again for every connection attempt, so proofs may be single-use.
+    fn sign(&self, base_url: &str, handler_id: u64) -> Option<(&'static str, String)>;
+}
+
+pub type SharedTunnelSigner = Arc<dyn TunnelSigner>;
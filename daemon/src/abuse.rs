@@ -0,0 +1,295 @@
+//! Abuse controls on exit nodes
+//!
+//! Exit addresses get blocklisted for spam and file sharing, so exits refuse
+//! outbound SMTP, traffic that looks like BitTorrent (peer handshakes, DHT and
+//! UDP tracker requests) and new connections beyond a per-user rate. Handlers
+//! name the user in every packet, so the rate covers all of a user's
+//! connections through the exit. Each offending connection is reported to its
+//! handler, at most once per kind and `REPORT_INTERVAL`, and the handler
+//! decides what happens to the session.
+
+use crate::config::AbuseConfig;
+use crate::nat::Owner;
+use crate::rate_limit::TokenBucket;
+use apfsds_protocol::{AbuseKind, ControlMessage, PlainPacket};
+use dashmap::DashMap;
+use etherparse::{SlicedPacket, TransportSlice};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Least time between two reports of the same connection and kind
+const REPORT_INTERVAL: Duration = Duration::from_secs(60);
+
+const SMTP_PORT: u16 = 25;
+
+/// Start of every BitTorrent peer handshake
+const BT_HANDSHAKE: &[u8] = b"\x13BitTorrent protocol";
+
+/// Protocol ID opening UDP tracker connect requests (BEP 15)
+const UDP_TRACKER_MAGIC: [u8; 8] = 0x0417_2710_1980_u64.to_be_bytes();
+
+/// Length of a uTP header without extensions (BEP 29)
+const UTP_HEADER: usize = 20;
+
+/// New-connection budget of one user
+struct ConnLimit {
+    bucket: TokenBucket,
+    last_seen: Instant,
+}
+
+/// Abuse state of one exit node
+pub struct AbuseGuard {
+    config: AbuseConfig,
+    limits: DashMap<u64, ConnLimit>,
+    /// Last report of each connection, per kind
+    reported: DashMap<Owner, HashMap<AbuseKind, Instant>>,
+}
+
+impl AbuseGuard {
+    pub fn new(config: &AbuseConfig) -> Self {
+        Self {
+            config: config.clone(),
+            limits: DashMap::new(),
+            reported: DashMap::new(),
+        }
+    }
+
+    /// Check a new connection of `user_id`; `tcp_port` is its TCP destination port
+    pub fn admit(&self, user_id: u64, tcp_port: Option<u16>) -> Result<(), AbuseKind> {
+        if self.config.block_smtp && tcp_port == Some(SMTP_PORT) {
+            return Err(AbuseKind::Smtp);
+        }
+
+        let cap = u64::from(self.config.max_new_connections_per_sec);
+        if cap == 0 {
+            return Ok(());
+        }
+        let mut limit = self.limits.entry(user_id).or_insert_with(|| ConnLimit {
+            bucket: TokenBucket::new(cap, cap),
+            last_seen: Instant::now(),
+        });
+        limit.last_seen = Instant::now();
+        if !limit.bucket.try_take(1) {
+            return Err(AbuseKind::ConnectionRate);
+        }
+        Ok(())
+    }
+
+    /// Check data sent on a connection; `datagram` for UDP payloads
+    pub fn inspect(&self, data: &[u8], datagram: bool) -> Result<(), AbuseKind> {
+        if self.config.block_bittorrent && is_bittorrent(data, datagram) {
+            return Err(AbuseKind::BitTorrent);
+        }
+        Ok(())
+    }
+
+    /// Check a stream packet: opens count as new connections
+    pub fn screen_stream(&self, packet: &PlainPacket) -> Result<(), AbuseKind> {
+        if packet.flags.is_open {
+            let tcp = !packet.flags.is_datagram && !packet.flags.is_bind;
+            self.admit(packet.user_id, tcp.then_some(packet.rport))?;
+        }
+        self.inspect(&packet.payload, packet.flags.is_datagram)
+    }
+
+    /// Check an IP packet of `user_id` headed into the TUN; `new_flow` if NAT
+    /// has no flow for it yet
+    pub fn screen_ip(&self, user_id: u64, packet: &[u8], new_flow: bool) -> Result<(), AbuseKind> {
+        let Ok(sliced) = SlicedPacket::from_ip(packet) else {
+            return Ok(());
+        };
+        let (tcp_port, datagram) = match &sliced.transport {
+            Some(TransportSlice::Tcp(tcp)) => (Some(tcp.destination_port()), false),
+            Some(TransportSlice::Udp(_)) => (None, true),
+            _ => (None, false),
+        };
+
+        if new_flow {
+            self.admit(user_id, tcp_port)?;
+        }
+        self.inspect(sliced.payload, datagram)
+    }
+
+    /// Report of `owner` for `kind`, unless one went out recently
+    pub fn report(&self, owner: Owner, kind: AbuseKind) -> Option<PlainPacket> {
+        self.report_at(owner, kind, Instant::now())
+    }
+
+    fn report_at(&self, owner: Owner, kind: AbuseKind, now: Instant) -> Option<PlainPacket> {
+        {
+            let mut reported = self.reported.entry(owner).or_default();
+            match reported.get(&kind) {
+                Some(last) if now.duration_since(*last) < REPORT_INTERVAL => return None,
+                _ => reported.insert(kind, now),
+            };
+        }
+
+        let msg = ControlMessage::AbuseReport { kind };
+        let payload = rkyv::to_bytes::<rkyv::rancor::Error>(&msg).ok()?;
+        let (handler_id, conn_id) = owner;
+        let mut packet = PlainPacket::response(conn_id, handler_id, payload.to_vec());
+        packet.flags.is_control = true;
+        Some(packet)
+    }
+
+    /// Forget a closed connection; its user's budget stays until it idles out
+    pub fn release(&self, owner: Owner) {
+        self.reported.remove(&owner);
+    }
+
+    /// Forget users and connections idle for `REPORT_INTERVAL`, in case a close
+    /// was lost
+    pub fn expire(&self) {
+        self.expire_at(Instant::now());
+    }
+
+    fn expire_at(&self, now: Instant) {
+        self.limits
+            .retain(|_, limit| now.duration_since(limit.last_seen) < REPORT_INTERVAL);
+        self.reported.retain(|_, kinds| {
+            kinds.retain(|_, last| now.duration_since(*last) < REPORT_INTERVAL);
+            !kinds.is_empty()
+        });
+    }
+}
+
+/// BitTorrent peer handshake (over TCP or uTP), DHT message or UDP tracker request
+fn is_bittorrent(data: &[u8], datagram: bool) -> bool {
+    if data.starts_with(BT_HANDSHAKE) {
+        return true;
+    }
+    if !datagram {
+        return false;
+    }
+
+    // uTP data packets (version 1, type ST_DATA) carry the handshake after the header
+    let utp_handshake = data.first() == Some(&0x01)
+        && data
+            .get(UTP_HEADER..)
+            .is_some_and(|rest| rest.starts_with(BT_HANDSHAKE));
+    utp_handshake || is_dht(data) || data.starts_with(&UDP_TRACKER_MAGIC)
+}
+
+/// KRPC message (BEP 5): a bencoded dictionary with a type and a 20-byte node ID
+fn is_dht(data: &[u8]) -> bool {
+    let contains = |needle: &[u8]| data.windows(needle.len()).any(|w| w == needle);
+    data.starts_with(b"d1:") && contains(b"1:y1:") && contains(b"2:id20:")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use etherparse::PacketBuilder;
+
+    const OWNER: Owner = (1, 42);
+    const USER: u64 = 7;
+
+    #[test]
+    fn test_bittorrent_detection() {
+        let mut handshake = BT_HANDSHAKE.to_vec();
+        handshake.extend_from_slice(&[0; 48]);
+        assert!(is_bittorrent(&handshake, false));
+
+        let mut utp = vec![0x01; UTP_HEADER];
+        utp.extend_from_slice(&handshake);
+        assert!(is_bittorrent(&utp, true));
+        assert!(!is_bittorrent(&utp, false));
+
+        let ping = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        assert!(is_bittorrent(ping, true));
+
+        let mut connect = UDP_TRACKER_MAGIC.to_vec();
+        connect.extend_from_slice(&[0, 0, 0, 0, 1, 2, 3, 4]);
+        assert!(is_bittorrent(&connect, true));
+
+        assert!(!is_bittorrent(b"GET / HTTP/1.1\r\n\r\n", false));
+        assert!(!is_bittorrent(b"d1:ae", true));
+    }
+
+    #[test]
+    fn test_admit() {
+        let config = AbuseConfig {
+            max_new_connections_per_sec: 2,
+            ..Default::default()
+        };
+        let guard = AbuseGuard::new(&config);
+
+        assert_eq!(guard.admit(USER, Some(25)), Err(AbuseKind::Smtp));
+        assert_eq!(guard.admit(USER, Some(443)), Ok(()));
+        assert_eq!(guard.admit(USER, None), Ok(()));
+        assert_eq!(guard.admit(USER, Some(443)), Err(AbuseKind::ConnectionRate));
+        // Other users have their own budget
+        assert_eq!(guard.admit(USER + 1, Some(443)), Ok(()));
+
+        // Closing a connection does not restore the user's budget
+        guard.release(OWNER);
+        assert_eq!(guard.admit(USER, Some(443)), Err(AbuseKind::ConnectionRate));
+        guard.expire_at(Instant::now() + REPORT_INTERVAL);
+        assert_eq!(guard.admit(USER, Some(443)), Ok(()));
+
+        let open = AbuseGuard::new(&AbuseConfig {
+            block_smtp: false,
+            max_new_connections_per_sec: 0,
+            ..Default::default()
+        });
+        for _ in 0..100 {
+            assert_eq!(open.admit(USER, Some(25)), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_screen_ip() {
+        let guard = AbuseGuard::new(&AbuseConfig::default());
+        let mut smtp = Vec::new();
+        PacketBuilder::ipv4([10, 0, 0, 1], [203, 0, 113, 5], 64)
+            .tcp(40000, 25, 1, 1024)
+            .syn()
+            .write(&mut smtp, &[])
+            .unwrap();
+        assert_eq!(guard.screen_ip(USER, &smtp, true), Err(AbuseKind::Smtp));
+
+        let mut dht = Vec::new();
+        PacketBuilder::ipv4([10, 0, 0, 1], [203, 0, 113, 5], 64)
+            .udp(6881, 6881)
+            .write(&mut dht, b"d1:rd2:id20:abcdefghij0123456789e1:t2:aa1:y1:re")
+            .unwrap();
+        assert_eq!(
+            guard.screen_ip(USER, &dht, false),
+            Err(AbuseKind::BitTorrent)
+        );
+    }
+
+    #[test]
+    fn test_reports_are_spaced() {
+        let guard = AbuseGuard::new(&AbuseConfig::default());
+        let start = Instant::now();
+
+        let report = guard.report_at(OWNER, AbuseKind::Smtp, start).unwrap();
+        assert!(report.flags.is_control && report.is_response);
+        assert_eq!((report.handler_id, report.conn_id), OWNER);
+        let msg = rkyv::from_bytes::<ControlMessage, rkyv::rancor::Error>(&report.payload);
+        assert!(matches!(
+            msg,
+            Ok(ControlMessage::AbuseReport {
+                kind: AbuseKind::Smtp
+            })
+        ));
+
+        assert!(guard.report_at(OWNER, AbuseKind::Smtp, start).is_none());
+        assert!(
+            guard
+                .report_at(OWNER, AbuseKind::BitTorrent, start)
+                .is_some()
+        );
+        let later = start + REPORT_INTERVAL;
+        assert!(guard.report_at(OWNER, AbuseKind::Smtp, later).is_some());
+
+        // A closed connection is forgotten with all of its reports
+        guard.release(OWNER);
+        assert!(guard.reported.is_empty());
+        assert!(guard.report_at(OWNER, AbuseKind::Smtp, later).is_some());
+
+        guard.expire_at(later + REPORT_INTERVAL);
+        assert!(guard.reported.is_empty());
+    }
+}
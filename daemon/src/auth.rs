@@ -33,6 +33,14 @@ pub enum AuthError {
     CryptoError(String),
 }
 
+/// Identity carried by a verified token
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenClaims {
+    pub user_id: u64,
+    /// Device the token was issued to
+    pub client_pk: [u8; 32],
+}
+
 /// Authenticator for handling client authentication
 pub struct Authenticator {
     /// Server key pair (ML-DSA-65)
@@ -111,7 +119,7 @@ impl Authenticator {
     }
 
     /// Generate a one-time token
-    pub fn generate_token(&self, user_id: u64, nonce: &[u8; 32]) -> Vec<u8> {
+    pub fn generate_token(&self, user_id: u64, nonce: &[u8; 32], client_pk: &[u8; 32]) -> Vec<u8> {
         let now = std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)
             .unwrap()
@@ -120,6 +128,7 @@ impl Authenticator {
         let payload = TokenPayload {
             user_id,
             nonce: *nonce,
+            client_pk: *client_pk,
             issued_at: now,
             valid_until: now + self.token_ttl_ms,
         };
@@ -140,7 +149,7 @@ impl Authenticator {
     }
 
     /// Verify and consume a one-time token
-    pub fn verify_and_consume_token(&self, token: &[u8]) -> Result<u64, AuthError> {
+    pub fn verify_and_consume_token(&self, token: &[u8]) -> Result<TokenClaims, AuthError> {
         let decoded = base64::Engine::decode(&base64::engine::general_purpose::STANDARD, token)
             .map_err(|_| AuthError::InvalidSignature)?;
 
@@ -178,7 +187,10 @@ impl Authenticator {
             return Err(AuthError::TokenAlreadyUsed);
         }
 
-        Ok(archived.user_id.to_native())
+        Ok(TokenClaims {
+            user_id: archived.user_id.to_native(),
+            client_pk: archived.client_pk,
+        })
     }
 
     /// Run cleanup tasks
@@ -218,10 +230,11 @@ mod tests {
         let auth = create_auth();
         let nonce = [1u8; 32];
 
-        let token = auth.generate_token(12345, &nonce);
-        let user_id = auth.verify_and_consume_token(&token).unwrap();
+        let token = auth.generate_token(12345, &nonce, &[2u8; 32]);
+        let claims = auth.verify_and_consume_token(&token).unwrap();
 
-        assert_eq!(user_id, 12345);
+        assert_eq!(claims.user_id, 12345);
+        assert_eq!(claims.client_pk, [2u8; 32]);
     }
 
     #[test]
@@ -229,7 +242,7 @@ mod tests {
         let auth = create_auth();
         let nonce = [1u8; 32];
 
-        let token = auth.generate_token(12345, &nonce);
+        let token = auth.generate_token(12345, &nonce, &[2u8; 32]);
 
         // First use should succeed
         assert!(auth.verify_and_consume_token(&token).is_ok());
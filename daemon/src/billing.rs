@@ -1,30 +1,259 @@
-use apfsds_storage::postgres::PgClient;
-use std::collections::HashMap;
+//! Usage billing and quota enforcement
+//!
+//! Traffic in both directions is counted per session, aggregated in memory and
+//! flushed to Postgres every `flush_interval`, deducting its cost from the
+//! user's balance. After each flush the day and month totals are reloaded, so
+//! quotas hold across handlers. Between refreshes sessions add their traffic
+//! to the user's totals every `SESSION_BATCH_BYTES`, so the data path only
+//! needs a map lookup to tell whether a user is over quota.
+//!
+//! Each flush is one batch with its own id, applied by the database at most
+//! once. Batches the database rejects are appended to an on-disk spill and
+//! retried on every later flush (including the first one after a restart), so
+//! an outage delays billing instead of losing it, and a batch that was applied
+//! although its flush failed is not billed again.
+
+use crate::config::{BillingConfig, OverQuotaAction, PlanConfig};
+use crate::group_cache::DEFAULT_GROUP_ID;
+use apfsds_protocol::{ControlMessage, QuotaKind};
+use apfsds_storage::Wal;
+use apfsds_storage::postgres::{PgClient, UsageCharge, UserUsage};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::time::Duration;
 use tokio::sync::Mutex;
-use tracing::{error, info};
+use tracing::{error, info, warn};
+
+const GIB: i128 = 1 << 30;
+
+/// Traffic a session counts on its own before adding it to the user's totals
+const SESSION_BATCH_BYTES: u64 = 256 * 1024;
+
+/// In-memory quota state of one user
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Account {
+    daily_used: u64,
+    monthly_used: u64,
+    daily_limit: Option<u64>,
+    monthly_limit: Option<u64>,
+    /// Balance as of the last refresh
+    balance: i64,
+}
+
+impl Account {
+    /// Build from database totals plus traffic not yet flushed
+    fn new(usage: &UserUsage, unflushed: u64, plans: &[PlanConfig]) -> Self {
+        let group_id = usage.group_id.unwrap_or(DEFAULT_GROUP_ID);
+        let plan = plans.iter().find(|p| p.group_id == group_id);
+        Self {
+            daily_used: usage.daily_bytes.max(0) as u64 + unflushed,
+            monthly_used: usage.monthly_bytes.max(0) as u64 + unflushed,
+            daily_limit: plan.and_then(|p| p.daily_bytes),
+            monthly_limit: usage
+                .quota_bytes
+                .map(|q| q.max(0) as u64)
+                .or(plan.and_then(|p| p.monthly_bytes)),
+            balance: usage.balance,
+        }
+    }
+
+    /// The first limit this account has run into
+    fn exhausted(&self, price_per_gib: i64) -> Option<QuotaKind> {
+        if self
+            .daily_limit
+            .is_some_and(|limit| self.daily_used >= limit)
+        {
+            Some(QuotaKind::Daily)
+        } else if self
+            .monthly_limit
+            .is_some_and(|limit| self.monthly_used >= limit)
+        {
+            Some(QuotaKind::Monthly)
+        } else if price_per_gib > 0 && self.balance <= 0 {
+            Some(QuotaKind::Balance)
+        } else {
+            None
+        }
+    }
+}
+
+/// Cost of `bytes` at `price_per_gib`, carrying fractions between flushes
+/// Returns (cost, new carry)
+fn charge(bytes: u64, price_per_gib: i64, carry: i128) -> (i64, i128) {
+    let total = bytes as i128 * price_per_gib as i128 + carry;
+    ((total / GIB) as i64, total % GIB)
+}
+
+/// Take the traffic counted by sessions, forgetting sessions that ended
+fn drain_sessions(sessions: &mut Vec<(i64, Arc<AtomicU64>)>) -> Vec<(i64, u64)> {
+    let mut drained = Vec::new();
+    sessions.retain(|(user_id, pending)| {
+        // Checked first: a session ending after this still gets drained next time
+        let alive = Arc::strong_count(pending) > 1;
+        let bytes = pending.swap(0, Ordering::Relaxed);
+        if bytes > 0 {
+            drained.push((*user_id, bytes));
+        }
+        alive
+    });
+    drained
+}
+
+/// Charges of one flush, applied to the database at most once
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Batch {
+    id: String,
+    charges: Vec<UsageCharge>,
+}
+
+impl Batch {
+    fn new(charges: Vec<UsageCharge>) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            charges,
+        }
+    }
+}
+
+/// Batches not yet written to the database, one WAL entry per failed flush
+struct Spill {
+    path: PathBuf,
+    wal: Wal,
+}
+
+impl Spill {
+    fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            wal: Wal::open(path)?,
+        })
+    }
+
+    /// All spilled batches
+    ///
+    /// A damaged tail, e.g. from a crash in the middle of a write, is cut off
+    /// so the entries before it are kept.
+    fn load(&mut self) -> Vec<Batch> {
+        let entries = match self.wal.recover() {
+            Ok((entries, 0)) => entries,
+            Ok((entries, dropped)) => {
+                error!(
+                    "Billing spill {} is damaged, dropped {} bytes after its last good entry",
+                    self.path.display(),
+                    dropped
+                );
+                entries
+            }
+            Err(e) => {
+                error!(
+                    "Failed to read billing spill {}: {}",
+                    self.path.display(),
+                    e
+                );
+                return Vec::new();
+            }
+        };
+
+        entries
+            .iter()
+            .filter_map(|entry| match serde_json::from_slice::<Batch>(entry) {
+                Ok(batch) => Some(batch),
+                Err(e) => {
+                    warn!("Skipping malformed billing spill entry: {}", e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn push(&self, batch: &Batch) -> io::Result<()> {
+        let entry = serde_json::to_vec(batch)?;
+        self.wal.append(&entry)?;
+        self.wal.sync()
+    }
+
+    fn clear(&self) -> io::Result<()> {
+        self.wal.truncate(0)
+    }
+}
 
 /// Aggregates user usage and flushes to database periodically
 pub struct BillingAggregator {
     pg_client: PgClient,
     usage: Arc<Mutex<HashMap<i64, u64>>>,
     flush_interval: Duration,
+    config: BillingConfig,
+    /// Quota state of users known to the database
+    accounts: DashMap<i64, Account>,
+    /// Fractional cost not yet deducted, per user
+    carry: Mutex<HashMap<i64, i128>>,
+    spill: Mutex<Spill>,
+    /// Traffic counted by each session and not yet aggregated
+    sessions: std::sync::Mutex<Vec<(i64, Arc<AtomicU64>)>>,
 }
 
 impl BillingAggregator {
-    pub fn new(pg_client: PgClient) -> Self {
-        Self {
+    pub fn new(pg_client: PgClient, config: &BillingConfig) -> io::Result<Self> {
+        let mut spill = Spill::open(Path::new(&config.spill_path))?;
+        let pending = spill.load().len();
+        if pending > 0 {
+            info!("Replaying {} spilled billing batches", pending);
+        }
+
+        Ok(Self {
             pg_client,
             usage: Arc::new(Mutex::new(HashMap::new())),
-            flush_interval: Duration::from_secs(60),
-        }
+            flush_interval: Duration::from_secs(config.flush_interval.max(1)),
+            config: config.clone(),
+            accounts: DashMap::new(),
+            carry: Mutex::new(HashMap::new()),
+            spill: Mutex::new(spill),
+            sessions: std::sync::Mutex::new(Vec::new()),
+        })
     }
 
     /// Record usage for a user
     pub async fn record_usage(&self, user_id: i64, bytes: u64) {
-        let mut usage = self.usage.lock().await;
-        *usage.entry(user_id).or_default() += bytes;
+        {
+            let mut usage = self.usage.lock().await;
+            *usage.entry(user_id).or_default() += bytes;
+        }
+        if let Some(mut account) = self.accounts.get_mut(&user_id) {
+            account.daily_used += bytes;
+            account.monthly_used += bytes;
+        }
+    }
+
+    /// The limit `user_id` has run into, if any
+    pub fn check(&self, user_id: i64) -> Option<QuotaKind> {
+        self.accounts
+            .get(&user_id)?
+            .exhausted(self.config.price_per_gib)
+    }
+
+    /// Whether over-quota users are refused instead of throttled
+    pub fn closes_sessions(&self) -> bool {
+        self.config.over_quota == OverQuotaAction::Close
+    }
+
+    /// Quota enforcement for one session of `user_id`
+    pub fn session(self: &Arc<Self>, user_id: i64) -> SessionQuota {
+        let pending = Arc::new(AtomicU64::new(0));
+        self.sessions
+            .lock()
+            .unwrap()
+            .push((user_id, pending.clone()));
+        SessionQuota {
+            billing: self.clone(),
+            user_id,
+            pending,
+            notified: AtomicBool::new(false),
+        }
     }
 
     /// Start the flush loop
@@ -34,31 +263,285 @@ impl BillingAggregator {
             loop {
                 interval.tick().await;
                 self.flush().await;
+                self.refresh().await;
             }
         })
     }
 
-    /// Flush aggregated usage to database
+    /// Flush aggregated usage, and anything spilled earlier, to database
     async fn flush(&self) {
-        let mut usage_map = {
-            let mut usage = self.usage.lock().await;
-            if usage.is_empty() {
+        let drained = drain_sessions(&mut self.sessions.lock().unwrap());
+        for (user_id, bytes) in drained {
+            self.record_usage(user_id, bytes).await;
+        }
+        let usage_map = std::mem::take(&mut *self.usage.lock().await);
+
+        // Costs are settled here: from now on the charge is either in the
+        // database or in the spill
+        let charges: Vec<UsageCharge> = {
+            let mut carry = self.carry.lock().await;
+            usage_map
+                .into_iter()
+                .map(|(user_id, bytes)| {
+                    let user_carry = carry.get(&user_id).copied().unwrap_or(0);
+                    let (cost, remainder) = charge(bytes, self.config.price_per_gib, user_carry);
+                    carry.insert(user_id, remainder);
+                    UsageCharge {
+                        user_id,
+                        bytes,
+                        cost,
+                    }
+                })
+                .collect()
+        };
+
+        let mut spill = self.spill.lock().await;
+        let spilled = spill.load();
+        if charges.is_empty() && spilled.is_empty() {
+            return;
+        }
+
+        // Oldest first; batches applied before are skipped by the database
+        let mut replayed = spilled.len();
+        for batch in &spilled {
+            if let Err(e) = self
+                .pg_client
+                .charge_usage_batch(&batch.id, &batch.charges)
+                .await
+            {
+                warn!("Failed to replay spilled billing: {}", e);
+                replayed = 0;
+                break;
+            }
+        }
+        if replayed > 0 {
+            info!("Replayed {} spilled billing batches", replayed);
+            if let Err(e) = spill.clear() {
+                // Harmless: replaying them again skips them
+                warn!("Failed to clear billing spill: {}", e);
+            }
+        }
+
+        if charges.is_empty() {
+            return;
+        }
+        let batch = Batch::new(charges);
+        info!("Flushing billing for {} users", batch.charges.len());
+        if let Err(e) = self
+            .pg_client
+            .charge_usage_batch(&batch.id, &batch.charges)
+            .await
+        {
+            warn!(
+                "Failed to flush billing, spilling {} charges: {}",
+                batch.charges.len(),
+                e
+            );
+            if let Err(e) = spill.push(&batch) {
+                error!("Failed to spill billing, usage is lost: {}", e);
+            }
+        }
+    }
+
+    /// Reload day/month totals and limits from the database
+    async fn refresh(&self) {
+        let users = match self.pg_client.load_usage().await {
+            Ok(users) => users,
+            Err(e) => {
+                warn!("Failed to refresh quotas: {}", e);
                 return;
             }
-            // Swap with empty map
-            std::mem::take(&mut *usage)
         };
+        let unflushed = self.usage.lock().await.clone();
 
-        info!("Flushing billing for {} users", usage_map.len());
+        let mut known = HashSet::with_capacity(users.len());
+        for usage in &users {
+            let pending = unflushed.get(&usage.id).copied().unwrap_or(0);
+            let account = Account::new(usage, pending, &self.config.plans);
+            let newly_exhausted = account
+                .exhausted(self.config.price_per_gib)
+                .filter(|_| self.check(usage.id).is_none());
+            if let Some(kind) = newly_exhausted {
+                info!("User {} is over {:?} quota", usage.id, kind);
+            }
+            self.accounts.insert(usage.id, account);
+            known.insert(usage.id);
+        }
+        self.accounts.retain(|id, _| known.contains(id));
+    }
+}
+
+/// What to do with traffic of a session
+#[derive(Debug, PartialEq)]
+pub enum QuotaVerdict {
+    Pass,
+    /// Forward after this delay
+    Throttle(Duration),
+    /// End the session
+    Close,
+}
+
+/// Per-session quota enforcement on the data path
+pub struct SessionQuota {
+    billing: Arc<BillingAggregator>,
+    user_id: i64,
+    /// Traffic not yet added to the user's totals; the flush loop takes the rest
+    pending: Arc<AtomicU64>,
+    notified: AtomicBool,
+}
 
-        for (user_id, bytes) in usage_map.drain() {
-            // Update balance and log usage
-            // We do this individually for now. In high load, use batch update.
-            if let Err(e) = self.pg_client.record_usage(user_id, bytes).await {
-                error!("Failed to record usage for user {}: {}", user_id, e);
-                // Re-queue? simpler to just log error for Phase 3.
-                // In prod, we should re-queue or have WAL.
+impl SessionQuota {
+    /// Account `bytes` of traffic and decide how to treat it
+    pub async fn consume(&self, bytes: u64) -> QuotaVerdict {
+        let pending = self.pending.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if pending >= SESSION_BATCH_BYTES {
+            let pending = self.pending.swap(0, Ordering::Relaxed);
+            self.billing.record_usage(self.user_id, pending).await;
+        }
+        if self.billing.check(self.user_id).is_none() {
+            return QuotaVerdict::Pass;
+        }
+
+        match self.billing.config.over_quota {
+            OverQuotaAction::Close => QuotaVerdict::Close,
+            OverQuotaAction::Throttle => {
+                let rate = self.billing.config.throttle_bps.max(1) as f64;
+                QuotaVerdict::Throttle(Duration::from_secs_f64(bytes as f64 / rate))
             }
         }
     }
+
+    /// Client notification, returned once per session after exhaustion
+    pub fn notice(&self) -> Option<ControlMessage> {
+        let kind = self.billing.check(self.user_id)?;
+        if self.notified.swap(true, Ordering::Relaxed) {
+            return None;
+        }
+        Some(ControlMessage::QuotaExceeded {
+            kind,
+            throttled: !self.billing.closes_sessions(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(group_id: Option<i32>, quota_bytes: Option<i64>, daily: i64) -> UserUsage {
+        UserUsage {
+            id: 1,
+            group_id,
+            quota_bytes,
+            balance: 100,
+            daily_bytes: daily,
+            monthly_bytes: daily * 2,
+        }
+    }
+
+    fn plans() -> Vec<PlanConfig> {
+        vec![PlanConfig {
+            name: "basic".to_string(),
+            group_id: 0,
+            monthly_bytes: Some(10_000),
+            daily_bytes: Some(1_000),
+        }]
+    }
+
+    #[test]
+    fn test_account_limits() {
+        let account = Account::new(&usage(None, None, 400), 100, &plans());
+        assert_eq!(account.daily_used, 500);
+        assert_eq!(account.monthly_used, 900);
+        assert_eq!(account.daily_limit, Some(1_000));
+        assert_eq!(account.exhausted(0), None);
+
+        // Unflushed traffic counts towards the quota
+        let account = Account::new(&usage(None, None, 400), 600, &plans());
+        assert_eq!(account.exhausted(0), Some(QuotaKind::Daily));
+
+        // A per-user quota overrides the plan's monthly quota
+        let account = Account::new(&usage(None, Some(300), 200), 0, &plans());
+        assert_eq!(account.exhausted(0), Some(QuotaKind::Monthly));
+
+        // Groups without a plan are unlimited
+        let account = Account::new(&usage(Some(7), None, 1 << 40), 0, &plans());
+        assert_eq!(account.exhausted(0), None);
+    }
+
+    #[test]
+    fn test_balance_exhaustion() {
+        let mut account = Account::new(&usage(Some(7), None, 0), 0, &plans());
+        account.balance = 0;
+        assert_eq!(account.exhausted(0), None);
+        assert_eq!(account.exhausted(10), Some(QuotaKind::Balance));
+    }
+
+    #[test]
+    fn test_charge_carries_fractions() {
+        // Half a GiB at 3 units/GiB: 1 unit now, half a unit carried
+        let (cost, carry) = charge(1 << 29, 3, 0);
+        assert_eq!(cost, 1);
+        let (cost, carry) = charge(1 << 29, 3, carry);
+        assert_eq!(cost, 2);
+        assert_eq!(carry, 0);
+
+        assert_eq!(charge(1 << 30, 0, 0), (0, 0));
+    }
+
+    #[test]
+    fn test_drain_sessions() {
+        let live = Arc::new(AtomicU64::new(100));
+        let ended = Arc::new(AtomicU64::new(20));
+        let mut sessions = vec![(1, live.clone()), (2, ended)];
+
+        assert_eq!(drain_sessions(&mut sessions), vec![(1, 100), (2, 20)]);
+        assert_eq!(sessions.len(), 1);
+
+        live.fetch_add(5, Ordering::Relaxed);
+        drop(live);
+        // The last bytes of a session are drained once it is gone
+        assert_eq!(drain_sessions(&mut sessions), vec![(1, 5)]);
+        assert!(sessions.is_empty());
+    }
+
+    #[test]
+    fn test_spill_roundtrip() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("billing.wal");
+        let batch = |user_id| {
+            Batch::new(vec![UsageCharge {
+                user_id,
+                bytes: 100,
+                cost: 1,
+            }])
+        };
+        let (first, second) = (batch(1), batch(2));
+        assert_ne!(first.id, second.id);
+
+        {
+            let spill = Spill::open(&path).unwrap();
+            spill.push(&first).unwrap();
+            spill.push(&second).unwrap();
+        }
+
+        // Survives a restart
+        let mut spill = Spill::open(&path).unwrap();
+        assert_eq!(spill.load(), vec![first.clone(), second.clone()]);
+
+        spill.clear().unwrap();
+        assert!(spill.load().is_empty());
+        spill.push(&first).unwrap();
+        assert_eq!(spill.load(), vec![first.clone()]);
+
+        // A torn write loses only the entry being written
+        drop(spill);
+        let mut data = std::fs::read(&path).unwrap();
+        data.extend_from_slice(&[0, 0, 0, 1, 0, 0]);
+        std::fs::write(&path, &data).unwrap();
+        let mut spill = Spill::open(&path).unwrap();
+        assert_eq!(spill.load(), vec![first.clone()]);
+        spill.push(&second).unwrap();
+        assert_eq!(spill.load(), vec![first, second]);
+    }
 }
@@ -2,7 +2,7 @@
 
 use anyhow::Result;
 use serde::Deserialize;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
 
 /// Daemon configuration
@@ -35,6 +35,38 @@ pub struct DaemonConfig {
     /// Monitoring configuration
     #[serde(default)]
     pub monitoring: MonitoringConfig,
+
+    /// DNS resolver configuration (answers client DohQuery messages)
+    #[serde(default)]
+    pub dns: DnsConfig,
+
+    /// Billing and quota configuration
+    #[serde(default)]
+    pub billing: BillingConfig,
+
+    /// Bandwidth limits per user and exit group
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+
+    /// Concurrent session and device limits per user
+    #[serde(default)]
+    pub session_limit: SessionLimitConfig,
+
+    /// Capacity exits are measured against when reporting group load
+    #[serde(default)]
+    pub exit_load: ExitLoadConfig,
+
+    /// Idle timeouts of the exit node's NAT flows
+    #[serde(default)]
+    pub nat: NatConfig,
+
+    /// Destinations exit nodes refuse to forward to
+    #[serde(default)]
+    pub egress_acl: EgressAclConfig,
+
+    /// Abuse controls on exits and what handlers do with reported sessions
+    #[serde(default)]
+    pub abuse: AbuseConfig,
 }
 
 impl DaemonConfig {
@@ -76,11 +108,23 @@ impl DaemonConfig {
         if other.server.max_connections != default_max_connections() {
             self.server.max_connections = other.server.max_connections;
         }
+        if other.server.public_ip.is_some() {
+            self.server.public_ip = other.server.public_ip;
+        }
+        if other.server.exit_key.is_some() {
+            self.server.exit_key = other.server.exit_key;
+        }
+        if other.server.exit_backend != ExitBackend::default() {
+            self.server.exit_backend = other.server.exit_backend;
+        }
 
         // Raft config
         if other.raft.node_id != 1 {
             self.raft.node_id = other.raft.node_id;
         }
+        if other.raft.peer_secret.is_some() {
+            self.raft.peer_secret = other.raft.peer_secret;
+        }
         if !other.raft.peers.is_empty() {
             // Merge peers by value (simple strings)
             for peer in other.raft.peers {
@@ -97,6 +141,7 @@ impl DaemonConfig {
                 existing.endpoint = node.endpoint;
                 existing.weight = node.weight;
                 existing.group_id = node.group_id;
+                existing.key = node.key;
             } else {
                 // Add new node
                 self.exit_nodes.push(node);
@@ -110,6 +155,9 @@ impl DaemonConfig {
         if other.security.hmac_secret.is_some() {
             self.security.hmac_secret = other.security.hmac_secret;
         }
+        if other.security.credential_key.is_some() {
+            self.security.credential_key = other.security.credential_key;
+        }
         if other.security.token_ttl != default_token_ttl() {
             self.security.token_ttl = other.security.token_ttl;
         }
@@ -126,6 +174,140 @@ impl DaemonConfig {
         if other.monitoring.prometheus_bind != default_prometheus_bind() {
             self.monitoring.prometheus_bind = other.monitoring.prometheus_bind;
         }
+
+        // DNS
+        if other.dns.upstreams != default_dns_upstreams() {
+            self.dns.upstreams = other.dns.upstreams;
+        }
+        if other.dns.timeout_ms != default_dns_timeout_ms() {
+            self.dns.timeout_ms = other.dns.timeout_ms;
+        }
+        if other.dns.cache_size != default_dns_cache_size() {
+            self.dns.cache_size = other.dns.cache_size;
+        }
+        if other.dns.max_qps_per_user != default_dns_max_qps() {
+            self.dns.max_qps_per_user = other.dns.max_qps_per_user;
+        }
+
+        // Billing: plans merge by name
+        if other.billing.flush_interval != default_billing_flush_interval() {
+            self.billing.flush_interval = other.billing.flush_interval;
+        }
+        if other.billing.price_per_gib != 0 {
+            self.billing.price_per_gib = other.billing.price_per_gib;
+        }
+        if other.billing.over_quota != OverQuotaAction::default() {
+            self.billing.over_quota = other.billing.over_quota;
+        }
+        if other.billing.throttle_bps != default_throttle_bps() {
+            self.billing.throttle_bps = other.billing.throttle_bps;
+        }
+        if other.billing.spill_path != default_billing_spill_path() {
+            self.billing.spill_path = other.billing.spill_path;
+        }
+        for plan in other.billing.plans {
+            if let Some(existing) = self.billing.plans.iter_mut().find(|p| p.name == plan.name) {
+                *existing = plan;
+            } else {
+                self.billing.plans.push(plan);
+            }
+        }
+
+        // Rate limits: groups merge by id
+        if other.rate_limit.user_up_bps.is_some() {
+            self.rate_limit.user_up_bps = other.rate_limit.user_up_bps;
+        }
+        if other.rate_limit.user_down_bps.is_some() {
+            self.rate_limit.user_down_bps = other.rate_limit.user_down_bps;
+        }
+        if other.rate_limit.burst_bytes != default_burst_bytes() {
+            self.rate_limit.burst_bytes = other.rate_limit.burst_bytes;
+        }
+        for group in other.rate_limit.groups {
+            if let Some(existing) = self
+                .rate_limit
+                .groups
+                .iter_mut()
+                .find(|g| g.group_id == group.group_id)
+            {
+                *existing = group;
+            } else {
+                self.rate_limit.groups.push(group);
+            }
+        }
+
+        // Session limits
+        if other.session_limit.max_sessions != default_max_sessions() {
+            self.session_limit.max_sessions = other.session_limit.max_sessions;
+        }
+        if other.session_limit.max_devices != default_max_devices() {
+            self.session_limit.max_devices = other.session_limit.max_devices;
+        }
+
+        // Exit load
+        if other.exit_load.node_connections != default_node_connections() {
+            self.exit_load.node_connections = other.exit_load.node_connections;
+        }
+        if other.exit_load.node_throughput_bps != default_node_throughput_bps() {
+            self.exit_load.node_throughput_bps = other.exit_load.node_throughput_bps;
+        }
+
+        // NAT timeouts
+        if other.nat.tcp_established_timeout != default_tcp_established_timeout() {
+            self.nat.tcp_established_timeout = other.nat.tcp_established_timeout;
+        }
+        if other.nat.tcp_transitory_timeout != default_tcp_transitory_timeout() {
+            self.nat.tcp_transitory_timeout = other.nat.tcp_transitory_timeout;
+        }
+        if other.nat.udp_timeout != default_udp_timeout() {
+            self.nat.udp_timeout = other.nat.udp_timeout;
+        }
+        if other.nat.icmp_timeout != default_icmp_timeout() {
+            self.nat.icmp_timeout = other.nat.icmp_timeout;
+        }
+
+        // Egress ACL: groups merge by id
+        if other.egress_acl.deny_cidrs != default_deny_cidrs() {
+            self.egress_acl.deny_cidrs = other.egress_acl.deny_cidrs;
+        }
+        if !other.egress_acl.allow_cidrs.is_empty() {
+            self.egress_acl.allow_cidrs = other.egress_acl.allow_cidrs;
+        }
+        if !other.egress_acl.allow_ports.is_empty() {
+            self.egress_acl.allow_ports = other.egress_acl.allow_ports;
+        }
+        if !other.egress_acl.deny_ports.is_empty() {
+            self.egress_acl.deny_ports = other.egress_acl.deny_ports;
+        }
+        for group in other.egress_acl.groups {
+            if let Some(existing) = self
+                .egress_acl
+                .groups
+                .iter_mut()
+                .find(|g| g.group_id == group.group_id)
+            {
+                *existing = group;
+            } else {
+                self.egress_acl.groups.push(group);
+            }
+        }
+
+        // Abuse controls
+        if !other.abuse.block_smtp {
+            self.abuse.block_smtp = false;
+        }
+        if !other.abuse.block_bittorrent {
+            self.abuse.block_bittorrent = false;
+        }
+        if other.abuse.max_new_connections_per_sec != default_max_new_connections_per_sec() {
+            self.abuse.max_new_connections_per_sec = other.abuse.max_new_connections_per_sec;
+        }
+        if other.abuse.action != AbuseAction::default() {
+            self.abuse.action = other.abuse.action;
+        }
+        if other.abuse.throttle_bps != default_throttle_bps() {
+            self.abuse.throttle_bps = other.abuse.throttle_bps;
+        }
     }
 }
 
@@ -139,6 +321,14 @@ impl Default for DaemonConfig {
             security: SecurityConfig::default(),
             database: DatabaseConfig::default(),
             monitoring: MonitoringConfig::default(),
+            dns: DnsConfig::default(),
+            billing: BillingConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            session_limit: SessionLimitConfig::default(),
+            exit_load: ExitLoadConfig::default(),
+            nat: NatConfig::default(),
+            egress_acl: EgressAclConfig::default(),
+            abuse: AbuseConfig::default(),
         }
     }
 }
@@ -171,8 +361,35 @@ pub struct ServerConfig {
     pub handler_endpoint: Option<String>,
 
     /// Preferred group ID (used in reverse_mode, None = auto-select)
+    ///
+    /// Other exits use it to select their egress ACL overrides.
     #[serde(default)]
     pub preferred_group_id: Option<i32>,
+
+    /// Hex key from `node enroll`, proves `location` to the handler (used in reverse_mode)
+    ///
+    /// Exits not in reverse mode check the tunnels of handlers with it.
+    #[serde(default)]
+    pub exit_key: Option<String>,
+
+    /// Public IP reported to clients for BIND listeners (exit mode)
+    #[serde(default)]
+    pub public_ip: Option<IpAddr>,
+
+    /// How traffic leaves an exit node (exit mode)
+    #[serde(default)]
+    pub exit_backend: ExitBackend,
+}
+
+/// Egress of an exit node
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExitBackend {
+    /// Raw IP packets through a TUN device (needs CAP_NET_ADMIN)
+    #[default]
+    Tun,
+    /// Streams only, no privileges needed
+    Socket,
 }
 
 fn default_mode() -> String {
@@ -197,6 +414,9 @@ impl Default for ServerConfig {
             reverse_mode: false,
             handler_endpoint: None,
             preferred_group_id: None,
+            exit_key: None,
+            public_ip: None,
+            exit_backend: ExitBackend::default(),
         }
     }
 }
@@ -219,6 +439,11 @@ pub struct RaftConfig {
     /// Heartbeat interval in ms
     #[serde(default = "default_heartbeat_interval")]
     pub heartbeat_interval: u64,
+
+    /// Secret signing requests between peers (32 bytes, hex); peers refuse
+    /// each other's requests without it
+    #[serde(default)]
+    pub peer_secret: Option<String>,
 }
 
 fn default_node_id() -> u64 {
@@ -240,6 +465,7 @@ impl Default for RaftConfig {
             peers: Vec::new(),
             election_timeout: default_election_timeout(),
             heartbeat_interval: default_heartbeat_interval(),
+            peer_secret: None,
         }
     }
 }
@@ -264,6 +490,10 @@ pub struct ExitNodeConfig {
     /// Group ID for routing (default: 0)
     #[serde(default)]
     pub group_id: i32,
+
+    /// Hex `exit_key` of the node, proves our tunnel to it
+    #[serde(default)]
+    pub key: Option<String>,
 }
 
 fn default_weight() -> f64 {
@@ -388,6 +618,11 @@ pub struct SecurityConfig {
     #[serde(default)]
     pub hmac_secret: Option<String>,
 
+    /// Key sealing user and exit secrets in the database (32 bytes, hex); must
+    /// be the same on every handler
+    #[serde(default)]
+    pub credential_key: Option<String>,
+
     /// Token TTL in seconds
     #[serde(default = "default_token_ttl")]
     pub token_ttl: u64,
@@ -438,6 +673,7 @@ impl Default for SecurityConfig {
         Self {
             server_sk: None,
             hmac_secret: None,
+            credential_key: None,
             token_ttl: default_token_ttl(),
             key_rotation_interval: default_rotation_interval(),
             grace_period: default_grace_period(),
@@ -497,6 +733,439 @@ impl Default for MonitoringConfig {
     }
 }
 
+/// DNS resolver configuration
+#[derive(Debug, Clone, Deserialize)]
+pub struct DnsConfig {
+    /// Upstream resolvers, tried in order with failover
+    #[serde(default = "default_dns_upstreams")]
+    pub upstreams: Vec<DnsUpstream>,
+
+    /// Per-upstream query timeout in milliseconds
+    #[serde(default = "default_dns_timeout_ms")]
+    pub timeout_ms: u64,
+
+    /// Maximum number of cached answers
+    #[serde(default = "default_dns_cache_size")]
+    pub cache_size: usize,
+
+    /// Queries per second allowed for each user (0 = unlimited)
+    #[serde(default = "default_dns_max_qps")]
+    pub max_qps_per_user: u32,
+}
+
+/// Upstream DNS resolver
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum DnsUpstream {
+    /// Plain DNS over UDP (falls back to TCP for truncated answers)
+    Udp { address: SocketAddr },
+    /// Plain DNS over TCP
+    Tcp { address: SocketAddr },
+    /// DNS over TLS (RFC 7858)
+    Tls {
+        address: SocketAddr,
+        server_name: String,
+    },
+    /// DNS over HTTPS (RFC 8484)
+    Https { url: String },
+}
+
+fn default_dns_upstreams() -> Vec<DnsUpstream> {
+    vec![
+        DnsUpstream::Udp {
+            address: "8.8.8.8:53".parse().unwrap(),
+        },
+        DnsUpstream::Udp {
+            address: "1.1.1.1:53".parse().unwrap(),
+        },
+    ]
+}
+
+fn default_dns_timeout_ms() -> u64 {
+    2000
+}
+
+fn default_dns_cache_size() -> usize {
+    10_000
+}
+
+fn default_dns_max_qps() -> u32 {
+    50
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            upstreams: default_dns_upstreams(),
+            timeout_ms: default_dns_timeout_ms(),
+            cache_size: default_dns_cache_size(),
+            max_qps_per_user: default_dns_max_qps(),
+        }
+    }
+}
+
+/// Billing and quota configuration
+#[derive(Debug, Clone, Deserialize)]
+pub struct BillingConfig {
+    /// Seconds between usage flushes (and quota refreshes) from the database
+    #[serde(default = "default_billing_flush_interval")]
+    pub flush_interval: u64,
+
+    /// Balance units deducted per GiB of traffic (0 = traffic is free)
+    #[serde(default)]
+    pub price_per_gib: i64,
+
+    /// What happens to sessions of users over quota or out of balance
+    #[serde(default)]
+    pub over_quota: OverQuotaAction,
+
+    /// Rate throttled sessions are limited to, in bytes per second
+    #[serde(default = "default_throttle_bps")]
+    pub throttle_bps: u64,
+
+    /// File usage is kept in while the database cannot be written; relative
+    /// to the working directory, like the Raft data
+    #[serde(default = "default_billing_spill_path")]
+    pub spill_path: String,
+
+    /// Quota plans, selected by the user's exit group
+    #[serde(default)]
+    pub plans: Vec<PlanConfig>,
+}
+
+/// Enforcement for users who ran out of quota or balance
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverQuotaAction {
+    /// Close the session after notifying the client
+    #[default]
+    Close,
+    /// Keep the session at `throttle_bps`
+    Throttle,
+}
+
+/// Traffic quota for the users of one exit group
+///
+/// A user's own `quota_bytes` overrides `monthly_bytes`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PlanConfig {
+    pub name: String,
+    pub group_id: i32,
+    #[serde(default)]
+    pub monthly_bytes: Option<u64>,
+    #[serde(default)]
+    pub daily_bytes: Option<u64>,
+}
+
+fn default_billing_flush_interval() -> u64 {
+    60
+}
+
+fn default_throttle_bps() -> u64 {
+    64 * 1024
+}
+
+fn default_billing_spill_path() -> String {
+    "data/billing.wal".to_string()
+}
+
+impl Default for BillingConfig {
+    fn default() -> Self {
+        Self {
+            flush_interval: default_billing_flush_interval(),
+            price_per_gib: 0,
+            over_quota: OverQuotaAction::default(),
+            throttle_bps: default_throttle_bps(),
+            spill_path: default_billing_spill_path(),
+            plans: Vec::new(),
+        }
+    }
+}
+
+/// Bandwidth limits in bytes per second; 0 is unlimited
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitConfig {
+    /// Upload limit of users without their own `rate_up_bps` (unset = unlimited)
+    #[serde(default)]
+    pub user_up_bps: Option<u64>,
+
+    /// Download limit of users without their own `rate_down_bps` (unset = unlimited)
+    #[serde(default)]
+    pub user_down_bps: Option<u64>,
+
+    /// Bytes a bucket may pass at once after being idle
+    #[serde(default = "default_burst_bytes")]
+    pub burst_bytes: u64,
+
+    /// Limits shared by all users of an exit group, unless the group has its
+    /// own in the database
+    #[serde(default)]
+    pub groups: Vec<GroupRateLimit>,
+}
+
+/// Aggregate bandwidth of one exit group
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct GroupRateLimit {
+    pub group_id: i32,
+    #[serde(default)]
+    pub up_bps: u64,
+    #[serde(default)]
+    pub down_bps: u64,
+}
+
+fn default_burst_bytes() -> u64 {
+    1024 * 1024
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            user_up_bps: None,
+            user_down_bps: None,
+            burst_bytes: default_burst_bytes(),
+            groups: Vec::new(),
+        }
+    }
+}
+
+/// Per-user session limits, enforced cluster-wide; 0 is unlimited
+#[derive(Debug, Clone, Deserialize)]
+pub struct SessionLimitConfig {
+    /// Concurrent sessions of users without their own `max_sessions`
+    #[serde(default = "default_max_sessions")]
+    pub max_sessions: u32,
+
+    /// Devices (client keys) with open sessions, for users without their own `max_devices`
+    #[serde(default = "default_max_devices")]
+    pub max_devices: u32,
+}
+
+fn default_max_sessions() -> u32 {
+    32
+}
+
+fn default_max_devices() -> u32 {
+    5
+}
+
+impl Default for SessionLimitConfig {
+    fn default() -> Self {
+        Self {
+            max_sessions: default_max_sessions(),
+            max_devices: default_max_devices(),
+        }
+    }
+}
+
+/// What one exit node is sized for; a group at this much traffic per healthy
+/// node reports a load of 100
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExitLoadConfig {
+    /// Concurrent connections per node
+    #[serde(default = "default_node_connections")]
+    pub node_connections: u64,
+
+    /// Bytes per second per node, both directions together
+    #[serde(default = "default_node_throughput_bps")]
+    pub node_throughput_bps: u64,
+}
+
+fn default_node_connections() -> u64 {
+    10_000
+}
+
+fn default_node_throughput_bps() -> u64 {
+    125_000_000 // 1 Gbit/s
+}
+
+impl Default for ExitLoadConfig {
+    fn default() -> Self {
+        Self {
+            node_connections: default_node_connections(),
+            node_throughput_bps: default_node_throughput_bps(),
+        }
+    }
+}
+
+/// Idle timeouts of NAT flows on exit nodes, in seconds
+#[derive(Debug, Clone, Deserialize)]
+pub struct NatConfig {
+    /// TCP connections after the handshake
+    #[serde(default = "default_tcp_established_timeout")]
+    pub tcp_established_timeout: u64,
+
+    /// TCP connections being opened or closed
+    #[serde(default = "default_tcp_transitory_timeout")]
+    pub tcp_transitory_timeout: u64,
+
+    /// UDP flows
+    #[serde(default = "default_udp_timeout")]
+    pub udp_timeout: u64,
+
+    /// ICMP echo
+    #[serde(default = "default_icmp_timeout")]
+    pub icmp_timeout: u64,
+}
+
+// RFC 5382 and RFC 4787 minimums
+fn default_tcp_established_timeout() -> u64 {
+    7440
+}
+
+fn default_tcp_transitory_timeout() -> u64 {
+    240
+}
+
+fn default_udp_timeout() -> u64 {
+    300
+}
+
+fn default_icmp_timeout() -> u64 {
+    60
+}
+
+impl Default for NatConfig {
+    fn default() -> Self {
+        Self {
+            tcp_established_timeout: default_tcp_established_timeout(),
+            tcp_transitory_timeout: default_tcp_transitory_timeout(),
+            udp_timeout: default_udp_timeout(),
+            icmp_timeout: default_icmp_timeout(),
+        }
+    }
+}
+
+/// Egress policy of exit nodes; ports are written "443" or "8000-8999"
+#[derive(Debug, Clone, Deserialize)]
+pub struct EgressAclConfig {
+    /// Networks nothing is forwarded to
+    #[serde(default = "default_deny_cidrs")]
+    pub deny_cidrs: Vec<String>,
+
+    /// Exceptions to `deny_cidrs`
+    #[serde(default)]
+    pub allow_cidrs: Vec<String>,
+
+    /// The only reachable ports, if any are given
+    #[serde(default)]
+    pub allow_ports: Vec<String>,
+
+    /// Ports nothing is forwarded to
+    #[serde(default)]
+    pub deny_ports: Vec<String>,
+
+    /// Overrides for the exits of one group
+    #[serde(default)]
+    pub groups: Vec<GroupEgressAcl>,
+}
+
+/// Lists replacing the defaults on the exits of one group
+#[derive(Debug, Clone, Deserialize)]
+pub struct GroupEgressAcl {
+    pub group_id: i32,
+    #[serde(default)]
+    pub deny_cidrs: Option<Vec<String>>,
+    #[serde(default)]
+    pub allow_cidrs: Option<Vec<String>>,
+    #[serde(default)]
+    pub allow_ports: Option<Vec<String>>,
+    #[serde(default)]
+    pub deny_ports: Option<Vec<String>>,
+}
+
+/// Loopback, private, link-local (cloud metadata), CGNAT, multicast and
+/// other special-purpose ranges, plus the NAT64 and 6to4 prefixes that would
+/// reach any of them through an embedded IPv4 address
+fn default_deny_cidrs() -> Vec<String> {
+    [
+        "0.0.0.0/8",
+        "10.0.0.0/8",
+        "100.64.0.0/10",
+        "127.0.0.0/8",
+        "169.254.0.0/16",
+        "172.16.0.0/12",
+        "192.0.0.0/24",
+        "192.168.0.0/16",
+        "198.18.0.0/15",
+        "224.0.0.0/3",
+        "::/128",
+        "::1/128",
+        "64:ff9b::/96",
+        "64:ff9b:1::/48",
+        "2002::/16",
+        "fc00::/7",
+        "fe80::/10",
+        "ff00::/8",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+/// Exit-side abuse controls; offenders are reported to their handler
+#[derive(Debug, Clone, Deserialize)]
+pub struct AbuseConfig {
+    /// Refuse outbound connections to port 25
+    #[serde(default = "default_true")]
+    pub block_smtp: bool,
+
+    /// Refuse traffic recognized as BitTorrent
+    #[serde(default = "default_true")]
+    pub block_bittorrent: bool,
+
+    /// New connections one user may open per second; 0 is unlimited
+    #[serde(default = "default_max_new_connections_per_sec")]
+    pub max_new_connections_per_sec: u32,
+
+    /// What a handler does with a reported session
+    #[serde(default)]
+    pub action: AbuseAction,
+
+    /// Rate of throttled sessions in bytes per second, each direction
+    #[serde(default = "default_throttle_bps")]
+    pub throttle_bps: u64,
+}
+
+/// Handler response to an exit's abuse report
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AbuseAction {
+    /// Only log the report
+    Log,
+    /// Keep the session at `throttle_bps`
+    #[default]
+    Throttle,
+    /// Close the session
+    Close,
+}
+
+fn default_max_new_connections_per_sec() -> u32 {
+    50
+}
+
+impl Default for AbuseConfig {
+    fn default() -> Self {
+        Self {
+            block_smtp: true,
+            block_bittorrent: true,
+            max_new_connections_per_sec: default_max_new_connections_per_sec(),
+            action: AbuseAction::default(),
+            throttle_bps: default_throttle_bps(),
+        }
+    }
+}
+
+impl Default for EgressAclConfig {
+    fn default() -> Self {
+        Self {
+            deny_cidrs: default_deny_cidrs(),
+            allow_cidrs: Vec::new(),
+            allow_ports: Vec::new(),
+            deny_ports: Vec::new(),
+            groups: Vec::new(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -541,6 +1210,7 @@ mod tests {
             weight: 1.0,
             location: None,
             group_id: 0,
+            key: None,
         });
 
         let mut other = DaemonConfig::default();
@@ -551,6 +1221,7 @@ mod tests {
             weight: 1.0,
             location: None,
             group_id: 0,
+            key: None,
         });
         // Add new
         other.exit_nodes.push(ExitNodeConfig {
@@ -559,6 +1230,7 @@ mod tests {
             weight: 2.0,
             location: None,
             group_id: 1,
+            key: None,
         });
 
         config.merge(other);
@@ -580,6 +1252,22 @@ mod tests {
         assert_eq!(n2.endpoint, "3.3.3.3");
     }
 
+    #[test]
+    fn test_merge_rate_limit_to_unlimited() {
+        let mut config = DaemonConfig::default();
+        config.rate_limit.user_up_bps = Some(1000);
+        config.rate_limit.user_down_bps = Some(1000);
+
+        let mut other = DaemonConfig::default();
+        other.rate_limit.user_up_bps = Some(0);
+
+        config.merge(other);
+
+        assert_eq!(config.rate_limit.user_up_bps, Some(0));
+        // Unset values leave the current limit alone
+        assert_eq!(config.rate_limit.user_down_bps, Some(1000));
+    }
+
     #[test]
     fn test_merge_raft_peers() {
         let mut config = DaemonConfig::default();
@@ -594,4 +1282,98 @@ mod tests {
         assert!(config.raft.peers.contains(&"peer1".to_string()));
         assert!(config.raft.peers.contains(&"peer2".to_string()));
     }
+
+    #[test]
+    fn test_parse_exit_backend() {
+        let config: DaemonConfig = toml::from_str(
+            r#"
+            [server]
+            mode = "exit"
+            exit_backend = "socket"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.server.exit_backend, ExitBackend::Socket);
+        assert_eq!(
+            DaemonConfig::default().server.exit_backend,
+            ExitBackend::Tun
+        );
+    }
+
+    #[test]
+    fn test_parse_dns_upstreams() {
+        let config: DaemonConfig = toml::from_str(
+            r#"
+            [dns]
+            upstreams = [
+                { type = "tls", address = "1.1.1.1:853", server_name = "cloudflare-dns.com" },
+                { type = "https", url = "https://dns.google/dns-query" },
+            ]
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.dns.upstreams.len(), 2);
+        assert_eq!(
+            config.dns.upstreams[1],
+            DnsUpstream::Https {
+                url: "https://dns.google/dns-query".to_string()
+            }
+        );
+        assert_eq!(config.dns.max_qps_per_user, default_dns_max_qps());
+    }
+
+    #[test]
+    fn test_merge_egress_acl() {
+        let mut config: DaemonConfig = toml::from_str(
+            r#"
+            [[egress_acl.groups]]
+            group_id = 2
+            deny_ports = ["25"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.egress_acl.deny_cidrs, default_deny_cidrs());
+
+        let other: DaemonConfig = toml::from_str(
+            r#"
+            [egress_acl]
+            deny_cidrs = ["169.254.169.254"]
+
+            [[egress_acl.groups]]
+            group_id = 2
+            allow_ports = ["443"]
+            "#,
+        )
+        .unwrap();
+        config.merge(other);
+
+        assert_eq!(config.egress_acl.deny_cidrs, vec!["169.254.169.254"]);
+        let group = &config.egress_acl.groups[0];
+        assert_eq!(group.allow_ports, Some(vec!["443".to_string()]));
+        assert_eq!(group.deny_ports, None);
+    }
+
+    #[test]
+    fn test_parse_billing_plans() {
+        let config: DaemonConfig = toml::from_str(
+            r#"
+            [billing]
+            price_per_gib = 10
+            over_quota = "throttle"
+
+            [[billing.plans]]
+            name = "premium"
+            group_id = 1
+            monthly_bytes = 1099511627776
+            daily_bytes = 53687091200
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.billing.over_quota, OverQuotaAction::Throttle);
+        assert_eq!(config.billing.plans[0].group_id, 1);
+        assert_eq!(config.billing.plans[0].daily_bytes, Some(50 << 30));
+        assert_eq!(config.billing.flush_interval, 60);
+    }
 }
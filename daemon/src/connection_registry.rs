@@ -38,9 +38,22 @@ impl PacketDispatcher for ConnectionRegistry {
     async fn dispatch(&self, packet: PlainPacket) {
         if let Some(sender) = self.connections.get(&packet.conn_id) {
             let conn_id = packet.conn_id;
-            // Convert PlainPacket -> ProxyFrame (Data)
-            let frame =
-                ProxyFrame::new_data(packet.conn_id, packet.rip, packet.rport, packet.payload);
+            let frame = if packet.flags.is_control {
+                // Messages from the exit about the session itself
+                let mut frame = ProxyFrame::new_control(packet.payload);
+                frame.conn_id = conn_id;
+                frame
+            } else {
+                // Convert PlainPacket -> ProxyFrame (Data)
+                let mut frame =
+                    ProxyFrame::new_data(packet.conn_id, packet.rip, packet.rport, packet.payload)
+                        .with_stream(packet.stream_id);
+                frame.flags.is_final = packet.flags.is_final;
+                frame.flags.is_half_close = packet.flags.is_half_close;
+                frame.flags.is_datagram = packet.flags.is_datagram;
+                frame.flags.is_open = packet.flags.is_open;
+                frame
+            };
 
             if let Err(e) = sender.send(frame) {
                 warn!("Failed to dispatch packet to conn {}: {}", conn_id, e);
@@ -0,0 +1,102 @@
+//! Credential secrets sealed at rest
+//!
+//! Handlers verify per-user HMACs with the user's own secret, so the secret has
+//! to be recoverable. The database keeps it encrypted with AES-256-GCM under
+//! `security.credential_key`, which only the handlers hold; a copy of the
+//! database alone does not yield usable credentials.
+
+use crate::config::SecurityConfig;
+use apfsds_crypto::Aes256GcmCipher;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum SealError {
+    #[error("security.credential_key is not set")]
+    NoKey,
+
+    #[error("security.credential_key must be 32 hex-encoded bytes")]
+    InvalidKey,
+
+    #[error("Sealed credential is corrupt or sealed under another key")]
+    Corrupt,
+}
+
+/// Seals and opens 32-byte credential secrets
+pub struct CredentialSeal {
+    cipher: Aes256GcmCipher,
+}
+
+impl CredentialSeal {
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self {
+            cipher: Aes256GcmCipher::new(key),
+        }
+    }
+
+    /// Seal with the configured `security.credential_key`
+    pub fn from_config(security: &SecurityConfig) -> Result<Self, SealError> {
+        let key = security.credential_key.as_ref().ok_or(SealError::NoKey)?;
+        let key: [u8; 32] = hex::decode(key)
+            .ok()
+            .and_then(|v| v.try_into().ok())
+            .ok_or(SealError::InvalidKey)?;
+        Ok(Self::new(&key))
+    }
+
+    /// Encrypt `secret` for storage; returns hex
+    pub fn seal(&self, secret: &[u8; 32]) -> String {
+        let sealed = self
+            .cipher
+            .encrypt(secret)
+            .expect("AES-GCM encryption of 32 bytes cannot fail");
+        hex::encode(sealed)
+    }
+
+    /// Recover a secret returned by `seal`
+    pub fn open(&self, sealed: &str) -> Result<[u8; 32], SealError> {
+        let sealed = hex::decode(sealed).map_err(|_| SealError::Corrupt)?;
+        self.cipher
+            .decrypt(&sealed)
+            .ok()
+            .and_then(|v| v.try_into().ok())
+            .ok_or(SealError::Corrupt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_roundtrip() {
+        let seal = CredentialSeal::new(&[1u8; 32]);
+        let sealed = seal.seal(&[7u8; 32]);
+        assert!(!sealed.contains(&hex::encode([7u8; 32])));
+        assert_eq!(seal.open(&sealed), Ok([7u8; 32]));
+
+        // Another key cannot open it, and tampering is detected
+        let other = CredentialSeal::new(&[2u8; 32]);
+        assert_eq!(other.open(&sealed), Err(SealError::Corrupt));
+        let mut tampered = sealed.into_bytes();
+        let last = tampered.len() - 1;
+        tampered[last] = if tampered[last] == b'0' { b'1' } else { b'0' };
+        let tampered = String::from_utf8(tampered).unwrap();
+        assert_eq!(seal.open(&tampered), Err(SealError::Corrupt));
+    }
+
+    #[test]
+    fn test_seal_from_config() {
+        let mut security = SecurityConfig::default();
+        assert!(matches!(
+            CredentialSeal::from_config(&security),
+            Err(SealError::NoKey)
+        ));
+        security.credential_key = Some("abcd".to_string());
+        assert!(matches!(
+            CredentialSeal::from_config(&security),
+            Err(SealError::InvalidKey)
+        ));
+        security.credential_key = Some(hex::encode([3u8; 32]));
+        assert!(CredentialSeal::from_config(&security).is_ok());
+    }
+}
@@ -0,0 +1,292 @@
+//! Egress access control on exit nodes
+//!
+//! Exits refuse to forward to denied networks and ports before anything
+//! reaches the TUN or a socket. By default that is every non-public range
+//! (private, loopback, link-local with cloud metadata, CGNAT, multicast), and
+//! the addresses of the exit's handlers are always denied. The exits of one
+//! group can replace any of the lists. Each refusal is counted per rule in
+//! `apfsds_egress_denied_total`.
+
+use crate::config::{EgressAclConfig, GroupEgressAcl};
+use crate::metrics::Metrics;
+use dashmap::DashSet;
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::sync::{LazyLock, RwLock};
+use thiserror::Error;
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum EgressAclError {
+    #[error("Invalid CIDR: {0}")]
+    InvalidCidr(String),
+
+    #[error("Invalid port range: {0}")]
+    InvalidPorts(String),
+}
+
+/// A destination was refused by `rule`
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("Egress denied by {rule}")]
+pub struct Denied {
+    pub rule: String,
+}
+
+/// The lists in effect on one exit
+struct Rules {
+    deny_cidrs: Vec<IpNet>,
+    allow_cidrs: Vec<IpNet>,
+    allow_ports: Vec<RangeInclusive<u16>>,
+    deny_ports: Vec<RangeInclusive<u16>>,
+}
+
+impl Rules {
+    fn new(
+        config: &EgressAclConfig,
+        group: Option<&GroupEgressAcl>,
+    ) -> Result<Self, EgressAclError> {
+        let own = |list: fn(&GroupEgressAcl) -> Option<&Vec<String>>| group.and_then(list);
+        Ok(Self {
+            deny_cidrs: parse_list(
+                own(|g| g.deny_cidrs.as_ref()).unwrap_or(&config.deny_cidrs),
+                parse_cidr,
+                EgressAclError::InvalidCidr,
+            )?,
+            allow_cidrs: parse_list(
+                own(|g| g.allow_cidrs.as_ref()).unwrap_or(&config.allow_cidrs),
+                parse_cidr,
+                EgressAclError::InvalidCidr,
+            )?,
+            allow_ports: parse_list(
+                own(|g| g.allow_ports.as_ref()).unwrap_or(&config.allow_ports),
+                parse_ports,
+                EgressAclError::InvalidPorts,
+            )?,
+            deny_ports: parse_list(
+                own(|g| g.deny_ports.as_ref()).unwrap_or(&config.deny_ports),
+                parse_ports,
+                EgressAclError::InvalidPorts,
+            )?,
+        })
+    }
+
+    /// Name of the rule refusing `ip:port`, if any
+    fn denies(&self, ip: IpAddr, port: Option<u16>) -> Option<String> {
+        let exempt = self.allow_cidrs.iter().any(|net| net.contains(&ip));
+        if let (Some(net), false) = (self.deny_cidrs.iter().find(|n| n.contains(&ip)), exempt) {
+            return Some(format!("deny_cidrs {}", net));
+        }
+
+        // ICMP has no port to judge
+        let port = port?;
+        if let Some(range) = self.deny_ports.iter().find(|range| range.contains(&port)) {
+            return Some(format!("deny_ports {}", format_ports(range)));
+        }
+        if !self.allow_ports.is_empty() && !self.allow_ports.iter().any(|r| r.contains(&port)) {
+            return Some("allow_ports".to_string());
+        }
+        None
+    }
+}
+
+/// Egress policy of one exit node
+pub struct EgressAcl {
+    default: Rules,
+    groups: HashMap<i32, Rules>,
+    /// Group the exit serves, selecting its overrides
+    group: RwLock<Option<i32>>,
+    handlers: DashSet<IpAddr>,
+}
+
+impl EgressAcl {
+    pub fn new(config: &EgressAclConfig, group: Option<i32>) -> Result<Self, EgressAclError> {
+        let groups = config
+            .groups
+            .iter()
+            .map(|g| Ok((g.group_id, Rules::new(config, Some(g))?)))
+            .collect::<Result<_, EgressAclError>>()?;
+
+        Ok(Self {
+            default: Rules::new(config, None)?,
+            groups,
+            group: RwLock::new(group),
+            handlers: DashSet::new(),
+        })
+    }
+
+    /// Switch to the overrides of the group the exit joined
+    pub fn set_group(&self, group_id: i32) {
+        *self.group.write().unwrap() = Some(group_id);
+    }
+
+    /// Never forward to `ip`, an address of one of our handlers
+    pub fn deny_handler(&self, ip: IpAddr) {
+        self.handlers.insert(ip.to_canonical());
+    }
+
+    /// Check a destination; `port` is None for ICMP
+    pub fn check(&self, ip: IpAddr, port: Option<u16>) -> Result<(), Denied> {
+        let ip = ip.to_canonical();
+        let rule = if self.handlers.contains(&ip) {
+            Some("handler".to_string())
+        } else {
+            let group = *self.group.read().unwrap();
+            group
+                .and_then(|g| self.groups.get(&g))
+                .unwrap_or(&self.default)
+                .denies(ip, port)
+        };
+
+        match rule {
+            Some(rule) => {
+                METRICS.egress_denied.with_label_values(&[&rule]).inc();
+                Err(Denied { rule })
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+fn parse_list<T>(
+    list: &[String],
+    parse: fn(&str) -> Option<T>,
+    error: fn(String) -> EgressAclError,
+) -> Result<Vec<T>, EgressAclError> {
+    list.iter()
+        .map(|value| parse(value).ok_or_else(|| error(value.clone())))
+        .collect()
+}
+
+/// A network, or a single address as its host route
+fn parse_cidr(value: &str) -> Option<IpNet> {
+    value
+        .parse()
+        .ok()
+        .or_else(|| value.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+/// "443" or "8000-8999"
+fn parse_ports(value: &str) -> Option<RangeInclusive<u16>> {
+    let (first, last) = value.split_once('-').unwrap_or((value, value));
+    let (first, last) = (first.trim().parse().ok()?, last.trim().parse().ok()?);
+    (first <= last).then_some(first..=last)
+}
+
+fn format_ports(range: &RangeInclusive<u16>) -> String {
+    if range.start() == range.end() {
+        range.start().to_string()
+    } else {
+        format!("{}-{}", range.start(), range.end())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn rule(result: Result<(), Denied>) -> Option<String> {
+        result.err().map(|denied| denied.rule)
+    }
+
+    #[test]
+    fn test_default_denies_private() {
+        let acl = EgressAcl::new(&EgressAclConfig::default(), None).unwrap();
+
+        assert_eq!(
+            rule(acl.check(ip("169.254.169.254"), Some(80))).as_deref(),
+            Some("deny_cidrs 169.254.0.0/16")
+        );
+        assert!(acl.check(ip("10.1.2.3"), Some(443)).is_err());
+        assert!(acl.check(ip("::1"), Some(22)).is_err());
+        assert!(acl.check(ip("fd12::1"), None).is_err());
+        // Mapped IPv4 is judged as IPv4
+        assert!(acl.check(ip("::ffff:192.168.1.1"), Some(80)).is_err());
+        // Nor can IPv4 be reached through NAT64 or 6to4
+        assert_eq!(
+            rule(acl.check(ip("64:ff9b::a9fe:a9fe"), Some(80))).as_deref(),
+            Some("deny_cidrs 64:ff9b::/96")
+        );
+        assert!(acl.check(ip("2002:c0a8:101::1"), Some(80)).is_err());
+
+        assert_eq!(acl.check(ip("203.0.113.5"), Some(443)), Ok(()));
+        assert_eq!(acl.check(ip("2001:db8::5"), Some(443)), Ok(()));
+
+        let before = METRICS
+            .egress_denied
+            .with_label_values(&["deny_cidrs 127.0.0.0/8"])
+            .get();
+        assert!(acl.check(ip("127.0.0.1"), Some(25348)).is_err());
+        let after = METRICS
+            .egress_denied
+            .with_label_values(&["deny_cidrs 127.0.0.0/8"])
+            .get();
+        assert_eq!(after, before + 1);
+    }
+
+    #[test]
+    fn test_ports_and_handlers() {
+        let config = EgressAclConfig {
+            allow_cidrs: vec!["10.20.0.0/16".into()],
+            allow_ports: vec!["80".into(), "443".into(), "1024-65535".into()],
+            deny_ports: vec!["6881-6889".into()],
+            ..Default::default()
+        };
+        let acl = EgressAcl::new(&config, None).unwrap();
+
+        assert_eq!(acl.check(ip("10.20.0.1"), Some(443)), Ok(()));
+        assert_eq!(
+            rule(acl.check(ip("203.0.113.5"), Some(25))).as_deref(),
+            Some("allow_ports")
+        );
+        assert_eq!(
+            rule(acl.check(ip("203.0.113.5"), Some(6881))).as_deref(),
+            Some("deny_ports 6881-6889")
+        );
+        // Port rules leave ICMP alone
+        assert_eq!(acl.check(ip("203.0.113.5"), None), Ok(()));
+
+        acl.deny_handler(ip("198.51.100.7"));
+        assert_eq!(
+            rule(acl.check(ip("::ffff:198.51.100.7"), Some(443))).as_deref(),
+            Some("handler")
+        );
+    }
+
+    #[test]
+    fn test_group_overrides() {
+        let config = EgressAclConfig {
+            deny_ports: vec!["25".into()],
+            groups: vec![GroupEgressAcl {
+                group_id: 2,
+                deny_cidrs: Some(Vec::new()),
+                allow_cidrs: None,
+                allow_ports: None,
+                deny_ports: None,
+            }],
+            ..Default::default()
+        };
+        let acl = EgressAcl::new(&config, Some(1)).unwrap();
+        assert!(acl.check(ip("10.1.2.3"), Some(443)).is_err());
+
+        // Lists the group leaves out keep their defaults
+        acl.set_group(2);
+        assert_eq!(acl.check(ip("10.1.2.3"), Some(443)), Ok(()));
+        assert!(acl.check(ip("203.0.113.5"), Some(25)).is_err());
+
+        let config = EgressAclConfig {
+            deny_ports: vec!["25-24".into()],
+            ..Default::default()
+        };
+        assert_eq!(
+            EgressAcl::new(&config, None).err(),
+            Some(EgressAclError::InvalidPorts("25-24".into()))
+        );
+    }
+}
@@ -0,0 +1,251 @@
+//! Exit-node registration credentials
+//!
+//! Reverse-mode exits are enrolled through the management API, which hands out
+//! a random key once and stores it sealed (see `crate::credential_seal`), so a
+//! copy of the database alone cannot forge registrations. When registering, an
+//! exit sends `X-Exit-Auth: <unix secs>:<hex nonce>:<hex mac>`, an HMAC-SHA256
+//! keyed with that key over its name and the nonce. The key never crosses the
+//! wire, and nonces are remembered while their timestamp is acceptable, so a
+//! captured header cannot be replayed.
+//!
+//! Handlers prove themselves the same way when they open their tunnel to an
+//! exit in `[[exit_nodes]]`: the name is then the handler ID the tunnel
+//! claims, and the key is the exit's own `exit_key`.
+
+use crate::config::ExitNodeConfig;
+use apfsds_crypto::{HmacAuthenticator, ReplayCache};
+use apfsds_transport::TunnelSigner;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+use tracing::warn;
+
+/// Header carrying an exit's registration proof
+pub const EXIT_AUTH_HEADER: &str = "X-Exit-Auth";
+
+/// Accepted clock difference between exit and handler
+const MAX_SKEW_SECS: u64 = 60;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ExitAuthError {
+    #[error("Malformed credentials")]
+    Malformed,
+
+    #[error("Timestamp outside the accepted window")]
+    Expired,
+
+    #[error("Nonce reused (replay attack)")]
+    Replayed,
+
+    #[error("Invalid credentials")]
+    Invalid,
+}
+
+fn mac(key: &[u8; 32], name: &str, nonce: &[u8; 32], timestamp: u64) -> [u8; 32] {
+    let data = [name.as_bytes(), nonce.as_slice()].concat();
+    HmacAuthenticator::new(*key).compute_with_timestamp(&data, timestamp)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Registration proof for exit `name` holding the hex `key`
+pub fn auth_header(name: &str, key: &str) -> Result<String, ExitAuthError> {
+    let key = decode_32(key)?;
+    Ok(auth_header_at(name, &key, unix_now()))
+}
+
+fn auth_header_at(name: &str, key: &[u8; 32], timestamp: u64) -> String {
+    let mut nonce = [0u8; 32];
+    rand::RngCore::fill_bytes(&mut rand::rngs::OsRng, &mut nonce);
+    let mac = mac(key, name, &nonce, timestamp);
+    format!("{}:{}:{}", timestamp, hex::encode(nonce), hex::encode(mac))
+}
+
+/// Name a handler's tunnel proof is made out to
+pub fn tunnel_name(handler_id: u64) -> String {
+    format!("handler-{}", handler_id)
+}
+
+/// Parse a hex key as configured in `exit_key`
+pub fn parse_key(key: &str) -> Result<[u8; 32], ExitAuthError> {
+    decode_32(key)
+}
+
+/// Signs the tunnels of a handler with the keys of its `[[exit_nodes]]`
+pub struct TunnelKeys {
+    /// Key of each node by endpoint
+    keys: HashMap<String, String>,
+}
+
+impl TunnelKeys {
+    pub fn new(nodes: &[ExitNodeConfig]) -> Self {
+        let mut keys = HashMap::new();
+        for node in nodes {
+            match &node.key {
+                Some(key) => {
+                    keys.insert(node.endpoint.clone(), key.clone());
+                }
+                None => warn!(
+                    "Exit node {} has no key, it will refuse our tunnel",
+                    node.name
+                ),
+            }
+        }
+        Self { keys }
+    }
+}
+
+impl TunnelSigner for TunnelKeys {
+    fn sign(&self, base_url: &str, handler_id: u64) -> Option<(&'static str, String)> {
+        let key = self.keys.get(base_url)?;
+        match auth_header(&tunnel_name(handler_id), key) {
+            Ok(proof) => Some((EXIT_AUTH_HEADER, proof)),
+            Err(_) => {
+                warn!("Key of exit node {} is not hex", base_url);
+                None
+            }
+        }
+    }
+}
+
+/// Checks registration proofs of exits
+pub struct ExitAuthenticator {
+    nonces: ReplayCache,
+}
+
+impl ExitAuthenticator {
+    pub fn new() -> Self {
+        Self {
+            nonces: ReplayCache::new(Duration::from_secs(2 * MAX_SKEW_SECS)),
+        }
+    }
+
+    /// Verify `header` of exit `name` against its unsealed `key`
+    pub fn verify(&self, name: &str, header: &str, key: &[u8; 32]) -> Result<(), ExitAuthError> {
+        self.verify_at(name, header, key, unix_now())
+    }
+
+    fn verify_at(
+        &self,
+        name: &str,
+        header: &str,
+        key: &[u8; 32],
+        now: u64,
+    ) -> Result<(), ExitAuthError> {
+        let mut parts = header.split(':');
+        let (Some(timestamp), Some(nonce), Some(mac_hex), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(ExitAuthError::Malformed);
+        };
+        let timestamp: u64 = timestamp.parse().map_err(|_| ExitAuthError::Malformed)?;
+        let nonce: [u8; 32] = decode_32(nonce)?;
+        let expected: [u8; 32] = decode_32(mac_hex)?;
+
+        if timestamp.abs_diff(now) > MAX_SKEW_SECS {
+            return Err(ExitAuthError::Expired);
+        }
+        HmacAuthenticator::new(*key)
+            .verify_with_timestamp(&[name.as_bytes(), &nonce].concat(), timestamp, &expected)
+            .map_err(|_| ExitAuthError::Invalid)?;
+
+        // Only genuine proofs reach the cache, so garbage cannot fill it
+        if !self.nonces.check_and_insert(&nonce) {
+            return Err(ExitAuthError::Replayed);
+        }
+        Ok(())
+    }
+}
+
+fn decode_32(value: &str) -> Result<[u8; 32], ExitAuthError> {
+    hex::decode(value)
+        .ok()
+        .and_then(|v| v.try_into().ok())
+        .ok_or(ExitAuthError::Malformed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000;
+
+    #[test]
+    fn test_exit_auth_roundtrip() {
+        let key = [7u8; 32];
+        let auth = ExitAuthenticator::new();
+
+        let header = auth_header_at("exit-1", &key, NOW);
+        assert_eq!(auth.verify_at("exit-1", &header, &key, NOW + 5), Ok(()));
+        // The same proof cannot be used twice
+        assert_eq!(
+            auth.verify_at("exit-1", &header, &key, NOW + 5),
+            Err(ExitAuthError::Replayed)
+        );
+
+        // Proofs are bound to the name and the key
+        let header = auth_header_at("exit-1", &key, NOW);
+        assert_eq!(
+            auth.verify_at("exit-2", &header, &key, NOW),
+            Err(ExitAuthError::Invalid)
+        );
+        assert_eq!(
+            auth.verify_at("exit-1", &header, &[8u8; 32], NOW),
+            Err(ExitAuthError::Invalid)
+        );
+    }
+
+    #[test]
+    fn test_tunnel_keys() {
+        let key = "07".repeat(32);
+        let node = |endpoint: &str, key: Option<String>| ExitNodeConfig {
+            name: endpoint.to_string(),
+            endpoint: endpoint.to_string(),
+            weight: 1.0,
+            location: None,
+            group_id: 0,
+            key,
+        };
+        let keys = TunnelKeys::new(&[
+            node("203.0.113.1:25347", Some(key.clone())),
+            node("203.0.113.2:25347", None),
+        ]);
+        let auth = ExitAuthenticator::new();
+
+        let (name, proof) = keys.sign("203.0.113.1:25347", 3).unwrap();
+        assert_eq!(name, EXIT_AUTH_HEADER);
+        // A proof for one handler cannot open the tunnel of another
+        assert_eq!(
+            auth.verify(&tunnel_name(4), &proof, &parse_key(&key).unwrap()),
+            Err(ExitAuthError::Invalid)
+        );
+        assert_eq!(
+            auth.verify(&tunnel_name(3), &proof, &parse_key(&key).unwrap()),
+            Ok(())
+        );
+
+        assert!(keys.sign("203.0.113.2:25347", 3).is_none());
+        assert!(keys.sign("203.0.113.9:25347", 3).is_none());
+    }
+
+    #[test]
+    fn test_exit_auth_rejects_stale_and_malformed() {
+        let key = [7u8; 32];
+        let auth = ExitAuthenticator::new();
+
+        let header = auth_header_at("exit-1", &key, NOW - MAX_SKEW_SECS - 1);
+        assert_eq!(
+            auth.verify_at("exit-1", &header, &key, NOW),
+            Err(ExitAuthError::Expired)
+        );
+        assert_eq!(
+            auth.verify_at("exit-1", "123:abcd", &key, NOW),
+            Err(ExitAuthError::Malformed)
+        );
+    }
+}
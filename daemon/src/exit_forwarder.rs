@@ -1,8 +1,8 @@
 //! Exit Node Forwarder
 //!
-//! Forwards ProxyFrame data to exit nodes via HTTP/2 and handles responses.
+//! Forwards ProxyFrame data to exit nodes over their tunnels.
 
-use apfsds_protocol::{PlainPacket, ProxyFrame};
+use apfsds_protocol::{ControlMessage, PlainPacket, ProxyFrame};
 use apfsds_transport::{ExitClientError, ExitPool};
 use std::sync::Arc;
 use tracing::{debug, error};
@@ -18,8 +18,18 @@ impl ExitForwarder {
         Self { pool, node_id }
     }
 
-    /// Forward a frame to an exit node
-    pub async fn forward(&self, frame: &ProxyFrame, group_id: i32) -> Result<(), ExitClientError> {
+    /// Pool of exits, shared with reverse-connected exit nodes
+    pub fn pool(&self) -> &Arc<ExitPool> {
+        &self.pool
+    }
+
+    /// Forward a frame of `user_id`'s connection to an exit node
+    pub async fn forward(
+        &self,
+        frame: &ProxyFrame,
+        user_id: u64,
+        group_id: i32,
+    ) -> Result<(), ExitClientError> {
         // Only forward DATA frames (not control frames)
         if frame.flags.is_control {
             return Ok(());
@@ -29,7 +39,7 @@ impl ExitForwarder {
         // Note: In a real implementation, we would need mapping from conn_id to remote endpoint.
         // For Phase 2, we assume the conn_id is sufficient or encoded in metadata.
 
-        let packet = PlainPacket::from_frame(frame, self.node_id);
+        let packet = PlainPacket::from_frame(frame, self.node_id).with_user(user_id);
 
         if let Err(e) = self.pool.forward(&packet, group_id).await {
             error!("Failed to forward packet for conn {}: {}", frame.conn_id, e);
@@ -39,4 +49,27 @@ impl ExitForwarder {
         debug!("Forwarded frame for conn {}", frame.conn_id);
         Ok(())
     }
+
+    /// Pass window the client granted one of its streams on to the exits, so
+    /// they read no more of the stream's target than the client can take
+    pub async fn grant(
+        &self,
+        conn_id: u64,
+        stream_id: u32,
+        increment: u32,
+        user_id: u64,
+        group_id: i32,
+    ) -> Result<(), ExitClientError> {
+        let update = ControlMessage::WindowUpdate {
+            stream_id,
+            increment,
+        };
+        let payload = rkyv::to_bytes::<rkyv::rancor::Error>(&update)
+            .map_err(|e| ExitClientError::SerializationError(e.to_string()))?;
+        let mut frame = ProxyFrame::new_control(payload.to_vec()).with_stream(stream_id);
+        frame.conn_id = conn_id;
+
+        let packet = PlainPacket::from_frame(&frame, self.node_id).with_user(user_id);
+        self.pool.forward(&packet, group_id).await
+    }
 }
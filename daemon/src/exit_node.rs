@@ -1,250 +1,547 @@
 //! Exit Node Service
 //!
-//! Manages TUN interface and forwards traffic between Handlers (via HTTP/2) and the OS.
-//! Implements user-space NAT using Client ID/Conn ID mapping.
+//! Forwards traffic between Handlers (via WebSocket tunnels) and the OS.
+//! Streams always leave as ordinary sockets. Raw client IP packets, sent on
+//! stream 0, go out through a TUN interface and are translated by the
+//! userspace NAT in [`crate::nat`], unless the exit runs without privileges
+//! (`exit_backend = "socket"`).
 
 use anyhow::Result;
 use dashmap::DashMap;
-use std::net::Ipv4Addr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
 use std::sync::Arc;
 use tokio::net::TcpListener;
-use tokio::sync::Mutex;
 use tracing::{debug, error, info, warn};
 // Updated import
-use crate::config::DaemonConfig;
-use apfsds_protocol::PlainPacket;
+use crate::abuse::AbuseGuard;
+use crate::config::{DaemonConfig, ExitBackend};
+use crate::egress_acl::EgressAcl;
+use crate::exit_auth::{self, ExitAuthenticator};
+use crate::exit_relay::{self, BindRelay, ConnectRelay, FlowKey, Responses, UdpRelay};
+use crate::nat::{self, NatTable};
+use apfsds_protocol::{AbuseKind, PlainPacket};
+use apfsds_transport::{PacketDispatcher, TUNNEL_PATH, TUNNEL_QUEUE, run_tunnel};
+use async_trait::async_trait;
 use bytes::Bytes;
 use futures::{SinkExt, stream::StreamExt};
-use http_body_util::{BodyExt, Full, StreamBody}; // Need StreamBody
+use http_body_util::{BodyExt, Full};
 use hyper::service::service_fn;
 use hyper::{Request, Response, body::Incoming};
 use hyper_util::rt::TokioIo;
-use tokio::sync::mpsc::{self, UnboundedSender};
-use tokio_stream::wrappers::UnboundedReceiverStream; // Need tokio-stream // Need futures
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+
+/// Longest a handler tunnel may stay silent before it is dropped
+const TUNNEL_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How often idle NAT flows are dropped
+const NAT_EXPIRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How often abuse state of idle connections is dropped
+const ABUSE_EXPIRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Packets a stream handled on its own task may have queued
+const FLOW_QUEUE: usize = 256;
+
+/// Idle time after which the task of a stream ends
+const FLOW_IDLE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Addresses NAT hands out, inside the TUN's 10.200.0.0/16 (10.200.0.1 is the TUN)
+const NAT_V4: RangeInclusive<Ipv4Addr> =
+    Ipv4Addr::new(10, 200, 0, 2)..=Ipv4Addr::new(10, 200, 255, 254);
+
+/// IPv6 counterpart inside fd00:200::/64 (fd00:200::1 is the TUN)
+const NAT_V6: RangeInclusive<Ipv6Addr> = Ipv6Addr::new(0xfd00, 0x200, 0, 0, 0, 0, 0, 2)
+    ..=Ipv6Addr::new(0xfd00, 0x200, 0, 0, 0, 0, 0, 0xffff);
 
 #[cfg(target_os = "linux")]
-use tun::platform::Device;
+use tun::platform::posix::{Reader, Writer};
+
+/// Client IP packets through the TUN, behind the userspace NAT
+///
+/// Only the write half lives here; the read half belongs to the reader
+/// thread, so writes never wait on a blocked read.
+struct TunEgress {
+    #[cfg(target_os = "linux")]
+    writer: std::sync::Mutex<Writer>,
+    nat: Box<NatTable>,
+}
 
 /// Exit Node Service
 pub struct ExitService {
-    #[cfg(target_os = "linux")]
-    tun: Arc<std::sync::Mutex<Device>>,
-    #[cfg(not(target_os = "linux"))]
-    tun: Arc<std::sync::Mutex<()>>,
+    /// Egress of raw IP packets, absent on the socket backend
+    tun: Option<TunEgress>,
 
-    /// Map of Virtual IP -> (HandlerID, ConnID) for return traffic routing
-    route_map: Arc<DashMap<Ipv4Addr, RouteEntry>>,
+    /// One TCP connection per connect stream
+    connect: ConnectRelay,
 
-    /// Map of HandlerID -> Sender for return stream
-    handler_streams:
-        Arc<DashMap<u64, UnboundedSender<Result<hyper::body::Frame<Bytes>, anyhow::Error>>>>,
+    /// Tunnel queue of each handler
+    responses: Responses,
 
-    ip_pool: Arc<std::sync::atomic::AtomicU16>,
-}
+    /// Streams whose packets are handled in order on a task of their own
+    flows: DashMap<FlowKey, mpsc::Sender<PlainPacket>>,
 
-#[derive(Debug, Clone)]
-struct RouteEntry {
-    handler_id: u64,
-    conn_id: u64,
+    /// Socket relay for datagram streams
+    udp: UdpRelay,
+
+    /// Listener relay for BIND streams
+    bind: BindRelay,
+
+    /// Destinations traffic may not go to
+    acl: Arc<EgressAcl>,
+
+    /// SMTP, BitTorrent and connection-rate controls
+    abuse: AbuseGuard,
+
+    /// Key handlers prove their tunnels with
+    tunnel_key: Option<[u8; 32]>,
+
+    /// Checks the tunnel proofs of handlers
+    tunnel_auth: ExitAuthenticator,
 }
 
 impl ExitService {
-    pub fn new() -> Result<Arc<Self>> {
+    pub fn new(config: &DaemonConfig) -> Result<Arc<Self>> {
+        let responses = Responses::default();
+        let acl = Arc::new(EgressAcl::new(
+            &config.egress_acl,
+            config.server.preferred_group_id,
+        )?);
         #[cfg(target_os = "linux")]
-        let tun = {
-            let mut config = tun::Configuration::default();
-            config
-                .address((10, 200, 0, 1))
-                .netmask((255, 255, 0, 0))
-                .up();
-
-            config.platform(|config| {
-                config.packet_information(false);
-            });
-
-            let dev =
-                tun::create(&config).map_err(|e| anyhow::anyhow!("Failed to create TUN: {}", e))?;
-            Arc::new(std::sync::Mutex::new(dev))
-        };
-
-        #[cfg(not(target_os = "linux"))]
-        let tun = {
-            warn!("TUN is only supported on Linux. Using mock.");
-            Arc::new(std::sync::Mutex::new(()))
+        let mut tun_reader = None;
+        let tun = match config.server.exit_backend {
+            ExitBackend::Tun => {
+                #[cfg(not(target_os = "linux"))]
+                warn!("TUN is only supported on Linux, use exit_backend = \"socket\"");
+                #[cfg(target_os = "linux")]
+                let writer = {
+                    let (reader, writer) = open_tun()?;
+                    tun_reader = Some(reader);
+                    writer
+                };
+                Some(TunEgress {
+                    #[cfg(target_os = "linux")]
+                    writer: std::sync::Mutex::new(writer),
+                    nat: Box::new(NatTable::new(NAT_V4, NAT_V6, &config.nat)),
+                })
+            }
+            ExitBackend::Socket => {
+                info!("Socket egress, no TUN interface");
+                None
+            }
         };
-
-        let route_map = Arc::new(DashMap::new());
-        let handler_streams = Arc::new(DashMap::new());
-        let ip_pool = Arc::new(std::sync::atomic::AtomicU16::new(2));
+        let tunnel_key = config
+            .server
+            .exit_key
+            .as_deref()
+            .map(exit_auth::parse_key)
+            .transpose()
+            .map_err(|_| anyhow::anyhow!("exit_key must be 32 bytes of hex"))?;
+        let connect = ConnectRelay::new(responses.clone(), acl.clone());
+        let udp = UdpRelay::new(responses.clone());
+        let bind = BindRelay::new(
+            responses.clone(),
+            config.server.bind.ip(),
+            config.server.public_ip,
+        );
 
         let service = Arc::new(Self {
             tun,
-            route_map,
-            handler_streams,
-            ip_pool,
+            connect,
+            responses,
+            flows: DashMap::new(),
+            udp,
+            bind,
+            acl,
+            abuse: AbuseGuard::new(&config.abuse),
+            tunnel_key,
+            tunnel_auth: ExitAuthenticator::new(),
         });
 
-        // Start TUN reader
-        service.clone().start_tun_reader();
+        service.clone().start_abuse_expiry();
+        #[cfg(target_os = "linux")]
+        if let Some(reader) = tun_reader {
+            service.clone().start_tun_reader(reader)?;
+        }
+        if service.tun.is_some() {
+            service.clone().start_nat_expiry();
+        }
 
         Ok(service)
     }
 
-    fn start_tun_reader(self: Arc<Self>) {
-        tokio::spawn(async move {
-            #[cfg(target_os = "linux")]
-            {
-                use std::io::Read;
+    /// Read return traffic off the TUN on a thread of its own, as reads block
+    #[cfg(target_os = "linux")]
+    fn start_tun_reader(self: Arc<Self>, mut reader: Reader) -> Result<()> {
+        use std::io::Read;
+        std::thread::Builder::new()
+            .name("tun-reader".into())
+            .spawn(move || {
+                let Some(TunEgress { nat, .. }) = &self.tun else {
+                    return;
+                };
                 let mut buf = [0u8; 2048];
                 loop {
-                    let n = {
-                        let mut tun = self.tun.lock().unwrap();
-                        match tun.read(&mut buf) {
-                            Ok(n) => n,
-                            Err(e) => {
-                                error!("TUN read error: {}", e);
-                                std::thread::sleep(std::time::Duration::from_millis(100));
-                                continue;
-                            }
+                    let n = match reader.read(&mut buf) {
+                        Ok(n) => n,
+                        Err(e) => {
+                            error!("TUN read error: {}", e);
+                            std::thread::sleep(std::time::Duration::from_millis(100));
+                            continue;
                         }
                     };
 
-                    let packet = &buf[..n];
-                    // Parse Dest IP (Return traffic)
-                    if let Ok(slice) = etherparse::Ipv4HeaderSlice::from_slice(packet) {
-                        let dst = slice.destination();
-                        let dst_addr = Ipv4Addr::new(dst[0], dst[1], dst[2], dst[3]);
-
-                        if let Some(route) = self.route_map.get(&dst_addr) {
-                            // Forward to handler stream
-                            if let Some(sender) = self.handler_streams.get(&route.handler_id) {
-                                // We need to wrap this in PlainPacket?
-                                // User said "convert to client-id and forward".
-                                // We send a PlainPacket with payload=packet, conn_id=route.conn_id
-
-                                let pp = PlainPacket {
-                                    magic: PlainPacket::MAGIC,
-                                    conn_id: route.conn_id,
-                                    handler_id: route.handler_id,
-                                    rip: [0; 16],
-                                    rport: 0,
-                                    payload: packet.to_vec(),
-                                    checksum: crc32fast::hash(packet),
-                                    is_response: true,
-                                };
-
-                                // Serialize?
-                                // If stream is raw bytes, we need framing.
-                                // Or stream of rkyv bytes?
-                                // For simplicity, let's assume the stream is "frames" or concatenated.
-                                // HTTP/2 allows DataFrame.
-                                // We send `Frame::<Bytes>::data(bytes)`.
-
-                                if let Ok(bytes) = rkyv::to_bytes::<rkyv::rancor::Error>(&pp) {
-                                    // Prefix with u32 length for framing
-                                    let len = bytes.len() as u32;
-                                    let mut payload = Vec::with_capacity(4 + bytes.len());
-                                    payload.extend_from_slice(&len.to_le_bytes());
-                                    payload.extend_from_slice(&bytes);
-
-                                    let frame = hyper::body::Frame::data(Bytes::from(payload));
-                                    let _ = sender.send(Ok(frame));
-                                }
-                            }
-                        }
+                    // Return traffic: translate back and route to its connection;
+                    // dropped when the handler falls behind, like on a full link
+                    let packet = &mut buf[..n];
+                    if let Some((handler_id, conn_id)) = nat.inbound(packet) {
+                        let pp = PlainPacket::response(conn_id, handler_id, packet.to_vec());
+                        self.responses.try_send(pp);
                     }
                 }
+            })?;
+        Ok(())
+    }
+
+    fn start_nat_expiry(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(NAT_EXPIRY_INTERVAL);
+            let Some(TunEgress { nat, .. }) = &self.tun else {
+                return;
+            };
+            loop {
+                ticker.tick().await;
+                let expired = nat.expire();
+                if expired > 0 {
+                    debug!("Expired {} NAT flows, {} open", expired, nat.len());
+                }
             }
         });
     }
 
-    pub async fn handle_forward(&self, mut packet: PlainPacket) -> Result<()> {
-        // 1. Allocate/Lookup IP
-        // We use the conn_id to map to an IP.
-        // Simplification: We need a map ConnID -> IP.
-        // But `route_map` is IP -> ConnID.
-        // Use a reverse lookup or separate map?
-        // Phase 3: Just linear search or assume IP is stable?
-        // Or allocate new if not found in route_map (checking values)?
-        // DashMap values iter is slow.
-        // Let's alloc IP every time for new conn_id?
-        // We need `conn_map: DashMap<u64, Ipv4Addr>`.
-        // I will add `conn_map` to struct? No, let's keep it simple:
-        // Just Alloc new if we don't know it? No, duplicate IPs.
-        // Let's skip IP reuse for now and use consistent hashing or just store it.
-        // I'll add `conn_map` to struct.
-
-        // Mock logic for IP assignment:
-        // Note: IP allocation uses simple incrementing; connection tracking for IP reuse
-        // would require a conn_id -> IP map (add to struct for production)
-        let virtual_ip = self.alloc_ip();
-
-        // 2. Rewrite Source IP (NAT)
-        if let Ok(mut header) = etherparse::Ipv4Header::from_slice(&packet.payload).map(|(h, _)| h)
-        {
-            header.source = virtual_ip.octets();
-            // Recalculate checksum?
-            // Etherparse write will do it.
-            // We need to write header + rest of payload.
-            // `packet.payload` contains Header + Data.
-
-            // Extract data
-            // We need to parse robustly.
-            // I'll use `etherparse::PacketBuilder`? No, that builds new.
-            // I modify header in place?
-            // `packet.payload` is `Vec<u8>`.
-            // Ipv4 header is 20 bytes (usually).
-
-            if packet.payload.len() >= 20 {
-                packet.payload[12..16].copy_from_slice(&virtual_ip.octets());
-                // Checksum at [10..12].
-                // Recomputing checksum is annoying manually.
-                // Use `etherparse` to re-serialize header?
-                // `header.write_to(&mut slice)`?
-
-                // For Phase 3, I'll trust `etherparse` to help or leave checksum invalid (bad idea).
-                // Correct way:
-                // let (header, rest) = Ipv4Header::read_from_slice(&payload)?;
-                // header.source = ...
-                // let mut new_payload = Vec::new();
-                // header.write(&mut new_payload)?;
-                // new_payload.extend_from_slice(rest);
+    fn start_abuse_expiry(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(ABUSE_EXPIRY_INTERVAL);
+            loop {
+                ticker.tick().await;
+                self.abuse.expire();
             }
+        });
+    }
+
+    /// Handle a packet from a tunnel
+    ///
+    /// Datagram and BIND streams, and targets given by hostname, may wait on
+    /// DNS or socket setup, so their packets are handled in order on a task per
+    /// stream and the tunnel moves on. Everything else is handled right away.
+    async fn dispatch(self: &Arc<Self>, packet: PlainPacket) {
+        // Window the client granted its streams' return traffic
+        if packet.flags.is_control {
+            if let Some((key, increment)) = exit_relay::window_update(&packet) {
+                self.connect.grant(key, increment);
+                self.bind.grant(key, increment);
+            }
+            return;
         }
 
-        // Update maps
-        self.route_map.insert(
-            virtual_ip,
-            RouteEntry {
-                handler_id: packet.handler_id,
-                conn_id: packet.conn_id,
+        let key = (packet.handler_id, packet.conn_id, packet.stream_id);
+        // The tunnel never waits for a stream: datagrams are dropped while their
+        // stream is behind, other streams are reset. Packets reaching a task
+        // that already ended are handled here
+        let packet = match self.flows.get(&key).map(|queue| queue.clone()) {
+            Some(queue) => match queue.try_send(packet) {
+                Ok(()) => return,
+                Err(TrySendError::Full(packet)) if packet.flags.is_datagram => {
+                    debug!(
+                        "Dropping datagram of busy stream {}/{}",
+                        packet.conn_id, packet.stream_id
+                    );
+                    return;
+                }
+                Err(TrySendError::Full(packet)) => {
+                    warn!(
+                        "Resetting stream {}/{}: too far behind",
+                        packet.conn_id, packet.stream_id
+                    );
+                    self.flows.remove(&key);
+                    self.close_stream(key);
+                    return;
+                }
+                Err(TrySendError::Closed(packet)) => packet,
             },
-        );
+            None => packet,
+        };
 
-        #[cfg(target_os = "linux")]
-        {
-            use std::io::Write;
-            let mut tun = self.tun.lock().unwrap();
-            tun.write_all(&packet.payload)?;
+        // Connect streams resolve their targets on the relay's own tasks
+        let connect_open =
+            packet.flags.is_open && !packet.flags.is_bind && !packet.flags.is_datagram;
+        let may_wait = !connect_open
+            && (packet.flags.is_datagram || packet.flags.is_bind || packet.rhost.is_some());
+        if !may_wait {
+            return self.forward(packet).await;
+        }
+
+        let (tx, rx) = mpsc::channel(FLOW_QUEUE);
+        self.flows.insert(key, tx);
+        tokio::spawn(self.clone().run_flow(key, packet, rx));
+    }
+
+    /// Handle the packets of one stream until it ends or goes idle
+    async fn run_flow(
+        self: Arc<Self>,
+        key: FlowKey,
+        first: PlainPacket,
+        mut rx: mpsc::Receiver<PlainPacket>,
+    ) {
+        let mut next = Some(first);
+        while let Some(packet) = next {
+            let last = packet.flags.is_final;
+            self.forward(packet).await;
+            if last {
+                break;
+            }
+            next = tokio::time::timeout(FLOW_IDLE_TIMEOUT, rx.recv())
+                .await
+                .ok()
+                .flatten();
+        }
+
+        // Packets queued before the queue closed are still ours
+        self.flows.remove(&key);
+        rx.close();
+        while let Some(packet) = rx.recv().await {
+            self.forward(packet).await;
+        }
+    }
+
+    async fn forward(&self, packet: PlainPacket) {
+        if let Err(e) = self.handle_forward(packet).await {
+            error!("Forward error: {}", e);
+        }
+    }
+
+    pub async fn handle_forward(&self, mut packet: PlainPacket) -> Result<()> {
+        // Stream packets are screened as they come, raw IP packets once parsed
+        let refused = if is_ip_packet(&packet) {
+            None
+        } else {
+            self.abuse.screen_stream(&packet).err()
+        };
+        if let Some(kind) = refused {
+            self.refuse_stream(&packet, kind);
+            return Ok(());
+        }
+
+        // The connect relay resolves targets itself to race both families
+        let connect_open =
+            packet.flags.is_open && !packet.flags.is_bind && !packet.flags.is_datagram;
+        if connect_open {
+            self.connect.open(packet);
+            return Ok(());
+        }
+
+        // Hostname targets are resolved here so clients never leak DNS locally
+        let resolved = if packet.flags.is_datagram {
+            self.udp.resolve(&mut packet).await
+        } else {
+            exit_relay::resolve_host(&mut packet).await
+        };
+        if let Err(e) = resolved {
+            if packet.flags.is_open {
+                self.responses.push(exit_relay::close_reply((
+                    packet.handler_id,
+                    packet.conn_id,
+                    packet.stream_id,
+                )));
+            }
+            return Err(e);
+        }
+
+        // Datagram and BIND streams always use per-stream sockets
+        if packet.flags.is_open && packet.flags.is_bind {
+            return self.bind.open(&packet).await;
+        }
+        if self.bind.owns(&packet) {
+            self.bind.send(packet);
+            return Ok(());
+        }
+        if packet.flags.is_final {
+            self.udp
+                .close(packet.handler_id, packet.conn_id, packet.stream_id);
+            // The whole connection closed, its NAT flows go with it
+            if let (Some(tun), 0) = (&self.tun, packet.stream_id) {
+                tun.nat.release((packet.handler_id, packet.conn_id));
+            }
+            if packet.stream_id == 0 {
+                self.abuse.release((packet.handler_id, packet.conn_id));
+            }
+        }
+        if packet.flags.is_datagram {
+            let target = exit_relay::packet_addr(&packet);
+            if !self.allowed(&packet, target.ip(), Some(target.port())) {
+                self.refuse(&packet);
+                return Ok(());
+            }
+            return self.udp.send(&packet).await;
+        }
+
+        if !is_ip_packet(&packet) {
+            self.connect.send(packet);
+            return Ok(());
+        }
+        if packet.payload.is_empty() {
+            return Ok(());
+        }
+        match &self.tun {
+            #[cfg(target_os = "linux")]
+            Some(TunEgress { writer, nat }) => {
+                use std::io::Write;
+                let denied = nat::destination(&packet.payload)
+                    .is_some_and(|(ip, port)| !self.allowed(&packet, ip, port));
+                if denied {
+                    self.refuse(&packet);
+                    return Ok(());
+                }
+                let owner = (packet.handler_id, packet.conn_id);
+                let new_flow = nat.is_new(owner, &packet.payload);
+                if let Err(kind) = self
+                    .abuse
+                    .screen_ip(packet.user_id, &packet.payload, new_flow)
+                {
+                    self.report(&packet, kind);
+                    return Ok(());
+                }
+                nat.outbound(owner, &mut packet.payload)?;
+                writer.lock().unwrap().write_all(&packet.payload)?;
+            }
+            #[cfg(not(target_os = "linux"))]
+            Some(_) => {}
+            None => debug!(
+                "No TUN egress, dropping IP packet of conn {}",
+                packet.conn_id
+            ),
         }
 
         Ok(())
     }
 
-    fn alloc_ip(&self) -> Ipv4Addr {
-        let id = self
-            .ip_pool
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        Ipv4Addr::new(10, 200, (id >> 8) as u8, (id & 0xFF) as u8)
+    /// Close a stream the abuse controls refused and report its connection
+    fn refuse_stream(&self, packet: &PlainPacket, kind: AbuseKind) {
+        self.report(packet, kind);
+        self.close_stream((packet.handler_id, packet.conn_id, packet.stream_id));
     }
 
-    pub fn register_stream(
-        &self,
-        handler_id: u64,
-    ) -> UnboundedReceiverStream<Result<hyper::body::Frame<Bytes>, anyhow::Error>> {
-        let (tx, rx) = mpsc::unbounded_channel();
-        self.handler_streams.insert(handler_id, tx);
-        UnboundedReceiverStream::new(rx)
+    /// Tear down whatever the exit holds for a stream and tell its client
+    fn close_stream(&self, key: FlowKey) {
+        self.udp.close(key.0, key.1, key.2);
+        // TCP streams report their own close as they stop
+        if !self.bind.close(key) && !self.connect.close(key) {
+            self.responses.push(exit_relay::close_reply(key));
+        }
+    }
+
+    /// Close the stream of a packet the egress ACL refused, so the client
+    /// learns of it right away
+    fn refuse(&self, packet: &PlainPacket) {
+        let key = (packet.handler_id, packet.conn_id, packet.stream_id);
+        self.udp.close(key.0, key.1, key.2);
+        self.responses.push(exit_relay::close_reply(key));
+    }
+
+    /// Tell the handler about an offending connection, unless it was told recently
+    fn report(&self, packet: &PlainPacket, kind: AbuseKind) {
+        debug!("Refused {:?} traffic of conn {}", kind, packet.conn_id);
+        if let Some(report) = self.abuse.report((packet.handler_id, packet.conn_id), kind) {
+            info!(
+                "Reporting conn {} to handler {} for {:?}",
+                packet.conn_id, packet.handler_id, kind
+            );
+            self.responses.push(report);
+        }
+    }
+
+    /// Check a destination against the egress ACL
+    fn allowed(&self, packet: &PlainPacket, ip: IpAddr, port: Option<u16>) -> bool {
+        match self.acl.check(ip, port) {
+            Ok(()) => true,
+            Err(denied) => {
+                debug!(
+                    "Refusing packet of conn {} to {}: {}",
+                    packet.conn_id, ip, denied
+                );
+                false
+            }
+        }
+    }
+
+    /// Carry packets of one handler until its tunnel closes
+    ///
+    /// A reconnecting handler takes over the return traffic of its old tunnel.
+    pub async fn serve_tunnel<S>(self: Arc<Self>, ws: WebSocketStream<S>, handler_id: u64)
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let (tx, mut rx) = mpsc::channel(TUNNEL_QUEUE);
+        self.responses.attach(handler_id, tx.clone());
+        info!("Handler {} tunnel up", handler_id);
+
+        let dispatcher = Dispatcher(self.clone());
+        match run_tunnel(ws, &mut rx, &dispatcher, TUNNEL_IDLE_TIMEOUT).await {
+            Ok(()) => info!("Handler {} tunnel closed", handler_id),
+            Err(e) => warn!("Handler {} tunnel failed: {}", handler_id, e),
+        }
+
+        self.responses.detach(handler_id, &tx);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_tun() -> Result<(Reader, Writer)> {
+    let mut config = tun::Configuration::default();
+    config
+        .address((10, 200, 0, 1))
+        .netmask((255, 255, 0, 0))
+        .up();
+
+    config.platform(|config| {
+        config.packet_information(false);
+    });
+
+    let dev = tun::create(&config).map_err(|e| anyhow::anyhow!("Failed to create TUN: {}", e))?;
+
+    // The tun crate only configures IPv4 addresses
+    let name = tun::Device::name(&dev).map_err(|e| anyhow::anyhow!("TUN has no name: {}", e))?;
+    let added = std::process::Command::new("ip")
+        .args(["-6", "addr", "add", "fd00:200::1/64", "dev", &name])
+        .status();
+    match added {
+        Ok(status) if status.success() => {
+            info!("TUN interface {} up (10.200.0.1/16, fd00:200::1/64)", name)
+        }
+        Ok(status) => warn!("TUN {} is IPv4 only, ip -6 exited with {}", name, status),
+        Err(e) => warn!("TUN {} is IPv4 only, cannot run ip -6: {}", name, e),
+    }
+    Ok(dev.split())
+}
+
+/// Whether `packet` carries a raw client IP packet rather than stream data
+fn is_ip_packet(packet: &PlainPacket) -> bool {
+    packet.stream_id == 0
+        && !packet.flags.is_open
+        && !packet.flags.is_datagram
+        && !packet.flags.is_bind
+}
+
+/// Hands the packets of a tunnel to the service
+struct Dispatcher(Arc<ExitService>);
+
+#[async_trait]
+impl PacketDispatcher for Dispatcher {
+    async fn dispatch(&self, packet: PlainPacket) {
+        self.0.dispatch(packet).await;
     }
 }
 
@@ -259,14 +556,16 @@ pub async fn run(config: &DaemonConfig) -> Result<()> {
     }
 
     // Traditional mode: exit-node as server
-    let service = ExitService::new()?;
-    info!("TUN interface up (10.200.0.1/16) [MOCK on Windows]");
+    if config.server.exit_key.is_none() {
+        anyhow::bail!("exit_key not configured; handlers prove their tunnels with it");
+    }
+    let service = ExitService::new(config)?;
 
     let listener = TcpListener::bind(config.server.bind).await?;
     info!("Exit Node listening on {}", config.server.bind);
 
     loop {
-        let (stream, addr) = listener.accept().await?;
+        let (stream, peer) = listener.accept().await?;
         let service = service.clone();
 
         tokio::spawn(async move {
@@ -275,11 +574,12 @@ pub async fn run(config: &DaemonConfig) -> Result<()> {
 
             let hyper_service = service_fn(move |req| {
                 let service = service.clone();
-                async move { handle_http_request(req, service).await }
+                async move { handle_http_request(req, service, peer).await }
             });
 
             if let Err(e) = hyper::server::conn::http1::Builder::new()
                 .serve_connection(io, hyper_service)
+                .with_upgrades()
                 .await
             {
                 debug!("Connection closed: {}", e);
@@ -291,36 +591,11 @@ pub async fn run(config: &DaemonConfig) -> Result<()> {
 async fn handle_http_request(
     req: Request<Incoming>,
     service: Arc<ExitService>,
+    peer: SocketAddr,
 ) -> Result<Response<BoxBody>, hyper::Error> {
     // Changed to BoxBody wrapper
     match (req.method(), req.uri().path()) {
-        (&hyper::Method::POST, "/forward") => {
-            let body = req.collect().await?.to_bytes();
-            if let Ok(packet) = rkyv::from_bytes::<PlainPacket, rkyv::rancor::Error>(&body) {
-                if let Err(e) = service.handle_forward(packet).await {
-                    error!("Forward error: {}", e);
-                }
-                Ok(Response::new(fullempty()))
-            } else {
-                Ok(Response::builder()
-                    .status(400)
-                    .body(full("Invalid Data"))
-                    .unwrap())
-            }
-        }
-        (&hyper::Method::GET, "/stream") => {
-            // handler_id query param?
-            // Assume 1 for demo or parse query
-            // Demo: Using handler_id=1; production should parse from query string
-            let handler_id = 1;
-            let stream = service.register_stream(handler_id);
-            let body = StreamBody::new(stream);
-            let boxed = BodyExt::boxed(body); // requires http-body-util BoxBody
-            // return Ok(Response::new(boxed));
-            // Type mismatch annoyance usually.
-            // Using a helper `BoxBody` type alias helps.
-            Ok(Response::new(boxed))
-        }
+        (&hyper::Method::GET, TUNNEL_PATH) => Ok(accept_tunnel(req, service, peer)),
         _ => Ok(Response::builder()
             .status(404)
             .body(full("Not Found"))
@@ -328,6 +603,86 @@ async fn handle_http_request(
     }
 }
 
+/// Upgrade a handler's request to its packet tunnel
+fn accept_tunnel(
+    mut req: Request<Incoming>,
+    service: Arc<ExitService>,
+    peer: SocketAddr,
+) -> Response<BoxBody> {
+    let handler_id = req.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == "handler_id")
+            .and_then(|(_, value)| value.parse::<u64>().ok())
+    });
+    let Some(handler_id) = handler_id else {
+        return Response::builder()
+            .status(400)
+            .body(full("Missing handler_id"))
+            .unwrap();
+    };
+
+    let Some(key) = req.headers().get("Sec-WebSocket-Key") else {
+        return Response::builder()
+            .status(400)
+            .body(full("Expected WebSocket upgrade"))
+            .unwrap();
+    };
+    let accept = derive_accept_key(key.as_bytes());
+
+    // The tunnel receives the return traffic of `handler_id`, so only that
+    // handler may open it
+    let proof = req
+        .headers()
+        .get(exit_auth::EXIT_AUTH_HEADER)
+        .and_then(|v| v.to_str().ok());
+    let (Some(tunnel_key), Some(proof)) = (&service.tunnel_key, proof) else {
+        return Response::builder()
+            .status(401)
+            .body(full("Missing credentials"))
+            .unwrap();
+    };
+    if let Err(e) =
+        service
+            .tunnel_auth
+            .verify(&exit_auth::tunnel_name(handler_id), proof, tunnel_key)
+    {
+        warn!(
+            "Refusing tunnel of handler {} from {}: {}",
+            handler_id, peer, e
+        );
+        return Response::builder()
+            .status(403)
+            .body(full("Invalid credentials"))
+            .unwrap();
+    }
+
+    // Clients must not reach the handler through its own exit
+    service.acl.deny_handler(peer.ip());
+
+    let upgrade = hyper::upgrade::on(&mut req);
+    tokio::spawn(async move {
+        match upgrade.await {
+            Ok(upgraded) => {
+                let ws =
+                    WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None)
+                        .await;
+                service.serve_tunnel(ws, handler_id).await;
+            }
+            Err(e) => error!("Tunnel upgrade error: {}", e),
+        }
+    });
+
+    Response::builder()
+        .status(101)
+        .header("Upgrade", "websocket")
+        .header("Connection", "Upgrade")
+        .header("Sec-WebSocket-Accept", accept)
+        .body(fullempty())
+        .unwrap()
+}
+
 // Helpers for body types
 type BoxBody = http_body_util::combinators::BoxBody<Bytes, anyhow::Error>;
 
@@ -351,12 +706,13 @@ async fn run_reverse_mode(config: &DaemonConfig) -> Result<()> {
         .as_ref()
         .ok_or_else(|| anyhow::anyhow!("handler_endpoint not configured for reverse_mode"))?;
 
-    let node_name = config
+    let node_name = config.server.location.as_deref().unwrap_or("exit-node");
+
+    let exit_key = config
         .server
-        .location
-        .as_ref()
-        .map(|s| s.as_str())
-        .unwrap_or("exit-node");
+        .exit_key
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("exit_key not configured for reverse_mode"))?;
 
     let preferred_group_id = config.server.preferred_group_id;
 
@@ -365,15 +721,14 @@ async fn run_reverse_mode(config: &DaemonConfig) -> Result<()> {
         handler_endpoint, node_name, preferred_group_id
     );
 
-    // Create ExitService for TUN interface
-    let service = ExitService::new()?;
-    info!("TUN interface up (10.200.0.1/16) [MOCK on Windows]");
+    let service = ExitService::new(config)?;
 
     // Connect to handler with retry logic
     loop {
         match connect_to_handler(
             handler_endpoint,
             node_name,
+            exit_key,
             preferred_group_id,
             service.clone(),
         )
@@ -394,10 +749,12 @@ async fn run_reverse_mode(config: &DaemonConfig) -> Result<()> {
 async fn connect_to_handler(
     handler_endpoint: &str,
     node_name: &str,
+    exit_key: &str,
     preferred_group_id: Option<i32>,
     service: Arc<ExitService>,
 ) -> Result<()> {
     use tokio_tungstenite::connect_async;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
 
     // Build WebSocket URL (no group_id - will be negotiated)
     let ws_url = format!(
@@ -407,105 +764,65 @@ async fn connect_to_handler(
 
     info!("Connecting to {}", ws_url);
 
-    // Connect to handler
-    let (ws_stream, _) = connect_async(&ws_url).await?;
-    info!("Connected to handler successfully");
+    // Prove our enrolled name; the proof is fresh for every attempt
+    let mut request = ws_url.as_str().into_client_request()?;
+    let proof = exit_auth::auth_header(node_name, exit_key)
+        .map_err(|_| anyhow::anyhow!("exit_key must be hex"))?;
+    request
+        .headers_mut()
+        .insert(exit_auth::EXIT_AUTH_HEADER, proof.parse()?);
+
+    // Connect to handler; return packets are addressed to its node ID
+    let (ws_stream, response) = connect_async(request).await?;
+    let handler_id = response
+        .headers()
+        .get("X-Handler-Id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .ok_or_else(|| anyhow::anyhow!("Handler did not send X-Handler-Id"))?;
+    info!("Connected to handler {} successfully", handler_id);
+
+    // Clients must not reach the handler through its own exit
+    match tokio::net::lookup_host(handler_endpoint).await {
+        Ok(addrs) => addrs.for_each(|addr| service.acl.deny_handler(addr.ip())),
+        Err(e) => warn!(
+            "Cannot resolve handler {} for the ACL: {}",
+            handler_endpoint, e
+        ),
+    }
 
     let (mut ws_sender, mut ws_receiver) = ws_stream.split();
 
     // Wait for GroupList from handler
-    use apfsds_protocol::{ControlMessage, GroupInfo};
-
-    let selected_group_id = if let Some(group_id) = preferred_group_id {
-        // Use configured group_id
-        info!("Using configured group_id: {}", group_id);
-
-        // Still need to receive GroupList from handler (protocol requirement)
-        loop {
-            match ws_receiver.next().await {
-                Some(Ok(tokio_tungstenite::tungstenite::Message::Binary(data))) => {
-                    if let Ok(msg) = rkyv::from_bytes::<ControlMessage, rkyv::rancor::Error>(&data)
-                    {
-                        if let ControlMessage::GroupList { groups } = msg {
-                            info!("Received {} available groups from handler", groups.len());
-
-                            // Verify that configured group exists
-                            if groups.iter().any(|g| g.group_id == group_id) {
-                                info!("Configured group {} found in available groups", group_id);
-                                break group_id;
-                            } else {
-                                warn!(
-                                    "Configured group {} not found, falling back to auto-select",
-                                    group_id
-                                );
-                                // Fall back to auto-select
-                                let selected = groups
-                                    .iter()
-                                    .min_by_key(|g| g.load)
-                                    .ok_or_else(|| anyhow::anyhow!("No groups available"))?;
-                                info!(
-                                    "Auto-selected group {} ({}), load: {}%",
-                                    selected.group_id, selected.name, selected.load
-                                );
-                                break selected.group_id;
-                            }
-                        }
-                    }
-                }
-                Some(Ok(tokio_tungstenite::tungstenite::Message::Close(_))) => {
-                    return Err(anyhow::anyhow!(
-                        "Handler closed connection before sending groups"
-                    ));
-                }
-                Some(Err(e)) => {
-                    return Err(anyhow::anyhow!("WebSocket error: {}", e));
-                }
-                None => {
-                    return Err(anyhow::anyhow!("Connection closed before receiving groups"));
+    use apfsds_protocol::ControlMessage;
+
+    let groups = loop {
+        match ws_receiver.next().await {
+            Some(Ok(tokio_tungstenite::tungstenite::Message::Binary(data))) => {
+                if let Ok(ControlMessage::GroupList { groups }) =
+                    rkyv::from_bytes::<ControlMessage, rkyv::rancor::Error>(&data)
+                {
+                    info!("Received {} available groups from handler", groups.len());
+                    break groups;
                 }
-                _ => {}
             }
-        }
-    } else {
-        // Auto-select group with lowest load
-        loop {
-            match ws_receiver.next().await {
-                Some(Ok(tokio_tungstenite::tungstenite::Message::Binary(data))) => {
-                    if let Ok(msg) = rkyv::from_bytes::<ControlMessage, rkyv::rancor::Error>(&data)
-                    {
-                        if let ControlMessage::GroupList { groups } = msg {
-                            info!("Received {} available groups from handler", groups.len());
-
-                            // Select group with lowest load
-                            let selected = groups
-                                .iter()
-                                .min_by_key(|g| g.load)
-                                .ok_or_else(|| anyhow::anyhow!("No groups available"))?;
-
-                            info!(
-                                "Auto-selected group {} ({}), load: {}%",
-                                selected.group_id, selected.name, selected.load
-                            );
-
-                            break selected.group_id;
-                        }
-                    }
-                }
-                Some(Ok(tokio_tungstenite::tungstenite::Message::Close(_))) => {
-                    return Err(anyhow::anyhow!(
-                        "Handler closed connection before sending groups"
-                    ));
-                }
-                Some(Err(e)) => {
-                    return Err(anyhow::anyhow!("WebSocket error: {}", e));
-                }
-                None => {
-                    return Err(anyhow::anyhow!("Connection closed before receiving groups"));
-                }
-                _ => {}
+            Some(Ok(tokio_tungstenite::tungstenite::Message::Close(_))) => {
+                return Err(anyhow::anyhow!(
+                    "Handler closed connection before sending groups"
+                ));
+            }
+            Some(Err(e)) => {
+                return Err(anyhow::anyhow!("WebSocket error: {}", e));
             }
+            None => {
+                return Err(anyhow::anyhow!("Connection closed before receiving groups"));
+            }
+            _ => {}
         }
     };
+    let selected_group_id = select_group(&groups, preferred_group_id)?;
+
+    service.acl.set_group(selected_group_id);
 
     // Send group selection back to handler
     let select_msg = ControlMessage::GroupSelect {
@@ -522,38 +839,62 @@ async fn connect_to_handler(
         return Err(anyhow::anyhow!("Failed to serialize group selection"));
     }
 
-    // Handle incoming messages from handler
-    while let Some(msg_result) = ws_receiver.next().await {
-        match msg_result {
-            Ok(tokio_tungstenite::tungstenite::Message::Binary(data)) => {
-                // Decode PlainPacket from handler
-                if let Ok(packet) = rkyv::from_bytes::<PlainPacket, rkyv::rancor::Error>(&data) {
-                    // Forward to TUN interface
-                    if let Err(e) = service.handle_forward(packet).await {
-                        error!("Failed to forward packet: {}", e);
-                    }
-                }
-            }
-            Ok(tokio_tungstenite::tungstenite::Message::Ping(data)) => {
-                if let Err(e) = ws_sender
-                    .send(tokio_tungstenite::tungstenite::Message::Pong(data))
-                    .await
-                {
-                    error!("Failed to send pong: {}", e);
-                    break;
-                }
-            }
-            Ok(tokio_tungstenite::tungstenite::Message::Close(_)) => {
-                info!("Handler closed connection");
-                break;
-            }
-            Err(e) => {
-                error!("WebSocket error: {}", e);
-                break;
-            }
-            _ => {}
+    // The connection carries packets from now on
+    let ws_stream = ws_sender
+        .reunite(ws_receiver)
+        .map_err(|_| anyhow::anyhow!("Failed to reunite WebSocket halves"))?;
+    service.serve_tunnel(ws_stream, handler_id).await;
+
+    Ok(())
+}
+
+/// Group to join: the configured one if the handler offers it, otherwise
+/// the least loaded
+fn select_group(groups: &[apfsds_protocol::GroupInfo], preferred: Option<i32>) -> Result<i32> {
+    if let Some(group_id) = preferred {
+        if groups.iter().any(|g| g.group_id == group_id) {
+            info!("Using configured group {}", group_id);
+            return Ok(group_id);
         }
+        warn!(
+            "Configured group {} not found, falling back to auto-select",
+            group_id
+        );
     }
 
-    Ok(())
+    let selected = groups
+        .iter()
+        .min_by_key(|g| g.load)
+        .ok_or_else(|| anyhow::anyhow!("No groups available"))?;
+    info!(
+        "Auto-selected group {} ({}), load: {}%",
+        selected.group_id, selected.name, selected.load
+    );
+    Ok(selected.group_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use apfsds_protocol::GroupInfo;
+
+    fn group(group_id: i32, load: u8) -> GroupInfo {
+        GroupInfo {
+            group_id,
+            name: format!("group-{}", group_id),
+            node_count: 1,
+            load,
+        }
+    }
+
+    #[test]
+    fn test_select_group() {
+        let groups = vec![group(1, 80), group(2, 10), group(3, 50)];
+
+        assert_eq!(select_group(&groups, Some(3)).unwrap(), 3);
+        // Unknown preference falls back to the least loaded group
+        assert_eq!(select_group(&groups, Some(9)).unwrap(), 2);
+        assert_eq!(select_group(&groups, None).unwrap(), 2);
+        assert!(select_group(&[], None).is_err());
+    }
 }
@@ -1,21 +1,19 @@
 //! Exit Node Connection Pool
 //!
 //! Manages reverse connections from exit-nodes without public IP.
-//! Exit-nodes connect to handler via WebSocket and register themselves.
+//! Exit-nodes connect to handler via WebSocket and register themselves;
+//! their traffic then goes through the same [`ExitPool`] as configured exits.
 
-use anyhow::Result;
+use crate::config::ExitLoadConfig;
+use apfsds_protocol::GroupInfo;
+use apfsds_storage::postgres::ExitGroup;
+use apfsds_transport::{ExitClientError, ExitPool, GroupLoad};
 use dashmap::DashMap;
-use futures::{SinkExt, StreamExt};
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
-use tokio::sync::mpsc;
-use tokio_tungstenite::tungstenite::Message;
-use tracing::{debug, error, info, warn};
-
-/// WebSocket sender type
-type WsSender = futures::stream::SplitSink<
-    tokio_tungstenite::WebSocketStream<hyper_util::rt::TokioIo<tokio::net::TcpStream>>,
-    Message,
->;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_tungstenite::WebSocketStream;
+use tracing::info;
 
 /// Exit node connection info
 #[derive(Debug)]
@@ -26,8 +24,6 @@ pub struct ExitNodeConnection {
     pub name: String,
     /// Group ID
     pub group_id: i32,
-    /// WebSocket sender
-    pub sender: mpsc::UnboundedSender<Message>,
 }
 
 /// Exit Node Pool
@@ -36,23 +32,42 @@ pub struct ExitNodePool {
     connections: Arc<DashMap<u64, ExitNodeConnection>>,
     /// Next node ID
     next_id: Arc<std::sync::atomic::AtomicU64>,
+    /// Pool the nodes' traffic is balanced in
+    exit_pool: Arc<ExitPool>,
 }
 
 impl ExitNodePool {
-    pub fn new() -> Self {
+    pub fn new(exit_pool: Arc<ExitPool>) -> Self {
         Self {
             connections: Arc::new(DashMap::new()),
             next_id: Arc::new(std::sync::atomic::AtomicU64::new(1)),
+            exit_pool,
         }
     }
 
-    /// Register a new exit-node connection
-    pub fn register(
+    /// Carry a registered exit-node's traffic until it disconnects or
+    /// `until` completes
+    pub async fn serve<S>(
         &self,
         name: String,
         group_id: i32,
-        sender: mpsc::UnboundedSender<Message>,
-    ) -> u64 {
+        ws: WebSocketStream<S>,
+        until: impl Future<Output = ()>,
+    ) -> Result<(), ExitClientError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let node_id = self.register(name.clone(), group_id);
+        let result = self
+            .exit_pool
+            .serve_reverse_node(&name, group_id, ws, until)
+            .await;
+        self.unregister(node_id);
+        result
+    }
+
+    /// Register a new exit-node connection
+    fn register(&self, name: String, group_id: i32) -> u64 {
         let node_id = self
             .next_id
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
@@ -61,7 +76,6 @@ impl ExitNodePool {
             node_id,
             name: name.clone(),
             group_id,
-            sender,
         };
 
         self.connections.insert(node_id, conn);
@@ -74,49 +88,152 @@ impl ExitNodePool {
     }
 
     /// Unregister an exit-node
-    pub fn unregister(&self, node_id: u64) {
+    fn unregister(&self, node_id: u64) {
         if let Some((_, conn)) = self.connections.remove(&node_id) {
-            info!("Exit-node unregistered: id={}, name={}", node_id, conn.name);
+            info!(
+                "Exit-node unregistered: id={}, name={}, group={}",
+                node_id, conn.name, conn.group_id
+            );
         }
     }
 
-    /// Get exit-node by ID
-    pub fn get(
+    /// Groups of `allowed` offered to a registering exit
+    ///
+    /// Names come from the groups defined in the database; node counts and
+    /// load from the configured and reverse-connected nodes in each group.
+    pub async fn group_list(
         &self,
-        node_id: u64,
-    ) -> Option<dashmap::mapref::one::Ref<'_, u64, ExitNodeConnection>> {
-        self.connections.get(&node_id)
+        defined: Vec<ExitGroup>,
+        allowed: &[i32],
+        capacity: &ExitLoadConfig,
+    ) -> Vec<GroupInfo> {
+        group_infos(
+            defined,
+            allowed,
+            self.exit_pool.group_loads().await,
+            capacity,
+        )
     }
 
-    /// Select an exit-node by group_id (simple round-robin)
-    pub fn select_by_group(&self, group_id: i32) -> Option<u64> {
-        let nodes: Vec<u64> = self
-            .connections
-            .iter()
-            .filter(|entry| entry.value().group_id == group_id)
-            .map(|entry| *entry.key())
-            .collect();
+    /// Get connection count
+    pub fn count(&self) -> usize {
+        self.connections.len()
+    }
+}
+
+fn group_infos(
+    defined: Vec<ExitGroup>,
+    allowed: &[i32],
+    mut loads: HashMap<i32, GroupLoad>,
+    capacity: &ExitLoadConfig,
+) -> Vec<GroupInfo> {
+    let mut names: BTreeMap<i32, String> = defined.into_iter().map(|g| (g.id, g.name)).collect();
+    // Enrolled groups may not be defined or have nodes yet
+    for id in loads.keys().chain(allowed) {
+        names.entry(*id).or_insert_with(|| match id {
+            0 => "default".to_string(),
+            id => format!("group-{}", id),
+        });
+    }
+
+    names
+        .into_iter()
+        .filter(|(group_id, _)| allowed.contains(group_id))
+        .map(|(group_id, name)| {
+            let stats = loads.remove(&group_id).unwrap_or_default();
+            GroupInfo {
+                group_id,
+                name,
+                node_count: stats.healthy as u32,
+                load: group_load(&stats, capacity),
+            }
+        })
+        .collect()
+}
 
-        if nodes.is_empty() {
-            None
-        } else {
-            // Simple selection: first available
-            // TODO: Implement proper load balancing
-            Some(nodes[0])
+/// Percentage of the healthy nodes' capacity in use, by connections or
+/// throughput, whichever is higher
+fn group_load(stats: &GroupLoad, capacity: &ExitLoadConfig) -> u8 {
+    if stats.healthy == 0 {
+        return 0;
+    }
+    let nodes = stats.healthy as u64;
+    let percent = |used: u64, per_node: u64| used.saturating_mul(100) / (nodes * per_node.max(1));
+
+    let connections = percent(stats.load.connections as u64, capacity.node_connections);
+    let throughput = percent(stats.load.throughput_bps, capacity.node_throughput_bps);
+    connections.max(throughput).min(100) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use apfsds_transport::ExitLoad;
+
+    fn stats(nodes: usize, healthy: usize, connections: usize, throughput_bps: u64) -> GroupLoad {
+        GroupLoad {
+            nodes,
+            healthy,
+            load: ExitLoad {
+                connections,
+                throughput_bps,
+            },
         }
     }
 
-    /// Get all node IDs in a group
-    pub fn get_group_nodes(&self, group_id: i32) -> Vec<u64> {
-        self.connections
-            .iter()
-            .filter(|entry| entry.value().group_id == group_id)
-            .map(|entry| *entry.key())
-            .collect()
+    #[test]
+    fn test_group_load() {
+        let capacity = ExitLoadConfig {
+            node_connections: 100,
+            node_throughput_bps: 1000,
+        };
+
+        assert_eq!(group_load(&stats(2, 2, 50, 0), &capacity), 25);
+        // The busier resource decides
+        assert_eq!(group_load(&stats(2, 2, 50, 1500), &capacity), 75);
+        // Unhealthy nodes add no capacity
+        assert_eq!(group_load(&stats(2, 1, 50, 0), &capacity), 50);
+        assert_eq!(group_load(&stats(1, 1, 500, 0), &capacity), 100);
+        assert_eq!(group_load(&stats(3, 0, 0, 0), &capacity), 0);
     }
 
-    /// Get connection count
-    pub fn count(&self) -> usize {
-        self.connections.len()
+    #[test]
+    fn test_group_infos() {
+        let defined = vec![
+            ExitGroup {
+                id: 1,
+                name: "premium".to_string(),
+                description: None,
+                rate_up_bps: None,
+                rate_down_bps: None,
+            },
+            ExitGroup {
+                id: 4,
+                name: "asia".to_string(),
+                description: None,
+                rate_up_bps: None,
+                rate_down_bps: None,
+            },
+        ];
+        let loads = HashMap::from([
+            (0, stats(1, 1, 5000, 0)),
+            (2, stats(1, 0, 0, 0)),
+            (3, stats(1, 1, 0, 0)),
+        ]);
+
+        let groups = group_infos(defined, &[0, 1, 2, 5], loads, &ExitLoadConfig::default());
+        let summary: Vec<_> = groups
+            .iter()
+            .map(|g| (g.group_id, g.name.as_str(), g.node_count, g.load))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (0, "default", 1, 50),
+                (1, "premium", 0, 0),
+                (2, "group-2", 0, 0),
+                (5, "group-5", 0, 0),
+            ]
+        );
     }
 }
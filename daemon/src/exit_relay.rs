@@ -0,0 +1,1145 @@
+//! Socket relays for datagram, BIND and connect streams
+//!
+//! These streams are not routed through the TUN interface. Each stream gets
+//! its own socket on the exit node (a UDP socket for SOCKS5 UDP ASSOCIATE, a
+//! one-shot TCP listener for SOCKS5 BIND, an outgoing TCP connection for
+//! every other stream), and replies are wrapped back into
+//! `PlainPacket`s for the owning stream and queued on its handler's tunnel.
+//!
+//! TCP streams read their socket only as far as the client's window allows:
+//! the handler passes the client's `WindowUpdate`s on, and a stream whose
+//! window is used up stops reading until more arrives.
+
+use crate::egress_acl::EgressAcl;
+use anyhow::Result;
+use apfsds_protocol::{ControlMessage, INITIAL_STREAM_WINDOW, PlainPacket, ReceiveWindow};
+use dashmap::DashMap;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedReadHalf;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::{Semaphore, oneshot};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, trace, warn};
+
+/// Idle time (nothing sent or received) after which a datagram flow is torn down
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Idle time after which a TCP stream is reset, e.g. one left waiting for
+/// window from a client that is gone
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How long a BIND listener waits for its inbound connection
+const BIND_ACCEPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long a connect stream may take to reach its target
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Hostnames a datagram stream keeps resolved before starting over
+const MAX_CACHED_HOSTS: usize = 256;
+
+/// Head start of each connection attempt over the next one (RFC 8305)
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Key identifying a stream's flow: (handler_id, conn_id, stream_id)
+pub type FlowKey = (u64, u64, u32);
+
+/// Return path to the handlers: the tunnel queue of each one
+///
+/// Relays wait on the queue of their own handler, so a handler that falls
+/// behind slows down its streams and nobody else's.
+#[derive(Clone, Default)]
+pub struct Responses {
+    tunnels: Arc<DashMap<u64, mpsc::Sender<PlainPacket>>>,
+}
+
+impl Responses {
+    /// Route packets for `handler_id` to `tunnel`, taking over from an older tunnel
+    ///
+    /// Only tunnels the handler proved to be its own may be attached.
+    pub fn attach(&self, handler_id: u64, tunnel: mpsc::Sender<PlainPacket>) {
+        if self.tunnels.insert(handler_id, tunnel).is_some() {
+            info!("Handler {} replaced its tunnel", handler_id);
+        }
+    }
+
+    /// Stop routing to `tunnel`, unless its handler has moved on to a newer one
+    pub fn detach(&self, handler_id: u64, tunnel: &mpsc::Sender<PlainPacket>) {
+        self.tunnels
+            .remove_if(&handler_id, |_, current| current.same_channel(tunnel));
+    }
+
+    fn tunnel(&self, handler_id: u64) -> Option<mpsc::Sender<PlainPacket>> {
+        self.tunnels.get(&handler_id).map(|t| t.clone())
+    }
+
+    /// Queue a packet, waiting while its tunnel is full
+    ///
+    /// Packets of handlers without a tunnel are dropped.
+    pub async fn send(&self, packet: PlainPacket) {
+        if let Some(tunnel) = self.tunnel(packet.handler_id) {
+            let _ = tunnel.send(packet).await;
+        }
+    }
+
+    /// Queue a packet unless its tunnel is full; returns whether it was queued
+    pub fn try_send(&self, packet: PlainPacket) -> bool {
+        self.tunnel(packet.handler_id)
+            .is_some_and(|tunnel| tunnel.try_send(packet).is_ok())
+    }
+
+    /// Queue a packet that must not be lost without waiting for room
+    pub fn push(&self, packet: PlainPacket) {
+        let Some(tunnel) = self.tunnel(packet.handler_id) else {
+            return;
+        };
+        if let Err(TrySendError::Full(packet)) = tunnel.try_send(packet) {
+            tokio::spawn(async move {
+                let _ = tunnel.send(packet).await;
+            });
+        }
+    }
+}
+
+/// State of a datagram stream, shared with its reader
+struct FlowState {
+    socket: UdpSocket,
+    /// Set on every send, so a flow nobody answers is not idle
+    sent: AtomicBool,
+    /// Addresses of the hostnames the stream sent to
+    hosts: std::sync::Mutex<HashMap<String, IpAddr>>,
+}
+
+struct UdpFlow {
+    state: Arc<FlowState>,
+    reader: JoinHandle<()>,
+}
+
+/// Relay for datagram streams
+pub struct UdpRelay {
+    flows: Arc<DashMap<FlowKey, UdpFlow>>,
+    responses: Responses,
+}
+
+impl UdpRelay {
+    /// Create a relay that delivers replies to `responses`
+    pub fn new(responses: Responses) -> Self {
+        Self {
+            flows: Arc::new(DashMap::new()),
+            responses,
+        }
+    }
+
+    /// Resolve the packet's `rhost` into `rip`, looking each host up once per stream
+    pub async fn resolve(&self, packet: &mut PlainPacket) -> Result<()> {
+        let Some(host) = packet.rhost.take() else {
+            return Ok(());
+        };
+        let flow = self
+            .flow((packet.handler_id, packet.conn_id, packet.stream_id))
+            .await?;
+
+        let cached = flow.hosts.lock().unwrap().get(&host).copied();
+        let ip = match cached {
+            Some(ip) => ip,
+            None => {
+                let ip = lookup(&host, packet.rport).await?;
+                let mut hosts = flow.hosts.lock().unwrap();
+                if hosts.len() >= MAX_CACHED_HOSTS {
+                    hosts.clear();
+                }
+                hosts.insert(host, ip);
+                ip
+            }
+        };
+        set_rip(packet, ip);
+        Ok(())
+    }
+
+    /// Send the packet's payload to `rip:rport` from the stream's socket
+    pub async fn send(&self, packet: &PlainPacket) -> Result<()> {
+        let flow = self
+            .flow((packet.handler_id, packet.conn_id, packet.stream_id))
+            .await?;
+
+        // An IPv4-only socket needs the unmapped address
+        let ip = Ipv6Addr::from(packet.rip);
+        let target = match ip.to_ipv4_mapped() {
+            Some(v4) if flow.socket.local_addr()?.is_ipv4() => {
+                SocketAddr::new(v4.into(), packet.rport)
+            }
+            _ => SocketAddr::new(ip.into(), packet.rport),
+        };
+
+        flow.socket.send_to(&packet.payload, target).await?;
+        flow.sent.store(true, Ordering::Relaxed);
+        trace!(
+            "Relayed {} bytes for stream {}/{} to {}",
+            packet.payload.len(),
+            packet.conn_id,
+            packet.stream_id,
+            target
+        );
+        Ok(())
+    }
+
+    /// Tear down the flow for a stream, if any
+    pub fn close(&self, handler_id: u64, conn_id: u64, stream_id: u32) {
+        if let Some((_, flow)) = self.flows.remove(&(handler_id, conn_id, stream_id)) {
+            flow.reader.abort();
+            debug!("Closed datagram flow {}/{}", conn_id, stream_id);
+        }
+    }
+
+    /// The stream's flow, opened on first use
+    async fn flow(&self, key: FlowKey) -> Result<Arc<FlowState>> {
+        if let Some(flow) = self.flows.get(&key) {
+            return Ok(flow.state.clone());
+        }
+
+        // Prefer a dual-stack socket; fall back to IPv4-only hosts
+        let socket = match UdpSocket::bind("[::]:0").await {
+            Ok(s) => s,
+            Err(_) => UdpSocket::bind("0.0.0.0:0").await?,
+        };
+        let state = Arc::new(FlowState {
+            socket,
+            sent: AtomicBool::new(false),
+            hosts: std::sync::Mutex::new(HashMap::new()),
+        });
+
+        let reader = tokio::spawn(Self::read_replies(
+            key,
+            state.clone(),
+            self.flows.clone(),
+            self.responses.clone(),
+        ));
+
+        self.flows.insert(
+            key,
+            UdpFlow {
+                state: state.clone(),
+                reader,
+            },
+        );
+
+        debug!("Opened datagram flow {}/{}", key.1, key.2);
+        Ok(state)
+    }
+
+    async fn read_replies(
+        key: FlowKey,
+        state: Arc<FlowState>,
+        flows: Arc<DashMap<FlowKey, UdpFlow>>,
+        responses: Responses,
+    ) {
+        let (_, conn_id, stream_id) = key;
+        let mut buf = vec![0u8; 65535];
+
+        loop {
+            let received = tokio::time::timeout(UDP_IDLE_TIMEOUT, state.socket.recv_from(&mut buf));
+            let (len, src) = match received.await {
+                Ok(Ok(v)) => v,
+                Ok(Err(e)) => {
+                    warn!("Datagram flow {}/{} read error: {}", conn_id, stream_id, e);
+                    break;
+                }
+                Err(_) if state.sent.swap(false, Ordering::Relaxed) => continue,
+                Err(_) => {
+                    debug!("Datagram flow {}/{} idle, closing", conn_id, stream_id);
+                    break;
+                }
+            };
+
+            let mut packet = reply(key, buf[..len].to_vec(), src);
+            packet.flags.is_datagram = true;
+            responses.send(packet).await;
+        }
+
+        flows.remove(&key);
+    }
+}
+
+/// A TCP stream as seen from the tunnel
+struct TcpFlow {
+    queue: mpsc::UnboundedSender<PlainPacket>,
+    /// Bytes queued for the socket
+    queued: Arc<AtomicUsize>,
+    /// Return traffic the client has room for, in bytes
+    window: Arc<Semaphore>,
+    /// Dropped to stop the stream's task
+    _stop: oneshot::Sender<()>,
+}
+
+/// The stream task's side of a `TcpFlow`
+struct FlowEnds {
+    rx: mpsc::UnboundedReceiver<PlainPacket>,
+    queued: Arc<AtomicUsize>,
+    window: Arc<Semaphore>,
+    stopped: oneshot::Receiver<()>,
+}
+
+/// The TCP streams of a relay
+#[derive(Clone, Default)]
+struct TcpFlows(Arc<DashMap<FlowKey, TcpFlow>>);
+
+impl TcpFlows {
+    /// Register a stream, returning what its task needs
+    fn insert(&self, key: FlowKey) -> FlowEnds {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (stop, stopped) = oneshot::channel();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let window = Arc::new(Semaphore::new(INITIAL_STREAM_WINDOW as usize));
+        self.0.insert(
+            key,
+            TcpFlow {
+                queue: tx,
+                queued: queued.clone(),
+                window: window.clone(),
+                _stop: stop,
+            },
+        );
+        FlowEnds {
+            rx,
+            queued,
+            window,
+            stopped,
+        }
+    }
+
+    fn contains(&self, key: &FlowKey) -> bool {
+        self.0.contains_key(key)
+    }
+
+    /// Forget a stream, stopping its task; returns whether it was known
+    fn remove(&self, key: &FlowKey) -> bool {
+        self.0.remove(key).is_some()
+    }
+
+    /// Add window the client granted a stream; unknown streams are ignored
+    fn grant(&self, key: &FlowKey, increment: u32) {
+        if let Some(flow) = self.0.get(key) {
+            // A client granting without end cannot overflow the semaphore
+            let room = Semaphore::MAX_PERMITS - flow.window.available_permits();
+            flow.window.add_permits((increment as usize).min(room));
+        }
+    }
+
+    /// Queue a packet for its stream without waiting
+    ///
+    /// The client has no more than its window in flight, and the stream hands
+    /// window back as its socket takes the data. A stream queuing more than
+    /// that is reset on its own; the tunnel never waits for it.
+    fn deliver(&self, packet: PlainPacket) {
+        let key = (packet.handler_id, packet.conn_id, packet.stream_id);
+        let Some(flow) = self.0.get(&key) else {
+            return;
+        };
+        let len = packet.payload.len();
+        if flow.queued.fetch_add(len, Ordering::Relaxed) + len <= INITIAL_STREAM_WINDOW as usize {
+            let _ = flow.queue.send(packet);
+            return;
+        }
+        // Not removed under the entry's lock
+        drop(flow);
+        warn!(
+            "Resetting stream {}/{}: client overran its window",
+            key.1, key.2
+        );
+        self.remove(&key);
+    }
+}
+
+/// Relay for BIND streams
+///
+/// Each stream listens for a single inbound TCP connection, reports the bound
+/// address and then the accepted peer with `is_open` replies, and relays data
+/// in both directions afterwards.
+pub struct BindRelay {
+    flows: TcpFlows,
+    responses: Responses,
+    bind_ip: IpAddr,
+    public_ip: Option<IpAddr>,
+}
+
+impl BindRelay {
+    /// Create a relay listening on `bind_ip` and advertising `public_ip`
+    /// (or the listener's own address) to clients
+    pub fn new(responses: Responses, bind_ip: IpAddr, public_ip: Option<IpAddr>) -> Self {
+        Self {
+            flows: TcpFlows::default(),
+            responses,
+            bind_ip,
+            public_ip,
+        }
+    }
+
+    /// Bind a listener for the stream opened by `packet`
+    pub async fn open(&self, packet: &PlainPacket) -> Result<()> {
+        let key = (packet.handler_id, packet.conn_id, packet.stream_id);
+
+        let listener = match TcpListener::bind(SocketAddr::new(self.bind_ip, 0)).await {
+            Ok(l) => l,
+            Err(e) => {
+                self.responses.send(close_reply(key)).await;
+                return Err(e.into());
+            }
+        };
+
+        let local = listener.local_addr()?;
+        let bound = SocketAddr::new(self.public_ip.unwrap_or(local.ip()), local.port());
+
+        let ends = self.flows.insert(key);
+
+        // First reply: where the client should tell its peer to connect
+        let mut bound_reply = reply(key, Vec::new(), bound);
+        bound_reply.flags.is_open = true;
+        self.responses.send(bound_reply).await;
+
+        let expected = packet_addr(packet);
+        tokio::spawn(Self::run_flow(
+            key,
+            listener,
+            expected,
+            ends,
+            self.flows.clone(),
+            self.responses.clone(),
+        ));
+
+        info!(
+            "BIND listener for stream {}/{} on {}",
+            packet.conn_id, packet.stream_id, bound
+        );
+        Ok(())
+    }
+
+    /// Whether the packet belongs to a BIND stream
+    pub fn owns(&self, packet: &PlainPacket) -> bool {
+        self.flows
+            .contains(&(packet.handler_id, packet.conn_id, packet.stream_id))
+    }
+
+    /// Deliver a packet to its BIND stream
+    pub fn send(&self, packet: PlainPacket) {
+        self.flows.deliver(packet);
+    }
+
+    /// Add window the client granted a BIND stream
+    pub fn grant(&self, key: FlowKey, increment: u32) {
+        self.flows.grant(&key, increment);
+    }
+
+    /// Tear down a BIND stream, which reports the close itself; returns
+    /// whether the stream was known
+    pub fn close(&self, key: FlowKey) -> bool {
+        self.flows.remove(&key)
+    }
+
+    async fn run_flow(
+        key: FlowKey,
+        listener: TcpListener,
+        expected: SocketAddr,
+        mut ends: FlowEnds,
+        flows: TcpFlows,
+        responses: Responses,
+    ) {
+        let (_, conn_id, stream_id) = key;
+
+        let accepted = tokio::select! {
+            accepted = tokio::time::timeout(BIND_ACCEPT_TIMEOUT, listener.accept()) => accepted,
+            _ = &mut ends.stopped => Ok(Err(std::io::ErrorKind::ConnectionReset.into())),
+        };
+        match accepted {
+            Ok(Ok((socket, peer))) if peer_allowed(expected, peer) => {
+                drop(listener);
+                debug!("BIND stream {}/{} accepted {}", conn_id, stream_id, peer);
+
+                // Second reply: the peer that connected
+                let mut peer_reply = reply(key, Vec::new(), peer);
+                peer_reply.flags.is_open = true;
+                responses.send(peer_reply).await;
+
+                relay(key, socket, ends, &responses).await;
+            }
+            Ok(Ok((_, peer))) => {
+                warn!(
+                    "BIND stream {}/{} rejected unexpected peer {}",
+                    conn_id, stream_id, peer
+                );
+            }
+            Ok(Err(e)) => warn!("BIND stream {}/{} accept failed: {}", conn_id, stream_id, e),
+            Err(_) => debug!("BIND stream {}/{} timed out", conn_id, stream_id),
+        }
+
+        flows.remove(&key);
+        responses.send(close_reply(key)).await;
+    }
+}
+
+/// Relay for connect streams
+///
+/// Each stream connects to its target over TCP; data queued while the
+/// connection is being made is sent once it is up. Hostnames are resolved to
+/// all of their addresses, which are raced Happy Eyeballs style. Addresses
+/// the egress ACL denies are never connected to.
+pub struct ConnectRelay {
+    flows: TcpFlows,
+    responses: Responses,
+    acl: Arc<EgressAcl>,
+}
+
+impl ConnectRelay {
+    /// Create a relay that delivers replies to `responses`
+    pub fn new(responses: Responses, acl: Arc<EgressAcl>) -> Self {
+        Self {
+            flows: TcpFlows::default(),
+            responses,
+            acl,
+        }
+    }
+
+    /// Connect the stream opened by `packet` to its target
+    pub fn open(&self, mut packet: PlainPacket) {
+        let key = (packet.handler_id, packet.conn_id, packet.stream_id);
+        let target = match packet.rhost.take() {
+            Some(host) => Target::Host(host, packet.rport),
+            None => Target::Addr(packet_addr(&packet)),
+        };
+
+        let ends = self.flows.insert(key);
+        if !packet.payload.is_empty() {
+            self.flows.deliver(packet);
+        }
+
+        tokio::spawn(Self::run_flow(
+            key,
+            target,
+            self.acl.clone(),
+            ends,
+            self.flows.clone(),
+            self.responses.clone(),
+        ));
+    }
+
+    /// Deliver a packet to its stream; packets of unknown streams are dropped
+    pub fn send(&self, packet: PlainPacket) {
+        self.flows.deliver(packet);
+    }
+
+    /// Add window the client granted a connect stream
+    pub fn grant(&self, key: FlowKey, increment: u32) {
+        self.flows.grant(&key, increment);
+    }
+
+    /// Tear down a connect stream, which reports the close itself; returns
+    /// whether the stream was known
+    pub fn close(&self, key: FlowKey) -> bool {
+        self.flows.remove(&key)
+    }
+
+    async fn run_flow(
+        key: FlowKey,
+        target: Target,
+        acl: Arc<EgressAcl>,
+        ends: FlowEnds,
+        flows: TcpFlows,
+        responses: Responses,
+    ) {
+        let (_, conn_id, stream_id) = key;
+
+        match tokio::time::timeout(CONNECT_TIMEOUT, target.connect(&acl)).await {
+            Ok(Ok(socket)) => {
+                debug!(
+                    "Stream {}/{} connected to {} ({:?})",
+                    conn_id,
+                    stream_id,
+                    target,
+                    socket.peer_addr()
+                );
+                relay(key, socket, ends, &responses).await;
+            }
+            Ok(Err(e)) => debug!(
+                "Stream {}/{} failed to connect to {}: {}",
+                conn_id, stream_id, target, e
+            ),
+            Err(_) => debug!(
+                "Stream {}/{} timed out connecting to {}",
+                conn_id, stream_id, target
+            ),
+        }
+
+        flows.remove(&key);
+        responses.send(close_reply(key)).await;
+    }
+}
+
+/// Where a connect stream goes
+enum Target {
+    Addr(SocketAddr),
+    Host(String, u16),
+}
+
+impl Target {
+    async fn connect(&self, acl: &EgressAcl) -> std::io::Result<TcpStream> {
+        let addrs: Vec<SocketAddr> = match self {
+            Target::Addr(addr) => vec![*addr],
+            Target::Host(host, port) => tokio::net::lookup_host((host.as_str(), *port))
+                .await?
+                .collect(),
+        };
+
+        let mut denied = None;
+        let allowed: Vec<SocketAddr> = addrs
+            .into_iter()
+            .filter(|addr| match acl.check(addr.ip(), Some(addr.port())) {
+                Ok(()) => true,
+                Err(e) => {
+                    denied = Some(e);
+                    false
+                }
+            })
+            .collect();
+        match (allowed.is_empty(), denied) {
+            (true, Some(e)) => Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, e)),
+            _ => connect_any(&interleave(allowed)).await,
+        }
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Addr(addr) => write!(f, "{}", addr),
+            Target::Host(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+/// Alternate address families, IPv6 first (RFC 8305 section 4)
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|addr| addr.is_ipv6());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    let mut ordered = Vec::new();
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connect to the first of `addrs` that answers
+///
+/// Attempts start in order, each one once the previous failed or had
+/// `CONNECTION_ATTEMPT_DELAY` to itself, and the first to succeed wins.
+async fn connect_any(addrs: &[SocketAddr]) -> std::io::Result<TcpStream> {
+    let mut pending = addrs.iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = std::io::Error::new(std::io::ErrorKind::NotFound, "No addresses");
+
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => attempts.push(TcpStream::connect(*addr)),
+                None => return Err(last_error),
+            }
+        }
+
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(socket) => return Ok(socket),
+                Err(e) => {
+                    last_error = e;
+                    if let Some(addr) = pending.next() {
+                        attempts.push(TcpStream::connect(*addr));
+                    }
+                }
+            },
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if pending.len() > 0 => {
+                if let Some(addr) = pending.next() {
+                    attempts.push(TcpStream::connect(*addr));
+                }
+            }
+        }
+    }
+}
+
+/// Shuttle data between a connected socket and its stream until both sides
+/// finish, the stream is reset or it idles for `TCP_IDLE_TIMEOUT`
+///
+/// The socket is read no faster than the client's window and the handler's
+/// tunnel allow. Data written to it is handed back to the client as window.
+async fn relay(key: FlowKey, socket: TcpStream, ends: FlowEnds, responses: &Responses) {
+    let FlowEnds {
+        mut rx,
+        queued,
+        window,
+        stopped,
+    } = ends;
+    let peer = socket
+        .peer_addr()
+        .unwrap_or(SocketAddr::from(([0, 0, 0, 0], 0)));
+    let (mut read, mut write) = socket.into_split();
+    let mut buf = vec![0u8; 16 * 1024];
+    let (mut read_done, mut write_done) = (false, false);
+    let mut written = ReceiveWindow::default();
+
+    let shuttle = async {
+        let idle = tokio::time::sleep(TCP_IDLE_TIMEOUT);
+        tokio::pin!(idle);
+
+        while !(read_done && write_done) {
+            tokio::select! {
+                res = read_credited(&mut read, &mut buf, &window), if !read_done => match res {
+                    Ok(0) | Err(_) => {
+                        read_done = true;
+                        let mut half = reply(key, Vec::new(), peer);
+                        half.flags.is_half_close = true;
+                        responses.send(half).await;
+                    }
+                    // Waits while the handler falls behind, which stops reading
+                    Ok(n) => responses.send(reply(key, buf[..n].to_vec(), peer)).await,
+                },
+                packet = rx.recv(), if !write_done => {
+                    let Some(packet) = packet else { break };
+                    let len = packet.payload.len();
+                    queued.fetch_sub(len, Ordering::Relaxed);
+                    if packet.flags.is_final {
+                        break;
+                    }
+                    if len > 0 {
+                        if write.write_all(&packet.payload).await.is_err() {
+                            break;
+                        }
+                        if let Some(update) =
+                            written.consume(len as u32).and_then(|inc| grant_reply(key, inc))
+                        {
+                            responses.send(update).await;
+                        }
+                    }
+                    if packet.flags.is_half_close {
+                        write_done = true;
+                        let _ = write.shutdown().await;
+                    }
+                }
+                _ = &mut idle => {
+                    debug!("Stream {}/{} idle, resetting", key.1, key.2);
+                    break;
+                }
+            }
+            idle.as_mut()
+                .reset(tokio::time::Instant::now() + TCP_IDLE_TIMEOUT);
+        }
+    };
+
+    tokio::select! {
+        _ = shuttle => {}
+        _ = stopped => debug!("Stream {}/{} reset", key.1, key.2),
+    }
+}
+
+/// Read into `buf` no more than `window` has room for, waiting while it has none
+///
+/// Cancel safe: window is only taken for bytes actually read.
+async fn read_credited(
+    read: &mut OwnedReadHalf,
+    buf: &mut [u8],
+    window: &Semaphore,
+) -> std::io::Result<usize> {
+    let Ok(mut permit) = window.acquire().await else {
+        return Ok(0);
+    };
+    let more = window.available_permits().min(buf.len() - 1);
+    if let Ok(extra) = window.try_acquire_many(more as u32) {
+        permit.merge(extra);
+    }
+    let n = read.read(&mut buf[..permit.num_permits()]).await?;
+    if let Some(used) = permit.split(n) {
+        used.forget();
+    }
+    Ok(n)
+}
+
+/// Parse a window update the handler passed on from a client
+pub fn window_update(packet: &PlainPacket) -> Option<(FlowKey, u32)> {
+    match rkyv::from_bytes::<ControlMessage, rkyv::rancor::Error>(&packet.payload) {
+        Ok(ControlMessage::WindowUpdate {
+            stream_id,
+            increment,
+        }) => Some(((packet.handler_id, packet.conn_id, stream_id), increment)),
+        _ => None,
+    }
+}
+
+/// Resolve a packet's `rhost` into `rip`, if it carries one
+pub async fn resolve_host(packet: &mut PlainPacket) -> Result<()> {
+    let Some(host) = packet.rhost.take() else {
+        return Ok(());
+    };
+    let ip = lookup(&host, packet.rport).await?;
+    set_rip(packet, ip);
+    Ok(())
+}
+
+async fn lookup(host: &str, port: u16) -> Result<IpAddr> {
+    let addr = tokio::net::lookup_host((host, port))
+        .await?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("No IP found for {}", host))?;
+    trace!("Resolved {} to {}", host, addr.ip());
+    Ok(addr.ip())
+}
+
+fn set_rip(packet: &mut PlainPacket, ip: IpAddr) {
+    packet.rip = match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    };
+}
+
+/// Build a response packet for a stream, addressed from `src`
+fn reply(key: FlowKey, payload: Vec<u8>, src: SocketAddr) -> PlainPacket {
+    let (handler_id, conn_id, stream_id) = key;
+    let mut packet = PlainPacket::response(conn_id, handler_id, payload).with_stream(stream_id);
+    packet.rip = match src.ip() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+        IpAddr::V6(ip) => ip.octets(),
+    };
+    packet.rport = src.port();
+    packet
+}
+
+/// Build a control response handing `increment` bytes of window back to the
+/// client of a stream
+fn grant_reply(key: FlowKey, increment: u32) -> Option<PlainPacket> {
+    let (handler_id, conn_id, stream_id) = key;
+    let update = ControlMessage::WindowUpdate {
+        stream_id,
+        increment,
+    };
+    let payload = rkyv::to_bytes::<rkyv::rancor::Error>(&update).ok()?;
+    let mut packet =
+        PlainPacket::response(conn_id, handler_id, payload.to_vec()).with_stream(stream_id);
+    packet.flags.is_control = true;
+    Some(packet)
+}
+
+/// Build a response that closes a stream
+pub fn close_reply(key: FlowKey) -> PlainPacket {
+    let (handler_id, conn_id, stream_id) = key;
+    let mut packet = PlainPacket::response(conn_id, handler_id, Vec::new()).with_stream(stream_id);
+    packet.flags.is_final = true;
+    packet
+}
+
+/// Socket address carried in a packet's rip/rport
+pub fn packet_addr(packet: &PlainPacket) -> SocketAddr {
+    let ip = Ipv6Addr::from(packet.rip);
+    match ip.to_ipv4_mapped() {
+        Some(v4) => SocketAddr::new(v4.into(), packet.rport),
+        None => SocketAddr::new(ip.into(), packet.rport),
+    }
+}
+
+/// Whether `peer` matches the host a BIND request expects (unspecified = any)
+///
+/// Only the address is compared: the inbound connection rarely comes from the
+/// announced port (FTP active mode connects from port 20).
+fn peer_allowed(expected: SocketAddr, peer: SocketAddr) -> bool {
+    let peer_ip = match peer.ip() {
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(peer.ip()),
+        ip => ip,
+    };
+    expected.ip().is_unspecified() || expected.ip() == peer_ip
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EgressAclConfig;
+
+    /// Return path with a tunnel for handler 1
+    fn responses(queue: usize) -> (Responses, mpsc::Receiver<PlainPacket>) {
+        let responses = Responses::default();
+        let (tx, rx) = mpsc::channel(queue);
+        responses.attach(1, tx);
+        (responses, rx)
+    }
+
+    #[tokio::test]
+    async fn test_responses_per_handler() {
+        let (responses, mut tunnel) = responses(1);
+        let packet = |handler_id| PlainPacket::response(7, handler_id, vec![1]);
+
+        // A full tunnel refuses try_send, push waits for room
+        assert!(responses.try_send(packet(1)));
+        assert!(!responses.try_send(packet(1)));
+        responses.push(packet(1));
+        assert!(tunnel.recv().await.is_some());
+        assert!(tunnel.recv().await.is_some());
+
+        // Handlers without a tunnel, or whose tunnel was replaced, get nothing
+        assert!(!responses.try_send(packet(2)));
+        let (newer, mut newer_rx) = mpsc::channel(1);
+        let (older, _) = mpsc::channel(1);
+        responses.attach(1, newer.clone());
+        responses.detach(1, &older);
+        responses.send(packet(1)).await;
+        assert!(newer_rx.recv().await.is_some());
+        responses.detach(1, &newer);
+        assert!(!responses.try_send(packet(1)));
+    }
+
+    #[test]
+    fn test_peer_allowed() {
+        let any = SocketAddr::from(([0, 0, 0, 0], 0));
+        let peer = SocketAddr::from(([192, 0, 2, 7], 40000));
+        assert!(peer_allowed(any, peer));
+
+        let host_only = SocketAddr::from(([192, 0, 2, 7], 0));
+        assert!(peer_allowed(host_only, peer));
+
+        let other = SocketAddr::from(([192, 0, 2, 8], 0));
+        assert!(!peer_allowed(other, peer));
+
+        // The announced port is not binding: FTP data comes from port 20
+        let announced = SocketAddr::from(([192, 0, 2, 7], 21));
+        let ftp_data = SocketAddr::from(([192, 0, 2, 7], 20));
+        assert!(peer_allowed(announced, ftp_data));
+
+        // Dual-stack listeners report IPv4 peers in mapped form
+        let mapped = SocketAddr::new(
+            std::net::Ipv4Addr::new(192, 0, 2, 7)
+                .to_ipv6_mapped()
+                .into(),
+            40000,
+        );
+        assert!(peer_allowed(host_only, mapped));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_host() {
+        let frame = apfsds_protocol::ProxyFrame::new_open(1, 2, [0; 16], 80).with_host("localhost");
+        let mut packet = PlainPacket::from_frame(&frame, 1);
+
+        resolve_host(&mut packet).await.unwrap();
+        assert!(packet.rhost.is_none());
+        assert!(packet_addr(&packet).ip().is_loopback());
+    }
+
+    #[tokio::test]
+    async fn test_udp_resolve_once_per_stream() {
+        let (responses, _replies) = responses(16);
+        let relay = UdpRelay::new(responses);
+        let frame = apfsds_protocol::ProxyFrame::new_datagram(1, 2, [0; 16], 53, vec![0])
+            .with_host("localhost");
+
+        let mut packet = PlainPacket::from_frame(&frame, 1);
+        relay.resolve(&mut packet).await.unwrap();
+        assert!(packet_addr(&packet).ip().is_loopback());
+
+        // Later datagrams to the host use the stream's cached address
+        let flow = relay.flows.get(&(1, 1, 2)).unwrap().state.clone();
+        let cached = flow.hosts.lock().unwrap().get("localhost").copied();
+        assert_eq!(cached, Some(packet_addr(&packet).ip()));
+
+        relay.close(1, 1, 2);
+        assert!(relay.flows.is_empty());
+    }
+
+    /// Egress ACL letting loopback through
+    fn open_acl() -> Arc<EgressAcl> {
+        let config = EgressAclConfig {
+            deny_cidrs: Vec::new(),
+            ..Default::default()
+        };
+        Arc::new(EgressAcl::new(&config, None).unwrap())
+    }
+
+    fn stream_packet(stream_id: u32, target: SocketAddr) -> PlainPacket {
+        let ip = match target.ip() {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped().octets(),
+            IpAddr::V6(ip) => ip.octets(),
+        };
+        let frame = apfsds_protocol::ProxyFrame::new_data(1, ip, target.port(), Vec::new())
+            .with_stream(stream_id);
+        PlainPacket::from_frame(&frame, 1)
+    }
+
+    #[tokio::test]
+    async fn test_connect_relay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let (responses, mut replies) = responses(16);
+        let relay = ConnectRelay::new(responses, open_acl());
+
+        // Data sent with the open frame goes out once connected
+        let mut open = stream_packet(2, target);
+        open.flags.is_open = true;
+        open.payload = b"ping".to_vec();
+        relay.open(open);
+
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 4];
+        socket.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        socket.write_all(b"pong").await.unwrap();
+        drop(socket);
+
+        let pong = replies.recv().await.unwrap();
+        assert_eq!((pong.stream_id, pong.payload.as_slice()), (2, &b"pong"[..]));
+        assert!(replies.recv().await.unwrap().flags.is_half_close);
+
+        // The stream ends once the client finished its side too
+        let mut done = stream_packet(2, target);
+        done.flags.is_half_close = true;
+        relay.send(done);
+        assert!(replies.recv().await.unwrap().flags.is_final);
+
+        // Unreachable targets close the stream
+        drop(listener);
+        let mut open = stream_packet(3, target);
+        open.flags.is_open = true;
+        relay.open(open);
+        let closed = replies.recv().await.unwrap();
+        assert!(closed.flags.is_final && closed.stream_id == 3);
+
+        // So are targets the egress ACL denies
+        let (responses, mut replies) = self::responses(16);
+        let acl = Arc::new(EgressAcl::new(&EgressAclConfig::default(), None).unwrap());
+        let relay = ConnectRelay::new(responses, acl);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut open = stream_packet(4, listener.local_addr().unwrap());
+        open.flags.is_open = true;
+        relay.open(open);
+        assert!(replies.recv().await.unwrap().flags.is_final);
+        let accepted = tokio::time::timeout(Duration::from_millis(100), listener.accept()).await;
+        assert!(accepted.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_relay_follows_window() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (responses, mut replies) = responses(1024);
+        let relay = ConnectRelay::new(responses, open_acl());
+        let mut open = stream_packet(2, listener.local_addr().unwrap());
+        open.flags.is_open = true;
+        relay.open(open);
+
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let window = INITIAL_STREAM_WINDOW as usize;
+        socket.write_all(&vec![7; window + 1000]).await.unwrap();
+
+        // Reading stops once the client's window is used up
+        let mut received = 0;
+        while received < window {
+            received += replies.recv().await.unwrap().payload.len();
+        }
+        assert_eq!(received, window);
+        let held = tokio::time::timeout(Duration::from_millis(100), replies.recv()).await;
+        assert!(held.is_err());
+
+        // and goes on as the client grants more
+        relay.grant((1, 1, 2), 1000);
+        while received < window + 1000 {
+            received += replies.recv().await.unwrap().payload.len();
+        }
+        assert_eq!(received, window + 1000);
+
+        // Data the target took is handed back to the client as window
+        let mut data = stream_packet(2, listener.local_addr().unwrap());
+        data.payload = vec![1; window / 2];
+        relay.send(data);
+        let mut sink = vec![0; window / 2];
+        socket.read_exact(&mut sink).await.unwrap();
+        let update = replies.recv().await.unwrap();
+        assert!(update.flags.is_control);
+        assert_eq!(window_update(&update), Some(((1, 1, 2), window as u32 / 2)));
+    }
+
+    #[test]
+    fn test_window_update() {
+        let update = ControlMessage::WindowUpdate {
+            stream_id: 2,
+            increment: 4096,
+        };
+        let payload = rkyv::to_bytes::<rkyv::rancor::Error>(&update).unwrap();
+        let mut frame = apfsds_protocol::ProxyFrame::new_control(payload.to_vec());
+        frame.conn_id = 7;
+        let packet = PlainPacket::from_frame(&frame, 1);
+        assert_eq!(window_update(&packet), Some(((1, 7, 2), 4096)));
+
+        let other = PlainPacket::response(7, 1, vec![1]);
+        assert_eq!(window_update(&other), None);
+    }
+
+    #[tokio::test]
+    async fn test_deliver_resets_overrun() {
+        let flows = TcpFlows::default();
+        let mut ends = flows.insert((1, 7, 2));
+        let window = INITIAL_STREAM_WINDOW as usize;
+        let packet = |len| PlainPacket::response(7, 1, vec![1; len]).with_stream(2);
+
+        // A window's worth queues up without waiting for the socket
+        flows.deliver(packet(window / 2));
+        flows.deliver(packet(window / 2));
+        assert!(flows.contains(&(1, 7, 2)));
+        assert!(ends.rx.try_recv().is_ok());
+
+        // Anything beyond it resets that stream alone
+        flows.deliver(packet(1));
+        assert!(!flows.contains(&(1, 7, 2)));
+        assert!((&mut ends.stopped).await.is_err());
+
+        // Unknown streams are ignored
+        flows.deliver(packet(1).with_stream(3));
+    }
+
+    #[test]
+    fn test_interleave() {
+        let v4 = |last| SocketAddr::from(([192, 0, 2, last], 80));
+        let v6 = |last| SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, last], 80));
+
+        assert_eq!(
+            interleave(vec![v4(1), v4(2), v4(3), v6(1), v6(2)]),
+            vec![v6(1), v4(1), v6(2), v4(2), v4(3)]
+        );
+        assert_eq!(interleave(vec![v4(1), v4(2)]), vec![v4(1), v4(2)]);
+    }
+
+    #[tokio::test]
+    async fn test_connect_any() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+
+        // A refused address falls through to the next one at once
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let refused = closed.local_addr().unwrap();
+        drop(closed);
+
+        let socket =
+            tokio::time::timeout(CONNECTION_ATTEMPT_DELAY, connect_any(&[refused, target]))
+                .await
+                .unwrap()
+                .unwrap();
+        assert_eq!(socket.peer_addr().unwrap(), target);
+        assert!(connect_any(&[refused]).await.is_err());
+        assert!(connect_any(&[]).await.is_err());
+    }
+
+    #[test]
+    fn test_reply_addressing() {
+        let src = SocketAddr::from(([198, 51, 100, 1], 53));
+        let packet = reply((1, 2, 3), vec![9], src);
+
+        assert_eq!(packet.stream_id, 3);
+        assert!(packet.is_response);
+        assert_eq!(packet_addr(&packet), src);
+    }
+}
@@ -0,0 +1,140 @@
+//! User -> exit group cache
+//!
+//! `handle_connect` needs the user's exit group (and bandwidth limits) for
+//! every session. Users and their group's limits are read from Postgres once
+//! and cached; triggers on `users` and `exit_groups` notify changes so entries
+//! are dropped as soon as an admin moves, limits or disables a user or limits
+//! its group. Entries also expire after `ttl` in case a
+//! notification is missed.
+
+use apfsds_storage::postgres::{PgClient, PgError};
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// Group used for users without one
+pub const DEFAULT_GROUP_ID: i32 = 0;
+
+/// Time a cached group is trusted without a change notification
+const DEFAULT_TTL: Duration = Duration::from_secs(300);
+
+/// Delay before re-subscribing after the listener failed
+const RELISTEN_DELAY: Duration = Duration::from_secs(5);
+
+/// What a session needs to know about its user
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UserProfile {
+    pub group_id: i32,
+    /// Own bandwidth limits, overriding the configured defaults
+    pub rate_up_bps: Option<u64>,
+    pub rate_down_bps: Option<u64>,
+    /// Own session limits, overriding the configured defaults
+    pub max_sessions: Option<u32>,
+    pub max_devices: Option<u32>,
+    /// Limits of the exit group, overriding the configured group limits
+    pub group_rate_up_bps: Option<u64>,
+    pub group_rate_down_bps: Option<u64>,
+}
+
+pub struct GroupCache {
+    pg_client: PgClient,
+    /// None marks a disabled user
+    groups: DashMap<u64, (Option<UserProfile>, Instant)>,
+    ttl: Duration,
+}
+
+impl GroupCache {
+    pub fn new(pg_client: PgClient) -> Self {
+        Self {
+            pg_client,
+            groups: DashMap::new(),
+            ttl: DEFAULT_TTL,
+        }
+    }
+
+    /// Exit group and limits of `user_id`, or None if the user is disabled or
+    /// unknown
+    ///
+    /// When the database cannot be reached, the last cached profile is used
+    /// even if expired; users never seen before are refused.
+    pub async fn profile_of(&self, user_id: u64) -> Option<UserProfile> {
+        let cached = self.groups.get(&user_id).map(|entry| *entry);
+        if let Some((profile, _)) = cached.filter(|(_, at)| at.elapsed() < self.ttl) {
+            return profile;
+        }
+
+        match self.load(user_id).await {
+            Ok(profile) => {
+                self.groups.insert(user_id, (profile, Instant::now()));
+                profile
+            }
+            Err(e) => {
+                // Not refreshed, so the next session retries the lookup
+                warn!("Failed to look up group of user {}: {}", user_id, e);
+                cached.and_then(|(profile, _)| profile)
+            }
+        }
+    }
+
+    async fn load(&self, user_id: u64) -> Result<Option<UserProfile>, PgError> {
+        let user = match self.pg_client.get_user(user_id as i64).await? {
+            Some(user) if !user.disabled => user,
+            _ => return Ok(None),
+        };
+        let group_id = user.group_id.unwrap_or(DEFAULT_GROUP_ID);
+        let group = self.pg_client.get_exit_group(group_id).await?;
+        let bps = |rate: Option<i64>| rate.map(|r| r.max(0) as u64);
+
+        Ok(Some(UserProfile {
+            group_id,
+            rate_up_bps: bps(user.rate_up_bps),
+            rate_down_bps: bps(user.rate_down_bps),
+            max_sessions: user.max_sessions.map(|m| m.max(0) as u32),
+            max_devices: user.max_devices.map(|m| m.max(0) as u32),
+            group_rate_up_bps: group.as_ref().and_then(|g| bps(g.rate_up_bps)),
+            group_rate_down_bps: group.as_ref().and_then(|g| bps(g.rate_down_bps)),
+        }))
+    }
+
+    /// Forget the cached group of `user_id`
+    pub fn invalidate(&self, user_id: u64) {
+        self.groups.remove(&user_id);
+    }
+
+    /// Drop cached groups on change notifications from the database
+    pub fn start(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let mut listener = match self.pg_client.listen_group_changes().await {
+                    Ok(l) => l,
+                    Err(e) => {
+                        warn!("Failed to subscribe to group changes: {}", e);
+                        tokio::time::sleep(RELISTEN_DELAY).await;
+                        continue;
+                    }
+                };
+                info!("Listening for user group changes");
+
+                loop {
+                    match listener.recv().await {
+                        Ok(Some(user_id)) => {
+                            debug!("User {} changed", user_id);
+                            self.invalidate(user_id as u64);
+                        }
+                        Ok(None) => {
+                            // Changes may have been missed while disconnected
+                            self.groups.clear();
+                        }
+                        Err(e) => {
+                            warn!("Group change listener failed: {}", e);
+                            self.groups.clear();
+                            break;
+                        }
+                    }
+                }
+                tokio::time::sleep(RELISTEN_DELAY).await;
+            }
+        })
+    }
+}
@@ -1,85 +1,97 @@
 //! HTTP and WebSocket handler
 
-use crate::config::DaemonConfig;
+use crate::config::{AbuseAction, DaemonConfig, ExitLoadConfig, SecurityConfig};
+use crate::credential_seal::CredentialSeal;
+use crate::exit_auth::{EXIT_AUTH_HEADER, ExitAuthenticator};
 use crate::exit_forwarder::ExitForwarder;
 use crate::exit_node_pool::ExitNodePool;
+use crate::group_cache::GroupCache;
 use crate::metrics::Metrics;
+use crate::rate_limit::{RateLimiter, TokenBucket};
+use crate::resolver::DnsResolver;
 use anyhow::Result;
-use apfsds_raft::RaftNode;
+use apfsds_obfuscation::{PaddingStrategy, XorMask};
+use apfsds_protocol::{AbuseKind, ControlMessage, INITIAL_STREAM_WINDOW, ProxyFrame, SendWindow};
+use apfsds_raft::{ClientRequest, ClientResponse, RaftNode, SessionLimit};
 use bytes::Bytes;
 use futures::{SinkExt, StreamExt};
 use http_body_util::Full;
 use hyper::{Request, Response, body::Incoming, server::conn::http1, service::service_fn};
 use hyper_util::rt::TokioIo;
+use std::collections::{HashMap, HashSet};
 use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::sync::LazyLock;
+use std::sync::{LazyLock, OnceLock};
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tokio::sync::mpsc;
-use tokio_tungstenite::{accept_async, tungstenite::Message};
-use tracing::{debug, error, info};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::{WebSocketStream, accept_async, tungstenite::Message};
+use tracing::{debug, error, info, trace, warn};
+
+/// Time to wait for the cluster to admit a session
+const SESSION_REGISTER_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// First and longest wait between attempts to unregister a session
+const SESSION_CLOSE_RETRY: Duration = Duration::from_secs(1);
+const SESSION_CLOSE_RETRY_MAX: Duration = Duration::from_secs(30);
+
+/// How often connected exit-nodes' credentials are checked for revocation
+const EXIT_CREDENTIAL_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Return traffic held back for a stream before the exit is taken to ignore
+/// the client's window (datagrams beyond it are dropped instead)
+const STREAM_BACKLOG_LIMIT: usize = 4 * INITIAL_STREAM_WINDOW as usize;
 
 /// Global metrics instance
 static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
 
-use crate::billing::BillingAggregator;
+use crate::billing::{BillingAggregator, QuotaVerdict};
 use crate::connection_registry::ConnectionRegistry;
 use apfsds_storage::postgres::PgClient;
 // Need ProxyFrame
 
+/// What a handler serves its requests and client sessions with
+pub struct HandlerContext {
+    pub config: DaemonConfig,
+    /// Exit pool the sessions' traffic leaves through
+    pub exit_forwarder: Arc<ExitForwarder>,
+    /// Reverse-mode exits connected to this handler
+    pub exit_node_pool: Arc<ExitNodePool>,
+    pub exit_auth: Arc<ExitAuthenticator>,
+    /// Session registration across the cluster
+    pub raft_node: Arc<RaftNode>,
+    pub pg_client: PgClient,
+    /// Quotas
+    pub billing: Arc<BillingAggregator>,
+    pub registry: Arc<ConnectionRegistry>,
+    pub resolver: Arc<DnsResolver>,
+    pub groups: Arc<GroupCache>,
+    /// Per-user bandwidth limits
+    pub limiter: Arc<RateLimiter>,
+}
+
 /// Run as handler (main proxy server)
-pub async fn run_handler(
-    config: &DaemonConfig,
-    exit_forwarder: Arc<ExitForwarder>,
-    raft_node: Arc<RaftNode>,
-    pg_client: PgClient,
-    billing: Arc<BillingAggregator>,
-    registry: Arc<ConnectionRegistry>,
-) -> Result<()> {
-    let listener = TcpListener::bind(config.server.bind).await?;
-    info!("Handler listening on {}", config.server.bind);
+pub async fn run_handler(ctx: HandlerContext) -> Result<()> {
+    let listener = TcpListener::bind(ctx.config.server.bind).await?;
+    info!("Handler listening on {}", ctx.config.server.bind);
 
-    let config = Arc::new(config.clone());
-    let exit_node_pool = Arc::new(ExitNodePool::new());
+    let ctx = Arc::new(ctx);
 
     loop {
         let (stream, addr) = listener.accept().await?;
         debug!("New connection from {}", addr);
 
-        let config = config.clone();
-        let exit_forwarder = exit_forwarder.clone();
-        let raft_node = raft_node.clone();
-        let pg_client = pg_client.clone();
-        let billing = billing.clone();
-        let registry = registry.clone();
-        let exit_node_pool = exit_node_pool.clone();
+        let ctx = ctx.clone();
 
         tokio::spawn(async move {
             let io = TokioIo::new(stream);
 
             let service = service_fn(move |req| {
-                let config = config.clone();
-                let exit_forwarder = exit_forwarder.clone();
-                let raft_node = raft_node.clone();
-                let pg_client = pg_client.clone();
-                let billing = billing.clone();
-                let registry = registry.clone();
-                let exit_node_pool = exit_node_pool.clone();
-                async move {
-                    handle_request(
-                        req,
-                        addr,
-                        &config,
-                        exit_forwarder,
-                        raft_node,
-                        pg_client,
-                        billing,
-                        registry,
-                        exit_node_pool,
-                    )
-                    .await
-                }
+                let ctx = ctx.clone();
+                async move { handle_request(req, addr, &ctx).await }
             });
 
             if let Err(e) = http1::Builder::new()
@@ -97,23 +109,27 @@ pub async fn run_handler(
 async fn handle_request(
     req: Request<Incoming>,
     addr: SocketAddr,
-    config: &DaemonConfig,
-    exit_forwarder: Arc<ExitForwarder>,
-    raft_node: Arc<RaftNode>,
-    pg_client: PgClient,
-    billing: Arc<BillingAggregator>,
-    registry: Arc<ConnectionRegistry>,
-    exit_node_pool: Arc<ExitNodePool>,
+    ctx: &HandlerContext,
 ) -> Result<Response<Full<Bytes>>, Infallible> {
+    let config = &ctx.config;
     let path = req.uri().path();
     // trace!("Request from {}: {} {}", addr, req.method(), path);
 
     let response = match path {
-        "/retrieve-token" => handle_retrieve_token(req, config, pg_client).await,
-        "/connect" => {
-            handle_connect(req, config, exit_forwarder, raft_node, billing, registry).await
+        "/retrieve-token" => handle_retrieve_token(req, config, &ctx.pg_client).await,
+        "/connect" => handle_connect(req, ctx).await,
+        "/exit-node/register" => {
+            handle_exit_node_register(
+                req,
+                config.raft.node_id,
+                config.exit_load.clone(),
+                ctx.pg_client.clone(),
+                ctx.exit_node_pool.clone(),
+                ctx.exit_auth.clone(),
+                &config.security,
+            )
+            .await
         }
-        "/exit-node/register" => handle_exit_node_register(req, exit_node_pool).await,
         "/health" => handle_health().await,
         "/ready" => handle_ready().await,
         _ => handle_decoy(req, config).await,
@@ -135,7 +151,7 @@ async fn handle_request(
 async fn handle_retrieve_token(
     req: Request<Incoming>,
     config: &DaemonConfig,
-    _pg_client: PgClient,
+    pg_client: &PgClient,
 ) -> Result<Response<Full<Bytes>>> {
     use apfsds_crypto::{Aes256GcmCipher, HmacAuthenticator, X25519KeyPair};
     use apfsds_protocol::{AuthRequest, AuthResponse};
@@ -184,29 +200,40 @@ async fn handle_retrieve_token(
             rkyv::from_bytes::<AuthRequest, rkyv::rancor::Error>(&decrypted)
                 .map_err(|_| "Invalid auth request")?;
 
-        // Verify HMAC
-        let hmac_secret = config
-            .security
-            .hmac_secret
-            .as_ref()
-            .and_then(|s| hex::decode(s).ok())
-            .and_then(|v| <[u8; 32]>::try_from(v).ok())
-            .unwrap_or([43u8; 32]);
-
-        let hmac = HmacAuthenticator::new(hmac_secret);
-        hmac.verify_with_timestamp(
-            &auth_req.hmac_base,
-            auth_req.timestamp,
-            &auth_req.hmac_signature,
-        )
-        .map_err(|_| "HMAC verification failed")?;
-
-        // Extract user_id from hmac_base
+        // The claimed user must exist and sign with its own secret
         let user_id = std::str::from_utf8(&auth_req.hmac_base)
             .ok()
             .and_then(|s| s.split(':').next())
-            .and_then(|s| s.parse::<u64>().ok())
-            .unwrap_or(0);
+            .and_then(|s| s.parse::<i64>().ok())
+            .filter(|&id| id > 0)
+            .ok_or("Missing user id")?;
+        let user = pg_client
+            .get_user(user_id)
+            .await
+            .map_err(|e| {
+                warn!("Failed to look up user {}: {}", user_id, e);
+                "User lookup failed"
+            })?
+            .filter(|user| !user.disabled)
+            .ok_or("Unknown or disabled user")?;
+        let seal = CredentialSeal::from_config(&config.security).map_err(|e| {
+            warn!("Cannot verify user secrets: {}", e);
+            "No credential key"
+        })?;
+        let user_secret = user
+            .sealed_secret
+            .as_deref()
+            .ok_or("User has no secret")
+            .and_then(|sealed| seal.open(sealed).map_err(|_| "Unreadable user secret"))?;
+
+        HmacAuthenticator::new(user_secret)
+            .verify_with_timestamp(
+                &auth_req.hmac_base,
+                auth_req.timestamp,
+                &auth_req.hmac_signature,
+            )
+            .map_err(|_| "HMAC verification failed")?;
+        let user_id = user_id as u64;
 
         // Generate token
         let now = std::time::SystemTime::now()
@@ -217,6 +244,7 @@ async fn handle_retrieve_token(
         let token_payload = apfsds_protocol::TokenPayload {
             user_id,
             nonce: auth_req.nonce,
+            client_pk: auth_req.client_pk,
             issued_at: now,
             valid_until: now + config.security.token_ttl * 1000,
         };
@@ -275,12 +303,16 @@ async fn handle_retrieve_token(
 /// Handle WebSocket connect request
 async fn handle_connect(
     req: Request<Incoming>,
-    config: &DaemonConfig,
-    exit_forwarder: Arc<ExitForwarder>,
-    raft_node: Arc<RaftNode>,
-    billing: Arc<BillingAggregator>,
-    registry: Arc<ConnectionRegistry>,
+    ctx: &HandlerContext,
 ) -> Result<Response<Full<Bytes>>> {
+    let config = &ctx.config;
+    let (raft_node, billing, groups, limiter) =
+        (&ctx.raft_node, &ctx.billing, &ctx.groups, &ctx.limiter);
+    // Carried into the session
+    let exit_forwarder = ctx.exit_forwarder.clone();
+    let registry = ctx.registry.clone();
+    let resolver = ctx.resolver.clone();
+
     // Check for WebSocket upgrade
     let is_upgrade = req
         .headers()
@@ -337,8 +369,8 @@ async fn handle_connect(
         crate::auth::Authenticator::new(&server_sk, hmac_secret, config.security.token_ttl)
             .map_err(|e| anyhow::anyhow!("Failed to create authenticator: {}", e))?;
 
-    let user_id = match authenticator.verify_and_consume_token(token.as_bytes()) {
-        Ok(uid) => uid,
+    let claims = match authenticator.verify_and_consume_token(token.as_bytes()) {
+        Ok(claims) => claims,
         Err(e) => {
             debug!("Token verification failed: {}", e);
             return Ok(Response::builder()
@@ -348,14 +380,82 @@ async fn handle_connect(
         }
     };
 
-    // TODO: Get group_id from database based on user_id
-    let group_id = 0;
+    let user_id = claims.user_id;
+
+    // Exit group the user's traffic is routed through
+    let Some(profile) = groups.profile_of(user_id).await else {
+        debug!("Rejecting disabled or unknown user {}", user_id);
+        return Ok(Response::builder()
+            .status(403)
+            .body(Full::new(Bytes::from("Forbidden: Account disabled")))
+            .unwrap());
+    };
+
+    // Refuse up front if the session would be closed on its first byte
+    let exhausted = billing
+        .check(user_id as i64)
+        .filter(|_| billing.closes_sessions());
+    if let Some(kind) = exhausted {
+        debug!("Rejecting user {} over {:?} quota", user_id, kind);
+        return Ok(Response::builder()
+            .status(402)
+            .body(Full::new(Bytes::from("Payment Required: Quota exceeded")))
+            .unwrap());
+    }
+
+    // Session and device limits hold across the cluster
+    let conn_id = fastrand::u64(..);
+    let max_sessions = profile
+        .max_sessions
+        .unwrap_or(config.session_limit.max_sessions);
+    let max_devices = profile
+        .max_devices
+        .unwrap_or(config.session_limit.max_devices);
+    let request = ClientRequest::SessionOpen {
+        conn_id,
+        user_id,
+        client_pk: claims.client_pk,
+        node_id: raft_node.node_id,
+        max_sessions,
+        max_devices,
+    };
+    let session = match register_session(&raft_node, conn_id, request).await {
+        Ok(session) => session,
+        Err(Refusal::Limit(limit)) => {
+            debug!("Rejecting user {}: {:?} limit reached", user_id, limit);
+            let (reason, body) = match limit {
+                SessionLimit::Sessions => ("sessions", "Too Many Requests: Session limit reached"),
+                SessionLimit::Devices => ("devices", "Too Many Requests: Device limit reached"),
+            };
+            return Ok(Response::builder()
+                .status(429)
+                .header("X-Session-Limit", reason)
+                .body(Full::new(Bytes::from(body)))
+                .unwrap());
+        }
+        Err(Refusal::Unavailable) => {
+            return Ok(Response::builder()
+                .status(503)
+                .header("Retry-After", "5")
+                .body(Full::new(Bytes::from(
+                    "Service Unavailable: Session limits cannot be checked",
+                )))
+                .unwrap());
+        }
+    };
+
+    let quota = Arc::new(billing.session(user_id as i64));
+    let limits = Arc::new(limiter.session(user_id, &profile));
+    let group_id = profile.group_id;
+    let abuse = config.abuse.clone();
+    // Set once an exit reports the session and it is to be throttled
+    let penalty: Arc<OnceLock<TokenBucket>> = Arc::new(OnceLock::new());
 
     // Spawn WebSocket handler
     tokio::task::spawn(async move {
-        use apfsds_obfuscation::{PaddingStrategy, XorMask};
-        use apfsds_protocol::{ControlMessage, ProxyFrame};
-        use tokio::net::UdpSocket;
+        use apfsds_protocol::ReceiveWindow;
+
+        let _session = session;
 
         match hyper::upgrade::on(req).await {
             Ok(upgraded) => {
@@ -367,12 +467,9 @@ async fn handle_connect(
                     }
                 };
 
-                info!("Client connected (User {})", user_id);
+                info!("Client connected (User {}, group {})", user_id, group_id);
                 METRICS.active_connections.inc();
 
-                // Conn ID allocation
-                let conn_id = fastrand::u64(..);
-
                 // Send Conn ID to client (Key Exchange)
                 if let Err(e) = ws_stream
                     .send(Message::Binary(conn_id.to_le_bytes().to_vec().into()))
@@ -391,72 +488,261 @@ async fn handle_connect(
 
                 // Registry Channel
                 let (registry_tx, mut registry_rx) = mpsc::unbounded_channel();
-                let dns_registry_tx = registry_tx.clone(); // Clone for DNS listener
+                let dns_tx = registry_tx.clone(); // Clone for DNS responses
+                let stream_tx = registry_tx.clone(); // Clone for stream resets/window updates
                 registry.register(conn_id, registry_tx);
 
-                // DNS Socket (Per connection)
-                let dns_socket = match UdpSocket::bind("0.0.0.0:0").await {
-                    Ok(s) => Arc::new(s),
-                    Err(e) => {
-                        error!("Failed to bind DNS socket: {}", e);
-                        return;
-                    }
-                };
+                // Window news from the client for the return path
+                let (credit_tx, mut credit_rx) = mpsc::unbounded_channel::<Credit>();
 
                 // Task: Registry Rx/DNS -> WS Tx (with obfuscation)
                 let registry_clone = registry.clone();
+                let tx_quota = quota.clone();
+                let tx_limits = limits.clone();
+                let tx_penalty = penalty.clone();
+                let tx_forwarder = exit_forwarder.clone();
                 let tx_task = tokio::spawn(async move {
                     let xor_mask = XorMask::new(session_key);
                     let padding = PaddingStrategy::default();
+                    let quota = tx_quota;
+                    let limits = tx_limits;
+                    let penalty = tx_penalty;
+                    // Send windows the client granted its streams
+                    let mut send_windows: HashMap<u32, SendWindow<ProxyFrame>> = HashMap::new();
 
-                    while let Some(frame) = registry_rx.recv().await {
-                        // Serialize frame
-                        let frame_bytes = match rkyv::to_bytes::<rkyv::rancor::Error>(&frame) {
-                            Ok(b) => b.to_vec(),
-                            Err(e) => {
-                                error!("Frame serialization error: {}", e);
+                    loop {
+                        let frame = tokio::select! {
+                            frame = registry_rx.recv() => match frame {
+                                Some(frame) => frame,
+                                None => break,
+                            },
+                            Some(credit) = credit_rx.recv() => {
+                                let ready = match credit {
+                                    Credit::Grant { stream_id, increment } => send_windows
+                                        .get_mut(&stream_id)
+                                        .map(|window| window.grant(increment))
+                                        .unwrap_or_default(),
+                                    Credit::Closed(stream_id) => {
+                                        send_windows.remove(&stream_id);
+                                        Vec::new()
+                                    }
+                                };
+                                if send_released(&mut ws_tx, ready, &mut send_windows, &padding, &xor_mask)
+                                    .await
+                                    .is_err()
+                                {
+                                    break;
+                                }
                                 continue;
                             }
                         };
 
-                        // Obfuscate
-                        let padded = padding.pad(&frame_bytes);
-                        let masked = xor_mask.apply(&padded);
+                        // Exits report abuse to us; the client never sees the report
+                        if let Some(kind) = abuse_report(&frame) {
+                            METRICS
+                                .abuse_reports
+                                .with_label_values(&[abuse_label(kind)])
+                                .inc();
+                            match abuse.action {
+                                AbuseAction::Log => {
+                                    warn!("Exit reported user {} for {:?}", user_id, kind)
+                                }
+                                AbuseAction::Throttle => {
+                                    warn!("Throttling user {}: exit reported {:?}", user_id, kind);
+                                    penalty.get_or_init(|| {
+                                        TokenBucket::new(abuse.throttle_bps, abuse.throttle_bps)
+                                    });
+                                }
+                                AbuseAction::Close => {
+                                    warn!(
+                                        "Closing session of user {}: exit reported {:?}",
+                                        user_id, kind
+                                    );
+                                    let _ = ws_tx.send(Message::Close(None)).await;
+                                    break;
+                                }
+                            }
+                            continue;
+                        }
 
-                        if let Err(e) = ws_tx.send(Message::Binary(masked.clone().into())).await {
+                        let verdict = if frame.flags.is_control {
+                            QuotaVerdict::Pass
+                        } else {
+                            quota.consume(frame.payload.len() as u64).await
+                        };
+                        if let QuotaVerdict::Throttle(delay) = verdict {
+                            tokio::time::sleep(delay).await;
+                        }
+                        if !frame.flags.is_control {
+                            let len = frame.payload.len() as u64;
+                            let delay = limits.download(len).max(penalty_delay(&penalty, len));
+                            if !delay.is_zero() {
+                                METRICS.throttled_download_bytes.inc_by(len);
+                                tokio::time::sleep(delay).await;
+                            }
+                        }
+
+                        // Tell the client once it runs out, ahead of the data
+                        if let Some(notice) =
+                            quota.notice().and_then(|m| control_frame(conn_id, &m))
+                            && send_frame(&mut ws_tx, &notice, &padding, &xor_mask)
+                                .await
+                                .is_err()
+                        {
+                            break;
+                        }
+                        if verdict == QuotaVerdict::Close {
+                            let _ = ws_tx.send(Message::Close(None)).await;
+                            break;
+                        }
+
+                        // Stream data waits for window from the client
+                        if frame.flags.is_control || frame.stream_id == 0 {
+                            if let Err(e) =
+                                send_frame(&mut ws_tx, &frame, &padding, &xor_mask).await
+                            {
+                                debug!("WS send error: {}", e);
+                                break;
+                            }
+                            continue;
+                        }
+                        // Exits read TCP targets no faster than the window allows,
+                        // so only datagrams and a misbehaving exit get this far ahead
+                        let stream_id = frame.stream_id;
+                        let window = send_windows.entry(stream_id).or_default();
+                        if window.queued() + frame.payload.len() > STREAM_BACKLOG_LIMIT {
+                            if frame.flags.is_datagram {
+                                trace!("Dropping datagram for busy stream {}", stream_id);
+                                continue;
+                            }
+                            warn!(
+                                "Resetting stream {} of user {}: exit overran the window",
+                                stream_id, user_id
+                            );
+                            send_windows.remove(&stream_id);
+                            let close = ProxyFrame::new_close(conn_id).with_stream(stream_id);
+                            let _ = tx_forwarder.forward(&close, user_id, group_id).await;
+                            if send_frame(&mut ws_tx, &close, &padding, &xor_mask)
+                                .await
+                                .is_err()
+                            {
+                                break;
+                            }
+                            continue;
+                        }
+                        let ready = window.push(frame.payload.len() as u32, frame);
+                        if let Err(e) =
+                            send_released(&mut ws_tx, ready, &mut send_windows, &padding, &xor_mask)
+                                .await
+                        {
                             debug!("WS send error: {}", e);
                             break;
                         }
-                        METRICS.frames_sent.inc();
-                        METRICS.frame_size.observe(masked.len() as f64);
                     }
                     debug!("WS Tx loop ended");
                 });
 
-                // Task: WS Rx -> Exit/DNS (with de-obfuscation)
-                let exit_forwarder = exit_forwarder.clone();
-                let dns_socket_clone = dns_socket.clone();
+                // Task: client data -> Exit, paced by quota and bandwidth limits
+                //
+                // Delays hold back data only; control frames (window updates,
+                // DNS) keep being read meanwhile. Queued data is bounded by the
+                // stream windows the client was granted.
+                let (upload_tx, mut upload_rx) = mpsc::unbounded_channel::<ProxyFrame>();
+                let up_forwarder = exit_forwarder.clone();
+                let up_stream_tx = stream_tx.clone();
+                let up_credit_tx = credit_tx.clone();
+                let up_quota = quota.clone();
+                let up_limits = limits.clone();
+                let up_penalty = penalty.clone();
+                let mut upload_task = tokio::spawn(async move {
+                    let exit_forwarder = up_forwarder;
+                    let stream_tx = up_stream_tx;
+                    let credit_tx = up_credit_tx;
+                    let quota = up_quota;
+                    let limits = up_limits;
+                    let penalty = up_penalty;
+                    // Per-stream receive windows for multiplexed streams
+                    let mut windows: HashMap<u32, ReceiveWindow> = HashMap::new();
+                    // Streams the exits may still hold, closed there on teardown
+                    let mut open_streams: HashSet<u32> = HashSet::new();
+
+                    while let Some(frame) = upload_rx.recv().await {
+                        let stream_id = frame.stream_id;
+                        let len = frame.payload.len();
+
+                        let verdict = quota.consume(len as u64).await;
+                        if let Some(notice) =
+                            quota.notice().and_then(|m| control_frame(conn_id, &m))
+                        {
+                            let _ = stream_tx.send(notice);
+                        }
+                        match verdict {
+                            QuotaVerdict::Pass => {}
+                            QuotaVerdict::Throttle(delay) => tokio::time::sleep(delay).await,
+                            QuotaVerdict::Close => {
+                                info!("Closing session of user {}: quota exceeded", user_id);
+                                break;
+                            }
+                        }
+                        let delay = limits
+                            .upload(len as u64)
+                            .max(penalty_delay(&penalty, len as u64));
+                        if !delay.is_zero() {
+                            METRICS.throttled_upload_bytes.inc_by(len as u64);
+                            tokio::time::sleep(delay).await;
+                        }
 
-                // DNS Response Listener Task
-                let dns_listener = tokio::spawn(async move {
-                    let mut buf = [0u8; 4096];
-                    loop {
-                        match dns_socket_clone.recv_from(&mut buf).await {
-                            Ok((len, _)) => {
-                                let response = buf[..len].to_vec();
-                                let msg = ControlMessage::DohResponse { response };
-                                if let Ok(payload) = rkyv::to_bytes::<rkyv::rancor::Error>(&msg) {
-                                    let mut frame = ProxyFrame::new_control(payload.to_vec());
-                                    frame.conn_id = conn_id; // Route to this client
-                                    let _ = dns_registry_tx.send(frame);
+                        // Data Frame -> Exit Node
+                        if let Err(e) = exit_forwarder.forward(&frame, user_id, group_id).await {
+                            error!("Forward error on stream {}: {}", stream_id, e);
+                            // Reset only this stream; other multiplexed streams stay up
+                            windows.remove(&stream_id);
+                            open_streams.remove(&stream_id);
+                            let _ = credit_tx.send(Credit::Closed(stream_id));
+                            let _ = stream_tx
+                                .send(ProxyFrame::new_close(conn_id).with_stream(stream_id));
+                            continue;
+                        }
+
+                        if frame.flags.is_open && stream_id != 0 {
+                            open_streams.insert(stream_id);
+                        }
+                        if frame.flags.is_final {
+                            windows.remove(&stream_id);
+                            open_streams.remove(&stream_id);
+                            let _ = credit_tx.send(Credit::Closed(stream_id));
+                        } else if len > 0 && (frame.flags.is_datagram || stream_id == 0) {
+                            // Forwarded bytes are consumed: hand credit back to the client.
+                            // TCP streams get theirs from the exit as it writes them out
+                            let credit = windows.entry(stream_id).or_default().consume(len as u32);
+                            if let Some(increment) = credit {
+                                let update = ControlMessage::WindowUpdate {
+                                    stream_id,
+                                    increment,
+                                };
+                                if let Some(frame) = control_frame(conn_id, &update) {
+                                    let _ = stream_tx.send(frame);
                                 }
                             }
-                            Err(_) => break,
                         }
                     }
+                    open_streams
                 });
-
-                while let Some(msg) = ws_rx.next().await {
+                // Set once the upload task ended the session and was joined
+                let mut upload_ended = None;
+
+                // WS Rx -> Exit/DNS (with de-obfuscation)
+                loop {
+                    let msg = tokio::select! {
+                        msg = ws_rx.next() => msg,
+                        open_streams = &mut upload_task => {
+                            upload_ended = Some(open_streams.unwrap_or_default());
+                            break;
+                        }
+                    };
+                    let Some(msg) = msg else {
+                        break;
+                    };
                     match msg {
                         Ok(Message::Binary(data)) => {
                             METRICS.frames_received.inc();
@@ -488,22 +774,50 @@ async fn handle_connect(
                                 {
                                     match ctrl {
                                         ControlMessage::DohQuery { query } => {
-                                            // Forward to Google DNS
-                                            // Note: We use the connection-specific socket
-                                            let _ = dns_socket.send_to(&query, "8.8.8.8:53").await;
+                                            let resolver = resolver.clone();
+                                            let dns_tx = dns_tx.clone();
+                                            tokio::spawn(async move {
+                                                let Some(response) =
+                                                    resolver.resolve(user_id, &query).await
+                                                else {
+                                                    return;
+                                                };
+                                                let msg = ControlMessage::DohResponse { response };
+                                                if let Ok(payload) =
+                                                    rkyv::to_bytes::<rkyv::rancor::Error>(&msg)
+                                                {
+                                                    let mut frame =
+                                                        ProxyFrame::new_control(payload.to_vec());
+                                                    frame.conn_id = conn_id; // Route to this client
+                                                    let _ = dns_tx.send(frame);
+                                                }
+                                            });
+                                        }
+                                        ControlMessage::WindowUpdate {
+                                            stream_id,
+                                            increment,
+                                        } => {
+                                            trace!("Window update for stream {}", stream_id);
+                                            let _ = credit_tx.send(Credit::Grant {
+                                                stream_id,
+                                                increment,
+                                            });
+                                            // The exit holds back reading the target meanwhile
+                                            let exit_forwarder = exit_forwarder.clone();
+                                            tokio::spawn(async move {
+                                                let _ = exit_forwarder
+                                                    .grant(
+                                                        conn_id, stream_id, increment, user_id,
+                                                        group_id,
+                                                    )
+                                                    .await;
+                                            });
                                         }
                                         _ => {}
                                     }
                                 }
-                            } else {
-                                // Data Frame -> Exit Node
-                                if let Err(e) = exit_forwarder.forward(&frame, group_id).await {
-                                    error!("Forward error: {}", e);
-                                    break;
-                                }
-                                billing
-                                    .record_usage(user_id as i64, frame.payload.len() as u64)
-                                    .await;
+                            } else if upload_tx.send(frame).is_err() {
+                                break;
                             }
                         }
                         Ok(Message::Close(_)) => break,
@@ -513,8 +827,26 @@ async fn handle_connect(
                 }
 
                 registry_clone.unregister(conn_id);
+                // Forward what the client sent before leaving
+                drop(upload_tx);
+                let open_streams = match upload_ended {
+                    Some(open_streams) => open_streams,
+                    None => upload_task.await.unwrap_or_default(),
+                };
+                // Exits keep a stream's socket until told, and the session's
+                // NAT flows until the connection itself closes
+                for stream_id in open_streams {
+                    let close = ProxyFrame::new_close(conn_id).with_stream(stream_id);
+                    let _ = exit_forwarder.forward(&close, user_id, group_id).await;
+                }
+                let _ = exit_forwarder
+                    .forward(&ProxyFrame::new_close(conn_id), user_id, group_id)
+                    .await;
+                // Let the tx task drain queued frames (e.g. the quota notice) and end
+                drop(stream_tx);
+                drop(dns_tx);
+                drop(credit_tx);
                 let _ = tx_task.await;
-                let _ = dns_listener.await;
                 METRICS.active_connections.dec();
                 info!("Client disconnected (User {})", user_id);
             }
@@ -531,6 +863,162 @@ async fn handle_connect(
         .unwrap())
 }
 
+/// Why a session was not registered
+enum Refusal {
+    Limit(SessionLimit),
+    /// The cluster could not commit, so the limits cannot be checked
+    Unavailable,
+}
+
+/// Register session `conn_id` with the cluster
+///
+/// Sessions are refused when the cluster cannot commit: letting them in would
+/// lift the limits for as long as the Raft leader is gone.
+async fn register_session(
+    raft_node: &Arc<RaftNode>,
+    conn_id: u64,
+    request: ClientRequest,
+) -> Result<RegisteredSession, Refusal> {
+    let registered = || RegisteredSession {
+        raft_node: raft_node.clone(),
+        conn_id,
+    };
+    let failure =
+        match tokio::time::timeout(SESSION_REGISTER_TIMEOUT, raft_node.propose(request)).await {
+            Ok(Ok(ClientResponse::SessionRejected { limit })) => return Err(Refusal::Limit(limit)),
+            Ok(Ok(ClientResponse::SessionClosed)) => return Err(Refusal::Unavailable),
+            Ok(Ok(_)) => return Ok(registered()),
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => "timed out".to_string(),
+        };
+    warn!("Failed to register session: {}", failure);
+    // The write may still commit later: the close leaves a tombstone that
+    // refuses it, whichever of the two commits first
+    drop(registered());
+    Err(Refusal::Unavailable)
+}
+
+/// A session registered with the cluster, removed from it on drop
+struct RegisteredSession {
+    raft_node: Arc<RaftNode>,
+    conn_id: u64,
+}
+
+impl Drop for RegisteredSession {
+    /// Close the session, retrying until the close commits: a session left
+    /// behind counts towards its user's limits until this node's lease ends
+    fn drop(&mut self) {
+        let raft_node = self.raft_node.clone();
+        let conn_id = self.conn_id;
+        tokio::spawn(async move {
+            let mut delay = SESSION_CLOSE_RETRY;
+            loop {
+                let close = raft_node.propose(ClientRequest::SessionClose { conn_id });
+                let failure = match tokio::time::timeout(SESSION_REGISTER_TIMEOUT, close).await {
+                    Ok(Ok(_)) => return,
+                    Ok(Err(e)) => e.to_string(),
+                    Err(_) => "timed out".to_string(),
+                };
+                warn!(
+                    "Failed to unregister session {}, retrying in {:?}: {}",
+                    conn_id, delay, failure
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(SESSION_CLOSE_RETRY_MAX);
+            }
+        });
+    }
+}
+
+/// Flow-control news from the client about the return path of a stream
+enum Credit {
+    Grant { stream_id: u32, increment: u32 },
+    Closed(u32),
+}
+
+/// Send frames a stream's window let out, forgetting the stream after its close
+async fn send_released<S>(
+    ws_tx: &mut S,
+    frames: Vec<ProxyFrame>,
+    send_windows: &mut HashMap<u32, SendWindow<ProxyFrame>>,
+    padding: &PaddingStrategy,
+    xor_mask: &XorMask,
+) -> Result<(), S::Error>
+where
+    S: futures::Sink<Message> + Unpin,
+{
+    for frame in frames {
+        if frame.flags.is_final {
+            send_windows.remove(&frame.stream_id);
+        }
+        send_frame(ws_tx, &frame, padding, xor_mask).await?;
+    }
+    Ok(())
+}
+
+/// Control frame addressed to the client on `conn_id`
+fn control_frame(conn_id: u64, msg: &ControlMessage) -> Option<ProxyFrame> {
+    let payload = rkyv::to_bytes::<rkyv::rancor::Error>(msg).ok()?;
+    let mut frame = ProxyFrame::new_control(payload.to_vec());
+    frame.conn_id = conn_id;
+    Some(frame)
+}
+
+/// Kind of an exit's abuse report, if `frame` is one
+fn abuse_report(frame: &ProxyFrame) -> Option<AbuseKind> {
+    if !frame.flags.is_control {
+        return None;
+    }
+    match rkyv::from_bytes::<ControlMessage, rkyv::rancor::Error>(&frame.payload) {
+        Ok(ControlMessage::AbuseReport { kind }) => Some(kind),
+        _ => None,
+    }
+}
+
+fn abuse_label(kind: AbuseKind) -> &'static str {
+    match kind {
+        AbuseKind::Smtp => "smtp",
+        AbuseKind::ConnectionRate => "connection_rate",
+        AbuseKind::BitTorrent => "bittorrent",
+    }
+}
+
+/// Delay owed to the abuse throttle of a reported session, if it has one
+fn penalty_delay(penalty: &OnceLock<TokenBucket>, bytes: u64) -> Duration {
+    penalty
+        .get()
+        .map_or(Duration::ZERO, |bucket| bucket.take(bytes))
+}
+
+/// Serialize, obfuscate and send one frame to the client
+async fn send_frame<S>(
+    ws_tx: &mut S,
+    frame: &ProxyFrame,
+    padding: &PaddingStrategy,
+    xor_mask: &XorMask,
+) -> Result<(), S::Error>
+where
+    S: futures::Sink<Message> + Unpin,
+{
+    let frame_bytes = match rkyv::to_bytes::<rkyv::rancor::Error>(frame) {
+        Ok(b) => b.to_vec(),
+        Err(e) => {
+            error!("Frame serialization error: {}", e);
+            return Ok(());
+        }
+    };
+
+    // Obfuscate
+    let padded = padding.pad(&frame_bytes);
+    let masked = xor_mask.apply(&padded);
+    let len = masked.len();
+
+    ws_tx.send(Message::Binary(masked.into())).await?;
+    METRICS.frames_sent.inc();
+    METRICS.frame_size.observe(len as f64);
+    Ok(())
+}
+
 /// Handle health check
 async fn handle_health() -> Result<Response<Full<Bytes>>> {
     Ok(Response::builder()
@@ -671,24 +1159,30 @@ async fn handle_reverse_proxy(
 }
 
 /// Handle exit-node registration (reverse connection)
+///
+/// Exits prove their enrolled name with `X-Exit-Auth` and may only join the
+/// groups of their credential. After the exit-node picks its group, the same
+/// WebSocket becomes its packet tunnel. The response carries our node ID in
+/// `X-Handler-Id`, which return packets are addressed to.
 async fn handle_exit_node_register(
-    req: Request<Incoming>,
+    mut req: Request<Incoming>,
+    handler_id: u64,
+    capacity: ExitLoadConfig,
+    pg_client: PgClient,
     exit_node_pool: Arc<ExitNodePool>,
+    exit_auth: Arc<ExitAuthenticator>,
+    security: &SecurityConfig,
 ) -> Result<Response<Full<Bytes>>> {
-    // Check for WebSocket upgrade
-    let is_upgrade = req
+    let Some(accept) = req
         .headers()
-        .get("upgrade")
-        .and_then(|v| v.to_str().ok())
-        .map(|s| s.eq_ignore_ascii_case("websocket"))
-        .unwrap_or(false);
-
-    if !is_upgrade {
+        .get("Sec-WebSocket-Key")
+        .map(|key| derive_accept_key(key.as_bytes()))
+    else {
         return Ok(Response::builder()
             .status(400)
             .body(Full::new(Bytes::from("Expected WebSocket upgrade")))
             .unwrap());
-    }
+    };
 
     // Parse query parameters for node info
     let query = req.uri().query().unwrap_or("");
@@ -700,53 +1194,80 @@ async fn handle_exit_node_register(
         })
         .collect();
 
-    let name = params
-        .get("name")
-        .cloned()
-        .unwrap_or_else(|| "unknown".to_string());
+    let Some(name) = params.get("name").cloned() else {
+        return Ok(Response::builder()
+            .status(400)
+            .body(Full::new(Bytes::from("Missing name")))
+            .unwrap());
+    };
 
     info!("Exit-node registration request: name={}", name);
 
+    let Some(proof) = req
+        .headers()
+        .get(EXIT_AUTH_HEADER)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return Ok(Response::builder()
+            .status(401)
+            .body(Full::new(Bytes::from("Unauthorized")))
+            .unwrap());
+    };
+
+    // Unknown, revoked and forged credentials all get the same answer
+    let credential = match pg_client.get_exit_credential(&name).await {
+        Ok(Some(credential)) if !credential.revoked => credential,
+        Ok(_) => {
+            warn!("Exit-node {} is not enrolled or revoked", name);
+            return Ok(exit_forbidden());
+        }
+        Err(e) => {
+            error!("Failed to load credential of exit-node {}: {}", name, e);
+            return Ok(Response::builder()
+                .status(503)
+                .body(Full::new(Bytes::from("Service Unavailable")))
+                .unwrap());
+        }
+    };
+    let key = match CredentialSeal::from_config(security)
+        .and_then(|seal| seal.open(&credential.sealed_key))
+    {
+        Ok(key) => key,
+        Err(e) => {
+            error!("Cannot open the key of exit-node {}: {}", name, e);
+            return Ok(Response::builder()
+                .status(503)
+                .body(Full::new(Bytes::from("Service Unavailable")))
+                .unwrap());
+        }
+    };
+    if let Err(e) = exit_auth.verify(&name, proof, &key) {
+        warn!("Exit-node {} failed authentication: {}", name, e);
+        return Ok(exit_forbidden());
+    }
+
+    let upgrade = hyper::upgrade::on(&mut req);
+
     // Spawn WebSocket handler
     tokio::task::spawn(async move {
-        match hyper::upgrade::on(req).await {
+        match upgrade.await {
             Ok(upgraded) => {
-                let ws_stream = match accept_async(TokioIo::new(upgraded)).await {
-                    Ok(ws) => ws,
-                    Err(e) => {
-                        error!("WS accept error for exit-node: {}", e);
-                        return;
-                    }
-                };
+                let ws_stream =
+                    WebSocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, None)
+                        .await;
 
                 let (mut ws_sender, mut ws_receiver) = ws_stream.split();
 
-                // Send available groups to exit-node
-                use apfsds_protocol::{ControlMessage, GroupInfo};
-
-                // TODO: Get groups from configuration or database
-                // For now, hardcode some example groups
-                let groups = vec![
-                    GroupInfo {
-                        group_id: 0,
-                        name: "Default".to_string(),
-                        node_count: exit_node_pool.get_group_nodes(0).len() as u32,
-                        load: 50,
-                    },
-                    GroupInfo {
-                        group_id: 1,
-                        name: "Premium".to_string(),
-                        node_count: exit_node_pool.get_group_nodes(1).len() as u32,
-                        load: 30,
-                    },
-                    GroupInfo {
-                        group_id: 2,
-                        name: "Asia".to_string(),
-                        node_count: exit_node_pool.get_group_nodes(2).len() as u32,
-                        load: 60,
-                    },
-                ];
+                // Send the groups this exit may join
+                let defined = pg_client.list_exit_groups().await.unwrap_or_else(|e| {
+                    warn!("Failed to load exit groups: {}", e);
+                    Vec::new()
+                });
+                let groups = exit_node_pool
+                    .group_list(defined, &credential.group_ids, &capacity)
+                    .await;
 
+                let group_list_len = groups.len();
                 let group_list_msg = ControlMessage::GroupList { groups };
                 if let Ok(msg_bytes) = rkyv::to_bytes::<rkyv::rancor::Error>(&group_list_msg) {
                     if let Err(e) = ws_sender
@@ -756,7 +1277,12 @@ async fn handle_exit_node_register(
                         error!("Failed to send group list to exit-node: {}", e);
                         return;
                     }
-                    info!("Sent group list to exit-node {}", name);
+                    info!(
+                        "Sent {} groups to exit-node {} ({} reverse exits connected)",
+                        group_list_len,
+                        name,
+                        exit_node_pool.count()
+                    );
                 } else {
                     error!("Failed to serialize group list");
                     return;
@@ -770,6 +1296,11 @@ async fn handle_exit_node_register(
                                 rkyv::from_bytes::<ControlMessage, rkyv::rancor::Error>(&data)
                             {
                                 if let ControlMessage::GroupSelect { group_id } = msg {
+                                    if !credential.group_ids.contains(&group_id) {
+                                        warn!("Exit-node {} may not join group {}", name, group_id);
+                                        let _ = ws_sender.send(Message::Close(None)).await;
+                                        return;
+                                    }
                                     info!("Exit-node {} selected group {}", name, group_id);
                                     break group_id;
                                 }
@@ -794,46 +1325,17 @@ async fn handle_exit_node_register(
                     }
                 };
 
-                // Now register in pool with selected group
-                let (tx, mut rx) = mpsc::unbounded_channel();
-                let node_id = exit_node_pool.register(name.clone(), selected_group_id, tx);
-
-                // Spawn sender task
-                let sender_task = tokio::spawn(async move {
-                    let mut ws_sender = ws_sender;
-                    while let Some(msg) = rx.recv().await {
-                        if let Err(e) = ws_sender.send(msg).await {
-                            error!("Failed to send to exit-node {}: {}", node_id, e);
-                            break;
-                        }
-                    }
-                });
-
-                // Handle incoming messages from exit-node
-                while let Some(msg_result) = ws_receiver.next().await {
-                    match msg_result {
-                        Ok(Message::Binary(_data)) => {
-                            // TODO: Handle response from exit-node
-                            // This would be PlainPacket responses
-                        }
-                        Ok(Message::Ping(data)) => {
-                            if let Some(conn) = exit_node_pool.get(node_id) {
-                                let _ = conn.sender.send(Message::Pong(data));
-                            }
-                        }
-                        Ok(Message::Close(_)) => break,
-                        Err(e) => {
-                            error!("WS error from exit-node {}: {}", node_id, e);
-                            break;
-                        }
-                        _ => {}
-                    }
+                let Ok(ws_stream) = ws_sender.reunite(ws_receiver) else {
+                    return;
+                };
+                let revoked = watch_exit_credential(pg_client, name.clone(), selected_group_id);
+                if let Err(e) = exit_node_pool
+                    .serve(name.clone(), selected_group_id, ws_stream, revoked)
+                    .await
+                {
+                    error!("Exit-node {} failed: {}", name, e);
                 }
-
-                // Cleanup
-                exit_node_pool.unregister(node_id);
-                let _ = sender_task.await;
-                info!("Exit-node {} disconnected", node_id);
+                info!("Exit-node {} disconnected", name);
             }
             Err(e) => error!("Upgrade error for exit-node: {}", e),
         }
@@ -843,11 +1345,37 @@ async fn handle_exit_node_register(
         .status(101)
         .header("Upgrade", "websocket")
         .header("Connection", "Upgrade")
-        .header("Sec-WebSocket-Accept", "exit-node-register")
+        .header("Sec-WebSocket-Accept", accept)
+        .header("X-Handler-Id", handler_id)
         .body(Full::new(Bytes::new()))
         .unwrap())
 }
 
+fn exit_forbidden() -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(403)
+        .body(Full::new(Bytes::from("Forbidden")))
+        .unwrap()
+}
+
+/// Resolves once the exit's credential is revoked or loses `group_id`
+async fn watch_exit_credential(pg_client: PgClient, name: String, group_id: i32) {
+    let mut ticker = tokio::time::interval(EXIT_CREDENTIAL_CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+        match pg_client.get_exit_credential(&name).await {
+            Ok(Some(credential))
+                if !credential.revoked && credential.group_ids.contains(&group_id) => {}
+            Ok(_) => {
+                info!("Credential of exit-node {} revoked, disconnecting", name);
+                return;
+            }
+            // Keep the exit while the database is unreachable
+            Err(e) => warn!("Failed to recheck exit-node {}: {}", name, e),
+        }
+    }
+}
+
 /// Run as exit node (simple forwarder) is deprecated/moved, but kept stub if needed by old calls
 /// But we updated main.rs to use exit_node::run
 pub async fn run_exit(_config: &DaemonConfig) -> Result<()> {
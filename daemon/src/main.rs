@@ -3,34 +3,46 @@
 //! The server-side component that handles client connections,
 //! authentication, and traffic forwarding.
 
+mod abuse;
 mod auth;
 mod billing;
 mod config;
 mod connection_registry;
+mod credential_seal;
+mod egress_acl;
 mod emergency;
+mod exit_auth;
 mod exit_forwarder;
 mod exit_node;
 mod exit_node_pool;
+mod exit_relay;
 mod geoip;
+mod group_cache;
 mod handler;
 mod key_rotation;
 mod management;
 mod metrics;
+mod nat;
 mod noise;
 mod plugin;
+mod rate_limit;
+mod resolver;
 
 use anyhow::Result;
 use clap::Parser;
 use std::sync::Arc;
-use tracing::{Level, info};
+use tracing::{Level, debug, info, warn};
 use tracing_subscriber::FmtSubscriber;
 
-use apfsds_raft::{Config as AsyncRaftConfig, RaftNode};
+use apfsds_raft::peer_auth::PeerAuth;
+use apfsds_raft::{ClientRequest, Config as AsyncRaftConfig, NODE_LEASE, RaftNode};
 use apfsds_storage::postgres::PgClient;
 use apfsds_transport::{ExitNodeDefinition, ExitPool, ExitPoolConfig};
 use billing::BillingAggregator;
 use config::DaemonConfig;
+use exit_auth::ExitAuthenticator;
 use exit_forwarder::ExitForwarder;
+use exit_node_pool::ExitNodePool;
 
 /// APFSDS Daemon - Server-side proxy handler
 #[derive(Parser, Debug)]
@@ -96,7 +108,10 @@ async fn main() -> Result<()> {
     info!("Database migrated");
 
     // Initialize Billing Aggregator
-    let billing = Arc::new(BillingAggregator::new(pg_client.clone()));
+    let billing = Arc::new(
+        BillingAggregator::new(pg_client.clone(), &config.billing)
+            .map_err(|e| anyhow::anyhow!("Failed to open billing spill: {}", e))?,
+    );
     let billing_handle = billing.clone().start();
 
     // Initialize Connection Registry
@@ -109,8 +124,34 @@ async fn main() -> Result<()> {
                 .validate()
                 .map_err(|e| anyhow::anyhow!("Invalid raft config: {}", e))?,
         );
-        let node = Arc::new(RaftNode::new(config.raft.node_id, raft_config));
+        let peer_auth = match &config.raft.peer_secret {
+            Some(secret) => {
+                let secret: [u8; 32] = hex::decode(secret)
+                    .ok()
+                    .and_then(|v| v.try_into().ok())
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("raft.peer_secret must be 32 hex-encoded bytes")
+                    })?;
+                Some(PeerAuth::new(secret))
+            }
+            None => {
+                if !config.raft.peers.is_empty() {
+                    warn!("raft.peer_secret is not set, requests from peers are refused");
+                }
+                None
+            }
+        };
+        let node = Arc::new(RaftNode::new(config.raft.node_id, raft_config, peer_auth));
         info!("Raft node initialized with ID: {}", config.raft.node_id);
+
+        if config.raft.peers.is_empty() {
+            // Standalone handler: a cluster of one, bootstrapped on first start
+            if let Err(e) = node.initialize([config.raft.node_id].into()).await {
+                debug!("Not bootstrapping Raft cluster: {}", e);
+            }
+        }
+        reset_sessions(&node).await;
+        tokio::spawn(renew_session_lease(node.clone()));
         Some(node)
     } else {
         None
@@ -121,10 +162,12 @@ async fn main() -> Result<()> {
     let mgmt_config = Arc::new(config.clone());
     let mgmt_registry = registry.clone();
     let mgmt_raft = raft_node.clone();
+    let mgmt_pg = pg_client.clone();
 
     tokio::spawn(async move {
         if let Err(e) =
-            management::start_server(mgmt_bind, mgmt_config, mgmt_registry, mgmt_raft).await
+            management::start_server(mgmt_bind, mgmt_config, mgmt_registry, mgmt_raft, mgmt_pg)
+                .await
         {
             tracing::error!("Management API error: {}", e);
         }
@@ -165,6 +208,7 @@ async fn main() -> Result<()> {
             exit_pool_config,
             config.raft.node_id,
             registry.clone(),
+            Arc::new(exit_auth::TunnelKeys::new(&config.exit_nodes)),
         )?);
 
         // Start background health checker
@@ -181,15 +225,29 @@ async fn main() -> Result<()> {
             }
         }
 
+        // Shared DNS resolver for client DohQuery messages
+        let resolver = Arc::new(resolver::DnsResolver::new(&config.dns)?);
+
+        // User -> exit group lookups, invalidated by database notifications
+        let groups = Arc::new(group_cache::GroupCache::new(pg_client.clone()));
+        groups.clone().start();
+        let limiter = Arc::new(rate_limit::RateLimiter::new(&config.rate_limit));
+
         info!("Starting as handler on {}", config.server.bind);
-        handler::run_handler(
-            &config,
+        let exit_node_pool = Arc::new(ExitNodePool::new(exit_forwarder.pool().clone()));
+        handler::run_handler(handler::HandlerContext {
+            config: config.clone(),
             exit_forwarder,
-            raft_node.expect("Raft node missing in handler mode"),
+            exit_node_pool,
+            exit_auth: Arc::new(ExitAuthenticator::new()),
+            raft_node: raft_node.expect("Raft node missing in handler mode"),
             pg_client,
             billing,
             registry,
-        )
+            resolver,
+            groups,
+            limiter,
+        })
         .await?;
 
         health_handle.abort();
@@ -201,3 +259,40 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Forget the sessions this node served before it (re)started
+async fn reset_sessions(node: &RaftNode) {
+    let request = ClientRequest::SessionReset {
+        node_id: node.node_id,
+    };
+    for _ in 0..5 {
+        match node.propose(request.clone()).await {
+            Ok(_) => return,
+            Err(e) => debug!("Session reset not committed yet: {}", e),
+        }
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+    warn!("Failed to reset sessions of this node; stale ones keep counting towards limits");
+}
+
+/// Keep the sessions this node serves alive in the cluster
+///
+/// Nodes that stop renewing (crashed, cut off) lose their sessions once
+/// `NODE_LEASE` passes, so they stop counting towards their users' limits.
+async fn renew_session_lease(node: Arc<RaftNode>) {
+    let mut ticker = tokio::time::interval(NODE_LEASE / 6);
+    loop {
+        ticker.tick().await;
+        let now_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let request = ClientRequest::SessionHeartbeat {
+            node_id: node.node_id,
+            now_ms,
+        };
+        if let Err(e) = node.propose(request).await {
+            debug!("Session heartbeat not committed: {}", e);
+        }
+    }
+}
@@ -7,21 +7,27 @@
 
 use crate::config::DaemonConfig;
 use crate::connection_registry::ConnectionRegistry;
+use crate::credential_seal::CredentialSeal;
 use anyhow::Result;
 use apfsds_raft;
+use apfsds_raft::peer_auth::{self, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use apfsds_storage::postgres::{ExitCredential, GroupUpdate, PgClient, PgError, User, UserUpdate};
 use axum::{
     Router,
+    body::Bytes,
     extract::{Json, Path, State},
-    http::StatusCode,
+    http::{HeaderMap, StatusCode},
     response::Html,
-    response::IntoResponse,
-    routing::{delete, get, post},
+    response::{IntoResponse, Response},
+    routing::{delete, get, patch, post},
 };
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::net::TcpListener;
-use tracing::info;
+use tracing::{error, info};
 
 /// Management API Configuration
 #[derive(Clone)]
@@ -29,7 +35,7 @@ struct AppState {
     config: Arc<DaemonConfig>,
     registry: Arc<ConnectionRegistry>,
     raft_node: Option<Arc<apfsds_raft::RaftNode>>,
-    // pg_client: PgClient, // Future: Database integration for user management
+    pg_client: PgClient,
 }
 
 /// Create User Request
@@ -37,6 +43,15 @@ struct AppState {
 pub struct CreateUserRequest {
     pub username: String,
     pub quota_bytes: Option<u64>,
+    pub group_id: Option<i32>,
+}
+
+/// Create User Response
+/// `hmac_secret` is only ever returned here; the database keeps it sealed.
+#[derive(Debug, Serialize)]
+pub struct CreateUserResponse {
+    pub user: User,
+    pub hmac_secret: String,
 }
 
 /// Register Node Request
@@ -47,6 +62,22 @@ pub struct RegisterNodeRequest {
     pub weight: f64,
 }
 
+/// Enroll Exit Request
+#[derive(Debug, Deserialize)]
+pub struct EnrollExitRequest {
+    pub name: String,
+    /// Groups the exit may join
+    pub group_ids: Vec<i32>,
+}
+
+/// Enroll Exit Response
+/// `exit_key` is only ever returned here; the database keeps it sealed.
+#[derive(Debug, Serialize)]
+pub struct EnrollExitResponse {
+    pub credential: ExitCredential,
+    pub exit_key: String,
+}
+
 /// System Statistics
 #[derive(Debug, Serialize)]
 pub struct SystemStats {
@@ -61,20 +92,31 @@ pub async fn start_server(
     config: Arc<DaemonConfig>,
     registry: Arc<ConnectionRegistry>,
     raft_node: Option<Arc<apfsds_raft::RaftNode>>,
+    pg_client: PgClient,
 ) -> Result<()> {
     let state = AppState {
         config,
         registry,
         raft_node,
+        pg_client,
     };
 
     let app = Router::new()
         .route("/", get(dashboard))
-        .route("/admin/users", post(create_user))
-        .route("/admin/users/:id", delete(delete_user))
+        .route("/admin/users", get(list_users).post(create_user))
+        .route(
+            "/admin/users/:id",
+            get(get_user).patch(update_user).delete(delete_user),
+        )
+        .route("/admin/users/:id/sessions", get(list_user_sessions))
+        .route("/admin/groups", get(list_groups))
+        .route("/admin/groups/:id", patch(update_group))
         .route("/admin/nodes", post(register_node))
+        .route("/admin/exits", get(list_exits).post(enroll_exit))
+        .route("/admin/exits/:name", delete(revoke_exit))
         .route("/admin/stats", get(get_stats))
         .route("/admin/cluster/membership", post(change_cluster_membership))
+        .route("/raft/write", post(raft_write))
         .with_state(state);
 
     info!("Management API listening on {}", bind);
@@ -127,21 +169,233 @@ async fn change_cluster_membership(
     }
 }
 
+/// Commit a client write forwarded by a follower
+///
+/// Only peers holding `raft.peer_secret` may write to the log.
+async fn raft_write(State(state): State<AppState>, headers: HeaderMap, body: Bytes) -> Response {
+    let Some(raft) = &state.raft_node else {
+        return error_response(StatusCode::SERVICE_UNAVAILABLE, "Raft node not initialized");
+    };
+    let Some(auth) = &raft.peer_auth else {
+        return error_response(StatusCode::FORBIDDEN, "raft.peer_secret is not set");
+    };
+
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    let timestamp = header(TIMESTAMP_HEADER).and_then(|v| v.parse().ok());
+    let authentic =
+        timestamp
+            .zip(header(SIGNATURE_HEADER))
+            .is_some_and(|(timestamp, signature)| {
+                auth.verify(&body, timestamp, signature, peer_auth::unix_now())
+            });
+    if !authentic {
+        return error_response(StatusCode::UNAUTHORIZED, "Invalid peer signature");
+    }
+
+    let request: apfsds_raft::ClientRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(e) => {
+            return error_response(StatusCode::BAD_REQUEST, format!("Invalid request: {}", e));
+        }
+    };
+    match raft.propose(request).await {
+        Ok(response) => Json(response).into_response(),
+        Err(e) => error_response(StatusCode::SERVICE_UNAVAILABLE, e),
+    }
+}
+
+/// JSON error body in the same shape as the other admin endpoints
+fn error_response(status: StatusCode, message: impl ToString) -> Response {
+    let body = serde_json::json!({ "status": "error", "message": message.to_string() });
+    (status, Json(body)).into_response()
+}
+
+fn db_error(e: PgError) -> Response {
+    match e {
+        PgError::Conflict(name) => error_response(
+            StatusCode::CONFLICT,
+            format!("User {} already exists", name),
+        ),
+        e => {
+            error!("Database error: {}", e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, e)
+        }
+    }
+}
+
+/// Name of the first field set to a negative value
+fn negative_field(fields: &[(&'static str, Option<i64>)]) -> Option<&'static str> {
+    fields
+        .iter()
+        .find(|(_, value)| value.is_some_and(|v| v < 0))
+        .map(|(name, _)| *name)
+}
+
+fn negative_response(field: &str) -> Response {
+    error_response(
+        StatusCode::BAD_REQUEST,
+        format!("{} must not be negative", field),
+    )
+}
+
+fn user_not_found(id: i64) -> Response {
+    error_response(StatusCode::NOT_FOUND, format!("User {} not found", id))
+}
+
+/// Generate a secret; returns (secret, hex SHA-256 digest)
+fn generate_credentials() -> ([u8; 32], String) {
+    let mut secret = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut secret);
+    let digest = Sha256::digest(secret);
+    (secret, hex::encode(digest))
+}
+
 async fn create_user(
-    State(_state): State<AppState>,
+    State(state): State<AppState>,
     Json(payload): Json<CreateUserRequest>,
-) -> impl IntoResponse {
+) -> Response {
     info!("Create user request: {:?}", payload);
-    // Note: Database integration pending - user data stored in Raft state machine
-    tracing::warn!("User creation stored in memory only (DB integration pending)");
-    (StatusCode::CREATED, Json("User created"))
+    if payload.username.is_empty() || payload.username.len() > 100 {
+        return error_response(StatusCode::BAD_REQUEST, "Username must be 1-100 characters");
+    }
+
+    // The secret is only usable if handlers can open it again
+    let seal = match CredentialSeal::from_config(&state.config.security) {
+        Ok(seal) => seal,
+        Err(e) => return error_response(StatusCode::SERVICE_UNAVAILABLE, e),
+    };
+
+    let (secret, token_hash) = generate_credentials();
+    let quota = payload.quota_bytes.map(|q| q.min(i64::MAX as u64) as i64);
+    match state
+        .pg_client
+        .create_user(
+            &payload.username,
+            &token_hash,
+            &seal.seal(&secret),
+            payload.group_id,
+            quota,
+        )
+        .await
+    {
+        Ok(user) => {
+            info!("Created user {} ({})", user.id, user.username);
+            (
+                StatusCode::CREATED,
+                Json(CreateUserResponse {
+                    user,
+                    hmac_secret: hex::encode(secret),
+                }),
+            )
+                .into_response()
+        }
+        Err(e) => db_error(e),
+    }
+}
+
+async fn list_users(State(state): State<AppState>) -> Response {
+    match state.pg_client.list_users().await {
+        Ok(users) => (StatusCode::OK, Json(users)).into_response(),
+        Err(e) => db_error(e),
+    }
 }
 
-async fn delete_user(State(_state): State<AppState>, Path(id): Path<u64>) -> impl IntoResponse {
+async fn get_user(State(state): State<AppState>, Path(id): Path<i64>) -> Response {
+    match state.pg_client.get_user(id).await {
+        Ok(Some(user)) => (StatusCode::OK, Json(user)).into_response(),
+        Ok(None) => user_not_found(id),
+        Err(e) => db_error(e),
+    }
+}
+
+/// Change a user's group, quota or limits, or disable/enable it; `null`
+/// resets a field to its default
+async fn update_user(
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+    Json(update): Json<UserUpdate>,
+) -> Response {
+    info!("Update user {} request: {:?}", id, update);
+    let limits = [
+        ("quota_bytes", update.quota_bytes.flatten()),
+        ("rate_up_bps", update.rate_up_bps.flatten()),
+        ("rate_down_bps", update.rate_down_bps.flatten()),
+        ("max_sessions", update.max_sessions.flatten().map(i64::from)),
+        ("max_devices", update.max_devices.flatten().map(i64::from)),
+    ];
+    if let Some(field) = negative_field(&limits) {
+        return negative_response(field);
+    }
+    match state.pg_client.update_user(id, &update).await {
+        Ok(Some(user)) => (StatusCode::OK, Json(user)).into_response(),
+        Ok(None) => user_not_found(id),
+        Err(e) => db_error(e),
+    }
+}
+
+async fn delete_user(State(state): State<AppState>, Path(id): Path<i64>) -> Response {
     info!("Delete user request: {}", id);
-    // Note: Database integration pending
-    tracing::warn!("User deletion in memory only (DB integration pending)");
-    (StatusCode::NO_CONTENT, Json("User deleted"))
+    match state.pg_client.delete_user(id).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => user_not_found(id),
+        Err(e) => db_error(e),
+    }
+}
+
+async fn list_groups(State(state): State<AppState>) -> Response {
+    match state.pg_client.list_exit_groups().await {
+        Ok(groups) => (StatusCode::OK, Json(groups)).into_response(),
+        Err(e) => db_error(e),
+    }
+}
+
+/// Set a group's bandwidth limits; members' cached profiles are dropped
+/// through the database trigger
+async fn update_group(
+    State(state): State<AppState>,
+    Path(id): Path<i32>,
+    Json(update): Json<GroupUpdate>,
+) -> Response {
+    info!("Update group {} request: {:?}", id, update);
+    let limits = [
+        ("rate_up_bps", update.rate_up_bps.flatten()),
+        ("rate_down_bps", update.rate_down_bps.flatten()),
+    ];
+    if let Some(field) = negative_field(&limits) {
+        return negative_response(field);
+    }
+    match state.pg_client.update_exit_group(id, &update).await {
+        Ok(Some(group)) => (StatusCode::OK, Json(group)).into_response(),
+        Ok(None) => error_response(StatusCode::NOT_FOUND, format!("Group {} not found", id)),
+        Err(e) => db_error(e),
+    }
+}
+
+/// An open session of a user, as seen by the cluster
+#[derive(Debug, Serialize)]
+struct SessionInfo {
+    conn_id: u64,
+    node_id: u64,
+    /// Hex client public key of the device
+    device: String,
+}
+
+async fn list_user_sessions(State(state): State<AppState>, Path(id): Path<i64>) -> Response {
+    let Some(raft) = &state.raft_node else {
+        return error_response(StatusCode::SERVICE_UNAVAILABLE, "Raft node not initialized");
+    };
+    let sessions: Vec<SessionInfo> = raft
+        .storage
+        .user_sessions(id as u64)
+        .await
+        .into_iter()
+        .map(|(conn_id, record)| SessionInfo {
+            conn_id,
+            node_id: record.node_id,
+            device: hex::encode(record.client_pk),
+        })
+        .collect();
+    (StatusCode::OK, Json(sessions)).into_response()
 }
 
 async fn register_node(
@@ -154,6 +408,66 @@ async fn register_node(
     (StatusCode::CREATED, Json("Node registered"))
 }
 
+async fn enroll_exit(
+    State(state): State<AppState>,
+    Json(payload): Json<EnrollExitRequest>,
+) -> Response {
+    info!("Enroll exit request: {:?}", payload);
+    if payload.name.is_empty() || payload.name.len() > 100 {
+        return error_response(StatusCode::BAD_REQUEST, "Name must be 1-100 characters");
+    }
+    if payload.group_ids.is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, "At least one group is required");
+    }
+
+    // Handlers need the key itself to check the exit's registrations
+    let seal = match CredentialSeal::from_config(&state.config.security) {
+        Ok(seal) => seal,
+        Err(e) => return error_response(StatusCode::SERVICE_UNAVAILABLE, e),
+    };
+
+    let (exit_key, _) = generate_credentials();
+    match state
+        .pg_client
+        .create_exit_credential(&payload.name, &seal.seal(&exit_key), &payload.group_ids)
+        .await
+    {
+        Ok(credential) => {
+            info!("Enrolled exit {}", credential.name);
+            (
+                StatusCode::CREATED,
+                Json(EnrollExitResponse {
+                    credential,
+                    exit_key: hex::encode(exit_key),
+                }),
+            )
+                .into_response()
+        }
+        Err(PgError::Conflict(name)) => error_response(
+            StatusCode::CONFLICT,
+            format!("Exit {} already exists", name),
+        ),
+        Err(e) => db_error(e),
+    }
+}
+
+async fn list_exits(State(state): State<AppState>) -> Response {
+    match state.pg_client.list_exit_credentials().await {
+        Ok(credentials) => (StatusCode::OK, Json(credentials)).into_response(),
+        Err(e) => db_error(e),
+    }
+}
+
+/// Revoke an exit's credential; connected handlers drop it within 30 seconds
+async fn revoke_exit(State(state): State<AppState>, Path(name): Path<String>) -> Response {
+    info!("Revoke exit request: {}", name);
+    match state.pg_client.revoke_exit_credential(&name).await {
+        Ok(true) => StatusCode::NO_CONTENT.into_response(),
+        Ok(false) => error_response(StatusCode::NOT_FOUND, format!("Exit {} not found", name)),
+        Err(e) => db_error(e),
+    }
+}
+
 async fn get_stats(State(state): State<AppState>) -> impl IntoResponse {
     // Basic stats from registry
     let stats = SystemStats {
@@ -163,3 +477,37 @@ async fn get_stats(State(state): State<AppState>) -> impl IntoResponse {
     };
     (StatusCode::OK, Json(stats))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_credentials() {
+        let (secret, digest) = generate_credentials();
+        let (other, _) = generate_credentials();
+
+        assert_ne!(secret, other);
+        assert_eq!(digest, hex::encode(Sha256::digest(secret)));
+    }
+
+    #[test]
+    fn test_update_null_resets() {
+        let update: UserUpdate =
+            serde_json::from_str(r#"{ "group_id": null, "rate_up_bps": 1024 }"#).unwrap();
+        assert_eq!(update.group_id, Some(None));
+        assert_eq!(update.rate_up_bps, Some(Some(1024)));
+        // Omitted fields stay as they are
+        assert_eq!(update.quota_bytes, None);
+        assert_eq!(update.disabled, None);
+
+        assert_eq!(
+            negative_field(&[("quota_bytes", None), ("rate_up_bps", Some(0))]),
+            None
+        );
+        assert_eq!(
+            negative_field(&[("quota_bytes", Some(1)), ("max_devices", Some(-1))]),
+            Some("max_devices")
+        );
+    }
+}
@@ -1,7 +1,7 @@
 //! Prometheus metrics
 
 use crate::config::MonitoringConfig;
-use prometheus::{Histogram, HistogramOpts, IntCounter, IntGauge, Opts, Registry};
+use prometheus::{Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry};
 use std::sync::LazyLock;
 use tokio::task::JoinHandle;
 use tracing::{error, info};
@@ -16,6 +16,10 @@ pub struct Metrics {
     pub frames_received: IntCounter,
     pub auth_successes: IntCounter,
     pub auth_failures: IntCounter,
+    pub throttled_upload_bytes: IntCounter,
+    pub throttled_download_bytes: IntCounter,
+    pub egress_denied: IntCounterVec,
+    pub abuse_reports: IntCounterVec,
 
     // Gauges
     pub active_connections: IntGauge,
@@ -52,6 +56,36 @@ impl Metrics {
         ))
         .unwrap();
 
+        let throttled_upload_bytes = IntCounter::with_opts(Opts::new(
+            "apfsds_throttled_upload_bytes_total",
+            "Client bytes delayed by bandwidth limits",
+        ))
+        .unwrap();
+
+        let throttled_download_bytes = IntCounter::with_opts(Opts::new(
+            "apfsds_throttled_download_bytes_total",
+            "Bytes to clients delayed by bandwidth limits",
+        ))
+        .unwrap();
+
+        let egress_denied = IntCounterVec::new(
+            Opts::new(
+                "apfsds_egress_denied_total",
+                "Packets and streams an exit refused, by egress rule",
+            ),
+            &["rule"],
+        )
+        .unwrap();
+
+        let abuse_reports = IntCounterVec::new(
+            Opts::new(
+                "apfsds_abuse_reports_total",
+                "Sessions exits reported for abuse, by kind",
+            ),
+            &["kind"],
+        )
+        .unwrap();
+
         let active_connections = IntGauge::with_opts(Opts::new(
             "apfsds_active_connections",
             "Number of active connections",
@@ -81,6 +115,14 @@ impl Metrics {
         REGISTRY.register(Box::new(frames_received.clone())).ok();
         REGISTRY.register(Box::new(auth_successes.clone())).ok();
         REGISTRY.register(Box::new(auth_failures.clone())).ok();
+        REGISTRY
+            .register(Box::new(throttled_upload_bytes.clone()))
+            .ok();
+        REGISTRY
+            .register(Box::new(throttled_download_bytes.clone()))
+            .ok();
+        REGISTRY.register(Box::new(egress_denied.clone())).ok();
+        REGISTRY.register(Box::new(abuse_reports.clone())).ok();
         REGISTRY.register(Box::new(active_connections.clone())).ok();
         REGISTRY.register(Box::new(pool_connections.clone())).ok();
         REGISTRY.register(Box::new(request_duration.clone())).ok();
@@ -91,6 +133,10 @@ impl Metrics {
             frames_received,
             auth_successes,
             auth_failures,
+            throttled_upload_bytes,
+            throttled_download_bytes,
+            egress_denied,
+            abuse_reports,
             active_connections,
             pool_connections,
             request_duration,
@@ -0,0 +1,877 @@
+//! Stateful userspace NAT for the exit node's TUN interface
+//!
+//! Packets from clients get a virtual source address before they enter the
+//! TUN, and replies are rewritten back and routed to the connection they
+//! belong to. Every client connection `(handler_id, conn_id)` leases one
+//! address per IP version from the pools, and each of its flows (protocol
+//! and 5-tuple) gets a port on that address, the client's own port when it
+//! is free. Flows expire after a per-protocol idle time; an address goes back
+//! to its pool with the last flow of its lease.
+
+use crate::config::NatConfig;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ops::RangeInclusive;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+const ICMP: u8 = 1;
+const TCP: u8 = 6;
+const UDP: u8 = 17;
+const ICMPV6: u8 = 58;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_ACK: u8 = 0x10;
+
+/// Lowest port handed out when a client's own port is taken
+const FIRST_PORT: u16 = 1024;
+
+/// Client connection a flow belongs to: (handler_id, conn_id)
+pub type Owner = (u64, u64);
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum NatError {
+    #[error("Malformed IP packet")]
+    Malformed,
+
+    #[error("Unsupported packet: {0}")]
+    Unsupported(&'static str),
+
+    #[error("Address pool exhausted")]
+    AddressesExhausted,
+
+    #[error("No free port on {0}")]
+    PortsExhausted(IpAddr),
+}
+
+/// Protocol and 5-tuple of a flow as the client sent it
+///
+/// ICMP echo flows use the identifier as source port and 0 as destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Tuple {
+    proto: u8,
+    src: IpAddr,
+    sport: u16,
+    dst: IpAddr,
+    dport: u16,
+}
+
+/// NAT side of a flow: (address, protocol, port)
+type NatKey = (IpAddr, u8, u16);
+
+/// Lease of a connection for one IP version: (owner, is IPv6)
+type LeaseKey = (Owner, bool);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TcpState {
+    Opening,
+    Established,
+    Closing,
+}
+
+struct Flow {
+    owner: Owner,
+    inside: Tuple,
+    tcp: TcpState,
+    last_seen: Instant,
+}
+
+/// Address of one client connection and the ports its flows use
+struct Lease {
+    ip: IpAddr,
+    ports: HashSet<(u8, u16)>,
+    next_port: u16,
+}
+
+/// Addresses of one IP version
+struct Pool {
+    /// Released addresses, reused oldest first
+    free: VecDeque<IpAddr>,
+    /// Next address never handed out
+    next: u128,
+    last: u128,
+    v6: bool,
+}
+
+impl Pool {
+    fn take(&mut self) -> Option<IpAddr> {
+        if let Some(ip) = self.free.pop_front() {
+            return Some(ip);
+        }
+        if self.next > self.last {
+            return None;
+        }
+        self.next += 1;
+        let ip = self.next - 1;
+        Some(if self.v6 {
+            Ipv6Addr::from(ip).into()
+        } else {
+            Ipv4Addr::from(ip as u32).into()
+        })
+    }
+}
+
+struct Table {
+    outbound: HashMap<(Owner, Tuple), NatKey>,
+    flows: HashMap<NatKey, Flow>,
+    leases: HashMap<LeaseKey, Lease>,
+    v4: Pool,
+    v6: Pool,
+}
+
+/// NAT table of an exit node
+pub struct NatTable {
+    timeouts: NatConfig,
+    table: Mutex<Table>,
+}
+
+impl NatTable {
+    /// NAT onto the addresses of `v4` and `v6`
+    pub fn new(
+        v4: RangeInclusive<Ipv4Addr>,
+        v6: RangeInclusive<Ipv6Addr>,
+        timeouts: &NatConfig,
+    ) -> Self {
+        let pool = |first: u128, last: u128, v6| Pool {
+            free: VecDeque::new(),
+            next: first,
+            last,
+            v6,
+        };
+        Self {
+            timeouts: timeouts.clone(),
+            table: Mutex::new(Table {
+                outbound: HashMap::new(),
+                flows: HashMap::new(),
+                leases: HashMap::new(),
+                v4: pool(
+                    u32::from(*v4.start()).into(),
+                    u32::from(*v4.end()).into(),
+                    false,
+                ),
+                v6: pool((*v6.start()).into(), (*v6.end()).into(), true),
+            }),
+        }
+    }
+
+    /// Rewrite the source of a packet `owner` sent, opening a flow if needed
+    pub fn outbound(&self, owner: Owner, packet: &mut [u8]) -> Result<(), NatError> {
+        let parsed = parse(packet)?;
+        if parsed.is_icmp() && !parsed.is_echo(true) {
+            return Err(NatError::Unsupported("ICMP other than echo request"));
+        }
+        let inside = parsed.tuple();
+
+        let mut table = self.table.lock().unwrap();
+        let key = match table.outbound.get(&(owner, inside)) {
+            Some(key) => *key,
+            None => self.open(&mut table, owner, inside)?,
+        };
+        let flow = table.flows.get_mut(&key).expect("flow of outbound entry");
+        flow.last_seen = Instant::now();
+        if parsed.proto == TCP {
+            flow.tcp = next_tcp_state(flow.tcp, parsed.tcp_flags, true);
+        }
+        drop(table);
+
+        rewrite(packet, &parsed, Side::Source, key.0, key.2);
+        Ok(())
+    }
+
+    /// Whether `packet` would open a new flow for `owner`
+    pub fn is_new(&self, owner: Owner, packet: &[u8]) -> bool {
+        let Ok(parsed) = parse(packet) else {
+            return false;
+        };
+        let table = self.table.lock().unwrap();
+        !table.outbound.contains_key(&(owner, parsed.tuple()))
+    }
+
+    /// Rewrite the destination of a reply, returning the connection it is for
+    ///
+    /// Packets that match no flow, or come from another peer than the flow
+    /// was opened to, return None.
+    pub fn inbound(&self, packet: &mut [u8]) -> Option<Owner> {
+        let parsed = parse(packet).ok()?;
+        if parsed.is_icmp() && !parsed.is_echo(false) {
+            return None;
+        }
+        // Echo replies carry the identifier in the source port slot
+        let port = if parsed.is_icmp() {
+            parsed.sport
+        } else {
+            parsed.dport
+        };
+
+        let mut table = self.table.lock().unwrap();
+        let flow = table.flows.get_mut(&(parsed.dst, parsed.proto, port))?;
+        let from_peer = parsed.src == flow.inside.dst
+            && (parsed.is_icmp() || parsed.sport == flow.inside.dport);
+        if !from_peer {
+            return None;
+        }
+        flow.last_seen = Instant::now();
+        if parsed.proto == TCP {
+            flow.tcp = next_tcp_state(flow.tcp, parsed.tcp_flags, false);
+        }
+        let (owner, inside) = (flow.owner, flow.inside);
+        drop(table);
+
+        rewrite(packet, &parsed, Side::Destination, inside.src, inside.sport);
+        Some(owner)
+    }
+
+    fn open(&self, table: &mut Table, owner: Owner, inside: Tuple) -> Result<NatKey, NatError> {
+        let lease_key = (owner, inside.src.is_ipv6());
+        if !table.leases.contains_key(&lease_key) {
+            let ip = table
+                .pool(lease_key.1)
+                .take()
+                .ok_or(NatError::AddressesExhausted)?;
+            table.leases.insert(
+                lease_key,
+                Lease {
+                    ip,
+                    ports: HashSet::new(),
+                    next_port: FIRST_PORT,
+                },
+            );
+        }
+
+        let lease = table.leases.get_mut(&lease_key).unwrap();
+        let port = lease
+            .allocate(inside.proto, inside.sport)
+            .ok_or(NatError::PortsExhausted(lease.ip))?;
+        let key = (lease.ip, inside.proto, port);
+
+        table.outbound.insert((owner, inside), key);
+        table.flows.insert(
+            key,
+            Flow {
+                owner,
+                inside,
+                tcp: TcpState::Opening,
+                last_seen: Instant::now(),
+            },
+        );
+        Ok(key)
+    }
+
+    /// Drop flows idle for longer than their protocol's timeout
+    ///
+    /// Returns how many were dropped.
+    pub fn expire(&self) -> usize {
+        self.expire_at(Instant::now())
+    }
+
+    fn expire_at(&self, now: Instant) -> usize {
+        let mut table = self.table.lock().unwrap();
+        let expired: Vec<NatKey> = table
+            .flows
+            .iter()
+            .filter(|(_, flow)| now.saturating_duration_since(flow.last_seen) > self.timeout(flow))
+            .map(|(key, _)| *key)
+            .collect();
+
+        for key in &expired {
+            table.remove(*key);
+        }
+        expired.len()
+    }
+
+    /// Drop all flows of a closed connection
+    pub fn release(&self, owner: Owner) {
+        let mut table = self.table.lock().unwrap();
+        let keys: Vec<NatKey> = [false, true]
+            .iter()
+            .filter_map(|v6| table.leases.get(&(owner, *v6)))
+            .flat_map(|lease| {
+                lease
+                    .ports
+                    .iter()
+                    .map(|(proto, port)| (lease.ip, *proto, *port))
+            })
+            .collect();
+
+        for key in keys {
+            table.remove(key);
+        }
+    }
+
+    /// Number of open flows
+    pub fn len(&self) -> usize {
+        self.table.lock().unwrap().flows.len()
+    }
+
+    fn timeout(&self, flow: &Flow) -> Duration {
+        let secs = match (flow.inside.proto, flow.tcp) {
+            (TCP, TcpState::Established) => self.timeouts.tcp_established_timeout,
+            (TCP, _) => self.timeouts.tcp_transitory_timeout,
+            (UDP, _) => self.timeouts.udp_timeout,
+            _ => self.timeouts.icmp_timeout,
+        };
+        Duration::from_secs(secs)
+    }
+}
+
+impl Table {
+    fn pool(&mut self, v6: bool) -> &mut Pool {
+        if v6 { &mut self.v6 } else { &mut self.v4 }
+    }
+
+    fn remove(&mut self, key: NatKey) {
+        let Some(flow) = self.flows.remove(&key) else {
+            return;
+        };
+        self.outbound.remove(&(flow.owner, flow.inside));
+
+        let lease_key = (flow.owner, key.0.is_ipv6());
+        if let Some(lease) = self.leases.get_mut(&lease_key) {
+            lease.ports.remove(&(key.1, key.2));
+            if lease.ports.is_empty() {
+                self.leases.remove(&lease_key);
+                self.pool(key.0.is_ipv6()).free.push_back(key.0);
+            }
+        }
+    }
+}
+
+impl Lease {
+    /// Port for a new flow, preferring the client's own
+    fn allocate(&mut self, proto: u8, wanted: u16) -> Option<u16> {
+        if self.ports.insert((proto, wanted)) {
+            return Some(wanted);
+        }
+
+        for _ in FIRST_PORT..=u16::MAX {
+            let port = self.next_port;
+            self.next_port = port.checked_add(1).unwrap_or(FIRST_PORT);
+            if self.ports.insert((proto, port)) {
+                return Some(port);
+            }
+        }
+        None
+    }
+}
+
+fn next_tcp_state(state: TcpState, flags: u8, outbound: bool) -> TcpState {
+    if flags & (TCP_FIN | TCP_RST) != 0 {
+        return TcpState::Closing;
+    }
+    match state {
+        // The client reuses the port for a new connection
+        TcpState::Closing if outbound && flags & TCP_SYN != 0 && flags & TCP_ACK == 0 => {
+            TcpState::Opening
+        }
+        TcpState::Opening if flags & TCP_ACK != 0 => TcpState::Established,
+        state => state,
+    }
+}
+
+/// What the NAT needs to know about a packet
+struct Parsed {
+    proto: u8,
+    /// Offset of the transport header
+    l4: usize,
+    src: IpAddr,
+    dst: IpAddr,
+    sport: u16,
+    dport: u16,
+    tcp_flags: u8,
+    icmp_type: u8,
+}
+
+impl Parsed {
+    fn tuple(&self) -> Tuple {
+        Tuple {
+            proto: self.proto,
+            src: self.src,
+            sport: self.sport,
+            dst: self.dst,
+            dport: self.dport,
+        }
+    }
+
+    fn is_icmp(&self) -> bool {
+        matches!(self.proto, ICMP | ICMPV6)
+    }
+
+    fn is_echo(&self, request: bool) -> bool {
+        match (self.proto, request) {
+            (ICMP, true) => self.icmp_type == ICMP_ECHO_REQUEST,
+            (ICMP, false) => self.icmp_type == ICMP_ECHO_REPLY,
+            (_, true) => self.icmp_type == ICMPV6_ECHO_REQUEST,
+            (_, false) => self.icmp_type == ICMPV6_ECHO_REPLY,
+        }
+    }
+}
+
+/// Destination of a packet headed into the TUN; ICMP has no port
+pub fn destination(packet: &[u8]) -> Option<(IpAddr, Option<u16>)> {
+    let parsed = parse(packet).ok()?;
+    Some((parsed.dst, (!parsed.is_icmp()).then_some(parsed.dport)))
+}
+
+fn parse(packet: &[u8]) -> Result<Parsed, NatError> {
+    let (proto, l4, total, src, dst) = match packet.first().map(|b| b >> 4) {
+        Some(4) => parse_ipv4(packet)?,
+        Some(6) => parse_ipv6(packet)?,
+        _ => return Err(NatError::Malformed),
+    };
+
+    let l4_bytes = &packet[l4..total];
+    let port = |at: usize| u16::from_be_bytes([l4_bytes[at], l4_bytes[at + 1]]);
+    let mut parsed = Parsed {
+        proto,
+        l4,
+        src,
+        dst,
+        sport: 0,
+        dport: 0,
+        tcp_flags: 0,
+        icmp_type: 0,
+    };
+
+    match proto {
+        TCP if l4_bytes.len() >= 20 => {
+            (parsed.sport, parsed.dport) = (port(0), port(2));
+            parsed.tcp_flags = l4_bytes[13];
+        }
+        UDP if l4_bytes.len() >= 8 => (parsed.sport, parsed.dport) = (port(0), port(2)),
+        ICMP | ICMPV6 if l4_bytes.len() >= 8 => {
+            parsed.icmp_type = l4_bytes[0];
+            parsed.sport = port(4);
+        }
+        TCP | UDP | ICMP | ICMPV6 => return Err(NatError::Malformed),
+        _ => return Err(NatError::Unsupported("protocol")),
+    }
+    // Each version only carries its own ICMP
+    if (proto == ICMP && src.is_ipv6()) || (proto == ICMPV6 && src.is_ipv4()) {
+        return Err(NatError::Unsupported("protocol"));
+    }
+    Ok(parsed)
+}
+
+/// Protocol, transport offset, packet length and addresses of an IPv4 packet
+fn parse_ipv4(packet: &[u8]) -> Result<(u8, usize, usize, IpAddr, IpAddr), NatError> {
+    if packet.len() < 20 {
+        return Err(NatError::Malformed);
+    }
+    let ihl = usize::from(packet[0] & 0x0f) * 4;
+    let total = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+    if ihl < 20 || total < ihl || packet.len() < total {
+        return Err(NatError::Malformed);
+    }
+    // Only the first fragment carries ports
+    if u16::from_be_bytes([packet[6], packet[7]]) & 0x3fff != 0 {
+        return Err(NatError::Unsupported("fragment"));
+    }
+
+    let src = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+    let dst = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+    Ok((packet[9], ihl, total, src.into(), dst.into()))
+}
+
+/// Protocol, transport offset, packet length and addresses of an IPv6 packet
+fn parse_ipv6(packet: &[u8]) -> Result<(u8, usize, usize, IpAddr, IpAddr), NatError> {
+    if packet.len() < 40 {
+        return Err(NatError::Malformed);
+    }
+    let total = 40 + usize::from(u16::from_be_bytes([packet[4], packet[5]]));
+    if packet.len() < total {
+        return Err(NatError::Malformed);
+    }
+    // Extension headers (fragments included) would have to be walked first
+    let proto = packet[6];
+    if !matches!(proto, TCP | UDP | ICMPV6) {
+        return Err(NatError::Unsupported("IPv6 extension header or protocol"));
+    }
+
+    let octets = |at: usize| <[u8; 16]>::try_from(&packet[at..at + 16]).unwrap();
+    let src = Ipv6Addr::from(octets(8));
+    let dst = Ipv6Addr::from(octets(24));
+    Ok((proto, 40, total, src.into(), dst.into()))
+}
+
+#[derive(Clone, Copy)]
+enum Side {
+    Source,
+    Destination,
+}
+
+/// Replace one side's address and port, keeping all checksums valid
+fn rewrite(packet: &mut [u8], parsed: &Parsed, side: Side, ip: IpAddr, port: u16) {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    let addr_at = match (side, ip.len()) {
+        (Side::Source, 4) => 12,
+        (Side::Destination, 4) => 16,
+        (Side::Source, _) => 8,
+        (Side::Destination, _) => 24,
+    };
+    let (port_at, check_at) = match (parsed.proto, side) {
+        (ICMP | ICMPV6, _) => (parsed.l4 + 4, parsed.l4 + 2),
+        (TCP, Side::Source) => (parsed.l4, parsed.l4 + 16),
+        (TCP, Side::Destination) => (parsed.l4 + 2, parsed.l4 + 16),
+        (_, Side::Source) => (parsed.l4, parsed.l4 + 6),
+        (_, Side::Destination) => (parsed.l4 + 2, parsed.l4 + 6),
+    };
+
+    let addr = addr_at..addr_at + ip.len();
+    let old: Vec<u8> = [&packet[addr.clone()], &packet[port_at..port_at + 2]].concat();
+    packet[addr].copy_from_slice(&ip);
+    packet[port_at..port_at + 2].copy_from_slice(&port.to_be_bytes());
+
+    let check = u16::from_be_bytes([packet[check_at], packet[check_at + 1]]);
+    let check = match parsed.proto {
+        // The ICMP checksum covers no pseudo-header, ICMPv6's does
+        ICMP => checksum_adjust(check, &old[4..], &port.to_be_bytes()),
+        // A zero UDP checksum means none was computed
+        UDP if check == 0 => 0,
+        proto => {
+            let new = [ip.as_slice(), &port.to_be_bytes()].concat();
+            match checksum_adjust(check, &old, &new) {
+                0 if proto == UDP => 0xffff,
+                check => check,
+            }
+        }
+    };
+    packet[check_at..check_at + 2].copy_from_slice(&check.to_be_bytes());
+
+    // IPv6 has no header checksum
+    if ip.len() == 4 {
+        let ihl = parsed.l4;
+        packet[10..12].copy_from_slice(&[0, 0]);
+        let check = !fold(sum_words(&packet[..ihl]));
+        packet[10..12].copy_from_slice(&check.to_be_bytes());
+    }
+}
+
+fn sum_words(data: &[u8]) -> u32 {
+    data.chunks(2)
+        .map(|w| u32::from(u16::from_be_bytes([w[0], *w.get(1).unwrap_or(&0)])))
+        .sum()
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// Incremental checksum update for `old` bytes replaced by `new` (RFC 1624)
+fn checksum_adjust(check: u16, old: &[u8], new: &[u8]) -> u16 {
+    let removed: u32 = old
+        .chunks(2)
+        .map(|w| u32::from(!u16::from_be_bytes([w[0], w[1]])))
+        .sum();
+    !fold(u32::from(!check) + removed + sum_words(new))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use etherparse::{InternetSlice, PacketBuilder, SlicedPacket, TransportSlice};
+
+    const CLIENT: [u8; 4] = [192, 168, 1, 10];
+    const SERVER: [u8; 4] = [203, 0, 113, 5];
+
+    const CLIENT6: [u8; 16] = [0xfd, 0x12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10];
+    const SERVER6: [u8; 16] = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5];
+
+    fn nat4(last: u8) -> IpAddr {
+        Ipv4Addr::new(10, 200, 0, last).into()
+    }
+
+    fn nat6(last: u16) -> IpAddr {
+        Ipv6Addr::new(0xfd00, 0x200, 0, 0, 0, 0, 0, last).into()
+    }
+
+    fn table() -> NatTable {
+        NatTable::new(
+            Ipv4Addr::new(10, 200, 0, 2)..=Ipv4Addr::new(10, 200, 0, 3),
+            Ipv6Addr::new(0xfd00, 0x200, 0, 0, 0, 0, 0, 2)
+                ..=Ipv6Addr::new(0xfd00, 0x200, 0, 0, 0, 0, 0, 3),
+            &NatConfig::default(),
+        )
+    }
+
+    fn tcp(src: [u8; 4], sport: u16, dst: [u8; 4], dport: u16, syn: bool) -> Vec<u8> {
+        let builder = PacketBuilder::ipv4(src, dst, 64).tcp(sport, dport, 1, 1024);
+        let builder = if syn { builder.syn() } else { builder.ack(1) };
+        let mut packet = Vec::new();
+        builder.write(&mut packet, b"hello").unwrap();
+        packet
+    }
+
+    fn tcp6(src: [u8; 16], sport: u16, dst: [u8; 16], dport: u16) -> Vec<u8> {
+        let mut packet = Vec::new();
+        PacketBuilder::ipv6(src, dst, 64)
+            .tcp(sport, dport, 1, 1024)
+            .syn()
+            .write(&mut packet, b"hello")
+            .unwrap();
+        packet
+    }
+
+    fn udp(src: [u8; 4], sport: u16, dst: [u8; 4], dport: u16) -> Vec<u8> {
+        let mut packet = Vec::new();
+        PacketBuilder::ipv4(src, dst, 64)
+            .udp(sport, dport)
+            .write(&mut packet, b"hello")
+            .unwrap();
+        packet
+    }
+
+    /// Source/destination address and port after checking every checksum
+    fn verify(packet: &[u8]) -> ((IpAddr, u16), (IpAddr, u16)) {
+        let sliced = SlicedPacket::from_ip(packet).unwrap();
+        let transport = sliced.transport.unwrap();
+        let (source, destination, expected) = match sliced.ip.unwrap() {
+            InternetSlice::Ipv4(ip, _) => {
+                let header = ip.to_header();
+                assert_eq!(
+                    header.header_checksum,
+                    header.calc_header_checksum().unwrap()
+                );
+                let expected = match &transport {
+                    TransportSlice::Tcp(tcp) => {
+                        tcp.to_header().calc_checksum_ipv4(&header, sliced.payload)
+                    }
+                    TransportSlice::Udp(udp) => {
+                        udp.to_header().calc_checksum_ipv4(&header, sliced.payload)
+                    }
+                    _ => panic!("unexpected transport"),
+                };
+                (header.source.into(), header.destination.into(), expected)
+            }
+            InternetSlice::Ipv6(ip, _) => {
+                let header = ip.to_header();
+                let expected = match &transport {
+                    TransportSlice::Tcp(tcp) => {
+                        tcp.to_header().calc_checksum_ipv6(&header, sliced.payload)
+                    }
+                    TransportSlice::Udp(udp) => {
+                        udp.to_header().calc_checksum_ipv6(&header, sliced.payload)
+                    }
+                    _ => panic!("unexpected transport"),
+                };
+                (header.source.into(), header.destination.into(), expected)
+            }
+        };
+
+        let (checksum, ports) = match transport {
+            TransportSlice::Tcp(tcp) => {
+                (tcp.checksum(), (tcp.source_port(), tcp.destination_port()))
+            }
+            TransportSlice::Udp(udp) => {
+                (udp.checksum(), (udp.source_port(), udp.destination_port()))
+            }
+            _ => panic!("unexpected transport"),
+        };
+        assert_eq!(checksum, expected.unwrap());
+        ((source, ports.0), (destination, ports.1))
+    }
+
+    #[test]
+    fn test_tcp_roundtrip() {
+        let nat = table();
+        let owner = (1, 42);
+
+        let mut out = tcp(CLIENT, 40000, SERVER, 443, true);
+        nat.outbound(owner, &mut out).unwrap();
+        let (source, dest) = verify(&out);
+        assert_eq!(source, (nat4(2), 40000));
+        assert_eq!(dest, (SERVER.into(), 443));
+
+        let mut reply = tcp(SERVER, 443, [10, 200, 0, 2], 40000, false);
+        assert_eq!(nat.inbound(&mut reply), Some(owner));
+        assert_eq!(verify(&reply).1, (CLIENT.into(), 40000));
+
+        // Other peers cannot reach into the flow
+        let mut stray = tcp([198, 51, 100, 1], 443, [10, 200, 0, 2], 40000, false);
+        assert_eq!(nat.inbound(&mut stray), None);
+    }
+
+    #[test]
+    fn test_port_allocation() {
+        let nat = table();
+
+        // One address per connection, the client's port while it is free
+        let mut a = udp(CLIENT, 5000, SERVER, 53);
+        let mut b = udp(CLIENT, 5000, [198, 51, 100, 1], 53);
+        let mut c = udp(CLIENT, 5000, SERVER, 53);
+        nat.outbound((1, 1), &mut a).unwrap();
+        nat.outbound((1, 1), &mut b).unwrap();
+        nat.outbound((1, 2), &mut c).unwrap();
+
+        assert_eq!(verify(&a).0, (nat4(2), 5000));
+        assert_eq!(verify(&b).0, (nat4(2), FIRST_PORT));
+        assert_eq!(verify(&c).0, (nat4(3), 5000));
+
+        // The same flow keeps its mapping
+        let mut again = udp(CLIENT, 5000, [198, 51, 100, 1], 53);
+        nat.outbound((1, 1), &mut again).unwrap();
+        assert_eq!(verify(&again).0.1, FIRST_PORT);
+        assert_eq!(nat.len(), 3);
+
+        let mut d = udp(CLIENT, 5000, SERVER, 53);
+        assert_eq!(
+            nat.outbound((1, 3), &mut d),
+            Err(NatError::AddressesExhausted)
+        );
+    }
+
+    #[test]
+    fn test_expiry_releases_addresses() {
+        let nat = table();
+        let now = Instant::now();
+
+        let mut established = tcp(CLIENT, 40000, SERVER, 443, true);
+        nat.outbound((1, 1), &mut established).unwrap();
+        let mut reply = tcp(SERVER, 443, [10, 200, 0, 2], 40000, false);
+        nat.inbound(&mut reply).unwrap();
+
+        let mut datagram = udp(CLIENT, 5000, SERVER, 53);
+        nat.outbound((1, 2), &mut datagram).unwrap();
+
+        // UDP times out long before an established TCP connection
+        let config = NatConfig::default();
+        let later = now + Duration::from_secs(config.udp_timeout + 1);
+        assert_eq!(nat.expire_at(later), 1);
+        assert_eq!(nat.len(), 1);
+
+        // The freed address is handed out again
+        let mut next = udp(CLIENT, 5000, SERVER, 53);
+        nat.outbound((1, 3), &mut next).unwrap();
+        assert_eq!(verify(&next).0.0, nat4(3));
+
+        nat.release((1, 1));
+        assert_eq!(nat.len(), 1);
+        let mut next = udp(CLIENT, 5000, SERVER, 53);
+        nat.outbound((1, 4), &mut next).unwrap();
+        assert_eq!(verify(&next).0.0, nat4(2));
+    }
+
+    #[test]
+    fn test_icmp_echo() {
+        let nat = table();
+        let mut echo = Vec::new();
+        PacketBuilder::ipv4(CLIENT, SERVER, 64)
+            .icmpv4_echo_request(7, 1)
+            .write(&mut echo, b"ping")
+            .unwrap();
+        nat.outbound((1, 1), &mut echo).unwrap();
+
+        let mut reply = Vec::new();
+        PacketBuilder::ipv4(SERVER, [10, 200, 0, 2], 64)
+            .icmpv4_echo_reply(7, 1)
+            .write(&mut reply, b"ping")
+            .unwrap();
+        assert_eq!(nat.inbound(&mut reply), Some((1, 1)));
+        assert_eq!(&reply[16..20], &CLIENT);
+    }
+
+    #[test]
+    fn test_ipv6() {
+        let nat = table();
+        let owner = (1, 42);
+
+        let mut out = tcp6(CLIENT6, 40000, SERVER6, 443);
+        nat.outbound(owner, &mut out).unwrap();
+        assert_eq!(verify(&out).0, (nat6(2), 40000));
+
+        let mut reply = tcp6(SERVER6, 443, nat6_octets(2), 40000);
+        assert_eq!(nat.inbound(&mut reply), Some(owner));
+        assert_eq!(verify(&reply).1, (CLIENT6.into(), 40000));
+
+        // The same connection leases one address of each version
+        let mut out = udp(CLIENT, 5000, SERVER, 53);
+        nat.outbound(owner, &mut out).unwrap();
+        assert_eq!(verify(&out).0, (nat4(2), 5000));
+
+        // The pools are separate
+        let mut other = tcp6(CLIENT6, 40000, SERVER6, 443);
+        nat.outbound((1, 43), &mut other).unwrap();
+        assert_eq!(verify(&other).0.0, nat6(3));
+
+        nat.release(owner);
+        assert_eq!(nat.len(), 1);
+        let mut out = tcp6(CLIENT6, 40000, SERVER6, 443);
+        nat.outbound((1, 44), &mut out).unwrap();
+        assert_eq!(verify(&out).0.0, nat6(2));
+    }
+
+    #[test]
+    fn test_icmpv6_echo() {
+        let nat = table();
+        let mut echo = Vec::new();
+        PacketBuilder::ipv6(CLIENT6, SERVER6, 64)
+            .icmpv6_echo_request(7, 1)
+            .write(&mut echo, b"ping")
+            .unwrap();
+        nat.outbound((1, 1), &mut echo).unwrap();
+        assert!(icmpv6_checksum_ok(&echo));
+
+        let mut reply = Vec::new();
+        PacketBuilder::ipv6(SERVER6, nat6_octets(2), 64)
+            .icmpv6_echo_reply(7, 1)
+            .write(&mut reply, b"ping")
+            .unwrap();
+        assert_eq!(nat.inbound(&mut reply), Some((1, 1)));
+        assert_eq!(&reply[24..40], &CLIENT6);
+        assert!(icmpv6_checksum_ok(&reply));
+    }
+
+    fn nat6_octets(last: u16) -> [u8; 16] {
+        Ipv6Addr::new(0xfd00, 0x200, 0, 0, 0, 0, 0, last).octets()
+    }
+
+    /// ICMPv6 sums to all ones over the pseudo-header and message
+    fn icmpv6_checksum_ok(packet: &[u8]) -> bool {
+        let len = packet.len() as u32 - 40;
+        let sum = sum_words(&packet[8..40]) + len + u32::from(ICMPV6) + sum_words(&packet[40..]);
+        fold(sum) == 0xffff
+    }
+
+    #[test]
+    fn test_is_new() {
+        let nat = table();
+        let mut syn = tcp(CLIENT, 40000, SERVER, 443, true);
+        assert!(nat.is_new((1, 1), &syn));
+        nat.outbound((1, 1), &mut syn).unwrap();
+
+        let ack = tcp(CLIENT, 40000, SERVER, 443, false);
+        assert!(!nat.is_new((1, 1), &ack));
+        assert!(nat.is_new((1, 2), &ack));
+        assert!(!nat.is_new((1, 1), b"garbage"));
+    }
+
+    #[test]
+    fn test_tcp_states() {
+        use TcpState::*;
+        assert_eq!(next_tcp_state(Opening, TCP_SYN, true), Opening);
+        assert_eq!(
+            next_tcp_state(Opening, TCP_SYN | TCP_ACK, false),
+            Established
+        );
+        assert_eq!(next_tcp_state(Established, TCP_ACK, true), Established);
+        assert_eq!(
+            next_tcp_state(Established, TCP_FIN | TCP_ACK, true),
+            Closing
+        );
+        assert_eq!(next_tcp_state(Closing, TCP_ACK, false), Closing);
+        assert_eq!(next_tcp_state(Closing, TCP_SYN, true), Opening);
+    }
+}
@@ -0,0 +1,343 @@
+//! Per-user and per-group bandwidth limits
+//!
+//! Every user has an upload and a download token bucket shared by all of its
+//! sessions; exit groups with a limit (set in the database, or configured)
+//! have another pair shared by all of their users, and traffic has to pass
+//! both. A bucket goes into debt
+//! for frames larger than what it holds, so callers just wait the returned
+//! delay before passing the frame on.
+
+use crate::config::RateLimitConfig;
+use crate::group_cache::UserProfile;
+use dashmap::DashMap;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Token bucket refilled at `rate` bytes per second up to `burst` bytes
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate_bps: u64, burst: u64) -> Self {
+        let burst = burst.max(1) as f64;
+        Self {
+            rate: rate_bps.max(1) as f64,
+            burst,
+            state: Mutex::new(BucketState {
+                tokens: burst,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `bytes`, returning how long to wait before passing them on
+    pub fn take(&self, bytes: u64) -> Duration {
+        self.take_at(bytes, Instant::now())
+    }
+
+    fn take_at(&self, bytes: u64, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state, now);
+
+        state.tokens -= bytes as f64;
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / self.rate)
+        }
+    }
+
+    /// Take `tokens` only if the bucket holds them, without going into debt
+    pub fn try_take(&self, tokens: u64) -> bool {
+        self.try_take_at(tokens, Instant::now())
+    }
+
+    fn try_take_at(&self, tokens: u64, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state, now);
+
+        let enough = state.tokens >= tokens as f64;
+        if enough {
+            state.tokens -= tokens as f64;
+        }
+        enough
+    }
+
+    fn refill(&self, state: &mut BucketState, now: Instant) {
+        let elapsed = now.saturating_duration_since(state.updated).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.burst);
+        state.updated = now;
+    }
+}
+
+/// Upload and download buckets; None is unlimited
+struct Buckets {
+    rates: (u64, u64),
+    up: Option<TokenBucket>,
+    down: Option<TokenBucket>,
+}
+
+impl Buckets {
+    fn new(up_bps: u64, down_bps: u64, burst: u64) -> Self {
+        let bucket = |rate| (rate > 0).then(|| TokenBucket::new(rate, burst));
+        Self {
+            rates: (up_bps, down_bps),
+            up: bucket(up_bps),
+            down: bucket(down_bps),
+        }
+    }
+}
+
+/// Shared state of all rate-limited sessions
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    users: DashMap<u64, Arc<Buckets>>,
+    /// Configured group limits, the defaults for groups without their own
+    group_defaults: HashMap<i32, (u64, u64)>,
+    groups: DashMap<i32, Arc<Buckets>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        let group_defaults = config
+            .groups
+            .iter()
+            .map(|g| (g.group_id, (g.up_bps, g.down_bps)))
+            .collect();
+
+        Self {
+            config: config.clone(),
+            users: DashMap::new(),
+            group_defaults,
+            groups: DashMap::new(),
+        }
+    }
+
+    /// Limits for one session of `user_id`
+    pub fn session(self: &Arc<Self>, user_id: u64, profile: &UserProfile) -> SessionLimiter {
+        let rates = (
+            profile
+                .rate_up_bps
+                .unwrap_or(self.config.user_up_bps.unwrap_or(0)),
+            profile
+                .rate_down_bps
+                .unwrap_or(self.config.user_down_bps.unwrap_or(0)),
+        );
+        let user = self.shared(&self.users, user_id, rates);
+
+        let defaults = self
+            .group_defaults
+            .get(&profile.group_id)
+            .copied()
+            .unwrap_or((0, 0));
+        let group_rates = (
+            profile.group_rate_up_bps.unwrap_or(defaults.0),
+            profile.group_rate_down_bps.unwrap_or(defaults.1),
+        );
+        let group = (group_rates != (0, 0))
+            .then(|| self.shared(&self.groups, profile.group_id, group_rates));
+
+        SessionLimiter {
+            limiter: self.clone(),
+            user_id,
+            user,
+            group_id: profile.group_id,
+            group,
+        }
+    }
+
+    /// Buckets of `key`, shared by its sessions unless its limits changed
+    fn shared<K>(&self, map: &DashMap<K, Arc<Buckets>>, key: K, rates: (u64, u64)) -> Arc<Buckets>
+    where
+        K: Eq + std::hash::Hash,
+    {
+        let burst = self.config.burst_bytes;
+        map.entry(key)
+            .and_modify(|buckets| {
+                if buckets.rates != rates {
+                    *buckets = Arc::new(Buckets::new(rates.0, rates.1, burst));
+                }
+            })
+            .or_insert_with(|| Arc::new(Buckets::new(rates.0, rates.1, burst)))
+            .clone()
+    }
+}
+
+/// Bandwidth limits applied to one session
+pub struct SessionLimiter {
+    limiter: Arc<RateLimiter>,
+    user_id: u64,
+    user: Arc<Buckets>,
+    group_id: i32,
+    group: Option<Arc<Buckets>>,
+}
+
+impl SessionLimiter {
+    /// Delay before forwarding `bytes` from the client
+    pub fn upload(&self, bytes: u64) -> Duration {
+        let group = self.group.as_ref().and_then(|g| g.up.as_ref());
+        Self::take([self.user.up.as_ref(), group], bytes)
+    }
+
+    /// Delay before sending `bytes` to the client
+    pub fn download(&self, bytes: u64) -> Duration {
+        let group = self.group.as_ref().and_then(|g| g.down.as_ref());
+        Self::take([self.user.down.as_ref(), group], bytes)
+    }
+
+    fn take(buckets: [Option<&TokenBucket>; 2], bytes: u64) -> Duration {
+        buckets
+            .into_iter()
+            .flatten()
+            .map(|bucket| bucket.take(bytes))
+            .max()
+            .unwrap_or(Duration::ZERO)
+    }
+}
+
+impl Drop for SessionLimiter {
+    fn drop(&mut self) {
+        // Held by the map and this session only: the last session ended
+        let last = |held: &Arc<Buckets>, buckets: &Arc<Buckets>| {
+            Arc::ptr_eq(held, buckets) && Arc::strong_count(buckets) <= 2
+        };
+        self.limiter
+            .users
+            .remove_if(&self.user_id, |_, buckets| last(&self.user, buckets));
+        if let Some(group) = &self.group {
+            self.limiter
+                .groups
+                .remove_if(&self.group_id, |_, buckets| last(group, buckets));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GroupRateLimit;
+
+    fn profile(rate_up_bps: Option<u64>) -> UserProfile {
+        UserProfile {
+            group_id: 1,
+            rate_up_bps,
+            rate_down_bps: None,
+            max_sessions: None,
+            max_devices: None,
+            group_rate_up_bps: None,
+            group_rate_down_bps: None,
+        }
+    }
+
+    /// Delays measured against the clock lose the time the test took
+    fn assert_close(delay: Duration, expected: Duration) {
+        assert!(delay <= expected && delay + Duration::from_millis(50) > expected);
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(1000, 500);
+        let start = Instant::now();
+
+        // The burst passes at once, the rest is paced at the rate
+        assert_eq!(bucket.take_at(500, start), Duration::ZERO);
+        assert_eq!(bucket.take_at(250, start), Duration::from_millis(250));
+
+        // Debt is paid off over time
+        let later = start + Duration::from_millis(125);
+        assert_eq!(bucket.take_at(250, later), Duration::from_millis(375));
+
+        // Idle time refills up to the burst only
+        let idle = later + Duration::from_secs(10);
+        assert_eq!(bucket.take_at(500, idle), Duration::ZERO);
+        assert_eq!(bucket.take_at(125, idle), Duration::from_millis(125));
+    }
+
+    #[test]
+    fn test_try_take() {
+        let bucket = TokenBucket::new(10, 2);
+        let start = Instant::now();
+
+        assert!(bucket.try_take_at(1, start));
+        assert!(bucket.try_take_at(1, start));
+        // Refusals cost nothing
+        assert!(!bucket.try_take_at(1, start));
+        assert!(!bucket.try_take_at(1, start));
+        assert!(bucket.try_take_at(1, start + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn test_session_limits() {
+        let config = RateLimitConfig {
+            user_up_bps: Some(1000),
+            user_down_bps: None,
+            burst_bytes: 100,
+            groups: vec![GroupRateLimit {
+                group_id: 1,
+                up_bps: 0,
+                down_bps: 100,
+            }],
+        };
+        let limiter = Arc::new(RateLimiter::new(&config));
+
+        let session = limiter.session(7, &profile(None));
+        assert_eq!(session.upload(100), Duration::ZERO);
+        assert_close(session.upload(100), Duration::from_millis(100));
+        // Only the group limits downloads
+        assert_eq!(session.download(100), Duration::ZERO);
+        assert_close(session.download(100), Duration::from_secs(1));
+
+        // A second session shares the user's bucket
+        let second = limiter.session(7, &profile(None));
+        assert_close(second.upload(100), Duration::from_millis(200));
+        drop(second);
+        assert!(limiter.users.contains_key(&7));
+
+        drop(session);
+        assert!(!limiter.users.contains_key(&7));
+
+        // The user's own limit overrides the default
+        let unlimited = limiter.session(8, &profile(Some(0)));
+        assert_eq!(unlimited.upload(1 << 20), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_group_limits_from_database() {
+        let config = RateLimitConfig {
+            burst_bytes: 100,
+            groups: vec![GroupRateLimit {
+                group_id: 1,
+                up_bps: 0,
+                down_bps: 100,
+            }],
+            ..Default::default()
+        };
+        let limiter = Arc::new(RateLimiter::new(&config));
+
+        // The group's own limits override the configured ones, 0 lifting them
+        let mut limited = profile(None);
+        limited.group_rate_up_bps = Some(100);
+        limited.group_rate_down_bps = Some(0);
+        let session = limiter.session(7, &limited);
+        assert_eq!(session.upload(100), Duration::ZERO);
+        assert_close(session.upload(100), Duration::from_secs(1));
+        assert_eq!(session.download(1 << 20), Duration::ZERO);
+
+        // Users of the group share its buckets
+        let other = limiter.session(8, &limited);
+        assert_close(other.upload(100), Duration::from_secs(2));
+        drop(session);
+        assert!(limiter.groups.contains_key(&1));
+        drop(other);
+        assert!(!limiter.groups.contains_key(&1));
+    }
+}
@@ -0,0 +1,357 @@
+//! DNS resolver for client `DohQuery` messages
+//!
+//! Queries are sent to the configured upstreams (UDP, TCP, DNS over TLS or
+//! DNS over HTTPS) in order. The upstream that last answered is tried first,
+//! so a dead resolver only costs one timeout until it recovers. Answers are
+//! shared between all connections through one TTL-bounded cache, and each user
+//! is limited to `max_qps_per_user` queries per second.
+
+use crate::config::{DnsConfig, DnsUpstream};
+use anyhow::{Result, anyhow};
+use apfsds_protocol::dns;
+use dashmap::DashMap;
+use rustls::pki_types::ServerName;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio_rustls::TlsConnector;
+use tracing::{debug, info, trace, warn};
+
+/// Upper bound on how long an answer is cached
+const MAX_CACHE_TTL: u32 = 3600;
+
+/// Maximum DNS message size accepted from a UDP upstream
+const MAX_UDP_SIZE: usize = 4096;
+
+/// Token bucket for one user
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Shared resolver used by all client connections
+pub struct DnsResolver {
+    upstreams: Vec<DnsUpstream>,
+    /// Index of the upstream that answered last
+    preferred: AtomicUsize,
+    timeout: Duration,
+    cache: Mutex<dns::AnswerCache>,
+    max_qps: u32,
+    buckets: DashMap<u64, Bucket>,
+    http: reqwest::Client,
+    tls: TlsConnector,
+}
+
+impl DnsResolver {
+    pub fn new(config: &DnsConfig) -> Result<Self> {
+        if config.upstreams.is_empty() {
+            return Err(anyhow!("No DNS upstreams configured"));
+        }
+
+        let timeout = Duration::from_millis(config.timeout_ms);
+        let http = reqwest::Client::builder().timeout(timeout).build()?;
+
+        let mut roots = rustls::RootCertStore::empty();
+        let native = rustls_native_certs::load_native_certs();
+        for e in &native.errors {
+            warn!("Failed to load system root certificate: {}", e);
+        }
+        roots.add_parsable_certificates(native.certs);
+        let tls_config = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+        info!("DNS resolver using {} upstream(s)", config.upstreams.len());
+
+        Ok(Self {
+            upstreams: config.upstreams.clone(),
+            preferred: AtomicUsize::new(0),
+            timeout,
+            cache: Mutex::new(dns::AnswerCache::new(config.cache_size, MAX_CACHE_TTL)),
+            max_qps: config.max_qps_per_user,
+            buckets: DashMap::new(),
+            http,
+            tls: TlsConnector::from(Arc::new(tls_config)),
+        })
+    }
+
+    /// Resolve a query on behalf of `user_id`
+    ///
+    /// Always answers a well-formed query: REFUSED when the user is over the
+    /// rate limit, SERVFAIL when every upstream failed. Returns None for
+    /// messages that are not queries.
+    pub async fn resolve(&self, user_id: u64, query: &[u8]) -> Option<Vec<u8>> {
+        let (question, _) = match dns::parse_query(query) {
+            Ok(q) => q,
+            Err(e) => {
+                debug!("Ignoring DNS message from user {}: {}", user_id, e);
+                return None;
+            }
+        };
+        let id = dns::message_id(query)?;
+
+        if !self.allow(user_id) {
+            debug!("DNS rate limit exceeded for user {}", user_id);
+            return dns::build_error(query, dns::RCODE_REFUSED).ok();
+        }
+
+        if let Some(answer) = self.cache.lock().unwrap().get(&question, id) {
+            trace!("DNS cache hit for {}", question.name);
+            return Some(answer);
+        }
+
+        let start = self.preferred.load(Ordering::Relaxed);
+        for i in 0..self.upstreams.len() {
+            let index = (start + i) % self.upstreams.len();
+            let upstream = &self.upstreams[index];
+
+            let response =
+                match tokio::time::timeout(self.timeout, self.exchange(upstream, query)).await {
+                    Ok(Ok(r)) => r,
+                    Ok(Err(e)) => {
+                        debug!("DNS upstream {:?} failed: {}", upstream, e);
+                        continue;
+                    }
+                    Err(_) => {
+                        debug!("DNS upstream {:?} timed out", upstream);
+                        continue;
+                    }
+                };
+
+            // Only accept an answer to the question that was asked
+            match dns::parse_question(&response) {
+                Ok((answered, _)) if answered == question => {}
+                _ => {
+                    debug!("DNS upstream {:?} sent a mismatched answer", upstream);
+                    continue;
+                }
+            }
+
+            if index != start {
+                info!("DNS failing over to {:?}", upstream);
+                self.preferred.store(index, Ordering::Relaxed);
+            }
+            self.cache.lock().unwrap().insert(question, &response);
+            return Some(response);
+        }
+
+        warn!("All DNS upstreams failed for {}", question.name);
+        dns::build_error(query, dns::RCODE_SERVFAIL).ok()
+    }
+
+    /// Take a token from the user's bucket
+    fn allow(&self, user_id: u64) -> bool {
+        if self.max_qps == 0 {
+            return true;
+        }
+        let rate = self.max_qps as f64;
+        let now = Instant::now();
+
+        let mut bucket = self.buckets.entry(user_id).or_insert(Bucket {
+            tokens: rate,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Send a query to one upstream and return its response
+    async fn exchange(&self, upstream: &DnsUpstream, query: &[u8]) -> Result<Vec<u8>> {
+        match upstream {
+            DnsUpstream::Udp { address } => {
+                let response = exchange_udp(*address, query).await?;
+                // Truncated: retry over TCP for the full answer
+                if response[2] & 0x02 != 0 {
+                    let stream = TcpStream::connect(address).await?;
+                    return exchange_stream(stream, query).await;
+                }
+                Ok(response)
+            }
+            DnsUpstream::Tcp { address } => {
+                let stream = TcpStream::connect(address).await?;
+                exchange_stream(stream, query).await
+            }
+            DnsUpstream::Tls {
+                address,
+                server_name,
+            } => {
+                let name = ServerName::try_from(server_name.clone())?;
+                let stream = TcpStream::connect(address).await?;
+                let stream = self.tls.connect(name, stream).await?;
+                exchange_stream(stream, query).await
+            }
+            DnsUpstream::Https { url } => {
+                let response = self
+                    .http
+                    .post(url)
+                    .header("Content-Type", "application/dns-message")
+                    .header("Accept", "application/dns-message")
+                    .body(query.to_vec())
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                Ok(response.bytes().await?.to_vec())
+            }
+        }
+    }
+}
+
+/// Send a query over UDP and wait for the matching response
+async fn exchange_udp(address: SocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+    let bind: SocketAddr = if address.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(address).await?;
+    socket.send(query).await?;
+
+    let mut buf = vec![0u8; MAX_UDP_SIZE];
+    loop {
+        let n = socket.recv(&mut buf).await?;
+        // Ignore stray datagrams that do not answer this query
+        if n >= dns::HEADER_LEN && buf[..2] == query[..2] {
+            return Ok(buf[..n].to_vec());
+        }
+    }
+}
+
+/// Send a length-prefixed query over a stream (TCP or TLS) and read the answer
+async fn exchange_stream<S>(mut stream: S, query: &[u8]) -> Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut msg = Vec::with_capacity(query.len() + 2);
+    msg.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    msg.extend_from_slice(query);
+    stream.write_all(&msg).await?;
+
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    let mut response = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut response).await?;
+
+    if response.len() < dns::HEADER_LEN || response[..2] != query[..2] {
+        return Err(anyhow!("Mismatched DNS response"));
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    /// UDP upstream answering every query with 192.0.2.1
+    async fn fake_upstream() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            while let Ok((n, src)) = socket.recv_from(&mut buf).await {
+                let answer = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+                let response = dns::build_response(&buf[..n], &[answer], 60).unwrap();
+                let _ = socket.send_to(&response, src).await;
+            }
+        });
+        addr
+    }
+
+    /// A UDP address nobody answers on
+    async fn dead_upstream() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        // Keep the port bound so sends do not fail fast
+        tokio::spawn(async move {
+            let _socket = socket;
+            std::future::pending::<()>().await;
+        });
+        addr
+    }
+
+    fn resolver(upstreams: Vec<SocketAddr>, max_qps_per_user: u32) -> DnsResolver {
+        DnsResolver::new(&DnsConfig {
+            upstreams: upstreams
+                .into_iter()
+                .map(|address| DnsUpstream::Udp { address })
+                .collect(),
+            timeout_ms: 200,
+            cache_size: 16,
+            max_qps_per_user,
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_failover_and_cache() {
+        let dead = dead_upstream().await;
+        let live = fake_upstream().await;
+        let resolver = resolver(vec![dead, live], 0);
+
+        let response = resolver
+            .resolve(1, &dns::build_query(5, "a.test", dns::TYPE_A))
+            .await
+            .unwrap();
+        assert_eq!(dns::message_id(&response), Some(5));
+        assert_eq!(dns::rcode(&response), Some(0));
+        assert_eq!(resolver.preferred.load(Ordering::Relaxed), 1);
+
+        // Served from cache with the new ID
+        let cached = resolver
+            .resolve(2, &dns::build_query(6, "a.test", dns::TYPE_A))
+            .await
+            .unwrap();
+        assert_eq!(dns::message_id(&cached), Some(6));
+        assert_eq!(resolver.cache.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_all_upstreams_fail() {
+        let resolver = resolver(vec![dead_upstream().await], 0);
+        let response = resolver
+            .resolve(1, &dns::build_query(5, "a.test", dns::TYPE_A))
+            .await
+            .unwrap();
+        assert_eq!(dns::rcode(&response), Some(dns::RCODE_SERVFAIL));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let resolver = resolver(vec![fake_upstream().await], 2);
+
+        for _ in 0..2 {
+            let response = resolver
+                .resolve(1, &dns::build_query(1, "a.test", dns::TYPE_A))
+                .await
+                .unwrap();
+            assert_eq!(dns::rcode(&response), Some(0));
+        }
+        let refused = resolver
+            .resolve(1, &dns::build_query(1, "a.test", dns::TYPE_A))
+            .await
+            .unwrap();
+        assert_eq!(dns::rcode(&refused), Some(dns::RCODE_REFUSED));
+
+        // Other users have their own budget
+        let response = resolver
+            .resolve(2, &dns::build_query(1, "a.test", dns::TYPE_A))
+            .await
+            .unwrap();
+        assert_eq!(dns::rcode(&response), Some(0));
+    }
+}